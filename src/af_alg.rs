@@ -0,0 +1,448 @@
+use std::convert::TryInto;
+use std::io::{Error as IoError, Result as IoResult};
+use std::mem;
+use std::os::unix::io::RawFd;
+use std::ptr;
+
+use openssl::symm::Mode;
+use zeroize::Zeroize;
+
+use crate::{CryptoIoError, SymmetricBackend};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AfAlgCipher {
+    Aes128Cbc,
+    Aes192Cbc,
+    Aes256Cbc,
+    Aes128Ctr,
+    Aes192Ctr,
+    Aes256Ctr,
+}
+impl AfAlgCipher {
+    // the kernel's ctr(aes) drops the rest of a partial keystream block at the end of each
+    // request, so CTR is built here on ecb(aes) instead
+    fn kernel_name(self) -> &'static [u8] {
+        if self.is_cbc() {
+            b"cbc(aes)"
+        } else {
+            b"ecb(aes)"
+        }
+    }
+
+    fn is_cbc(self) -> bool {
+        matches!(
+            self,
+            AfAlgCipher::Aes128Cbc | AfAlgCipher::Aes192Cbc | AfAlgCipher::Aes256Cbc
+        )
+    }
+
+    pub fn key_len(self) -> usize {
+        match self {
+            AfAlgCipher::Aes128Cbc | AfAlgCipher::Aes128Ctr => 16,
+            AfAlgCipher::Aes192Cbc | AfAlgCipher::Aes192Ctr => 24,
+            AfAlgCipher::Aes256Cbc | AfAlgCipher::Aes256Ctr => 32,
+        }
+    }
+
+    pub fn iv_len(self) -> usize {
+        16
+    }
+
+    pub fn block_size(self) -> usize {
+        if self.is_cbc() {
+            16
+        } else {
+            1
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AfAlgCipher::Aes128Cbc => "AES-128-CBC",
+            AfAlgCipher::Aes192Cbc => "AES-192-CBC",
+            AfAlgCipher::Aes256Cbc => "AES-256-CBC",
+            AfAlgCipher::Aes128Ctr => "AES-128-CTR",
+            AfAlgCipher::Aes192Ctr => "AES-192-CTR",
+            AfAlgCipher::Aes256Ctr => "AES-256-CTR",
+        }
+    }
+}
+
+const CHUNK_LEN: usize = 16 * 1024;
+
+struct Fd(RawFd);
+impl Drop for Fd {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.0) };
+    }
+}
+
+fn cvt(res: libc::c_int) -> IoResult<libc::c_int> {
+    if res < 0 {
+        Err(IoError::last_os_error())
+    } else {
+        Ok(res)
+    }
+}
+
+// Offloads AES to the Linux kernel crypto API, which uses whatever driver the kernel prefers
+// (including dedicated crypto blocks on SoCs). The kernel does no padding, so PKCS#7 is applied
+// here and the output matches the OpenSSL ciphers. Calls are blocking syscalls on the caller's
+// thread.
+pub struct AfAlgBackend {
+    cipher: AfAlgCipher,
+    mode: Mode,
+    op: Fd,
+    _tfm: Fd,
+    // the operation (and for CBC the IV) still has to be sent with the first message; after that
+    // the kernel carries the chaining state
+    first: bool,
+    iv: Vec<u8>,
+    // input not yet sent: a partial block, or when decrypting CBC, the last full block (which
+    // carries the padding) until finalize
+    pending: Vec<u8>,
+    // unused CTR keystream
+    keystream: Vec<u8>,
+}
+impl AfAlgBackend {
+    pub fn new(
+        cipher: AfAlgCipher,
+        mode: Mode,
+        key: &[u8],
+        iv: &[u8],
+    ) -> Result<Self, CryptoIoError> {
+        if key.len() != cipher.key_len() {
+            return Err(CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_len(),
+                actual: key.len(),
+            });
+        }
+        if iv.len() != cipher.iv_len() {
+            return Err(CryptoIoError::InvalidIvLen {
+                expected: cipher.iv_len(),
+                actual: iv.len(),
+            });
+        }
+        unsafe {
+            let tfm = Fd(cvt(libc::socket(
+                libc::AF_ALG,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+            ))?);
+            let mut addr: libc::sockaddr_alg = mem::zeroed();
+            addr.salg_family = libc::AF_ALG as libc::sa_family_t;
+            addr.salg_type[..8].copy_from_slice(b"skcipher");
+            let name = cipher.kernel_name();
+            addr.salg_name[..name.len()].copy_from_slice(name);
+            cvt(libc::bind(
+                tfm.0,
+                &addr as *const _ as *const libc::sockaddr,
+                mem::size_of::<libc::sockaddr_alg>() as libc::socklen_t,
+            ))?;
+            cvt(libc::setsockopt(
+                tfm.0,
+                libc::SOL_ALG,
+                libc::ALG_SET_KEY,
+                key.as_ptr() as *const libc::c_void,
+                key.len() as libc::socklen_t,
+            ))?;
+            let op = Fd(cvt(libc::accept4(
+                tfm.0,
+                ptr::null_mut(),
+                ptr::null_mut(),
+                libc::SOCK_CLOEXEC,
+            ))?);
+            Ok(AfAlgBackend {
+                cipher,
+                mode,
+                op,
+                _tfm: tfm,
+                first: true,
+                iv: iv.to_vec(),
+                pending: Vec::new(),
+                keystream: Vec::new(),
+            })
+        }
+    }
+
+    pub fn cipher(&self) -> AfAlgCipher {
+        self.cipher
+    }
+
+    // runs whole blocks of `input` through the kernel into `output`, in pieces small enough that a
+    // send never waits on the socket buffer
+    fn process(&mut self, input: &[u8], more: bool, output: &mut [u8]) -> IoResult<()> {
+        let chunks = input.chunks(CHUNK_LEN).zip(output.chunks_mut(CHUNK_LEN));
+        let count = chunks.len();
+        for (i, (input, output)) in chunks.enumerate() {
+            self.process_chunk(input, more || i + 1 < count, output)?;
+        }
+        Ok(())
+    }
+
+    fn process_chunk(&mut self, input: &[u8], more: bool, output: &mut [u8]) -> IoResult<()> {
+        let mut control = Vec::new();
+        if self.first {
+            let op = match (self.cipher.is_cbc(), self.mode) {
+                (true, Mode::Decrypt) => libc::ALG_OP_DECRYPT,
+                _ => libc::ALG_OP_ENCRYPT,
+            };
+            push_cmsg(&mut control, libc::ALG_SET_OP, &(op as u32).to_ne_bytes());
+            if self.cipher.is_cbc() {
+                let mut alg_iv = (self.iv.len() as u32).to_ne_bytes().to_vec();
+                alg_iv.extend_from_slice(&self.iv);
+                push_cmsg(&mut control, libc::ALG_SET_IV, &alg_iv);
+            }
+        }
+        let len = input.len();
+        let mut iov = libc::iovec {
+            iov_base: input.as_ptr() as *mut libc::c_void,
+            iov_len: len,
+        };
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        if !control.is_empty() {
+            msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+            msg.msg_controllen = control.len() as _;
+        }
+        let flags = if more { libc::MSG_MORE } else { 0 };
+        let sent = unsafe { libc::sendmsg(self.op.0, &msg, flags) };
+        if sent < 0 {
+            return Err(IoError::last_os_error());
+        }
+        if sent as usize != len {
+            return Err(IoError::other("short write to AF_ALG socket"));
+        }
+        self.first = false;
+        let mut read = 0;
+        while read < len {
+            let n = unsafe {
+                libc::read(
+                    self.op.0,
+                    output[read..len].as_mut_ptr() as *mut libc::c_void,
+                    len - read,
+                )
+            };
+            if n < 0 {
+                return Err(IoError::last_os_error());
+            }
+            if n == 0 {
+                return Err(IoError::other("AF_ALG socket closed"));
+            }
+            read += n as usize;
+        }
+        Ok(())
+    }
+
+    // sends the first `len` bytes of `pending`
+    fn process_pending(&mut self, len: usize, more: bool, output: &mut [u8]) -> IoResult<()> {
+        let mut pending = mem::take(&mut self.pending);
+        let res = self.process(&pending[..len], more, output);
+        pending.drain(..len);
+        self.pending = pending;
+        res
+    }
+
+    fn ctr(&mut self, input: &[u8], output: &mut [u8]) -> IoResult<()> {
+        if self.keystream.len() < input.len() {
+            let blocks = (input.len() - self.keystream.len()).div_ceil(16);
+            let mut counters = Vec::with_capacity(blocks * 16);
+            for _ in 0..blocks {
+                counters.extend_from_slice(&self.iv);
+                let counter = u128::from_be_bytes(self.iv[..].try_into().unwrap());
+                self.iv
+                    .copy_from_slice(&counter.wrapping_add(1).to_be_bytes());
+            }
+            let start = self.keystream.len();
+            self.keystream.resize(start + counters.len(), 0);
+            let mut keystream = mem::take(&mut self.keystream);
+            let res = self.process(&counters, true, &mut keystream[start..]);
+            self.keystream = keystream;
+            res?;
+        }
+        for ((o, i), k) in output.iter_mut().zip(input).zip(&self.keystream) {
+            *o = i ^ k;
+        }
+        self.keystream.drain(..input.len());
+        Ok(())
+    }
+}
+
+fn push_cmsg(control: &mut Vec<u8>, ty: libc::c_int, data: &[u8]) {
+    unsafe {
+        let start = control.len();
+        control.resize(start + libc::CMSG_SPACE(data.len() as u32) as usize, 0);
+        // a Vec<u8> is not aligned for cmsghdr
+        let hdr = control[start..].as_mut_ptr() as *mut libc::cmsghdr;
+        let mut header: libc::cmsghdr = mem::zeroed();
+        header.cmsg_level = libc::SOL_ALG;
+        header.cmsg_type = ty;
+        header.cmsg_len = libc::CMSG_LEN(data.len() as u32) as _;
+        ptr::write_unaligned(hdr, header);
+        ptr::copy_nonoverlapping(data.as_ptr(), libc::CMSG_DATA(hdr), data.len());
+    }
+}
+
+impl SymmetricBackend for AfAlgBackend {
+    fn update(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        if !self.cipher.is_cbc() {
+            self.ctr(input, &mut output[..input.len()])?;
+            return Ok(input.len());
+        }
+        let block_size = self.cipher.block_size();
+        self.pending.extend_from_slice(input);
+        let ready = match self.mode {
+            Mode::Encrypt => self.pending.len() / block_size * block_size,
+            Mode::Decrypt => self.pending.len().saturating_sub(1) / block_size * block_size,
+        };
+        if ready > 0 {
+            self.process_pending(ready, true, output)?;
+        }
+        Ok(ready)
+    }
+
+    fn finalize(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        let block_size = self.cipher.block_size();
+        if block_size == 1 {
+            return Ok(0);
+        }
+        match self.mode {
+            Mode::Encrypt => {
+                let pad = block_size - self.pending.len();
+                self.pending.resize(block_size, pad as u8);
+                self.process_pending(block_size, false, output)?;
+                Ok(block_size)
+            }
+            Mode::Decrypt => {
+                if self.pending.len() != block_size {
+                    return Err(CryptoIoError::Truncated);
+                }
+                self.process_pending(block_size, false, output)?;
+                let pad = output[block_size - 1] as usize;
+                if pad == 0
+                    || pad > block_size
+                    || output[block_size - pad..block_size]
+                        .iter()
+                        .any(|b| *b as usize != pad)
+                {
+                    return Err(CryptoIoError::BadPadding);
+                }
+                Ok(block_size - pad)
+            }
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        self.cipher.block_size()
+    }
+
+    fn name(&self) -> &'static str {
+        self.cipher.name()
+    }
+}
+
+impl Drop for AfAlgBackend {
+    fn drop(&mut self) {
+        self.pending.zeroize();
+        self.keystream.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Result as IoResult;
+
+    use openssl::symm::{Cipher, Mode};
+
+    use super::{AfAlgBackend, AfAlgCipher};
+    use crate::testing::{block_on, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter, OpensslBackend};
+
+    // these need a kernel (and sandbox) that lets us open AF_ALG sockets, so they are opt-in:
+    // run them with `cargo test --features af-alg -- --ignored`
+
+    fn ciphers() -> Vec<(AfAlgCipher, Cipher)> {
+        vec![
+            (AfAlgCipher::Aes128Cbc, Cipher::aes_128_cbc()),
+            (AfAlgCipher::Aes192Cbc, Cipher::aes_192_cbc()),
+            (AfAlgCipher::Aes256Cbc, Cipher::aes_256_cbc()),
+            (AfAlgCipher::Aes128Ctr, Cipher::aes_128_ctr()),
+            (AfAlgCipher::Aes192Ctr, Cipher::aes_192_ctr()),
+            (AfAlgCipher::Aes256Ctr, Cipher::aes_256_ctr()),
+        ]
+    }
+
+    fn key_iv(cipher: AfAlgCipher) -> (Vec<u8>, Vec<u8>) {
+        (vec![0x42; cipher.key_len()], vec![0x24; cipher.iv_len()])
+    }
+
+    fn backend(cipher: AfAlgCipher, mode: Mode) -> AfAlgBackend {
+        let (key, iv) = key_iv(cipher);
+        AfAlgBackend::new(cipher, mode, &key, &iv).expect("AF_ALG unavailable")
+    }
+
+    fn seal_openssl(cipher: AfAlgCipher, openssl: Cipher, plaintext: &[u8]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let backend = OpensslBackend::new(openssl, Mode::Encrypt, &key, Some(&iv)).unwrap();
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::with_backend(&mut out, backend);
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn open(backend: AfAlgBackend, ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        let mut reader = DecryptReader::with_backend(ciphertext, backend);
+        block_on(read_to_end(&mut reader))
+    }
+
+    // the backends are interchangeable: each decrypts what the other encrypts
+    #[test]
+    #[ignore = "needs AF_ALG sockets"]
+    fn matches_openssl() {
+        for (cipher, openssl) in ciphers() {
+            for &len in &[0, 1, 15, 16, 17, 2500, 100_000] {
+                let plaintext = sample(len);
+                let mut out = Vec::new();
+                block_on(async {
+                    let encrypter = backend(cipher, Mode::Encrypt);
+                    let mut writer = EncryptWriter::with_backend(&mut out, encrypter);
+                    write_all(&mut writer, &plaintext).await.unwrap();
+                    shutdown(&mut writer).await.unwrap();
+                });
+                let expected = seal_openssl(cipher, openssl, &plaintext);
+                assert_eq!(out, expected, "{} at {} bytes", cipher.name(), len);
+                let decrypter = backend(cipher, Mode::Decrypt);
+                assert_eq!(open(decrypter, &expected).unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    #[ignore = "needs AF_ALG sockets"]
+    fn bad_padding() {
+        let cipher = AfAlgCipher::Aes128Cbc;
+        let mut ciphertext = seal_openssl(cipher, Cipher::aes_128_cbc(), &sample(100));
+        *ciphertext.last_mut().unwrap() ^= 1;
+        let e = open(backend(cipher, Mode::Decrypt), &ciphertext).unwrap_err();
+        let root = CryptoIoError::from_io(&e).map(|e| e.root());
+        assert!(matches!(root, Some(CryptoIoError::BadPadding)));
+    }
+
+    // the lengths are checked before any socket is opened, so this runs everywhere
+    #[test]
+    fn invalid_lengths() {
+        let cipher = AfAlgCipher::Aes128Cbc;
+        let res = AfAlgBackend::new(cipher, Mode::Encrypt, &[0; 32], &[0; 16]);
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidKeyLen {
+                expected: 16,
+                actual: 32
+            })
+        ));
+    }
+}
@@ -0,0 +1,295 @@
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::str::FromStr;
+
+use openssl::symm::Cipher;
+
+use crate::CryptoIoError;
+
+// A cipher named independently of the backend that runs it, for configs (by `name`, which is
+// what serde uses) and wire headers (by `id`), so neither depends on OpenSSL's NIDs or on which
+// backend is enabled. The names are OpenSSL's conventional ones, parsed case-insensitively.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    Aes128Cbc,
+    Aes192Cbc,
+    Aes256Cbc,
+    Aes128Ctr,
+    Aes192Ctr,
+    Aes256Ctr,
+    Aes128Gcm,
+    Aes256Gcm,
+    ChaCha20,
+    ChaCha20Poly1305,
+}
+impl Algorithm {
+    pub const ALL: &'static [Algorithm] = &[
+        Algorithm::Aes128Cbc,
+        Algorithm::Aes192Cbc,
+        Algorithm::Aes256Cbc,
+        Algorithm::Aes128Ctr,
+        Algorithm::Aes192Ctr,
+        Algorithm::Aes256Ctr,
+        Algorithm::Aes128Gcm,
+        Algorithm::Aes256Gcm,
+        Algorithm::ChaCha20,
+        Algorithm::ChaCha20Poly1305,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Algorithm::Aes128Cbc => "AES-128-CBC",
+            Algorithm::Aes192Cbc => "AES-192-CBC",
+            Algorithm::Aes256Cbc => "AES-256-CBC",
+            Algorithm::Aes128Ctr => "AES-128-CTR",
+            Algorithm::Aes192Ctr => "AES-192-CTR",
+            Algorithm::Aes256Ctr => "AES-256-CTR",
+            Algorithm::Aes128Gcm => "AES-128-GCM",
+            Algorithm::Aes256Gcm => "AES-256-GCM",
+            Algorithm::ChaCha20 => "ChaCha20",
+            Algorithm::ChaCha20Poly1305 => "ChaCha20-Poly1305",
+        }
+    }
+
+    // the byte that stands for this algorithm in headers; these never change or get reused
+    pub fn id(self) -> u8 {
+        match self {
+            Algorithm::Aes128Cbc => 1,
+            Algorithm::Aes192Cbc => 2,
+            Algorithm::Aes256Cbc => 3,
+            Algorithm::Aes128Ctr => 4,
+            Algorithm::Aes192Ctr => 5,
+            Algorithm::Aes256Ctr => 6,
+            Algorithm::Aes128Gcm => 7,
+            Algorithm::Aes256Gcm => 8,
+            Algorithm::ChaCha20 => 9,
+            Algorithm::ChaCha20Poly1305 => 10,
+        }
+    }
+
+    pub fn from_id(id: u8) -> Result<Self, CryptoIoError> {
+        Algorithm::ALL
+            .iter()
+            .copied()
+            .find(|a| a.id() == id)
+            .ok_or(CryptoIoError::UnknownAlgorithm(id))
+    }
+
+    pub fn key_len(self) -> usize {
+        match self {
+            Algorithm::Aes128Cbc | Algorithm::Aes128Ctr | Algorithm::Aes128Gcm => 16,
+            Algorithm::Aes192Cbc | Algorithm::Aes192Ctr => 24,
+            _ => 32,
+        }
+    }
+
+    // the IV length the adapters expect; the GCM modes accept others, but 12 bytes is the one
+    // to use
+    pub fn iv_len(self) -> usize {
+        match self {
+            Algorithm::Aes128Gcm | Algorithm::Aes256Gcm | Algorithm::ChaCha20Poly1305 => 12,
+            _ => 16,
+        }
+    }
+
+    pub fn block_size(self) -> usize {
+        match self {
+            Algorithm::Aes128Cbc | Algorithm::Aes192Cbc | Algorithm::Aes256Cbc => 16,
+            _ => 1,
+        }
+    }
+
+    pub fn is_aead(self) -> bool {
+        matches!(
+            self,
+            Algorithm::Aes128Gcm | Algorithm::Aes256Gcm | Algorithm::ChaCha20Poly1305
+        )
+    }
+
+    // the OpenSSL cipher, for `EncryptWriter::new` and the other OpenSSL-backed constructors
+    pub fn cipher(self) -> Cipher {
+        match self {
+            Algorithm::Aes128Cbc => Cipher::aes_128_cbc(),
+            Algorithm::Aes192Cbc => Cipher::aes_192_cbc(),
+            Algorithm::Aes256Cbc => Cipher::aes_256_cbc(),
+            Algorithm::Aes128Ctr => Cipher::aes_128_ctr(),
+            Algorithm::Aes192Ctr => Cipher::aes_192_ctr(),
+            Algorithm::Aes256Ctr => Cipher::aes_256_ctr(),
+            Algorithm::Aes128Gcm => Cipher::aes_128_gcm(),
+            Algorithm::Aes256Gcm => Cipher::aes_256_gcm(),
+            Algorithm::ChaCha20 => Cipher::chacha20(),
+            Algorithm::ChaCha20Poly1305 => Cipher::chacha20_poly1305(),
+        }
+    }
+
+    // None for OpenSSL ciphers without a counterpart here
+    pub fn from_cipher(cipher: Cipher) -> Option<Self> {
+        Algorithm::ALL
+            .iter()
+            .copied()
+            .find(|a| a.cipher().nid() == cipher.nid())
+    }
+
+    // None where the pure-Rust backend lacks the cipher (the AEADs)
+    #[cfg(feature = "rustcrypto")]
+    pub fn rustcrypto_cipher(self) -> Option<crate::RustCryptoCipher> {
+        use crate::RustCryptoCipher;
+        Some(match self {
+            Algorithm::Aes128Cbc => RustCryptoCipher::Aes128Cbc,
+            Algorithm::Aes192Cbc => RustCryptoCipher::Aes192Cbc,
+            Algorithm::Aes256Cbc => RustCryptoCipher::Aes256Cbc,
+            Algorithm::Aes128Ctr => RustCryptoCipher::Aes128Ctr,
+            Algorithm::Aes192Ctr => RustCryptoCipher::Aes192Ctr,
+            Algorithm::Aes256Ctr => RustCryptoCipher::Aes256Ctr,
+            Algorithm::ChaCha20 => RustCryptoCipher::ChaCha20,
+            _ => return None,
+        })
+    }
+
+    // None outside AES-CBC and AES-CTR
+    #[cfg(feature = "aws-lc-rs")]
+    pub fn aws_lc_cipher(self) -> Option<crate::AwsLcCipher> {
+        use crate::AwsLcCipher;
+        Some(match self {
+            Algorithm::Aes128Cbc => AwsLcCipher::Aes128Cbc,
+            Algorithm::Aes192Cbc => AwsLcCipher::Aes192Cbc,
+            Algorithm::Aes256Cbc => AwsLcCipher::Aes256Cbc,
+            Algorithm::Aes128Ctr => AwsLcCipher::Aes128Ctr,
+            Algorithm::Aes192Ctr => AwsLcCipher::Aes192Ctr,
+            Algorithm::Aes256Ctr => AwsLcCipher::Aes256Ctr,
+            _ => return None,
+        })
+    }
+
+    // None outside AES-CBC and AES-CTR
+    #[cfg(all(target_os = "linux", feature = "af-alg"))]
+    pub fn af_alg_cipher(self) -> Option<crate::AfAlgCipher> {
+        use crate::AfAlgCipher;
+        Some(match self {
+            Algorithm::Aes128Cbc => AfAlgCipher::Aes128Cbc,
+            Algorithm::Aes192Cbc => AfAlgCipher::Aes192Cbc,
+            Algorithm::Aes256Cbc => AfAlgCipher::Aes256Cbc,
+            Algorithm::Aes128Ctr => AfAlgCipher::Aes128Ctr,
+            Algorithm::Aes192Ctr => AfAlgCipher::Aes192Ctr,
+            Algorithm::Aes256Ctr => AfAlgCipher::Aes256Ctr,
+            _ => return None,
+        })
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.pad(self.name())
+    }
+}
+
+impl FromStr for Algorithm {
+    type Err = CryptoIoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Algorithm::ALL
+            .iter()
+            .copied()
+            .find(|a| a.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| {
+                IoError::new(
+                    IoErrorKind::InvalidInput,
+                    format!("unknown algorithm {}", s),
+                )
+                .into()
+            })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Algorithm {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Algorithm {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use openssl::symm::Cipher;
+
+    use super::Algorithm;
+    use crate::CryptoIoError;
+
+    #[test]
+    fn matches_openssl() {
+        for &algorithm in Algorithm::ALL {
+            let cipher = algorithm.cipher();
+            assert_eq!(algorithm.key_len(), cipher.key_len());
+            assert_eq!(Some(algorithm.iv_len()), cipher.iv_len());
+            assert_eq!(algorithm.block_size(), cipher.block_size());
+            assert_eq!(Algorithm::from_cipher(cipher), Some(algorithm));
+        }
+        assert_eq!(Algorithm::from_cipher(Cipher::aes_128_ecb()), None);
+        let aeads: Vec<_> = Algorithm::ALL.iter().filter(|a| a.is_aead()).collect();
+        assert_eq!(
+            aeads,
+            [
+                &Algorithm::Aes128Gcm,
+                &Algorithm::Aes256Gcm,
+                &Algorithm::ChaCha20Poly1305
+            ]
+        );
+    }
+
+    // ids are on the wire, so they must never change
+    #[test]
+    fn ids() {
+        let ids: Vec<u8> = Algorithm::ALL.iter().map(|a| a.id()).collect();
+        assert_eq!(ids, (1..=10).collect::<Vec<u8>>());
+        for &algorithm in Algorithm::ALL {
+            assert_eq!(Algorithm::from_id(algorithm.id()).unwrap(), algorithm);
+        }
+        assert!(matches!(
+            Algorithm::from_id(0),
+            Err(CryptoIoError::UnknownAlgorithm(0))
+        ));
+    }
+
+    #[test]
+    fn names() {
+        let names: HashSet<_> = Algorithm::ALL.iter().map(|a| a.name()).collect();
+        assert_eq!(names.len(), Algorithm::ALL.len());
+        for &algorithm in Algorithm::ALL {
+            assert_eq!(
+                algorithm.to_string().parse::<Algorithm>().unwrap(),
+                algorithm
+            );
+        }
+        assert_eq!(
+            "aes-256-gcm".parse::<Algorithm>().unwrap(),
+            Algorithm::Aes256Gcm
+        );
+        assert_eq!(format!("{:>12}", Algorithm::ChaCha20), "    ChaCha20");
+        assert!("AES-512-GCM".parse::<Algorithm>().is_err());
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serde() {
+        use serde::de::value::{Error, StrDeserializer};
+        use serde::Deserialize;
+
+        let de = StrDeserializer::<Error>::new("chacha20-poly1305");
+        assert_eq!(
+            Algorithm::deserialize(de).unwrap(),
+            Algorithm::ChaCha20Poly1305
+        );
+        let de = StrDeserializer::<Error>::new("rot13");
+        assert!(Algorithm::deserialize(de).is_err());
+    }
+}
@@ -0,0 +1,311 @@
+use std::convert::TryFrom;
+use std::io::Error as IoError;
+
+use aws_lc_rs::cipher::{
+    Algorithm, DecryptionContext, EncryptionContext, StreamingDecryptingKey,
+    StreamingEncryptingKey, UnboundCipherKey, AES_128, AES_192, AES_256,
+};
+use aws_lc_rs::error::Unspecified;
+use aws_lc_rs::iv::FixedLength;
+use openssl::symm::Mode;
+use zeroize::Zeroize;
+
+use crate::{CryptoIoError, SymmetricBackend};
+
+// AWS-LC sizes its output checks by the AES block even in CTR mode
+const AES_BLOCK: usize = 16;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AwsLcCipher {
+    Aes128Cbc,
+    Aes192Cbc,
+    Aes256Cbc,
+    Aes128Ctr,
+    Aes192Ctr,
+    Aes256Ctr,
+}
+impl AwsLcCipher {
+    fn algorithm(self) -> &'static Algorithm {
+        match self {
+            AwsLcCipher::Aes128Cbc | AwsLcCipher::Aes128Ctr => &AES_128,
+            AwsLcCipher::Aes192Cbc | AwsLcCipher::Aes192Ctr => &AES_192,
+            AwsLcCipher::Aes256Cbc | AwsLcCipher::Aes256Ctr => &AES_256,
+        }
+    }
+
+    fn is_cbc(self) -> bool {
+        matches!(
+            self,
+            AwsLcCipher::Aes128Cbc | AwsLcCipher::Aes192Cbc | AwsLcCipher::Aes256Cbc
+        )
+    }
+
+    pub fn key_len(self) -> usize {
+        match self {
+            AwsLcCipher::Aes128Cbc | AwsLcCipher::Aes128Ctr => 16,
+            AwsLcCipher::Aes192Cbc | AwsLcCipher::Aes192Ctr => 24,
+            AwsLcCipher::Aes256Cbc | AwsLcCipher::Aes256Ctr => 32,
+        }
+    }
+
+    pub fn iv_len(self) -> usize {
+        16
+    }
+
+    pub fn block_size(self) -> usize {
+        if self.is_cbc() {
+            16
+        } else {
+            1
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            AwsLcCipher::Aes128Cbc => "AES-128-CBC",
+            AwsLcCipher::Aes192Cbc => "AES-192-CBC",
+            AwsLcCipher::Aes256Cbc => "AES-256-CBC",
+            AwsLcCipher::Aes128Ctr => "AES-128-CTR",
+            AwsLcCipher::Aes192Ctr => "AES-192-CTR",
+            AwsLcCipher::Aes256Ctr => "AES-256-CTR",
+        }
+    }
+}
+
+enum Key {
+    Encrypt(StreamingEncryptingKey),
+    Decrypt(StreamingDecryptingKey),
+}
+
+// Drives AWS-LC's streaming cipher API, for deployments that standardize on AWS-LC (including its
+// FIPS build). Output is identical to the corresponding OpenSSL ciphers.
+pub struct AwsLcBackend {
+    cipher: AwsLcCipher,
+    // taken by finalize, which consumes the key
+    key: Option<Key>,
+    // stands in for an output buffer smaller than AWS-LC requires
+    scratch: Vec<u8>,
+}
+impl AwsLcBackend {
+    pub fn new(
+        cipher: AwsLcCipher,
+        mode: Mode,
+        key: &[u8],
+        iv: &[u8],
+    ) -> Result<Self, CryptoIoError> {
+        if key.len() != cipher.key_len() {
+            return Err(CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_len(),
+                actual: key.len(),
+            });
+        }
+        let iv = match FixedLength::<16>::try_from(iv) {
+            Ok(a) => a,
+            Err(_) => {
+                return Err(CryptoIoError::InvalidIvLen {
+                    expected: cipher.iv_len(),
+                    actual: iv.len(),
+                })
+            }
+        };
+        let unbound = UnboundCipherKey::new(cipher.algorithm(), key).map_err(aws_lc_err)?;
+        let key = match (mode, cipher.is_cbc()) {
+            (Mode::Encrypt, true) => {
+                StreamingEncryptingKey::less_safe_cbc_pkcs7(unbound, EncryptionContext::Iv128(iv))
+                    .map(Key::Encrypt)
+            }
+            (Mode::Encrypt, false) => {
+                StreamingEncryptingKey::less_safe_ctr(unbound, EncryptionContext::Iv128(iv))
+                    .map(Key::Encrypt)
+            }
+            (Mode::Decrypt, true) => {
+                StreamingDecryptingKey::cbc_pkcs7(unbound, DecryptionContext::Iv128(iv))
+                    .map(Key::Decrypt)
+            }
+            (Mode::Decrypt, false) => {
+                StreamingDecryptingKey::ctr(unbound, DecryptionContext::Iv128(iv)).map(Key::Decrypt)
+            }
+        }
+        .map_err(aws_lc_err)?;
+        Ok(AwsLcBackend {
+            cipher,
+            key: Some(key),
+            scratch: Vec::new(),
+        })
+    }
+
+    pub fn cipher(&self) -> AwsLcCipher {
+        self.cipher
+    }
+}
+
+fn aws_lc_err(e: Unspecified) -> CryptoIoError {
+    CryptoIoError::Io(IoError::other(e))
+}
+
+impl AwsLcBackend {
+    fn with_room<F>(
+        &mut self,
+        needed: usize,
+        output: &mut [u8],
+        f: F,
+    ) -> Result<usize, CryptoIoError>
+    where
+        F: FnOnce(&mut Self, &mut [u8]) -> Result<usize, CryptoIoError>,
+    {
+        if output.len() >= needed {
+            return f(self, output);
+        }
+        let mut scratch = std::mem::take(&mut self.scratch);
+        scratch.resize(needed, 0);
+        let res = f(self, &mut scratch);
+        if let Ok(n) = res {
+            output[..n].copy_from_slice(&scratch[..n]);
+        }
+        scratch.zeroize();
+        self.scratch = scratch;
+        res
+    }
+
+    fn update_into(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        let res = match &mut self.key {
+            Some(Key::Encrypt(key)) => key.update(input, output),
+            Some(Key::Decrypt(key)) => key.update(input, output),
+            None => return Err(CryptoIoError::Finalized),
+        };
+        Ok(res.map_err(aws_lc_err)?.written().len())
+    }
+
+    fn finalize_into(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        match self.key.take() {
+            Some(Key::Encrypt(key)) => {
+                Ok(key.finish(output).map_err(aws_lc_err)?.1.written().len())
+            }
+            Some(Key::Decrypt(key)) => match key.finish(output) {
+                Ok(a) => Ok(a.written().len()),
+                Err(_) if self.cipher.is_cbc() => Err(CryptoIoError::BadPadding),
+                Err(e) => Err(aws_lc_err(e)),
+            },
+            None => Err(CryptoIoError::Finalized),
+        }
+    }
+}
+
+impl SymmetricBackend for AwsLcBackend {
+    fn update(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        self.with_room(input.len() + AES_BLOCK - 1, output, |b, out| {
+            b.update_into(input, out)
+        })
+    }
+
+    fn finalize(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        self.with_room(AES_BLOCK, output, Self::finalize_into)
+    }
+
+    fn block_size(&self) -> usize {
+        self.cipher.block_size()
+    }
+
+    fn name(&self) -> &'static str {
+        self.cipher.name()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Result as IoResult;
+
+    use openssl::symm::{Cipher, Mode};
+
+    use super::{AwsLcBackend, AwsLcCipher};
+    use crate::testing::{block_on, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter, OpensslBackend};
+
+    fn ciphers() -> Vec<(AwsLcCipher, Cipher)> {
+        vec![
+            (AwsLcCipher::Aes128Cbc, Cipher::aes_128_cbc()),
+            (AwsLcCipher::Aes192Cbc, Cipher::aes_192_cbc()),
+            (AwsLcCipher::Aes256Cbc, Cipher::aes_256_cbc()),
+            (AwsLcCipher::Aes128Ctr, Cipher::aes_128_ctr()),
+            (AwsLcCipher::Aes192Ctr, Cipher::aes_192_ctr()),
+            (AwsLcCipher::Aes256Ctr, Cipher::aes_256_ctr()),
+        ]
+    }
+
+    fn key_iv(cipher: AwsLcCipher) -> (Vec<u8>, Vec<u8>) {
+        (vec![0x42; cipher.key_len()], vec![0x24; cipher.iv_len()])
+    }
+
+    fn backend(cipher: AwsLcCipher, mode: Mode) -> AwsLcBackend {
+        let (key, iv) = key_iv(cipher);
+        AwsLcBackend::new(cipher, mode, &key, &iv).unwrap()
+    }
+
+    fn seal_openssl(cipher: AwsLcCipher, openssl: Cipher, plaintext: &[u8]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let backend = OpensslBackend::new(openssl, Mode::Encrypt, &key, Some(&iv)).unwrap();
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::with_backend(&mut out, backend);
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn open(cipher: AwsLcCipher, ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        let mut reader = DecryptReader::with_backend(ciphertext, backend(cipher, Mode::Decrypt));
+        block_on(read_to_end(&mut reader))
+    }
+
+    // the backends are interchangeable: each decrypts what the other encrypts
+    #[test]
+    fn matches_openssl() {
+        for (cipher, openssl) in ciphers() {
+            for &len in &[0, 1, 15, 16, 17, 2500] {
+                let plaintext = sample(len);
+                let mut out = Vec::new();
+                block_on(async {
+                    let backend = backend(cipher, Mode::Encrypt);
+                    let mut writer = EncryptWriter::with_backend(&mut out, backend);
+                    write_all(&mut writer, &plaintext).await.unwrap();
+                    shutdown(&mut writer).await.unwrap();
+                });
+                let expected = seal_openssl(cipher, openssl, &plaintext);
+                assert_eq!(out, expected, "{} at {} bytes", cipher.name(), len);
+                assert_eq!(open(cipher, &expected).unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_padding() {
+        let cipher = AwsLcCipher::Aes256Cbc;
+        let mut ciphertext = seal_openssl(cipher, Cipher::aes_256_cbc(), &sample(100));
+        *ciphertext.last_mut().unwrap() ^= 1;
+        let e = open(cipher, &ciphertext).unwrap_err();
+        let root = CryptoIoError::from_io(&e).map(|e| e.root());
+        assert!(matches!(root, Some(CryptoIoError::BadPadding)));
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        let cipher = AwsLcCipher::Aes128Cbc;
+        let res = AwsLcBackend::new(cipher, Mode::Encrypt, &[0; 32], &[0; 16]);
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidKeyLen {
+                expected: 16,
+                actual: 32
+            })
+        ));
+        let res = AwsLcBackend::new(cipher, Mode::Encrypt, &[0; 16], &[0; 12]);
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidIvLen {
+                expected: 16,
+                actual: 12
+            })
+        ));
+    }
+}
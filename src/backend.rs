@@ -0,0 +1,339 @@
+use bytes::{Buf, Bytes, BytesMut};
+use openssl::symm::{Cipher, Crypter, Mode};
+use zeroize::Zeroizing;
+
+use crate::cipher::{check_iv, is_ocb, tag_len_range};
+use crate::telemetry::cipher_name;
+use crate::{check_nonce, CryptoIoError};
+
+// The cipher the adapters drive. `update` and `finalize` behave like their `Crypter` counterparts:
+// the adapters always pass `update` an output buffer with room for the input plus one block, and
+// `finalize` one with room for `finalize_len` bytes.
+pub trait SymmetricBackend {
+    fn update(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError>;
+    fn finalize(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError>;
+    fn block_size(&self) -> usize;
+
+    // the room `finalize` needs in its output buffer, for backends that hold back more than a
+    // block until the end
+    fn finalize_len(&self) -> usize {
+        self.block_size()
+    }
+
+    // used to label logs and metrics
+    fn name(&self) -> &'static str {
+        "unknown"
+    }
+
+    // the length of the authentication tag of an AEAD backend, or 0. When it is nonzero the
+    // adapters call `get_tag` after finalizing encryption and `set_tag` before finalizing
+    // decryption.
+    fn tag_len(&self) -> usize {
+        0
+    }
+
+    fn get_tag(&mut self, _tag: &mut [u8]) -> Result<(), CryptoIoError> {
+        Ok(())
+    }
+
+    fn set_tag(&mut self, _tag: &[u8]) -> Result<(), CryptoIoError> {
+        Ok(())
+    }
+
+    // authenticates `aad` without encrypting it, for an AEAD backend; called before any data
+    fn update_aad(&mut self, _aad: &[u8]) -> Result<(), CryptoIoError> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "backend does not support associated data",
+        )
+        .into())
+    }
+
+    // runs each chunk through `update` in turn (so they form one continuous stream), writing all
+    // of the output into a single allocation; element `i` is the output produced by chunk `i`
+    fn update_chunks(&mut self, chunks: &[Bytes]) -> Result<Vec<Bytes>, CryptoIoError> {
+        let block_size = self.block_size();
+        let total = chunks.iter().map(|c| c.len() + block_size).sum();
+        let mut out = BytesMut::with_capacity(total);
+        out.resize(total, 0);
+        let mut res = Vec::with_capacity(chunks.len());
+        for chunk in chunks {
+            let len = self.update(chunk, &mut out[..chunk.len() + block_size])?;
+            res.push(out.split_to(len).freeze());
+            out.advance(chunk.len() + block_size - len);
+        }
+        Ok(res)
+    }
+}
+
+// Keeps its own zeroizing copy of the key and IV, so the crypter can be set up again (see
+// `restart` and `rotate_key`) without the caller holding on to them.
+pub struct OpensslBackend {
+    cipher: Cipher,
+    mode: Mode,
+    key: Zeroizing<Vec<u8>>,
+    iv: Option<Zeroizing<Vec<u8>>>,
+    crypter: Crypter,
+    tag_len: usize,
+}
+impl OpensslBackend {
+    pub fn new(
+        cipher: Cipher,
+        mode: Mode,
+        key: &[u8],
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError> {
+        check_iv(cipher, iv)?;
+        Ok(OpensslBackend {
+            cipher,
+            mode,
+            key: Zeroizing::new(key.to_vec()),
+            iv: iv.map(|iv| Zeroizing::new(iv.to_vec())),
+            crypter: new_crypter(cipher, mode, key, iv)?,
+            tag_len: tag_len_range(cipher).1,
+        })
+    }
+
+    fn reset_crypter(&mut self) -> Result<(), CryptoIoError> {
+        let iv = self.iv.as_deref().map(|iv| &iv[..]);
+        let mut crypter = new_crypter(self.cipher, self.mode, &self.key, iv)?;
+        if is_ocb(self.cipher) {
+            crypter.set_tag_len(self.tag_len)?;
+        }
+        self.crypter = crypter;
+        Ok(())
+    }
+
+    // Starts the stream over under the same key and IV, discarding any state. When encrypting,
+    // the same plaintext then gives the same ciphertext again, which is safe and is how an
+    // interrupted upload can be resumed; anything else encrypted this way reuses the IV.
+    pub fn restart(&mut self) -> Result<(), CryptoIoError> {
+        self.reset_crypter()
+    }
+
+    // a separate backend, like this one but at the start of the stream
+    pub fn restarted(&self) -> Result<Self, CryptoIoError> {
+        let iv = self.iv.as_deref().map(|iv| &iv[..]);
+        let mut res = OpensslBackend::new(self.cipher, self.mode, &self.key, iv)?;
+        if self.tag_len != res.tag_len {
+            res = res.with_tag_len(self.tag_len)?;
+        }
+        Ok(res)
+    }
+
+    // a separate backend for a new stream under the same key and `iv`, which when encrypting
+    // goes past the nonce guard
+    pub fn next_stream(&self, iv: Option<&[u8]>) -> Result<Self, CryptoIoError> {
+        let mut res = self.restarted()?;
+        res.rotate_key(&self.key, iv)?;
+        Ok(res)
+    }
+
+    // starts a new stream under `key` and `iv`, which when encrypting go past the nonce guard
+    pub fn rotate_key(&mut self, key: &[u8], iv: Option<&[u8]>) -> Result<(), CryptoIoError> {
+        check_iv(self.cipher, iv)?;
+        if let Mode::Encrypt = self.mode {
+            check_nonce(key, iv)?;
+        }
+        self.key = Zeroizing::new(key.to_vec());
+        self.iv = iv.map(|iv| Zeroizing::new(iv.to_vec()));
+        self.reset_crypter()
+    }
+
+    // truncates the tag of an AEAD cipher, which defaults to its full length; GCM accepts 12 to
+    // 16 bytes and OCB 8 to 16. Both ends must use the same length, set before any data.
+    pub fn with_tag_len(mut self, tag_len: usize) -> Result<Self, CryptoIoError> {
+        let (min, max) = tag_len_range(self.cipher);
+        if tag_len < min || tag_len > max {
+            return Err(CryptoIoError::InvalidTagLen {
+                min,
+                max,
+                actual: tag_len,
+            });
+        }
+        // OCB computes a tag of the configured length rather than truncating a full one
+        if is_ocb(self.cipher) {
+            self.crypter.set_tag_len(tag_len)?;
+        }
+        self.tag_len = tag_len;
+        Ok(self)
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+}
+
+fn new_crypter(
+    cipher: Cipher,
+    mode: Mode,
+    key: &[u8],
+    iv: Option<&[u8]>,
+) -> Result<Crypter, CryptoIoError> {
+    match Crypter::new(cipher, mode, key, iv) {
+        Ok(a) => Ok(a),
+        Err(e) => {
+            event!(tracing::Level::ERROR, error = %e, cipher = cipher_name(cipher), "failed to initialize cipher");
+            Err(CryptoIoError::init(cipher, key, e))
+        }
+    }
+}
+
+impl SymmetricBackend for OpensslBackend {
+    fn update(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        Ok(self.crypter.update(input, output)?)
+    }
+
+    fn finalize(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        Ok(self.crypter.finalize(output)?)
+    }
+
+    fn block_size(&self) -> usize {
+        self.cipher.block_size()
+    }
+
+    fn name(&self) -> &'static str {
+        cipher_name(self.cipher)
+    }
+
+    fn tag_len(&self) -> usize {
+        self.tag_len
+    }
+
+    fn get_tag(&mut self, tag: &mut [u8]) -> Result<(), CryptoIoError> {
+        Ok(self.crypter.get_tag(tag)?)
+    }
+
+    fn set_tag(&mut self, tag: &[u8]) -> Result<(), CryptoIoError> {
+        Ok(self.crypter.set_tag(tag)?)
+    }
+
+    fn update_aad(&mut self, aad: &[u8]) -> Result<(), CryptoIoError> {
+        Ok(self.crypter.aad_update(aad)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use openssl::symm::{Cipher, Mode};
+
+    use super::{OpensslBackend, SymmetricBackend};
+    use crate::testing::{key_iv, sample};
+    use crate::CryptoIoError;
+
+    fn run<B: SymmetricBackend>(backend: &mut B, input: &[u8]) -> Vec<u8> {
+        let mut out = vec![0; input.len() + backend.block_size()];
+        let mut len = backend.update(input, &mut out).unwrap();
+        out.resize(len + backend.finalize_len(), 0);
+        len += backend.finalize(&mut out[len..]).unwrap();
+        out.truncate(len);
+        out
+    }
+
+    fn backend(mode: Mode, iv: &[u8]) -> OpensslBackend {
+        let cipher = Cipher::aes_256_cbc();
+        OpensslBackend::new(cipher, mode, &key_iv(cipher).0, Some(iv)).unwrap()
+    }
+
+    #[test]
+    fn restart_repeats_the_stream() {
+        let plaintext = sample(100);
+        let mut encrypter = backend(Mode::Encrypt, &[1; 16]);
+        let ciphertext = run(&mut encrypter, &plaintext);
+        assert_eq!(
+            run(&mut encrypter.restarted().unwrap(), &plaintext),
+            ciphertext
+        );
+        encrypter.restart().unwrap();
+        assert_eq!(run(&mut encrypter, &plaintext), ciphertext);
+        let mut decrypter = backend(Mode::Decrypt, &[1; 16]);
+        assert_eq!(run(&mut decrypter, &ciphertext), plaintext);
+    }
+
+    #[test]
+    fn next_stream_and_rotate_key() {
+        let plaintext = sample(100);
+        let mut encrypter = backend(Mode::Encrypt, &[1; 16]);
+        let first = run(&mut encrypter.restarted().unwrap(), &plaintext);
+        let second = run(
+            &mut encrypter.next_stream(Some(&[2; 16])).unwrap(),
+            &plaintext,
+        );
+        assert_ne!(first, second);
+        assert_eq!(
+            run(&mut backend(Mode::Decrypt, &[2; 16]), &second),
+            plaintext
+        );
+
+        let key = [7; 32];
+        encrypter.rotate_key(&key, Some(&[1; 16])).unwrap();
+        let rotated = run(&mut encrypter, &plaintext);
+        assert_ne!(rotated, first);
+        let cipher = Cipher::aes_256_cbc();
+        let mut decrypter =
+            OpensslBackend::new(cipher, Mode::Decrypt, &key, Some(&[1; 16])).unwrap();
+        assert_eq!(run(&mut decrypter, &rotated), plaintext);
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let res = OpensslBackend::new(cipher, Mode::Encrypt, &key[1..], iv.as_deref());
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidKeyLen {
+                expected: 32,
+                actual: 31
+            })
+        ));
+        let cipher = Cipher::aes_256_cbc();
+        let res = OpensslBackend::new(cipher, Mode::Encrypt, &key, Some(&[0; 12]));
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidIvLen {
+                expected: 16,
+                actual: 12
+            })
+        ));
+        let cipher = Cipher::aes_256_gcm();
+        let backend = OpensslBackend::new(cipher, Mode::Encrypt, &key, iv.as_deref()).unwrap();
+        assert_eq!(backend.tag_len(), 16);
+        let res = backend.with_tag_len(11);
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidTagLen {
+                min: 12,
+                max: 16,
+                actual: 11
+            })
+        ));
+    }
+
+    // one continuous stream, the same as a single `update` over the chunks joined together
+    #[test]
+    fn update_chunks() {
+        let plaintext = sample(1000);
+        let chunks = [
+            &plaintext[..0],
+            &plaintext[..7],
+            &plaintext[7..500],
+            &plaintext[500..],
+        ];
+        let chunks: Vec<Bytes> = chunks.iter().map(|c| Bytes::copy_from_slice(c)).collect();
+        let mut encrypter = backend(Mode::Encrypt, &[1; 16]);
+        let out = encrypter.update_chunks(&chunks).unwrap();
+        // CBC releases whole blocks only
+        let lens: Vec<usize> = out.iter().map(Bytes::len).collect();
+        assert_eq!(lens, [0, 0, 496, 496]);
+        let mut ciphertext = out.concat();
+        let mut last = [0; 16];
+        let len = encrypter.finalize(&mut last).unwrap();
+        ciphertext.extend_from_slice(&last[..len]);
+        assert_eq!(
+            ciphertext,
+            run(&mut backend(Mode::Encrypt, &[1; 16]), &plaintext)
+        );
+    }
+}
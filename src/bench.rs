@@ -0,0 +1,117 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::time::{Duration, Instant};
+
+use openssl::symm::Mode;
+
+use crate::{Algorithm, CryptoIoError, EncryptCore, OpensslBackend};
+
+const CHUNK_LEN: usize = 64 * 1024;
+// streams are finished and restarted at this length, so the tag and final block count too
+const STREAM_LEN: usize = 16 * 1024 * 1024;
+
+#[derive(Clone, Copy, Debug)]
+pub struct Throughput {
+    pub algorithm: Algorithm,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+impl Throughput {
+    // in millions of bytes per second
+    pub fn mb_per_sec(&self) -> f64 {
+        self.bytes as f64 / self.elapsed.as_secs_f64() / 1e6
+    }
+}
+
+// Encrypts zeros with `algorithm` through `EncryptCore` on the OpenSSL backend for about
+// `seconds`, in 64 KiB writes, and reports how much got through. It runs on the calling thread
+// and keeps it busy throughout, so from async code call it in `spawn_blocking`. The bytes count
+// towards the `metrics` counters like any other encryption.
+pub fn measure_throughput(algorithm: Algorithm, seconds: f64) -> Result<Throughput, CryptoIoError> {
+    let duration = Duration::try_from_secs_f64(seconds).map_err(|_| {
+        IoError::new(
+            IoErrorKind::InvalidInput,
+            format!("invalid benchmark duration {}", seconds),
+        )
+    })?;
+    let key = vec![0; algorithm.key_len()];
+    let iv = vec![0; algorithm.iv_len()];
+    let chunk = vec![0; CHUNK_LEN];
+    let mut bytes = 0;
+    let start = Instant::now();
+    loop {
+        // not `EncryptCore::new`: the nonce guard would reject the repeated IV
+        let backend = OpensslBackend::new(algorithm.cipher(), Mode::Encrypt, &key, Some(&iv))?;
+        let mut core = EncryptCore::with_backend(backend);
+        let mut stream_len = 0;
+        loop {
+            core.push_plaintext(&chunk)?;
+            core.consume(core.ciphertext().len());
+            stream_len += CHUNK_LEN;
+            if stream_len >= STREAM_LEN || start.elapsed() >= duration {
+                break;
+            }
+        }
+        core.finish()?;
+        core.consume(core.ciphertext().len());
+        bytes += stream_len as u64;
+        if start.elapsed() >= duration {
+            break;
+        }
+    }
+    let result = Throughput {
+        algorithm,
+        bytes,
+        elapsed: start.elapsed(),
+    };
+    event!(
+        tracing::Level::DEBUG,
+        algorithm = algorithm.name(),
+        mb_per_sec = result.mb_per_sec(),
+        "measured throughput"
+    );
+    Ok(result)
+}
+
+// Measures each AEAD for `seconds` and returns the fastest, for services that pick their
+// cipher on the host they land on. Unlike `best_aead`, this reflects the actual CPU and OpenSSL
+// build, at the cost of the time spent measuring.
+pub fn fastest_aead(seconds: f64) -> Result<Throughput, CryptoIoError> {
+    let mut fastest: Option<Throughput> = None;
+    for &algorithm in Algorithm::ALL.iter().filter(|a| a.is_aead()) {
+        let result = measure_throughput(algorithm, seconds)?;
+        match fastest {
+            Some(f) if f.mb_per_sec() >= result.mb_per_sec() => (),
+            _ => fastest = Some(result),
+        }
+    }
+    Ok(fastest.unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind as IoErrorKind;
+
+    use super::{fastest_aead, measure_throughput, CHUNK_LEN};
+    use crate::{Algorithm, CryptoIoError};
+
+    #[test]
+    fn measures() {
+        let result = measure_throughput(Algorithm::Aes128Ctr, 0.01).unwrap();
+        assert_eq!(result.algorithm, Algorithm::Aes128Ctr);
+        assert!(result.bytes > 0);
+        assert_eq!(result.bytes % CHUNK_LEN as u64, 0);
+        assert!(result.mb_per_sec() > 0.0);
+
+        assert!(fastest_aead(0.005).unwrap().algorithm.is_aead());
+    }
+
+    #[test]
+    fn invalid_durations() {
+        for seconds in [-1.0, f64::NAN, f64::INFINITY] {
+            assert!(matches!(
+                measure_throughput(Algorithm::Aes256Gcm, seconds),
+                Err(CryptoIoError::Io(e)) if e.kind() == IoErrorKind::InvalidInput
+            ));
+        }
+    }
+}
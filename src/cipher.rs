@@ -0,0 +1,130 @@
+use std::os::raw::c_ulong;
+
+use openssl::nid::Nid;
+use openssl::symm::Cipher;
+
+use crate::CryptoIoError;
+
+const EVP_CIPH_CUSTOM_IV: c_ulong = 0x10;
+const EVP_CIPH_FLAG_AEAD_CIPHER: c_ulong = 0x20_0000;
+const EVP_CIPH_MODE: c_ulong = 0xF0007;
+const EVP_CIPH_GCM_MODE: c_ulong = 0x6;
+const EVP_CIPH_OCB_MODE: c_ulong = 0x10003;
+const OCB_MAX_NONCE_LEN: usize = 15;
+
+fn flags(cipher: Cipher) -> c_ulong {
+    unsafe { openssl_sys::EVP_CIPHER_flags(cipher.as_ptr()) }
+}
+
+pub(crate) fn is_ocb(cipher: Cipher) -> bool {
+    flags(cipher) & EVP_CIPH_MODE == EVP_CIPH_OCB_MODE
+}
+
+// Crypter asserts on a missing or short IV, so reject those up front. Ciphers with a custom IV
+// (the AEAD modes) accept other IV lengths, though OCB nonces are at most 15 bytes.
+pub(crate) fn check_iv(cipher: Cipher, iv: Option<&[u8]>) -> Result<(), CryptoIoError> {
+    match (cipher.iv_len(), iv) {
+        (Some(expected), Some(iv))
+            if is_ocb(cipher) && (iv.is_empty() || iv.len() > OCB_MAX_NONCE_LEN) =>
+        {
+            Err(CryptoIoError::InvalidIvLen {
+                expected,
+                actual: iv.len(),
+            })
+        }
+        (Some(expected), None) => Err(CryptoIoError::InvalidIvLen {
+            expected,
+            actual: 0,
+        }),
+        (Some(expected), Some(iv))
+            if iv.len() != expected && flags(cipher) & EVP_CIPH_CUSTOM_IV == 0 =>
+        {
+            Err(CryptoIoError::InvalidIvLen {
+                expected,
+                actual: iv.len(),
+            })
+        }
+        _ => Ok(()),
+    }
+}
+
+// the (min, max) tag lengths the cipher supports; (0, 0) if it is not an AEAD. GCM tags may be
+// truncated to 96 bits and OCB tags to 64, other AEADs always use their full 128-bit tag.
+pub(crate) fn tag_len_range(cipher: Cipher) -> (usize, usize) {
+    let flags = flags(cipher);
+    if flags & EVP_CIPH_FLAG_AEAD_CIPHER == 0 {
+        return (0, 0);
+    }
+    match flags & EVP_CIPH_MODE {
+        EVP_CIPH_GCM_MODE => (12, 16),
+        EVP_CIPH_OCB_MODE => (8, 16),
+        _ => (16, 16),
+    }
+}
+
+// whether this CPU has instructions that make AES-GCM fast and constant-time (AES-NI with
+// CLMUL on x86, the crypto extensions on ARMv8)
+pub fn has_aes_acceleration() -> bool {
+    #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+    {
+        is_x86_feature_detected!("aes") && is_x86_feature_detected!("pclmulqdq")
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::is_aarch64_feature_detected!("aes")
+            && std::arch::is_aarch64_feature_detected!("pmull")
+    }
+    #[cfg(not(any(target_arch = "x86", target_arch = "x86_64", target_arch = "aarch64")))]
+    {
+        false
+    }
+}
+
+// AES-256-GCM where the hardware accelerates it, otherwise ChaCha20-Poly1305 if the linked
+// OpenSSL provides it
+pub fn best_aead() -> Cipher {
+    let chacha = Cipher::from_nid(Nid::CHACHA20_POLY1305);
+    match chacha {
+        Some(chacha) if !has_aes_acceleration() => chacha,
+        _ => Cipher::aes_256_gcm(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::nid::Nid;
+    use openssl::symm::Cipher;
+
+    use super::{best_aead, check_iv, has_aes_acceleration, is_ocb, tag_len_range};
+    use crate::CryptoIoError;
+
+    #[test]
+    fn best_aead_follows_the_hardware() {
+        let best = best_aead();
+        assert_ne!(tag_len_range(best), (0, 0));
+        if has_aes_acceleration() || Cipher::from_nid(Nid::CHACHA20_POLY1305).is_none() {
+            assert_eq!(best.nid(), Nid::AES_256_GCM);
+        } else {
+            assert_eq!(best.nid(), Nid::CHACHA20_POLY1305);
+        }
+    }
+
+    #[test]
+    fn ocb() {
+        let ocb = Cipher::aes_128_ocb();
+        assert!(is_ocb(ocb) && !is_ocb(Cipher::aes_128_gcm()));
+        assert_eq!(tag_len_range(ocb), (8, 16));
+        assert_eq!(tag_len_range(Cipher::aes_128_gcm()), (12, 16));
+        assert_eq!(tag_len_range(Cipher::chacha20_poly1305()), (16, 16));
+        assert_eq!(tag_len_range(Cipher::aes_128_cbc()), (0, 0));
+        // OCB nonces are 1 to 15 bytes
+        assert!(check_iv(ocb, Some(&[0; 1])).is_ok());
+        assert!(check_iv(ocb, Some(&[0; 15])).is_ok());
+        for len in [0, 16] {
+            assert!(matches!(
+                check_iv(ocb, Some(&vec![0; len])),
+                Err(CryptoIoError::InvalidIvLen { expected: 12, actual }) if actual == len
+            ));
+        }
+    }
+}
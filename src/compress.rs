@@ -0,0 +1,538 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::CryptoIoError;
+
+// Compresses plaintext before it reaches an `EncryptWriter` (ciphertext never compresses), and
+// undoes it after a `DecryptReader`. The stream starts with one byte naming the algorithm, so the
+// reader needs no configuration and a writer can choose `None` for data that is already
+// compressed. That byte is in the plaintext, so it is covered by the tag or MAC.
+//
+// Compressing secret data next to attacker-controlled data leaks how well they compress together
+// through the ciphertext length (CRIME, BREACH), so don't compress where an attacker can inject
+// plaintext and observe the size of the result.
+
+const CHUNK_LEN: usize = 16 * 1024;
+
+const ID_NONE: u8 = 0;
+#[cfg(feature = "flate2")]
+const ID_DEFLATE: u8 = 1;
+#[cfg(feature = "zstd")]
+const ID_ZSTD: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    // zlib format, level 0 to 9
+    #[cfg(feature = "flate2")]
+    Deflate(u32),
+    // level 1 to 22, or 0 for zstd's default
+    #[cfg(feature = "zstd")]
+    Zstd(i32),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Flush {
+    None,
+    Sync,
+    Finish,
+}
+
+enum Codec {
+    None,
+    #[cfg(feature = "flate2")]
+    DeflateEncode(flate2::Compress),
+    #[cfg(feature = "flate2")]
+    DeflateDecode(flate2::Decompress),
+    #[cfg(feature = "zstd")]
+    ZstdEncode(zstd::stream::raw::Encoder<'static>),
+    #[cfg(feature = "zstd")]
+    ZstdDecode(zstd::stream::raw::Decoder<'static>),
+}
+impl Codec {
+    fn encoder(compression: Compression) -> Result<Self, CryptoIoError> {
+        Ok(match compression {
+            Compression::None => Codec::None,
+            #[cfg(feature = "flate2")]
+            Compression::Deflate(level) => {
+                Codec::DeflateEncode(flate2::Compress::new(flate2::Compression::new(level), true))
+            }
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(level) => Codec::ZstdEncode(zstd::stream::raw::Encoder::new(level)?),
+        })
+    }
+
+    fn decoder(id: u8) -> Result<Self, CryptoIoError> {
+        Ok(match id {
+            ID_NONE => Codec::None,
+            #[cfg(feature = "flate2")]
+            ID_DEFLATE => Codec::DeflateDecode(flate2::Decompress::new(true)),
+            #[cfg(feature = "zstd")]
+            ID_ZSTD => Codec::ZstdDecode(zstd::stream::raw::Decoder::new()?),
+            id => return Err(CryptoIoError::UnknownCompression(id)),
+        })
+    }
+
+    // one step over `input`, returning (bytes consumed, bytes produced, whether the flush or the
+    // compressed stream is complete)
+    fn step(
+        &mut self,
+        input: &[u8],
+        output: &mut [u8],
+        flush: Flush,
+    ) -> IoResult<(usize, usize, bool)> {
+        match self {
+            Codec::None => {
+                let n = input.len().min(output.len());
+                output[..n].copy_from_slice(&input[..n]);
+                Ok((n, n, flush != Flush::None))
+            }
+            #[cfg(feature = "flate2")]
+            Codec::DeflateEncode(c) => {
+                let (total_in, total_out) = (c.total_in(), c.total_out());
+                let flush = match flush {
+                    Flush::None => flate2::FlushCompress::None,
+                    Flush::Sync => flate2::FlushCompress::Sync,
+                    Flush::Finish => flate2::FlushCompress::Finish,
+                };
+                let status = c.compress(input, output, flush).map_err(invalid_data)?;
+                let read = (c.total_in() - total_in) as usize;
+                let written = (c.total_out() - total_out) as usize;
+                let done = match flush {
+                    flate2::FlushCompress::Finish => status == flate2::Status::StreamEnd,
+                    _ => written < output.len(),
+                };
+                Ok((read, written, done))
+            }
+            #[cfg(feature = "flate2")]
+            Codec::DeflateDecode(d) => {
+                let (total_in, total_out) = (d.total_in(), d.total_out());
+                let status = d
+                    .decompress(input, output, flate2::FlushDecompress::None)
+                    .map_err(invalid_data)?;
+                let read = (d.total_in() - total_in) as usize;
+                let written = (d.total_out() - total_out) as usize;
+                Ok((read, written, status == flate2::Status::StreamEnd))
+            }
+            #[cfg(feature = "zstd")]
+            Codec::ZstdEncode(e) => {
+                use zstd::stream::raw::{Operation, OutBuffer};
+                match flush {
+                    Flush::None => {
+                        let status = e.run_on_buffers(input, output)?;
+                        Ok((status.bytes_read, status.bytes_written, false))
+                    }
+                    Flush::Sync | Flush::Finish => {
+                        let mut out = OutBuffer::around(output);
+                        let remaining = if flush == Flush::Sync {
+                            e.flush(&mut out)?
+                        } else {
+                            e.finish(&mut out, false)?
+                        };
+                        Ok((0, out.pos(), remaining == 0))
+                    }
+                }
+            }
+            #[cfg(feature = "zstd")]
+            Codec::ZstdDecode(d) => {
+                use zstd::stream::raw::Operation;
+                let status = d.run_on_buffers(input, output)?;
+                Ok((
+                    status.bytes_read,
+                    status.bytes_written,
+                    status.remaining == 0,
+                ))
+            }
+        }
+    }
+}
+
+#[cfg(feature = "flate2")]
+fn invalid_data<E: std::error::Error + Send + Sync + 'static>(e: E) -> IoError {
+    IoError::new(IoErrorKind::InvalidData, e)
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum WriteState {
+    Writing,
+    Flushed,
+    Finished,
+}
+
+pub struct CompressWriter<W> {
+    writer: W,
+    codec: Codec,
+    out: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    state: WriteState,
+}
+impl<W> CompressWriter<W> {
+    pub fn new(writer: W, compression: Compression) -> Result<Self, CryptoIoError> {
+        let mut out = vec![0; CHUNK_LEN].into_boxed_slice();
+        out[0] = match compression {
+            Compression::None => ID_NONE,
+            #[cfg(feature = "flate2")]
+            Compression::Deflate(_) => ID_DEFLATE,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(_) => ID_ZSTD,
+        };
+        Ok(CompressWriter {
+            writer,
+            codec: Codec::encoder(compression)?,
+            out,
+            pos: 0,
+            len: 1,
+            state: WriteState::Writing,
+        })
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W> CompressWriter<W>
+where
+    W: AsyncWrite,
+{
+    // self must be pinned
+    unsafe fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        while self.pos < self.len {
+            match Pin::new_unchecked(&mut self.writer).poll_write(cx, &self.out[self.pos..self.len])
+            {
+                Poll::Ready(Ok(0)) => {
+                    return Poll::Ready(Err(IoError::new(
+                        IoErrorKind::WriteZero,
+                        "failed to write compressed data",
+                    )))
+                }
+                Poll::Ready(Ok(n)) => self.pos += n,
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Ok(()).into()
+    }
+
+    // self must be pinned; runs `flush` until the codec reports it complete
+    unsafe fn poll_codec(
+        &mut self,
+        cx: &mut Context<'_>,
+        flush: Flush,
+        state: WriteState,
+    ) -> Poll<IoResult<()>> {
+        loop {
+            match self.poll_drain(cx) {
+                Poll::Ready(Ok(())) => (),
+                a => return a,
+            }
+            if self.state == state || self.state == WriteState::Finished {
+                return Ok(()).into();
+            }
+            let (_, written, done) = self.codec.step(&[], &mut self.out, flush)?;
+            self.pos = 0;
+            self.len = written;
+            if done {
+                self.state = state;
+            }
+        }
+    }
+}
+
+impl<W> AsyncWrite for CompressWriter<W>
+where
+    W: AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            if inner.state == WriteState::Finished {
+                return Poll::Ready(Err(CryptoIoError::Finalized.into()));
+            }
+            loop {
+                match inner.poll_drain(cx) {
+                    Poll::Ready(Ok(())) => (),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+                if buf.is_empty() {
+                    return Poll::Ready(Ok(0));
+                }
+                let (read, written, _) = inner.codec.step(buf, &mut inner.out, Flush::None)?;
+                inner.pos = 0;
+                inner.len = written;
+                if read > 0 {
+                    inner.state = WriteState::Writing;
+                    return Poll::Ready(Ok(read));
+                }
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            match inner.poll_codec(cx, Flush::Sync, WriteState::Flushed) {
+                Poll::Ready(Ok(())) => (),
+                a => return a,
+            }
+            Pin::new_unchecked(&mut inner.writer).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            match inner.poll_codec(cx, Flush::Finish, WriteState::Finished) {
+                Poll::Ready(Ok(())) => (),
+                a => return a,
+            }
+            Pin::new_unchecked(&mut inner.writer).poll_shutdown(cx)
+        }
+    }
+}
+
+pub struct DecompressReader<R> {
+    reader: R,
+    // None until the algorithm byte has been read
+    codec: Option<Codec>,
+    input: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+    done: bool,
+}
+impl<R> DecompressReader<R> {
+    pub fn new(reader: R) -> Self {
+        DecompressReader {
+            reader,
+            codec: None,
+            input: vec![0; CHUNK_LEN].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            eof: false,
+            done: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R> DecompressReader<R>
+where
+    R: AsyncRead,
+{
+    // self must be pinned
+    unsafe fn poll_fill(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        match Pin::new_unchecked(&mut self.reader).poll_read(cx, &mut self.input) {
+            Poll::Ready(Ok(n)) => {
+                self.pos = 0;
+                self.len = n;
+                self.eof = n == 0;
+                Poll::Ready(Ok(()))
+            }
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    // Reads the inner reader to its end after the compressed stream has ended, so a
+    // `DecryptReader` gets to check its tag or MAC before this reports EOF. Anything after the
+    // end of the compressed stream is an error.
+    // self must be pinned
+    unsafe fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<usize>> {
+        loop {
+            if self.pos < self.len {
+                return Poll::Ready(Err(IoError::new(
+                    IoErrorKind::InvalidData,
+                    "data after the end of the compressed stream",
+                )));
+            }
+            if self.eof {
+                return Poll::Ready(Ok(0));
+            }
+            match self.poll_fill(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    // self must be pinned
+    unsafe fn read_impl(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        loop {
+            if self.codec.is_none() {
+                if self.pos == self.len {
+                    if self.eof {
+                        return Poll::Ready(Err(CryptoIoError::Truncated.into()));
+                    }
+                    match self.poll_fill(cx) {
+                        Poll::Ready(Ok(())) => continue,
+                        Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                        Poll::Pending => return Poll::Pending,
+                    }
+                }
+                self.codec = Some(Codec::decoder(self.input[self.pos])?);
+                self.pos += 1;
+            }
+            if self.done {
+                return self.poll_drain(cx);
+            }
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let codec = self.codec.as_mut().unwrap();
+            let flush = if self.eof { Flush::Finish } else { Flush::None };
+            let (read, written, done) = codec.step(&self.input[self.pos..self.len], buf, flush)?;
+            self.pos += read;
+            // an uncompressed stream has no end marker of its own and ends with its input
+            self.done = match codec {
+                Codec::None => self.eof && self.pos == self.len,
+                _ => done,
+            };
+            if written > 0 {
+                return Poll::Ready(Ok(written));
+            }
+            if self.done {
+                continue;
+            }
+            if self.pos < self.len {
+                continue;
+            }
+            if self.eof {
+                return Poll::Ready(Err(CryptoIoError::Truncated.into()));
+            }
+            match self.poll_fill(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<R> AsyncRead for DecompressReader<R>
+where
+    R: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        unsafe { self.get_unchecked_mut().read_impl(cx, buf) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Cipher;
+
+    use super::Compression;
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+    fn encrypt(cipher: Cipher, compression: Compression, plaintext: &[u8]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::new(&mut out, cipher, &key, iv.as_deref())
+                .unwrap()
+                .compressed(compression)
+                .unwrap();
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn decrypt(cipher: Cipher, ciphertext: &[u8]) -> std::io::Result<Vec<u8>> {
+        let (key, iv) = key_iv(cipher);
+        block_on(async {
+            let mut reader =
+                DecryptReader::new(ciphertext, cipher, &key, iv.as_deref())?.decompressed();
+            read_to_end(&mut reader).await
+        })
+    }
+
+    fn compressions() -> Vec<Compression> {
+        vec![
+            Compression::None,
+            #[cfg(feature = "flate2")]
+            Compression::Deflate(6),
+            #[cfg(feature = "zstd")]
+            Compression::Zstd(3),
+        ]
+    }
+
+    #[test]
+    fn round_trip() {
+        let plaintext = sample(50_000);
+        for cipher in [Cipher::aes_256_gcm(), Cipher::aes_128_cbc()] {
+            for compression in compressions() {
+                let ciphertext = encrypt(cipher, compression, &plaintext);
+                assert_eq!(decrypt(cipher, &ciphertext).unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn empty_round_trip() {
+        for compression in compressions() {
+            let ciphertext = encrypt(Cipher::aes_256_gcm(), compression, b"");
+            assert_eq!(decrypt(Cipher::aes_256_gcm(), &ciphertext).unwrap(), b"");
+        }
+    }
+
+    // the compressed stream ends before the tag, which must still be checked
+    #[test]
+    fn tampered_tag() {
+        let cipher = Cipher::aes_256_gcm();
+        for compression in compressions() {
+            let mut ciphertext = encrypt(cipher, compression, &sample(5000));
+            *ciphertext.last_mut().unwrap() ^= 1;
+            let err = decrypt(cipher, &ciphertext).unwrap_err();
+            assert!(
+                matches!(
+                    CryptoIoError::from_io(&err).map(|e| e.root()),
+                    Some(CryptoIoError::BadTag)
+                ),
+                "{:?}: {}",
+                compression,
+                err
+            );
+        }
+    }
+
+    #[test]
+    fn truncated() {
+        let cipher = Cipher::aes_256_gcm();
+        for compression in compressions() {
+            let ciphertext = encrypt(cipher, compression, &sample(5000));
+            assert!(decrypt(cipher, &ciphertext[..ciphertext.len() / 2]).is_err());
+        }
+    }
+}
@@ -0,0 +1,217 @@
+use std::future::poll_fn;
+use std::io::Result as IoResult;
+use std::pin::Pin;
+
+use bytes::Bytes;
+use openssl::sha::Sha256;
+use openssl::symm::{Cipher, Mode};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+use zeroize::Zeroizing;
+
+use crate::dedup::sha256_and_rewind;
+use crate::mac::{hkdf_expand, hkdf_extract};
+use crate::reencrypt::copy_zeroizing;
+use crate::{
+    CryptoIoError, DecryptCore, DecryptReader, EncryptCore, EncryptWriter, InspectWriter,
+    MessageCipher, OpensslBackend,
+};
+
+const LABEL: &[u8] = b"tokio-openssl-symm convergent v1";
+type Secret = Zeroizing<Vec<u8>>;
+
+// what `ConvergentCipher::encrypt` stored
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConvergentBlob {
+    // the content key sealed under the user's secret; keep it with whatever refers to the blob
+    pub wrapped_key: Vec<u8>,
+    // the SHA-256 digest of the ciphertext, to store it under
+    pub address: Vec<u8>,
+    pub plaintext_len: u64,
+}
+
+// Convergent encryption for content-addressed storage: each plaintext is encrypted under a key
+// derived from its own SHA-256 digest, so equal plaintexts encrypt to equal ciphertexts
+// whoever stores them, and the store keeps one copy. The content key is then sealed under the
+// user's secret (AES-256-GCM, with a key derived from the secret) for the user to keep. Like
+// `EqualityRevealingCipher`, this reveals which blobs are equal, and more: anyone who can guess
+// a plaintext can confirm that it is stored, by encrypting the guess. Setting a convergence
+// secret limits both to those who share it, at the cost of deduplicating only among them.
+pub struct ConvergentCipher {
+    cipher: Cipher,
+    wrapping: MessageCipher,
+    convergence_secret: Secret,
+}
+impl ConvergentCipher {
+    pub fn new(cipher: Cipher, secret: &[u8]) -> Result<Self, CryptoIoError> {
+        let wrapping_cipher = Cipher::aes_256_gcm();
+        let prk = hkdf_extract(LABEL, &[secret])?;
+        let wrapping_key = hkdf_expand(&prk, &[b"wrap"], wrapping_cipher.key_len())?;
+        Ok(ConvergentCipher {
+            cipher,
+            wrapping: MessageCipher::new(wrapping_cipher, &wrapping_key)?,
+            convergence_secret: Zeroizing::new(Vec::new()),
+        })
+    }
+
+    // mixes `secret` into every content key, so only holders of it derive the same ones
+    pub fn with_convergence_secret(mut self, secret: &[u8]) -> Self {
+        self.convergence_secret = Zeroizing::new(secret.to_vec());
+        self
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    // the content key for a plaintext with this SHA-256 digest
+    fn content_key(&self, plaintext_sha256: &[u8; 32]) -> Result<Secret, CryptoIoError> {
+        let nid = (self.cipher.nid().as_raw() as u32).to_be_bytes();
+        let prk = hkdf_extract(&self.convergence_secret, &[LABEL, plaintext_sha256])?;
+        Ok(hkdf_expand(&prk, &[&nid, b" key"], self.cipher.key_len())?)
+    }
+
+    // The IV follows from the content key, which is only ever used for the one plaintext, so
+    // neither needs to be stored with the ciphertext.
+    fn backend(&self, mode: Mode, key: &[u8]) -> Result<OpensslBackend, CryptoIoError> {
+        let iv = match self.cipher.iv_len() {
+            Some(len) => Some(hkdf_expand(key, &[LABEL, b" iv"], len)?),
+            None => None,
+        };
+        OpensslBackend::new(self.cipher, mode, key, iv.as_deref().map(|iv| &iv[..]))
+    }
+
+    // Encrypts into `writer` a plaintext whose SHA-256 digest is already known, returning the
+    // writer and the wrapped content key.
+    pub fn writer<W>(
+        &self,
+        writer: W,
+        plaintext_sha256: &[u8; 32],
+    ) -> Result<(EncryptWriter<W>, Vec<u8>), CryptoIoError> {
+        let key = self.content_key(plaintext_sha256)?;
+        let wrapped_key = self.wrapping.encrypt_message(&key)?.to_vec();
+        // not `EncryptCore::new`: every copy of a plaintext repeats its key and IV, which the
+        // nonce guard would reject
+        let core = EncryptCore::with_backend(self.backend(Mode::Encrypt, &key)?);
+        Ok((EncryptWriter::with_core(writer, core), wrapped_key))
+    }
+
+    // Reads `reader` twice, once to derive the content key from it and again, after seeking back
+    // to where it started, to encrypt it into `writer`. The plaintext must not change in between.
+    pub async fn encrypt<R, W>(&self, mut reader: R, writer: W) -> IoResult<ConvergentBlob>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let digest = sha256_and_rewind(&mut reader).await?;
+        let key = self.content_key(&digest)?;
+        let wrapped_key = self.wrapping.encrypt_message(&key)?.to_vec();
+        let core = EncryptCore::with_backend(self.backend(Mode::Encrypt, &key)?);
+        let mut address = Sha256::new();
+        let writer = InspectWriter::new(writer, |ciphertext: &[u8]| {
+            address.update(ciphertext);
+            Ok(())
+        });
+        let mut writer = EncryptWriter::with_core(writer, core);
+        let plaintext_len = copy_zeroizing(&mut reader, &mut writer).await?;
+        poll_fn(|cx| Pin::new(&mut writer).poll_shutdown(cx)).await?;
+        drop(writer);
+        Ok(ConvergentBlob {
+            wrapped_key,
+            address: address.finish().to_vec(),
+            plaintext_len,
+        })
+    }
+
+    // decrypts a blob given its wrapped key, failing with `BadTag` if the key was not wrapped
+    // under this secret
+    pub fn reader<R>(
+        &self,
+        reader: R,
+        wrapped_key: &[u8],
+    ) -> Result<DecryptReader<R>, CryptoIoError> {
+        let key = Zeroizing::new(
+            self.wrapping
+                .decrypt_message(Bytes::copy_from_slice(wrapped_key))?
+                .to_vec(),
+        );
+        let backend = self.backend(Mode::Decrypt, &key)?;
+        Ok(DecryptReader::with_core(
+            reader,
+            DecryptCore::with_backend(backend),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use openssl::sha::sha256;
+    use openssl::symm::Cipher;
+
+    use super::{ConvergentBlob, ConvergentCipher};
+    use crate::testing::{block_on, read_to_end, sample, shutdown, write_all};
+    use crate::CryptoIoError;
+
+    fn store(convergent: &ConvergentCipher, plaintext: &[u8]) -> (ConvergentBlob, Vec<u8>) {
+        let mut out = Vec::new();
+        let blob = block_on(convergent.encrypt(Cursor::new(plaintext), &mut out)).unwrap();
+        assert_eq!(blob.address, sha256(&out));
+        assert_eq!(blob.plaintext_len, plaintext.len() as u64);
+        (blob, out)
+    }
+
+    fn load(convergent: &ConvergentCipher, blob: &ConvergentBlob, stored: &[u8]) -> Vec<u8> {
+        let mut reader = convergent.reader(stored, &blob.wrapped_key).unwrap();
+        block_on(read_to_end(&mut reader)).unwrap()
+    }
+
+    #[test]
+    fn deduplicates_across_users() {
+        let cipher = Cipher::aes_256_gcm();
+        let (alice, bob) = (
+            ConvergentCipher::new(cipher, b"alice").unwrap(),
+            ConvergentCipher::new(cipher, b"bob").unwrap(),
+        );
+        let plaintext = sample(20_000);
+        let (alice_blob, stored) = store(&alice, &plaintext);
+        let (bob_blob, bob_stored) = store(&bob, &plaintext);
+        assert_eq!(bob_stored, stored);
+        assert_eq!(bob_blob.address, alice_blob.address);
+        assert_ne!(bob_blob.wrapped_key, alice_blob.wrapped_key);
+        assert_eq!(load(&alice, &alice_blob, &stored), plaintext);
+        assert_eq!(load(&bob, &bob_blob, &stored), plaintext);
+        assert_ne!(store(&alice, &sample(20_001)).0.address, alice_blob.address);
+
+        // a key wrapped for someone else doesn't open
+        assert!(matches!(
+            bob.reader(&stored[..], &alice_blob.wrapped_key),
+            Err(CryptoIoError::BadTag)
+        ));
+
+        // the same stream from a digest known up front
+        let mut out = Vec::new();
+        block_on(async {
+            let (mut writer, _) = alice.writer(&mut out, &sha256(&plaintext)).unwrap();
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        assert_eq!(out, stored);
+    }
+
+    #[test]
+    fn convergence_secret() {
+        let cipher = Cipher::chacha20_poly1305();
+        let plaintext = sample(5000);
+        let shared = |user: &[u8]| {
+            ConvergentCipher::new(cipher, user)
+                .unwrap()
+                .with_convergence_secret(b"team")
+        };
+        let (blob, stored) = store(&shared(b"alice"), &plaintext);
+        assert_eq!(store(&shared(b"bob"), &plaintext).1, stored);
+        let outsider = ConvergentCipher::new(cipher, b"eve").unwrap();
+        assert_ne!(store(&outsider, &plaintext).1, stored);
+        assert_eq!(load(&shared(b"alice"), &blob, &stored), plaintext);
+    }
+}
@@ -0,0 +1,1535 @@
+use std::convert::TryInto;
+use std::time::Instant;
+
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::memcmp;
+use openssl::symm::{Cipher, Mode};
+use zeroize::Zeroize;
+
+use crate::digest::StreamDigest;
+use crate::mac::{key_commitment, Mac};
+use crate::metadata::{self, Metadata};
+use crate::telemetry::*;
+use crate::{check_nonce, CryptoIoError, OpensslBackend, SymmetricBackend};
+
+// the block size PKCS#7 padding rounds up to, or 1 for stream ciphers and AEAD modes (OCB works
+// on 16-byte blocks but does not pad)
+fn padding_block<B: SymmetricBackend>(backend: &B) -> usize {
+    if backend.tag_len() > 0 {
+        1
+    } else {
+        backend.block_size()
+    }
+}
+
+// padded ciphers always add between one byte and a whole block of PKCS#7 padding
+fn ciphertext_len(block_size: usize, header_len: usize, trailer_len: usize, len: u64) -> u64 {
+    let block_size = block_size as u64;
+    let body = if block_size > 1 {
+        (len / block_size + 1) * block_size
+    } else {
+        len
+    };
+    header_len as u64 + body + trailer_len as u64
+}
+
+fn max_plaintext_len(block_size: usize, header_len: usize, trailer_len: usize, len: u64) -> u64 {
+    let block_size = block_size as u64;
+    let body = len.saturating_sub(header_len as u64 + trailer_len as u64);
+    if block_size > 1 {
+        (body / block_size * block_size).saturating_sub(1)
+    } else {
+        body
+    }
+}
+
+// Where the AEAD tag goes. `Last` is this crate's own layout; the others are for matching
+// existing formats.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TagPlacement {
+    // after the ciphertext
+    Last,
+    // before the ciphertext, after any header and key commitment. The tag is only known once
+    // encryption finishes, so the encrypting side holds back all of its output until then.
+    First,
+    // not in the stream at all; the encrypting side hands it out from `tag` and the decrypting
+    // side is given it up front
+    Detached,
+}
+
+// the plaintext length as a u64 and its SHA-256
+const FOOTER_LEN: usize = 8 + 32;
+
+// What `with_footer` records at the end of the plaintext, inside the encryption: how long the
+// plaintext was and its SHA-256.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Footer {
+    pub plaintext_len: u64,
+    pub sha256: [u8; 32],
+}
+impl Footer {
+    fn encode(&self) -> [u8; FOOTER_LEN] {
+        let mut out = [0; FOOTER_LEN];
+        out[..8].copy_from_slice(&self.plaintext_len.to_be_bytes());
+        out[8..].copy_from_slice(&self.sha256);
+        out
+    }
+
+    fn decode(data: &[u8]) -> Self {
+        Footer {
+            plaintext_len: u64::from_be_bytes(data[..8].try_into().unwrap()),
+            sha256: data[8..FOOTER_LEN].try_into().unwrap(),
+        }
+    }
+}
+
+// The encrypting half of the stream format (key commitment, ciphertext, MAC trailer) as a
+// push/pull state machine with no IO: push plaintext in, take ciphertext out, and `finish` once
+// the plaintext is complete. `EncryptWriter` drives one of these.
+pub struct EncryptCore<B = OpensslBackend> {
+    backend: B,
+    // ciphertext from `taken` on has not been handed out yet
+    buf: Vec<u8>,
+    taken: usize,
+    is_finalized: bool,
+    panic_on_unfinalized: bool,
+    reject_empty: bool,
+    // whether the stream starts with the AEAD tag length byte, after any prefix
+    tag_header: bool,
+    // length of the `with_prefix` prefix, which comes ahead of the tag length byte
+    prefix_len: usize,
+    // length of the prefix, tag length byte and key commitment, if any
+    header_len: usize,
+    tag_placement: TagPlacement,
+    tag: Option<Vec<u8>>,
+    mac: Option<Mac>,
+    digest: Option<StreamDigest>,
+    // the metadata block, until it is written out ahead of the first ciphertext
+    metadata: Option<Vec<u8>>,
+    // hashes the plaintext for the footer, if there is to be one
+    footer: Option<StreamDigest>,
+    plaintext_bytes: u64,
+    ciphertext_bytes: u64,
+}
+impl EncryptCore {
+    pub fn new(cipher: Cipher, key: &[u8], iv: Option<&[u8]>) -> Result<Self, CryptoIoError> {
+        let backend = OpensslBackend::new(cipher, Mode::Encrypt, key, iv)?;
+        check_nonce(key, iv)?;
+        Ok(Self::with_backend(backend))
+    }
+}
+
+impl<B> EncryptCore<B>
+where
+    B: SymmetricBackend,
+{
+    // encrypts through `backend`, which must already be keyed for encryption
+    pub fn with_backend(backend: B) -> Self {
+        // AEAD streams record their tag length up front
+        let buf = match backend.tag_len() {
+            0 => Vec::new(),
+            tag_len => vec![tag_len as u8],
+        };
+        EncryptCore {
+            backend,
+            tag_header: !buf.is_empty(),
+            prefix_len: 0,
+            header_len: buf.len(),
+            buf,
+            taken: 0,
+            is_finalized: false,
+            panic_on_unfinalized: false,
+            reject_empty: false,
+            tag_placement: TagPlacement::Last,
+            tag: None,
+            mac: None,
+            digest: None,
+            metadata: None,
+            footer: None,
+            plaintext_bytes: 0,
+            ciphertext_bytes: 0,
+        }
+    }
+
+    pub fn push_plaintext(&mut self, data: &[u8]) -> Result<(), CryptoIoError> {
+        if self.is_finalized {
+            return Err(CryptoIoError::Finalized);
+        }
+        self.write_metadata()?;
+        self.encrypt(data)?;
+        if let Some(digest) = &mut self.digest {
+            digest.update(data)?;
+        }
+        if let Some(footer) = &mut self.footer {
+            footer.update(data)?;
+        }
+        self.plaintext_bytes += data.len() as u64;
+        record_encrypted(self.backend.name(), data.len());
+        Ok(())
+    }
+
+    fn encrypt(&mut self, data: &[u8]) -> Result<(), CryptoIoError> {
+        let init_len = self.buf.len();
+        self.buf
+            .resize(init_len + data.len() + self.backend.block_size(), 0);
+        let len = match self.backend.update(data, &mut self.buf[init_len..]) {
+            Ok(a) => a,
+            Err(e) => {
+                self.buf.truncate(init_len);
+                event!(tracing::Level::ERROR, error = %e, "encryption failed");
+                return Err(e);
+            }
+        };
+        self.buf.truncate(init_len + len);
+        if let Some(mac) = &mut self.mac {
+            if let Err(e) = mac.update(&self.buf[init_len..]) {
+                event!(tracing::Level::ERROR, error = %e, "authentication failed");
+                return Err(e.into());
+            }
+        }
+        Ok(())
+    }
+
+    // finalizes the cipher and adds the AEAD tag and MAC, if any; the last of the ciphertext is
+    // then available from `ciphertext`. Later calls do nothing.
+    pub fn finish(&mut self) -> Result<(), CryptoIoError> {
+        if self.is_finalized {
+            return Ok(());
+        }
+        if self.reject_empty && self.plaintext_bytes == 0 {
+            event!(tracing::Level::ERROR, "refusing to encrypt an empty stream");
+            return Err(CryptoIoError::Empty);
+        }
+        self.write_metadata()?;
+        let footer = match &mut self.footer {
+            Some(digest) => {
+                digest.finish()?;
+                Some(Footer {
+                    plaintext_len: self.plaintext_bytes,
+                    sha256: digest.value().unwrap().try_into().unwrap(),
+                })
+            }
+            None => None,
+        };
+        if let Some(footer) = footer {
+            self.encrypt(&footer.encode())?;
+        }
+        let init_len = self.buf.len();
+        self.buf.resize(init_len + self.backend.finalize_len(), 0);
+        let start = Instant::now();
+        let count = match self.backend.finalize(&mut self.buf[init_len..]) {
+            Ok(a) => a,
+            Err(e) => {
+                self.buf.truncate(init_len);
+                event!(tracing::Level::ERROR, error = %e, "finalization failed");
+                return Err(e);
+            }
+        };
+        record_finalize(self.backend.name(), "encrypt", start);
+        self.buf.truncate(init_len + count);
+        let mut tag = vec![0; self.backend.tag_len()];
+        if !tag.is_empty() {
+            self.backend.get_tag(&mut tag)?;
+        }
+        // the MAC covers the tag wherever it sits in the stream
+        let tag_in_stream = self.tag_placement != TagPlacement::Detached;
+        let mut mac_tag = Vec::new();
+        if let Some(mac) = &mut self.mac {
+            let res = mac
+                .update(&self.buf[init_len..])
+                .and_then(|_| mac.update(if tag_in_stream { &tag } else { &[] }))
+                .and_then(|_| mac.finish(&mut mac_tag));
+            if let Err(e) = res {
+                event!(tracing::Level::ERROR, error = %e, "authentication failed");
+                return Err(e.into());
+            }
+        }
+        match self.tag_placement {
+            TagPlacement::Last => self.buf.extend_from_slice(&tag),
+            TagPlacement::First => {
+                let at = self.header_len;
+                self.buf.splice(at..at, tag.iter().copied());
+            }
+            TagPlacement::Detached => (),
+        }
+        self.buf.extend_from_slice(&mac_tag);
+        self.tag = Some(tag);
+        if let Some(digest) = &mut self.digest {
+            digest.finish()?;
+        }
+        self.is_finalized = true;
+        event!(
+            tracing::Level::DEBUG,
+            plaintext_bytes = self.plaintext_bytes,
+            ciphertext_bytes = self.ciphertext_bytes + self.ciphertext().len() as u64,
+            "finalized"
+        );
+        Ok(())
+    }
+
+    // binds the metadata block into the tag or MAC and writes it out after the header
+    fn write_metadata(&mut self) -> Result<(), CryptoIoError> {
+        let block = match self.metadata.take() {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        if self.backend.tag_len() > 0 {
+            self.backend.update_aad(&block)?;
+        }
+        if let Some(mac) = &mut self.mac {
+            mac.update(&block)?;
+        }
+        self.buf.extend_from_slice(&block);
+        Ok(())
+    }
+
+    // the exact length of the stream produced from `plaintext_len` bytes of plaintext
+    pub fn ciphertext_len_for(&self, plaintext_len: u64) -> u64 {
+        ciphertext_len(
+            padding_block(&self.backend),
+            self.header_len,
+            self.trailer_len(),
+            plaintext_len + self.footer_len(),
+        )
+    }
+
+    // the most plaintext a stream of `ciphertext_len` bytes can hold
+    pub fn max_plaintext_len_for(&self, ciphertext_len: u64) -> u64 {
+        max_plaintext_len(
+            padding_block(&self.backend),
+            self.header_len,
+            self.trailer_len(),
+            ciphertext_len,
+        )
+        .saturating_sub(self.footer_len())
+    }
+
+    fn footer_len(&self) -> u64 {
+        if self.footer.is_some() {
+            FOOTER_LEN as u64
+        } else {
+            0
+        }
+    }
+
+    // the AEAD tag, if it is in the stream, and the MAC
+    fn trailer_len(&self) -> usize {
+        let tag_len = match self.tag_placement {
+            TagPlacement::Detached => 0,
+            _ => self.backend.tag_len(),
+        };
+        tag_len + self.mac.as_ref().map_or(0, Mac::len)
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    pub(crate) fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B> EncryptCore<B> {
+    // panic if dropped before `finish`, instead of only logging it
+    pub fn panic_on_unfinalized(mut self, panic: bool) -> Self {
+        self.panic_on_unfinalized = panic;
+        self
+    }
+
+    // An empty plaintext still makes a complete stream, with whatever header, padding, tag and
+    // MAC the cipher and options call for, which decrypts back to nothing. With `reject` set,
+    // `finish` fails with `Empty` instead of producing one.
+    pub fn reject_empty(mut self, reject: bool) -> Self {
+        self.reject_empty = reject;
+        self
+    }
+
+    // appends an HMAC-SHA256 of the ciphertext on `finish`; must be set before any data is pushed
+    pub fn with_hmac(mut self, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.mac = Some(Mac::hmac_sha256(key)?);
+        Ok(self)
+    }
+
+    // like `with_hmac`, but authenticates with CMAC under `mac_cipher` (e.g. AES-256-CBC)
+    pub fn with_cmac(mut self, mac_cipher: Cipher, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.mac = Some(Mac::cmac(mac_cipher, key)?);
+        Ok(self)
+    }
+
+    // hashes the plaintext as it is pushed; the result is available from `digest` after `finish`
+    pub fn with_digest(mut self, md: MessageDigest) -> Result<Self, CryptoIoError> {
+        self.digest = Some(StreamDigest::new(md)?);
+        Ok(self)
+    }
+
+    pub fn digest(&self) -> Option<&[u8]> {
+        self.digest.as_ref().and_then(StreamDigest::value)
+    }
+
+    // ends the plaintext with a `Footer` giving its length and SHA-256, so the reader can catch
+    // truncation even without a tag or MAC; must be set before any data is pushed
+    pub fn with_footer(mut self) -> Result<Self, CryptoIoError> {
+        self.footer = Some(StreamDigest::new(MessageDigest::sha256())?);
+        Ok(self)
+    }
+
+    // prefixes the stream with a commitment to `key` (which must be the key the backend was
+    // created with), so a reader can reject the wrong key before decrypting anything; must be set
+    // before any data is pushed. The commitment is the same for every stream under `key`, so it
+    // shows which streams share a key.
+    pub fn with_key_commitment(mut self, key: &[u8]) -> Result<Self, CryptoIoError> {
+        let commitment = key_commitment(key)?;
+        self.header_len += commitment.len();
+        self.buf.extend_from_slice(&commitment);
+        Ok(self)
+    }
+
+    // where the AEAD tag goes; must be set before any data is pushed
+    pub fn with_tag_placement(mut self, placement: TagPlacement) -> Self {
+        self.tag_placement = placement;
+        self
+    }
+
+    // writes `metadata` in the clear after the header, authenticated as associated data of an
+    // AEAD cipher or by the MAC (an unauthenticated stream leaves it unauthenticated too); must be
+    // set before any data is pushed
+    pub fn with_metadata(mut self, metadata: &Metadata) -> Result<Self, CryptoIoError> {
+        let block = metadata::encode(metadata)?;
+        self.header_len += block.len();
+        self.metadata = Some(block);
+        Ok(self)
+    }
+
+    // puts `prefix` at the very start of the stream, ahead of any header
+    pub(crate) fn with_prefix(mut self, prefix: &[u8]) -> Self {
+        self.prefix_len += prefix.len();
+        self.header_len += prefix.len();
+        self.buf.splice(0..0, prefix.iter().copied());
+        self
+    }
+
+    // leaves out the tag length byte, for formats without a header; both ends must then be set
+    // up with the same tag length. Must be set before any data is pushed.
+    pub fn without_header(mut self) -> Self {
+        if self.tag_header {
+            self.buf.remove(self.prefix_len);
+            self.header_len -= 1;
+            self.tag_header = false;
+        }
+        self
+    }
+
+    // the AEAD tag, once `finish` has run
+    pub fn tag(&self) -> Option<&[u8]> {
+        self.tag.as_deref().filter(|tag| !tag.is_empty())
+    }
+
+    fn holds_back(&self) -> bool {
+        self.tag_placement == TagPlacement::First && !self.is_finalized
+    }
+
+    // ciphertext produced but not yet taken or consumed
+    pub fn ciphertext(&self) -> &[u8] {
+        if self.holds_back() {
+            return &[];
+        }
+        &self.buf[self.taken..]
+    }
+
+    // marks the first `n` bytes of `ciphertext` as handed out
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.ciphertext().len());
+        self.taken += n;
+        self.ciphertext_bytes += n as u64;
+        if self.taken == self.buf.len() {
+            self.buf.clear();
+            self.taken = 0;
+        }
+    }
+
+    pub fn take_ciphertext(&mut self) -> Vec<u8> {
+        if self.holds_back() {
+            return Vec::new();
+        }
+        let mut out = std::mem::take(&mut self.buf);
+        out.drain(..self.taken);
+        self.taken = 0;
+        self.ciphertext_bytes += out.len() as u64;
+        out
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.is_finalized
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.mac.is_some()
+    }
+
+    pub fn plaintext_bytes(&self) -> u64 {
+        self.plaintext_bytes
+    }
+
+    // ciphertext handed out so far
+    pub fn ciphertext_bytes(&self) -> u64 {
+        self.ciphertext_bytes
+    }
+}
+
+impl<B> Drop for EncryptCore<B> {
+    fn drop(&mut self) {
+        if self.is_finalized {
+            return;
+        }
+        event!(
+            tracing::Level::WARN,
+            plaintext_bytes = self.plaintext_bytes,
+            "dropped without finalizing, final block lost"
+        );
+        if self.panic_on_unfinalized && !std::thread::panicking() {
+            panic!("encrypted stream dropped without being finalized");
+        }
+    }
+}
+
+// The decrypting half: push ciphertext in as it arrives, take plaintext out, and `finish` at the
+// end of the ciphertext to verify the commitment, MAC and padding. `DecryptReader` drives one of
+// these.
+pub struct DecryptCore<B = OpensslBackend> {
+    backend: B,
+    // plaintext from `read` on has not been handed out yet
+    buf: Vec<u8>,
+    read: usize,
+    // plaintext before this has been hashed
+    hashed: usize,
+    is_finalized: bool,
+    mac: Option<Mac>,
+    digest: Option<StreamDigest>,
+    // hashes the plaintext to check against the footer, if one is expected; the last
+    // `FOOTER_LEN` bytes decrypted are held back until the end in case they are it
+    footer: Option<StreamDigest>,
+    trailer: Option<Footer>,
+    // trailing ciphertext withheld from the crypter until it is known not to be the MAC
+    held: Vec<u8>,
+    // whether the tag length byte of an AEAD stream is still to be read and checked
+    tag_header: bool,
+    // expected key commitment, until it has been read and checked
+    commitment: Option<Vec<u8>>,
+    header_len: usize,
+    tag_placement: TagPlacement,
+    // the AEAD tag as read from the start of the stream or given up front
+    tag: Vec<u8>,
+    // the key commitment or metadata block read so far
+    prefix: Vec<u8>,
+    // whether a metadata block is still to be read
+    expects_metadata: bool,
+    metadata: Option<Metadata>,
+    strict: bool,
+    reject_empty: bool,
+    // ciphertext collected until there is at least `coalesce` bytes to hand to the crypter
+    batch: Vec<u8>,
+    coalesce: usize,
+    // ciphertext passed to the crypter, excluding header and trailer
+    body_bytes: u64,
+    plaintext_bytes: u64,
+    ciphertext_bytes: u64,
+}
+impl DecryptCore {
+    pub fn new(cipher: Cipher, key: &[u8], iv: Option<&[u8]>) -> Result<Self, CryptoIoError> {
+        let backend = OpensslBackend::new(cipher, Mode::Decrypt, key, iv)?;
+        Ok(Self::with_backend(backend))
+    }
+}
+
+impl<B> DecryptCore<B>
+where
+    B: SymmetricBackend,
+{
+    // decrypts through `backend`, which must already be keyed for decryption
+    pub fn with_backend(backend: B) -> Self {
+        let strict = padding_block(&backend) > 1 || backend.tag_len() > 0;
+        let coalesce = backend.block_size();
+        let tag_header = backend.tag_len() > 0;
+        DecryptCore {
+            backend,
+            buf: Vec::new(),
+            read: 0,
+            hashed: 0,
+            is_finalized: false,
+            mac: None,
+            digest: None,
+            footer: None,
+            trailer: None,
+            held: Vec::new(),
+            tag_header,
+            commitment: None,
+            header_len: tag_header as usize,
+            tag_placement: TagPlacement::Last,
+            tag: Vec::new(),
+            prefix: Vec::new(),
+            expects_metadata: false,
+            metadata: None,
+            strict,
+            reject_empty: false,
+            batch: Vec::new(),
+            coalesce,
+            body_bytes: 0,
+            plaintext_bytes: 0,
+            ciphertext_bytes: 0,
+        }
+    }
+
+    pub fn push_ciphertext(&mut self, data: &[u8]) -> Result<(), CryptoIoError> {
+        if self.is_finalized {
+            return Err(CryptoIoError::Finalized);
+        }
+        self.ciphertext_bytes += data.len() as u64;
+        let mut data = data;
+        if self.tag_header && !data.is_empty() {
+            let (expected, actual) = (self.backend.tag_len(), data[0] as usize);
+            if actual != expected {
+                event!(
+                    tracing::Level::ERROR,
+                    expected,
+                    actual,
+                    "tag length mismatch"
+                );
+                return Err(CryptoIoError::TagLenMismatch { expected, actual });
+            }
+            self.tag_header = false;
+            data = &data[1..];
+        }
+        if let Some(expected) = &self.commitment {
+            let (prefix, rest) =
+                data.split_at((expected.len() - self.prefix.len()).min(data.len()));
+            self.prefix.extend_from_slice(prefix);
+            data = rest;
+            if self.prefix.len() < expected.len() {
+                return Ok(());
+            }
+            if !memcmp::eq(expected, &self.prefix) {
+                event!(tracing::Level::ERROR, "key commitment mismatch");
+                record_tag_failure(self.backend.name());
+                return Err(CryptoIoError::KeyMismatch);
+            }
+            self.commitment = None;
+            self.prefix = Vec::new();
+        }
+        if self.expects_metadata {
+            // the length comes first, then the rest of the block it gives
+            let len = metadata::block_len(&self.prefix)?;
+            if !self.fill_prefix(&mut data, len) {
+                return Ok(());
+            }
+            let len = metadata::block_len(&self.prefix)?;
+            if !self.fill_prefix(&mut data, len) {
+                return Ok(());
+            }
+            self.read_metadata()?;
+        }
+        if self.tag_placement == TagPlacement::First {
+            let tag_len = self.backend.tag_len();
+            let (tag, rest) = data.split_at((tag_len - self.tag.len()).min(data.len()));
+            self.tag.extend_from_slice(tag);
+            data = rest;
+            if self.tag.len() < tag_len {
+                return Ok(());
+            }
+        }
+        let trailer_len = self.trailer_len();
+        if trailer_len == 0 {
+            return self.decrypt(data);
+        }
+        let mut held = std::mem::take(&mut self.held);
+        held.extend_from_slice(data);
+        let split = held.len().saturating_sub(trailer_len);
+        let res = self.decrypt(&held[..split]);
+        held.drain(..split);
+        self.held = held;
+        res
+    }
+
+    // moves bytes from `data` into `prefix` until it holds `len`, returning whether it does
+    fn fill_prefix(&mut self, data: &mut &[u8], len: usize) -> bool {
+        let (head, rest) = data.split_at((len - self.prefix.len()).min(data.len()));
+        self.prefix.extend_from_slice(head);
+        *data = rest;
+        self.prefix.len() == len
+    }
+
+    fn read_metadata(&mut self) -> Result<(), CryptoIoError> {
+        let block = std::mem::take(&mut self.prefix);
+        let metadata = match metadata::decode(&block) {
+            Ok(a) => a,
+            Err(e) => {
+                event!(tracing::Level::ERROR, "malformed metadata");
+                return Err(e);
+            }
+        };
+        if self.backend.tag_len() > 0 {
+            self.backend.update_aad(&block)?;
+        }
+        if let Some(mac) = &mut self.mac {
+            mac.update(&block)?;
+        }
+        self.header_len += block.len();
+        self.expects_metadata = false;
+        self.metadata = Some(metadata);
+        Ok(())
+    }
+
+    fn decrypt(&mut self, data: &[u8]) -> Result<(), CryptoIoError> {
+        if self.batch.is_empty() && data.len() >= self.coalesce {
+            return self.feed(data);
+        }
+        self.batch.extend_from_slice(data);
+        if self.batch.len() < self.coalesce {
+            return Ok(());
+        }
+        self.flush_batch()
+    }
+
+    fn flush_batch(&mut self) -> Result<(), CryptoIoError> {
+        let mut batch = std::mem::take(&mut self.batch);
+        let res = self.feed(&batch);
+        batch.clear();
+        self.batch = batch;
+        res
+    }
+
+    fn feed(&mut self, data: &[u8]) -> Result<(), CryptoIoError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        let offset = self.body_offset() + self.body_bytes;
+        self.body_bytes += data.len() as u64;
+        if let Some(mac) = &mut self.mac {
+            if let Err(e) = mac.update(data) {
+                event!(tracing::Level::ERROR, error = %e, "authentication failed");
+                return Err(e.into());
+            }
+        }
+        let init_len = self.buf.len();
+        self.buf
+            .resize(init_len + data.len() + self.backend.block_size(), 0);
+        let count = match self.backend.update(data, &mut self.buf[init_len..]) {
+            Ok(a) => a,
+            Err(e) => {
+                self.buf.truncate(init_len);
+                event!(tracing::Level::ERROR, error = %e, offset, "decryption failed");
+                return Err(e.at_offset(offset));
+            }
+        };
+        self.buf.truncate(init_len + count);
+        self.hash_released()?;
+        self.plaintext_bytes += count as u64;
+        record_decrypted(self.backend.name(), count);
+        Ok(())
+    }
+
+    // hashes the plaintext that has become available since the last call
+    fn hash_released(&mut self) -> Result<(), ErrorStack> {
+        let end = self.released_len();
+        let data = &self.buf[self.hashed..end];
+        if let Some(digest) = &mut self.digest {
+            digest.update(data)?;
+        }
+        if let Some(footer) = &mut self.footer {
+            footer.update(data)?;
+        }
+        self.hashed = end;
+        Ok(())
+    }
+
+    // checks the footer at the end of the decrypted plaintext and strips it off
+    fn check_footer(&mut self) -> Result<(), CryptoIoError> {
+        let digest = match &mut self.footer {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        if self.buf.len() < FOOTER_LEN {
+            return Err(self.truncated(CryptoIoError::BadTag));
+        }
+        digest.finish()?;
+        let at = self.buf.len() - FOOTER_LEN;
+        let footer = Footer::decode(&self.buf[at..]);
+        let plaintext_len = self.plaintext_bytes - FOOTER_LEN as u64;
+        if footer.plaintext_len != plaintext_len {
+            event!(
+                tracing::Level::ERROR,
+                expected = footer.plaintext_len,
+                actual = plaintext_len,
+                "plaintext length does not match the footer"
+            );
+            return Err(CryptoIoError::Truncated);
+        }
+        if !memcmp::eq(&footer.sha256, digest.value().unwrap()) {
+            event!(
+                tracing::Level::ERROR,
+                "plaintext digest does not match the footer"
+            );
+            record_tag_failure(self.backend.name());
+            return Err(CryptoIoError::BadTag);
+        }
+        self.buf[at..].zeroize();
+        self.buf.truncate(at);
+        self.plaintext_bytes = plaintext_len;
+        self.trailer = Some(footer);
+        Ok(())
+    }
+
+    // outside strict mode a truncated stream is reported as the authentication failure it causes
+    fn truncated(&self, otherwise: CryptoIoError) -> CryptoIoError {
+        event!(tracing::Level::ERROR, "stream truncated");
+        if self.strict {
+            CryptoIoError::Truncated
+        } else {
+            otherwise
+        }
+    }
+
+    // called at the end of the ciphertext; the last of the plaintext is then available from
+    // `plaintext`. Later calls do nothing.
+    pub fn finish(&mut self) -> Result<(), CryptoIoError> {
+        if self.is_finalized {
+            return Ok(());
+        }
+        // no stream at all, where even an empty plaintext would have left something, is cut short
+        // rather than forged, whatever the cipher
+        if self.ciphertext_bytes == 0 && self.ciphertext_len_for(0) > 0 {
+            event!(tracing::Level::ERROR, "empty ciphertext");
+            return Err(CryptoIoError::Truncated);
+        }
+        if self.tag_header {
+            return Err(self.truncated(CryptoIoError::BadTag));
+        }
+        if self.commitment.is_some() {
+            return Err(self.truncated(CryptoIoError::KeyMismatch));
+        }
+        if self.expects_metadata {
+            return Err(self.truncated(CryptoIoError::BadTag));
+        }
+        let leading_tag_len = self.body_offset() as usize - self.header_len;
+        if self.tag.len() < leading_tag_len || self.held.len() < self.trailer_len() {
+            return Err(self.truncated(CryptoIoError::BadTag));
+        }
+        self.flush_batch()?;
+        let block_size = padding_block(&self.backend) as u64;
+        if self.strict
+            && block_size > 1
+            && (self.body_bytes == 0 || self.body_bytes % block_size != 0)
+        {
+            return Err(CryptoIoError::Truncated);
+        }
+        let (tag, mac_tag) = match self.tag_placement {
+            TagPlacement::Last => self.held.split_at(self.backend.tag_len()),
+            _ => (&self.tag[..], &self.held[..]),
+        };
+        if tag.len() != self.backend.tag_len() {
+            return Err(CryptoIoError::TagLenMismatch {
+                expected: self.backend.tag_len(),
+                actual: tag.len(),
+            });
+        }
+        let tag_in_stream = self.tag_placement != TagPlacement::Detached;
+        if let Some(mac) = &mut self.mac {
+            let res = mac
+                .update(if tag_in_stream { tag } else { &[] })
+                .and_then(|_| mac.verify(mac_tag));
+            let verified = match res {
+                Ok(a) => a,
+                Err(e) => {
+                    event!(tracing::Level::ERROR, error = %e, "authentication failed");
+                    return Err(e.into());
+                }
+            };
+            if !verified {
+                event!(tracing::Level::ERROR, "tag verification failed");
+                record_tag_failure(self.backend.name());
+                return Err(CryptoIoError::BadTag);
+            }
+        }
+        if !tag.is_empty() {
+            self.backend.set_tag(tag)?;
+        }
+        let init_len = self.buf.len();
+        self.buf.resize(init_len + self.backend.finalize_len(), 0);
+        let start = Instant::now();
+        let count = match self.backend.finalize(&mut self.buf[init_len..]) {
+            Ok(a) => a,
+            Err(e) => {
+                self.buf.truncate(init_len);
+                let offset = (self.header_len + leading_tag_len) as u64 + self.body_bytes;
+                event!(tracing::Level::ERROR, error = %e, offset, "finalization failed");
+                record_tag_failure(self.backend.name());
+                // OpenSSL reports a bad AEAD tag with an empty error stack
+                if !tag.is_empty() {
+                    return Err(CryptoIoError::BadTag);
+                }
+                return Err(e.at_offset(offset));
+            }
+        };
+        record_finalize(self.backend.name(), "decrypt", start);
+        self.buf.truncate(init_len + count);
+        self.plaintext_bytes += count as u64;
+        record_decrypted(self.backend.name(), count);
+        self.hash_released()?;
+        self.check_footer()?;
+        if self.reject_empty && self.plaintext_bytes == 0 {
+            event!(tracing::Level::ERROR, "stream holds no plaintext");
+            return Err(CryptoIoError::Empty);
+        }
+        if let Some(digest) = &mut self.digest {
+            digest.finish()?;
+        }
+        self.is_finalized = true;
+        event!(
+            tracing::Level::DEBUG,
+            plaintext_bytes = self.plaintext_bytes,
+            ciphertext_bytes = self.ciphertext_bytes,
+            "finalized"
+        );
+        Ok(())
+    }
+
+    // the exact length of a stream holding `plaintext_len` bytes of plaintext
+    pub fn ciphertext_len_for(&self, plaintext_len: u64) -> u64 {
+        ciphertext_len(
+            padding_block(&self.backend),
+            self.body_offset() as usize,
+            self.trailer_len(),
+            plaintext_len + self.footer_len(),
+        )
+    }
+
+    // the most plaintext a stream of `ciphertext_len` bytes can decrypt to, e.g. to size the
+    // output buffer
+    pub fn max_plaintext_len_for(&self, ciphertext_len: u64) -> u64 {
+        max_plaintext_len(
+            padding_block(&self.backend),
+            self.body_offset() as usize,
+            self.trailer_len(),
+            ciphertext_len,
+        )
+        .saturating_sub(self.footer_len())
+    }
+
+    fn footer_len(&self) -> u64 {
+        if self.footer.is_some() {
+            FOOTER_LEN as u64
+        } else {
+            0
+        }
+    }
+
+    // where the ciphertext proper starts: after the header, key commitment and any leading tag
+    fn body_offset(&self) -> u64 {
+        let tag_len = match self.tag_placement {
+            TagPlacement::First => self.backend.tag_len(),
+            _ => 0,
+        };
+        (self.header_len + tag_len) as u64
+    }
+
+    // what follows the ciphertext proper: the AEAD tag, if it goes last, and the MAC
+    fn trailer_len(&self) -> usize {
+        let tag_len = match self.tag_placement {
+            TagPlacement::Last => self.backend.tag_len(),
+            _ => 0,
+        };
+        tag_len + self.mac.as_ref().map_or(0, Mac::len)
+    }
+
+    pub(crate) fn name(&self) -> &'static str {
+        self.backend.name()
+    }
+
+    pub(crate) fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B> DecryptCore<B> {
+    // fails `finish` with `Empty` if the stream, though authentic, holds no plaintext
+    pub fn reject_empty(mut self, reject: bool) -> Self {
+        self.reject_empty = reject;
+        self
+    }
+
+    // expects an HMAC-SHA256 trailer as written by `EncryptCore::with_hmac`, and verifies it
+    // before the final block is released
+    pub fn with_hmac(mut self, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.mac = Some(Mac::hmac_sha256(key)?);
+        Ok(self)
+    }
+
+    // expects a CMAC trailer as written by `EncryptCore::with_cmac`
+    pub fn with_cmac(mut self, mac_cipher: Cipher, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.mac = Some(Mac::cmac(mac_cipher, key)?);
+        Ok(self)
+    }
+
+    // hashes the decrypted output; the result is available from `digest` after `finish`
+    pub fn with_digest(mut self, md: MessageDigest) -> Result<Self, CryptoIoError> {
+        self.digest = Some(StreamDigest::new(md)?);
+        Ok(self)
+    }
+
+    pub fn digest(&self) -> Option<&[u8]> {
+        self.digest.as_ref().and_then(StreamDigest::value)
+    }
+
+    // expects the plaintext to end with a `Footer`, as written by `EncryptCore::with_footer`,
+    // and checks the length and digest it records at the end of the stream
+    pub fn with_footer(mut self) -> Result<Self, CryptoIoError> {
+        self.footer = Some(StreamDigest::new(MessageDigest::sha256())?);
+        Ok(self)
+    }
+
+    // the verified footer, once `finish` has run
+    pub fn trailer(&self) -> Option<&Footer> {
+        self.trailer.as_ref()
+    }
+
+    // expects the stream to start with a commitment to `key`, as written by
+    // `EncryptCore::with_key_commitment`, and fails before decrypting if it does not match
+    pub fn with_key_commitment(mut self, key: &[u8]) -> Result<Self, CryptoIoError> {
+        let commitment = key_commitment(key)?;
+        self.header_len += commitment.len();
+        self.commitment = Some(commitment);
+        Ok(self)
+    }
+
+    // where to find the AEAD tag, which must match the encrypting side
+    pub fn with_tag_placement(mut self, placement: TagPlacement) -> Self {
+        self.tag_placement = placement;
+        self
+    }
+
+    // checks the stream against `tag`, carried separately as with `TagPlacement::Detached`
+    pub fn with_detached_tag(mut self, tag: &[u8]) -> Self {
+        self.tag_placement = TagPlacement::Detached;
+        self.tag = tag.to_vec();
+        self
+    }
+
+    // expects a metadata block after the header, as written by `EncryptCore::with_metadata`
+    pub fn with_metadata(mut self) -> Self {
+        self.expects_metadata = true;
+        self
+    }
+
+    // the metadata, once the block has been read; like the plaintext, it is only known to be
+    // authentic once `finish` succeeds
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.metadata.as_ref()
+    }
+
+    pub(crate) fn expects_metadata(&self) -> bool {
+        self.expects_metadata
+    }
+
+    // expects no tag length byte, as written by `EncryptCore::without_header`
+    pub fn without_header(mut self) -> Self {
+        if self.tag_header {
+            self.header_len -= 1;
+            self.tag_header = false;
+        }
+        self
+    }
+
+    // in strict mode (the default for padded and AEAD ciphers), a stream that ends mid-block or
+    // before its header, commitment, tag or MAC is complete fails with `Truncated` rather than
+    // being handed to the crypter. An AEAD stream cut anywhere after that still fails with
+    // `BadTag`: without a recorded length, its end cannot be told from a forged tag.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    // collects ciphertext until at least `bytes` (by default one block) are available before
+    // calling the crypter, for callers that push tiny fragments
+    pub fn coalesce(mut self, bytes: usize) -> Self {
+        self.coalesce = bytes;
+        self
+    }
+
+    // the decrypted plaintext that can be handed out: all of it but what might be the footer
+    fn released_len(&self) -> usize {
+        if self.footer.is_some() && !self.is_finalized {
+            self.buf.len().saturating_sub(FOOTER_LEN)
+        } else {
+            self.buf.len()
+        }
+    }
+
+    // plaintext produced but not yet taken or consumed
+    pub fn plaintext(&self) -> &[u8] {
+        &self.buf[self.read..self.released_len()]
+    }
+
+    // marks the first `n` bytes of `plaintext` as handed out
+    pub fn consume(&mut self, n: usize) {
+        assert!(n <= self.plaintext().len());
+        self.read += n;
+        if self.read == self.buf.len() {
+            self.buf.zeroize();
+            self.read = 0;
+            self.hashed = 0;
+        } else if self.read == self.released_len() {
+            // only the held-back bytes are left, so move them to the front
+            self.buf[..self.read].zeroize();
+            self.buf.drain(..self.read);
+            self.hashed -= self.read;
+            self.read = 0;
+        }
+    }
+
+    pub fn take_plaintext(&mut self) -> Vec<u8> {
+        let out = self.plaintext().to_vec();
+        self.consume(out.len());
+        out
+    }
+
+    pub fn is_finalized(&self) -> bool {
+        self.is_finalized
+    }
+
+    pub fn is_authenticated(&self) -> bool {
+        self.mac.is_some()
+    }
+
+    // plaintext produced so far
+    pub fn plaintext_bytes(&self) -> u64 {
+        self.plaintext_bytes
+    }
+
+    pub fn ciphertext_bytes(&self) -> u64 {
+        self.ciphertext_bytes
+    }
+}
+
+impl<B> Drop for DecryptCore<B> {
+    fn drop(&mut self) {
+        self.buf.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::sha::sha256;
+    use openssl::symm::{Cipher, Mode};
+
+    use super::{DecryptCore, EncryptCore, Footer, TagPlacement};
+    use crate::cipher::tag_len_range;
+    use crate::testing::{key_iv, sample};
+    use crate::{CryptoIoError, OpensslBackend};
+
+    fn ciphers() -> Vec<Cipher> {
+        vec![
+            Cipher::aes_128_cbc(),
+            Cipher::aes_256_ctr(),
+            Cipher::aes_256_gcm(),
+            Cipher::aes_128_ocb(),
+            Cipher::chacha20_poly1305(),
+        ]
+    }
+
+    fn encryptor(cipher: Cipher) -> EncryptCore {
+        let (key, iv) = key_iv(cipher);
+        EncryptCore::new(cipher, &key, iv.as_deref()).unwrap()
+    }
+
+    fn decryptor(cipher: Cipher) -> DecryptCore {
+        let (key, iv) = key_iv(cipher);
+        DecryptCore::new(cipher, &key, iv.as_deref()).unwrap()
+    }
+
+    // pushes the plaintext in uneven pieces
+    fn seal(mut core: EncryptCore, plaintext: &[u8]) -> Vec<u8> {
+        for chunk in plaintext.chunks(777) {
+            core.push_plaintext(chunk).unwrap();
+        }
+        core.finish().unwrap();
+        core.take_ciphertext()
+    }
+
+    fn open(mut core: DecryptCore, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoIoError> {
+        let mut out = Vec::new();
+        for chunk in ciphertext.chunks(333) {
+            core.push_ciphertext(chunk)?;
+            out.extend(core.take_plaintext());
+        }
+        core.finish()?;
+        out.extend(core.take_plaintext());
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trip() {
+        let plaintext = sample(10_000);
+        for cipher in ciphers() {
+            let ciphertext = seal(encryptor(cipher), &plaintext);
+            assert_eq!(open(decryptor(cipher), &ciphertext).unwrap(), plaintext);
+        }
+    }
+
+    // the predicted lengths match the stream, with and without the options that add to it
+    #[test]
+    fn stream_lengths() {
+        for cipher in ciphers() {
+            let (key, _) = key_iv(cipher);
+            for options in 0..3 {
+                let configure = |core: EncryptCore| match options {
+                    0 => core,
+                    1 => core
+                        .with_key_commitment(&key)
+                        .unwrap()
+                        .with_footer()
+                        .unwrap(),
+                    _ => core.with_hmac(b"mac key").unwrap(),
+                };
+                let configure_decryptor = |core: DecryptCore| match options {
+                    0 => core,
+                    1 => core
+                        .with_key_commitment(&key)
+                        .unwrap()
+                        .with_footer()
+                        .unwrap(),
+                    _ => core.with_hmac(b"mac key").unwrap(),
+                };
+                for len in (0..40).chain([1000, 1024]) {
+                    let core = configure(encryptor(cipher));
+                    let predicted = core.ciphertext_len_for(len as u64);
+                    let actual = seal(core, &sample(len)).len() as u64;
+                    assert_eq!(
+                        predicted,
+                        actual,
+                        "{:?} {} at {}",
+                        cipher.nid(),
+                        options,
+                        len
+                    );
+                    let decryptor = configure_decryptor(decryptor(cipher));
+                    assert_eq!(decryptor.ciphertext_len_for(len as u64), actual);
+                    let max = decryptor.max_plaintext_len_for(actual);
+                    assert!(max >= len as u64 && max < len as u64 + 16);
+                    assert_eq!(decryptor.ciphertext_len_for(max), actual);
+                }
+            }
+        }
+    }
+
+    fn is_truncated(result: Result<Vec<u8>, CryptoIoError>) -> bool {
+        match result {
+            Err(e) => e.kind() == std::io::ErrorKind::UnexpectedEof,
+            Ok(_) => false,
+        }
+    }
+
+    // cut before the tag (or padded block) is complete, which strict mode reports as EOF
+    #[test]
+    fn truncated_is_eof() {
+        let plaintext = sample(100);
+        for cipher in ciphers() {
+            let ciphertext = seal(encryptor(cipher), &plaintext);
+            let tag_len = tag_len_range(cipher).1;
+            let mut cuts = vec![0, 1];
+            if tag_len > 0 {
+                cuts.push(tag_len);
+            } else if cipher.block_size() > 1 {
+                // a cut at a block boundary leaves a well-formed stream that fails its padding
+                cuts.push(ciphertext.len() - 1);
+            } else {
+                // a stream cipher without a tag has no way to notice
+                continue;
+            }
+            for cut in cuts {
+                let result = open(decryptor(cipher), &ciphertext[..cut]);
+                assert!(is_truncated(result), "{:?} cut to {}", cipher.nid(), cut);
+            }
+        }
+    }
+
+    // past the point where the tag could be complete, a cut looks like a forged tag
+    #[test]
+    fn truncated_aead_body_is_bad_tag() {
+        let plaintext = sample(100);
+        for cipher in ciphers() {
+            if tag_len_range(cipher).1 == 0 {
+                continue;
+            }
+            let ciphertext = seal(encryptor(cipher), &plaintext);
+            let result = open(decryptor(cipher), &ciphertext[..ciphertext.len() - 1]);
+            assert!(matches!(result, Err(CryptoIoError::BadTag)));
+        }
+    }
+
+    #[test]
+    fn tampered() {
+        let plaintext = sample(100);
+        for cipher in ciphers() {
+            let mut ciphertext = seal(encryptor(cipher), &plaintext);
+            if tag_len_range(cipher).1 == 0 && cipher.block_size() == 1 {
+                continue;
+            }
+            *ciphertext.last_mut().unwrap() ^= 1;
+            assert!(open(decryptor(cipher), &ciphertext).is_err());
+        }
+    }
+
+    #[test]
+    fn lenient_truncation() {
+        let cipher = Cipher::aes_256_gcm();
+        let ciphertext = seal(encryptor(cipher), b"hello");
+        let result = open(decryptor(cipher).strict(false), &ciphertext[..5]);
+        assert!(matches!(result, Err(CryptoIoError::BadTag)));
+    }
+
+    fn backend(cipher: Cipher, mode: Mode, tag_len: usize) -> OpensslBackend {
+        let (key, iv) = key_iv(cipher);
+        let backend = OpensslBackend::new(cipher, mode, &key, iv.as_deref()).unwrap();
+        backend.with_tag_len(tag_len).unwrap()
+    }
+
+    #[test]
+    fn short_tags() {
+        let plaintext = sample(1000);
+        for &(cipher, tag_len) in &[
+            (Cipher::aes_128_gcm(), 12),
+            (Cipher::aes_256_gcm(), 14),
+            (Cipher::aes_128_ocb(), 8),
+        ] {
+            let encryptor = EncryptCore::with_backend(backend(cipher, Mode::Encrypt, tag_len));
+            let ciphertext = seal(encryptor, &plaintext);
+            assert_eq!(ciphertext[0] as usize, tag_len);
+            assert_eq!(ciphertext.len(), 1 + plaintext.len() + tag_len);
+            let decryptor = DecryptCore::with_backend(backend(cipher, Mode::Decrypt, tag_len));
+            assert_eq!(open(decryptor, &ciphertext).unwrap(), plaintext);
+
+            let mut tampered = ciphertext.clone();
+            *tampered.last_mut().unwrap() ^= 1;
+            let decryptor = DecryptCore::with_backend(backend(cipher, Mode::Decrypt, tag_len));
+            assert!(matches!(
+                open(decryptor, &tampered),
+                Err(CryptoIoError::BadTag)
+            ));
+        }
+    }
+
+    #[test]
+    fn tag_len_mismatch() {
+        let cipher = Cipher::aes_256_gcm();
+        let ciphertext = seal(
+            EncryptCore::with_backend(backend(cipher, Mode::Encrypt, 12)),
+            b"hello",
+        );
+        let result = open(decryptor(cipher), &ciphertext);
+        assert!(matches!(
+            result,
+            Err(CryptoIoError::TagLenMismatch {
+                expected: 16,
+                actual: 12
+            })
+        ));
+    }
+
+    #[test]
+    fn invalid_tag_len() {
+        let (key, iv) = key_iv(Cipher::aes_256_gcm());
+        let gcm = OpensslBackend::new(Cipher::aes_256_gcm(), Mode::Encrypt, &key, iv.as_deref());
+        assert!(matches!(
+            gcm.unwrap().with_tag_len(11),
+            Err(CryptoIoError::InvalidTagLen {
+                min: 12,
+                max: 16,
+                actual: 11
+            })
+        ));
+        let poly = Cipher::chacha20_poly1305();
+        let (key, iv) = key_iv(poly);
+        let poly = OpensslBackend::new(poly, Mode::Encrypt, &key, iv.as_deref()).unwrap();
+        assert!(poly.with_tag_len(12).is_err());
+    }
+
+    const AEADS: [fn() -> Cipher; 3] = [
+        Cipher::aes_256_gcm,
+        Cipher::aes_128_ocb,
+        Cipher::chacha20_poly1305,
+    ];
+
+    #[test]
+    fn tag_first() {
+        let plaintext = sample(1000);
+        for cipher in AEADS.iter().map(|c| c()) {
+            let last = seal(encryptor(cipher), &plaintext);
+            let mut core = encryptor(cipher).with_tag_placement(TagPlacement::First);
+            core.push_plaintext(&plaintext).unwrap();
+            // the tag is not known yet, so nothing can go out
+            assert!(core.ciphertext().is_empty());
+            core.finish().unwrap();
+            let first = core.take_ciphertext();
+            let tag = &last[last.len() - 16..];
+            assert_eq!(&first[1..17], tag);
+            assert_eq!(&first[17..], &last[1..last.len() - 16]);
+
+            let reader = decryptor(cipher).with_tag_placement(TagPlacement::First);
+            assert_eq!(open(reader, &first).unwrap(), plaintext);
+            let mut tampered = first.clone();
+            tampered[1] ^= 1;
+            let reader = decryptor(cipher).with_tag_placement(TagPlacement::First);
+            assert!(matches!(
+                open(reader, &tampered),
+                Err(CryptoIoError::BadTag)
+            ));
+        }
+    }
+
+    #[test]
+    fn detached_tag() {
+        let plaintext = sample(1000);
+        for cipher in AEADS.iter().map(|c| c()) {
+            let mut core = encryptor(cipher).with_tag_placement(TagPlacement::Detached);
+            core.push_plaintext(&plaintext).unwrap();
+            core.finish().unwrap();
+            let tag = core.tag().unwrap().to_vec();
+            let ciphertext = core.take_ciphertext();
+            assert_eq!(ciphertext.len(), 1 + plaintext.len());
+
+            let reader = decryptor(cipher).with_detached_tag(&tag);
+            assert_eq!(open(reader, &ciphertext).unwrap(), plaintext);
+            let mut wrong = tag.clone();
+            wrong[0] ^= 1;
+            let reader = decryptor(cipher).with_detached_tag(&wrong);
+            assert!(matches!(
+                open(reader, &ciphertext),
+                Err(CryptoIoError::BadTag)
+            ));
+        }
+    }
+
+    // the MAC covers the tag in either position, and leaves out a detached one
+    #[test]
+    fn placement_with_hmac() {
+        let plaintext = sample(1000);
+        let cipher = Cipher::aes_256_gcm();
+        for &placement in &[TagPlacement::First, TagPlacement::Detached] {
+            let core = encryptor(cipher).with_tag_placement(placement);
+            let mut core = core.with_hmac(b"mac key").unwrap();
+            core.push_plaintext(&plaintext).unwrap();
+            core.finish().unwrap();
+            let tag = core.tag().unwrap().to_vec();
+            let ciphertext = core.take_ciphertext();
+            let reader = || {
+                let core = decryptor(cipher).with_hmac(b"mac key").unwrap();
+                match placement {
+                    TagPlacement::Detached => core.with_detached_tag(&tag),
+                    _ => core.with_tag_placement(placement),
+                }
+            };
+            assert_eq!(open(reader(), &ciphertext).unwrap(), plaintext);
+            let mut tampered = ciphertext.clone();
+            tampered[1] ^= 1;
+            assert!(matches!(
+                open(reader(), &tampered),
+                Err(CryptoIoError::BadTag)
+            ));
+        }
+    }
+
+    #[test]
+    fn without_header_round_trip() {
+        let plaintext = sample(1000);
+        for cipher in ciphers() {
+            let with = seal(encryptor(cipher), &plaintext);
+            let without = seal(encryptor(cipher).without_header(), &plaintext);
+            assert_eq!(with.len() - without.len(), cipher_tag_header(cipher));
+            let decryptor = decryptor(cipher).without_header();
+            assert_eq!(open(decryptor, &without).unwrap(), plaintext);
+        }
+    }
+
+    // the tag length byte follows the prefix, so it is what `without_header` must drop
+    #[test]
+    fn without_header_after_prefix() {
+        let plaintext = sample(1000);
+        for cipher in ciphers() {
+            let core = encryptor(cipher).with_prefix(b"salt").without_header();
+            let ciphertext = seal(core, &plaintext);
+            assert_eq!(&ciphertext[..4], b"salt");
+            let decryptor = decryptor(cipher).without_header();
+            assert_eq!(open(decryptor, &ciphertext[4..]).unwrap(), plaintext);
+        }
+    }
+
+    fn seal_with_footer(cipher: Cipher, plaintext: &[u8]) -> Vec<u8> {
+        seal(encryptor(cipher).with_footer().unwrap(), plaintext)
+    }
+
+    fn open_with_footer(cipher: Cipher, ciphertext: &[u8]) -> Result<Footer, CryptoIoError> {
+        let mut core = decryptor(cipher).with_footer().unwrap();
+        let mut out = Vec::new();
+        for chunk in ciphertext.chunks(333) {
+            core.push_ciphertext(chunk)?;
+            out.extend(core.take_plaintext());
+        }
+        core.finish()?;
+        out.extend(core.take_plaintext());
+        assert_eq!(core.trailer().unwrap().plaintext_len, out.len() as u64);
+        Ok(*core.trailer().unwrap())
+    }
+
+    #[test]
+    fn footer_round_trip() {
+        for &len in &[0, 39, 40, 10_000] {
+            let plaintext = sample(len);
+            for cipher in ciphers() {
+                let ciphertext = seal_with_footer(cipher, &plaintext);
+                let core = encryptor(cipher).with_footer().unwrap();
+                assert_eq!(ciphertext.len() as u64, core.ciphertext_len_for(len as u64));
+                let footer = open_with_footer(cipher, &ciphertext).unwrap();
+                assert_eq!(footer.plaintext_len, len as u64);
+                assert_eq!(footer.sha256, sha256(&plaintext));
+                // the footer is stripped from what is handed out
+                let core = decryptor(cipher).with_footer().unwrap();
+                assert_eq!(open(core, &ciphertext).unwrap(), plaintext);
+            }
+        }
+    }
+
+    // what the footer adds: a stream cipher without a tag or MAC now notices truncation and
+    // tampering
+    #[test]
+    fn footer_without_tag() {
+        let cipher = Cipher::aes_256_ctr();
+        let ciphertext = seal_with_footer(cipher, &sample(1000));
+        for cut in &[1, 10, 40, 500] {
+            let result = open_with_footer(cipher, &ciphertext[..ciphertext.len() - cut]);
+            assert!(result.is_err(), "cut by {}", cut);
+        }
+        let mut tampered = ciphertext.clone();
+        tampered[100] ^= 1;
+        assert!(matches!(
+            open_with_footer(cipher, &tampered),
+            Err(CryptoIoError::BadTag)
+        ));
+        // the recorded length, just ahead of the digest
+        let mut tampered = ciphertext.clone();
+        let at = ciphertext.len() - 33;
+        tampered[at] ^= 1;
+        assert!(matches!(
+            open_with_footer(cipher, &tampered),
+            Err(CryptoIoError::Truncated)
+        ));
+        assert!(open_with_footer(cipher, &ciphertext[..30]).is_err());
+    }
+
+    fn cipher_tag_header(cipher: Cipher) -> usize {
+        match tag_len_range(cipher).1 {
+            0 => 0,
+            _ => 1,
+        }
+    }
+}
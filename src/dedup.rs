@@ -0,0 +1,198 @@
+use std::future::poll_fn;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, SeekFrom};
+use std::pin::Pin;
+
+use openssl::sha::Sha256;
+use openssl::symm::{Cipher, Mode};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+use zeroize::Zeroizing;
+
+use crate::mac::{hkdf_expand, hkdf_extract};
+use crate::reencrypt::copy_zeroizing;
+use crate::{
+    read_prefix, CryptoIoError, DecryptReader, EncryptCore, EncryptWriter, OpensslBackend,
+};
+
+const LABEL: &[u8] = b"tokio-openssl-symm deterministic iv v1";
+const READ_CHUNK_LEN: usize = 8192;
+
+// the SHA-256 digest of the rest of `reader`, which is then put back where it was
+pub(crate) async fn sha256_and_rewind<R>(reader: &mut R) -> IoResult<[u8; 32]>
+where
+    R: AsyncRead + AsyncSeek + Unpin,
+{
+    poll_fn(|cx| Pin::new(&mut *reader).start_seek(cx, SeekFrom::Current(0))).await?;
+    let start = poll_fn(|cx| Pin::new(&mut *reader).poll_complete(cx)).await?;
+    let mut hasher = Sha256::new();
+    let mut buf = Zeroizing::new(vec![0; READ_CHUNK_LEN]);
+    loop {
+        let n = poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut buf)).await?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    poll_fn(|cx| Pin::new(&mut *reader).start_seek(cx, SeekFrom::Start(start))).await?;
+    poll_fn(|cx| Pin::new(&mut *reader).poll_complete(cx)).await?;
+    Ok(hasher.finish())
+}
+
+// DELIBERATELY WEAKER than the rest of this crate: the IV is derived from the key and a SHA-256
+// digest of the plaintext instead of drawn at random, so the same plaintext under the same key
+// always encrypts to the same ciphertext, which lets content-addressed or deduplicating storage
+// keep one copy. The price is in the name: anyone who can see ciphertexts learns which of them
+// hold equal plaintexts, and whether a plaintext is stored again later. For GCM and the stream
+// modes, two different plaintexts only share an IV if their derived IVs collide, so keep well
+// under 2^32 distinct plaintexts per key with 96-bit IVs. Streams carry their IV at the start.
+pub struct EqualityRevealingCipher {
+    cipher: Cipher,
+    key: Zeroizing<Vec<u8>>,
+    iv_key: Zeroizing<Vec<u8>>,
+    iv_len: usize,
+}
+impl EqualityRevealingCipher {
+    pub fn new(cipher: Cipher, key: &[u8]) -> Result<Self, CryptoIoError> {
+        let iv_len = match cipher.iv_len() {
+            Some(len) => len,
+            None => {
+                return Err(IoError::new(
+                    IoErrorKind::InvalidInput,
+                    "deterministic IVs need a cipher that takes an IV",
+                )
+                .into())
+            }
+        };
+        if key.len() != cipher.key_len() {
+            return Err(CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_len(),
+                actual: key.len(),
+            });
+        }
+        let nid = (cipher.nid().as_raw() as u32).to_be_bytes();
+        let iv_key = hkdf_extract(LABEL, &[&nid, key])?;
+        Ok(EqualityRevealingCipher {
+            cipher,
+            key: Zeroizing::new(key.to_vec()),
+            iv_key,
+            iv_len,
+        })
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    // the IV for a plaintext with this SHA-256 digest, from HMAC-SHA256 under a key derived from
+    // the encryption key
+    pub fn iv_for_digest(&self, plaintext_sha256: &[u8; 32]) -> Result<Vec<u8>, CryptoIoError> {
+        Ok(hkdf_expand(&self.iv_key, &[plaintext_sha256], self.iv_len)?.to_vec())
+    }
+
+    // Encrypts into `writer` a plaintext whose SHA-256 digest is already known, e.g. because the
+    // storage layer addresses content by it. The stream starts with the IV.
+    pub fn writer<W>(
+        &self,
+        writer: W,
+        plaintext_sha256: &[u8; 32],
+    ) -> Result<EncryptWriter<W>, CryptoIoError> {
+        let iv = self.iv_for_digest(plaintext_sha256)?;
+        // not `EncryptCore::new`: repeating the IV for a repeated plaintext is the point here,
+        // and the nonce guard would reject it
+        let backend = OpensslBackend::new(self.cipher, Mode::Encrypt, &self.key, Some(&iv))?;
+        let core = EncryptCore::with_backend(backend).with_prefix(&iv);
+        Ok(EncryptWriter::with_core(writer, core))
+    }
+
+    // Reads `reader` twice, once to digest it and again, after seeking back to where it started,
+    // to encrypt it into `writer`. Returns the plaintext length. The plaintext must not change
+    // in between, or the IV no longer matches it.
+    pub async fn encrypt<R, W>(&self, mut reader: R, writer: W) -> IoResult<u64>
+    where
+        R: AsyncRead + AsyncSeek + Unpin,
+        W: AsyncWrite + Unpin,
+    {
+        let digest = sha256_and_rewind(&mut reader).await?;
+        let mut writer = self.writer(writer, &digest)?;
+        let len = copy_zeroizing(&mut reader, &mut writer).await?;
+        poll_fn(|cx| Pin::new(&mut writer).poll_shutdown(cx)).await?;
+        Ok(len)
+    }
+
+    // reads the IV from the start of a stream written by `writer` or `encrypt`
+    pub async fn reader<R>(&self, mut reader: R) -> Result<DecryptReader<R>, CryptoIoError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut iv = vec![0; self.iv_len];
+        read_prefix(&mut reader, &mut iv).await?;
+        DecryptReader::new(reader, self.cipher, &self.key, Some(&iv))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use openssl::sha::sha256;
+    use openssl::symm::Cipher;
+
+    use super::EqualityRevealingCipher;
+    use crate::testing::{block_on, read_to_end, sample, shutdown, write_all};
+    use crate::CryptoIoError;
+
+    fn encrypt(dedup: &EqualityRevealingCipher, plaintext: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        // from the middle of the reader, which must end up there again to be encrypted
+        let mut reader = Cursor::new([b"skipped".as_slice(), plaintext].concat());
+        reader.set_position(7);
+        let len = block_on(dedup.encrypt(reader, &mut out)).unwrap();
+        assert_eq!(len, plaintext.len() as u64);
+        out
+    }
+
+    fn decrypt(dedup: &EqualityRevealingCipher, ciphertext: &[u8]) -> Vec<u8> {
+        block_on(async {
+            let mut reader = dedup.reader(ciphertext).await.unwrap();
+            read_to_end(&mut reader).await.unwrap()
+        })
+    }
+
+    #[test]
+    fn equal_plaintexts_encrypt_equally() {
+        for cipher in [Cipher::aes_256_gcm(), Cipher::aes_128_cbc()] {
+            let dedup = EqualityRevealingCipher::new(cipher, &vec![7; cipher.key_len()]).unwrap();
+            let plaintext = sample(20_000);
+            let ciphertext = encrypt(&dedup, &plaintext);
+            assert_eq!(encrypt(&dedup, &plaintext), ciphertext);
+            assert_ne!(encrypt(&dedup, &sample(20_001)), ciphertext);
+            assert_eq!(decrypt(&dedup, &ciphertext), plaintext);
+
+            // the same stream from a digest known up front
+            let mut out = Vec::new();
+            block_on(async {
+                let mut writer = dedup.writer(&mut out, &sha256(&plaintext)).unwrap();
+                write_all(&mut writer, &plaintext).await.unwrap();
+                shutdown(&mut writer).await.unwrap();
+            });
+            assert_eq!(out, ciphertext);
+            let iv = dedup.iv_for_digest(&sha256(&plaintext)).unwrap();
+            assert_eq!(ciphertext[..iv.len()], iv[..]);
+
+            // another key gets other IVs
+            let other = EqualityRevealingCipher::new(cipher, &vec![8; cipher.key_len()]).unwrap();
+            assert_ne!(other.iv_for_digest(&sha256(&plaintext)).unwrap(), iv);
+        }
+    }
+
+    #[test]
+    fn invalid_setup() {
+        assert!(EqualityRevealingCipher::new(Cipher::aes_256_ecb(), &[7; 32]).is_err());
+        assert!(matches!(
+            EqualityRevealingCipher::new(Cipher::aes_256_gcm(), &[7; 16]),
+            Err(CryptoIoError::InvalidKeyLen {
+                expected: 32,
+                actual: 16
+            })
+        ));
+    }
+}
@@ -0,0 +1,89 @@
+use openssl::error::ErrorStack;
+use openssl::hash::{DigestBytes, Hasher, MessageDigest};
+
+pub(crate) struct StreamDigest {
+    hasher: Hasher,
+    value: Option<DigestBytes>,
+}
+impl StreamDigest {
+    pub(crate) fn new(md: MessageDigest) -> Result<Self, ErrorStack> {
+        Ok(StreamDigest {
+            hasher: Hasher::new(md)?,
+            value: None,
+        })
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) -> Result<(), ErrorStack> {
+        self.hasher.update(data)
+    }
+
+    pub(crate) fn finish(&mut self) -> Result<(), ErrorStack> {
+        self.value = Some(self.hasher.finish()?);
+        Ok(())
+    }
+
+    pub(crate) fn value(&self) -> Option<&[u8]> {
+        self.value.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::hash::MessageDigest;
+    use openssl::sha::sha256;
+    use openssl::symm::Cipher;
+
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::{DecryptReader, EncryptWriter};
+
+    // both ends hash the plaintext alone, and only report it once finished
+    #[test]
+    fn plaintext_digests() {
+        let plaintext = sample(10_000);
+        for &cipher in &[Cipher::aes_128_cbc(), Cipher::aes_128_ctr()] {
+            let (key, iv) = key_iv(cipher);
+            let mut ciphertext = Vec::new();
+            let digest = block_on(async {
+                let writer = EncryptWriter::new(&mut ciphertext, cipher, &key, iv.as_deref());
+                let mut writer = writer
+                    .unwrap()
+                    .with_digest(MessageDigest::sha256())
+                    .unwrap();
+                write_all(&mut writer, &plaintext).await.unwrap();
+                assert_eq!(writer.digest(), None);
+                shutdown(&mut writer).await.unwrap();
+                writer.digest().map(<[u8]>::to_vec)
+            });
+            assert_eq!(digest.as_deref(), Some(&sha256(&plaintext)[..]));
+
+            let reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref());
+            let mut reader = reader
+                .unwrap()
+                .with_digest(MessageDigest::sha256())
+                .unwrap();
+            assert_eq!(reader.digest(), None);
+            assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+            assert_eq!(reader.digest(), Some(&sha256(&plaintext)[..]));
+        }
+    }
+
+    // a stream that fails to authenticate gives no digest to trust
+    #[test]
+    fn no_digest_after_bad_mac() {
+        let cipher = Cipher::aes_128_ctr();
+        let (key, iv) = key_iv(cipher);
+        let mut ciphertext = Vec::new();
+        block_on(async {
+            let writer = EncryptWriter::new(&mut ciphertext, cipher, &key, iv.as_deref());
+            let mut writer = writer.unwrap().with_hmac(&[7; 32]).unwrap();
+            write_all(&mut writer, b"hello").await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        *ciphertext.last_mut().unwrap() ^= 1;
+        let reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref());
+        let reader = reader.unwrap().with_hmac(&[7; 32]).unwrap();
+        let mut reader = reader.with_digest(MessageDigest::sha256()).unwrap();
+        assert!(block_on(read_to_end(&mut reader)).is_err());
+        assert_eq!(reader.digest(), None);
+    }
+}
@@ -0,0 +1,313 @@
+use std::future::{poll_fn, Future};
+use std::io::Result as IoResult;
+use std::pin::{pin, Pin};
+use std::task::{Context, Poll};
+
+use openssl::symm::Cipher;
+use tokio::io::{self, AsyncRead, AsyncWrite, DuplexStream, ReadHalf, WriteHalf};
+
+use crate::record::{RecordReader, RecordWriter};
+use crate::reencrypt::copy_zeroizing;
+use crate::rng::fill_random;
+use crate::{derive_stream_keys, CryptoIoError, Role, StreamKeys};
+
+const MAX_BUF_SIZE: usize = 64 * 1024;
+// the random context `encrypted_duplex` derives each pipe's keys under
+const CONTEXT_LEN: usize = 32;
+
+// One end of an encrypted channel, such as an `encrypted_duplex` pipe: writes are encrypted on
+// the way in and the peer's writes are decrypted on the way out. Each direction is a series of
+// sealed records under an AEAD cipher, each checked before any of it is read, so bytes flipped,
+// dropped, reordered or replayed on the wire fail the read that reaches them with `BadTag`
+// instead of being handed out. Writes are sealed into a record once it is full (16 KiB) or on
+// flush, so flush after each message of an interactive protocol. `shutdown` sends this end's
+// final record, and the peer then reads EOF; a direction that stops without one reads as
+// `Truncated`, so a cut connection is not mistaken for a clean end. `rekey` moves this end's
+// writes onto a new key in-band.
+#[derive(Debug)]
+pub struct EncryptedStream<S = DuplexStream> {
+    writer: RecordWriter<WriteHalf<S>>,
+    reader: RecordReader<ReadHalf<S>>,
+}
+impl<S> EncryptedStream<S>
+where
+    S: AsyncRead + AsyncWrite,
+{
+    // encrypts what is written to `stream` under `write_key` and decrypts what is read from it
+    // under `read_key`, with an AEAD `cipher`. The last 8 bytes of each IV are replaced by the
+    // record number, so each direction needs its own key, or an IV that differs in what is left.
+    pub fn new(
+        stream: S,
+        cipher: Cipher,
+        write_key: &[u8],
+        write_iv: Option<&[u8]>,
+        read_key: &[u8],
+        read_iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError> {
+        let (read, write) = io::split(stream);
+        Ok(EncryptedStream {
+            writer: RecordWriter::new(write, cipher, write_key, write_iv)?,
+            reader: RecordReader::new(read, cipher, read_key, read_iv)?,
+        })
+    }
+
+    // Moves this end's writes onto a fresh key: what has been written so far is sealed, then a
+    // rekey record, and later records are sealed under a key derived from the old one, which is
+    // forgotten. The peer follows when it reads the rekey record. Nothing is sent until the next
+    // write, flush or shutdown. Each key starts its record numbering again, so rekeying also
+    // keeps a long-lived stream clear of `RecordLimit`.
+    pub fn rekey(&mut self) -> Result<(), CryptoIoError> {
+        self.writer.rekey()
+    }
+}
+
+// An in-memory, bidirectional channel encrypted under `key` with an AEAD `cipher`, for tests and
+// in-process pipelines. Each pipe derives its own key per direction from `key` and a random
+// context, with `derive_stream_keys`.
+pub fn encrypted_duplex(
+    key: &[u8],
+    cipher: Cipher,
+) -> Result<(EncryptedStream, EncryptedStream), CryptoIoError> {
+    let mut context = [0; CONTEXT_LEN];
+    fill_random(&mut context)?;
+    let a_keys = derive_stream_keys(key, &context, cipher, Role::Client)?;
+    let b_keys = derive_stream_keys(key, &context, cipher, Role::Server)?;
+    let (a, b) = io::duplex(MAX_BUF_SIZE);
+    Ok((a_keys.encrypted_stream(a)?, b_keys.encrypted_stream(b)?))
+}
+
+impl<S> AsyncRead for EncryptedStream<S>
+where
+    S: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.get_mut().reader).poll_read(cx, buf)
+    }
+}
+
+impl<S> AsyncWrite for EncryptedStream<S>
+where
+    S: AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        Pin::new(&mut self.get_mut().writer).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_shutdown(cx)
+    }
+}
+
+// plaintext bytes carried each way by `tunnel`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TunnelStats {
+    // from the plaintext side out over the encrypted side
+    pub sent: u64,
+    // from the encrypted side back to the plaintext side
+    pub received: u64,
+}
+
+// Relays between a plaintext connection and an encrypted one until both directions have ended,
+// the core of a port forwarder: what arrives on `plain` is encrypted under the send keys onto
+// `encrypted`, and what arrives on `encrypted` is decrypted under the receive keys back onto
+// `plain`. When either source reaches EOF its direction is finalized and the writing side shut
+// down, while the other direction carries on. The first error in either direction ends both.
+// The encrypted side speaks the same sealed records as `EncryptedStream`, so each record is
+// checked before it is forwarded to `plain`, and a cut connection fails with `Truncated`.
+pub async fn tunnel<A, B>(plain: A, encrypted: B, keys: &StreamKeys) -> IoResult<TunnelStats>
+where
+    A: AsyncRead + AsyncWrite,
+    B: AsyncRead + AsyncWrite,
+{
+    let (mut plain_read, mut plain_write) = io::split(plain);
+    let (encrypted_read, encrypted_write) = io::split(encrypted);
+    let cipher = keys.cipher();
+    let mut writer = RecordWriter::new(encrypted_write, cipher, keys.send_key(), keys.send_iv())?;
+    let mut reader = RecordReader::new(
+        encrypted_read,
+        cipher,
+        keys.receive_key(),
+        keys.receive_iv(),
+    )?;
+    let sent = async {
+        let n = copy_zeroizing(&mut plain_read, &mut writer).await?;
+        poll_fn(|cx| Pin::new(&mut writer).poll_shutdown(cx)).await?;
+        Ok(n)
+    };
+    let received = async {
+        let n = copy_zeroizing(&mut reader, &mut plain_write).await?;
+        poll_fn(|cx| Pin::new(&mut plain_write).poll_shutdown(cx)).await?;
+        Ok(n)
+    };
+    let (sent, received) = try_join(sent, received).await?;
+    Ok(TunnelStats { sent, received })
+}
+
+// runs both futures to completion, or until one fails
+async fn try_join<A, B, T, U>(a: A, b: B) -> IoResult<(T, U)>
+where
+    A: Future<Output = IoResult<T>>,
+    B: Future<Output = IoResult<U>>,
+{
+    let (mut a, mut b) = (pin!(a), pin!(b));
+    let (mut a_res, mut b_res) = (None, None);
+    poll_fn(|cx| -> Poll<IoResult<()>> {
+        if a_res.is_none() {
+            if let Poll::Ready(res) = a.as_mut().poll(cx) {
+                a_res = Some(res?);
+            }
+        }
+        if b_res.is_none() {
+            if let Poll::Ready(res) = b.as_mut().poll(cx) {
+                b_res = Some(res?);
+            }
+        }
+        if a_res.is_some() && b_res.is_some() {
+            Poll::Ready(Ok(()))
+        } else {
+            Poll::Pending
+        }
+    })
+    .await?;
+    Ok((a_res.unwrap(), b_res.unwrap()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::io::Error as IoError;
+    use std::pin::Pin;
+
+    use openssl::symm::Cipher;
+    use tokio::io::AsyncWrite;
+
+    use super::{encrypted_duplex, try_join, tunnel, TunnelStats};
+    use crate::testing::{join, read_to_end, sample, shutdown, write_all};
+    use crate::{derive_stream_keys, CryptoIoError, Role};
+
+    // more than the pipe buffers, so each side has to wait for the other to read
+    #[test]
+    fn both_directions() {
+        let cipher = Cipher::aes_256_gcm();
+        let (mut a, mut b) = encrypted_duplex(&[7; 32], cipher).unwrap();
+        let (ping, pong) = (sample(200_000), sample(150_000));
+        let (read_by_a, read_by_b) = join(
+            async {
+                write_all(&mut a, &ping).await?;
+                shutdown(&mut a).await?;
+                read_to_end(&mut a).await
+            },
+            async {
+                let read = read_to_end(&mut b).await?;
+                write_all(&mut b, &pong).await?;
+                shutdown(&mut b).await?;
+                Ok::<_, std::io::Error>(read)
+            },
+        );
+        assert_eq!(read_by_b.unwrap(), ping);
+        assert_eq!(read_by_a.unwrap(), pong);
+    }
+
+    // a peer that goes away without shutting down reads as cut off, not as a clean end
+    #[test]
+    fn cut_connection() {
+        let (mut a, mut b) = encrypted_duplex(&[7; 32], Cipher::aes_256_gcm()).unwrap();
+        let (_, read) = join(
+            async {
+                write_all(&mut a, b"partial").await?;
+                poll_fn(|cx| Pin::new(&mut a).poll_flush(cx)).await?;
+                drop(a);
+                Ok::<_, IoError>(())
+            },
+            read_to_end(&mut b),
+        );
+        let e = read.unwrap_err();
+        assert!(matches!(
+            CryptoIoError::from_io(&e).map(|e| e.root()),
+            Some(CryptoIoError::Truncated)
+        ));
+    }
+
+    // rekeying mid-stream, more than once, is invisible to the reader
+    #[test]
+    fn rekey_mid_stream() {
+        let (mut a, mut b) = encrypted_duplex(&[7; 32], Cipher::aes_256_gcm()).unwrap();
+        let data = sample(100_000);
+        let (_, read) = join(
+            async {
+                for chunk in data.chunks(30_000) {
+                    write_all(&mut a, chunk).await?;
+                    a.rekey()?;
+                }
+                shutdown(&mut a).await
+            },
+            read_to_end(&mut b),
+        );
+        assert_eq!(read.unwrap(), data);
+    }
+
+    // an app talking through a tunnel to a server that speaks the encrypted stream itself
+    #[test]
+    fn tunnel_relays_both_ways() {
+        let cipher = Cipher::aes_256_gcm();
+        let client_keys = derive_stream_keys(&[7; 32], b"conn", cipher, Role::Client).unwrap();
+        let server_keys = derive_stream_keys(&[7; 32], b"conn", cipher, Role::Server).unwrap();
+        let (mut app, plain) = tokio::io::duplex(4096);
+        let (encrypted, wire) = tokio::io::duplex(4096);
+        let mut server = server_keys.encrypted_stream(wire).unwrap();
+        let (ping, pong) = (sample(50_000), sample(30_000));
+        let (stats, read) = join(
+            tunnel(plain, encrypted, &client_keys),
+            try_join(
+                async {
+                    write_all(&mut app, &ping).await?;
+                    shutdown(&mut app).await?;
+                    read_to_end(&mut app).await
+                },
+                async {
+                    let read = read_to_end(&mut server).await?;
+                    write_all(&mut server, &pong).await?;
+                    shutdown(&mut server).await?;
+                    Ok::<_, IoError>(read)
+                },
+            ),
+        );
+        let (read_by_app, read_by_server) = read.unwrap();
+        assert_eq!(read_by_server, ping);
+        assert_eq!(read_by_app, pong);
+        assert_eq!(
+            stats.unwrap(),
+            TunnelStats {
+                sent: ping.len() as u64,
+                received: pong.len() as u64,
+            }
+        );
+    }
+
+    #[test]
+    fn tunnel_fails_on_a_bad_stream() {
+        let cipher = Cipher::aes_256_gcm();
+        let keys = derive_stream_keys(&[7; 32], b"conn", cipher, Role::Client).unwrap();
+        let (mut app, plain) = tokio::io::duplex(4096);
+        let (encrypted, mut wire) = tokio::io::duplex(4096);
+        let (stats, _) = join(tunnel(plain, encrypted, &keys), async {
+            shutdown(&mut app).await?;
+            // an empty record that doesn't open
+            write_all(&mut wire, &[0; 21]).await?;
+            shutdown(&mut wire).await
+        });
+        let e = stats.unwrap_err();
+        assert!(matches!(
+            CryptoIoError::from_io(&e).map(|e| e.root()),
+            Some(CryptoIoError::BadTag)
+        ));
+    }
+}
@@ -0,0 +1,397 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::task::Poll;
+
+use openssl::error::ErrorStack;
+use openssl::symm::Cipher;
+
+#[derive(Debug)]
+pub enum CryptoIoError {
+    Io(IoError),
+    OpenSsl(ErrorStack),
+    // the crypter rejected the ciphertext (bad padding or tag) at this stream offset
+    Decrypt {
+        offset: u64,
+        error: ErrorStack,
+    },
+    // MAC or AEAD tag verification failed
+    BadTag,
+    // the final block did not carry valid padding
+    BadPadding,
+    // the stream does not start with a commitment to the configured key
+    KeyMismatch,
+    // the stream ended mid-block or before its commitment/MAC
+    Truncated,
+    InvalidKeyLen {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidIvLen {
+        expected: usize,
+        actual: usize,
+    },
+    InvalidTagLen {
+        min: usize,
+        max: usize,
+        actual: usize,
+    },
+    // the stream header records a different AEAD tag length than the one configured
+    TagLenMismatch {
+        expected: usize,
+        actual: usize,
+    },
+    // the nonce guard has already seen this IV used for encryption under this key
+    NonceReuse,
+    // the peer offered or chose a handshake protocol version outside the accepted range
+    UnsupportedVersion {
+        min: u8,
+        max: u8,
+        actual: u8,
+    },
+    // the compressed stream names an algorithm that is unknown or not enabled in this build
+    UnknownCompression(u8),
+    // a header names an algorithm id this version does not know
+    UnknownAlgorithm(u8),
+    // the stream metadata block is malformed or too large
+    InvalidMetadata,
+    // the ciphertext differs from its `Manifest` in this chunk
+    ManifestMismatch {
+        chunk: u64,
+    },
+    // the stream holds no plaintext, and was set up to reject that
+    Empty,
+    // data was supplied after the stream was finalized
+    Finalized,
+    // a sealed record stream has numbered as many records as its nonces can tell apart
+    RecordLimit,
+    // an earlier error left the stream in an unknown state
+    Poisoned,
+    // data was written after `shutdown` was called
+    Closed,
+    // an error from the adapter, with how far into the stream it had got
+    At {
+        plaintext_offset: u64,
+        ciphertext_offset: u64,
+        error: Box<CryptoIoError>,
+    },
+}
+impl CryptoIoError {
+    // recovers the typed error from an `io::Error` produced by one of the adapters
+    pub fn from_io(err: &IoError) -> Option<&CryptoIoError> {
+        err.get_ref().and_then(|e| e.downcast_ref())
+    }
+
+    // the underlying error, without offset context
+    pub fn root(&self) -> &CryptoIoError {
+        match self {
+            CryptoIoError::At { error, .. } => error.root(),
+            e => e,
+        }
+    }
+
+    // (plaintext, ciphertext) offsets at which the error occurred
+    pub fn offsets(&self) -> Option<(u64, u64)> {
+        match self {
+            CryptoIoError::At {
+                plaintext_offset,
+                ciphertext_offset,
+                ..
+            } => Some((*plaintext_offset, *ciphertext_offset)),
+            _ => None,
+        }
+    }
+
+    pub fn kind(&self) -> IoErrorKind {
+        match self {
+            CryptoIoError::At { error, .. } => error.kind(),
+            CryptoIoError::Io(e) => e.kind(),
+            CryptoIoError::OpenSsl(_) | CryptoIoError::Finalized | CryptoIoError::RecordLimit => {
+                IoErrorKind::Other
+            }
+            CryptoIoError::Poisoned | CryptoIoError::Closed => IoErrorKind::BrokenPipe,
+            CryptoIoError::Decrypt { .. }
+            | CryptoIoError::BadTag
+            | CryptoIoError::BadPadding
+            | CryptoIoError::KeyMismatch
+            | CryptoIoError::TagLenMismatch { .. }
+            | CryptoIoError::UnsupportedVersion { .. }
+            | CryptoIoError::UnknownCompression(_)
+            | CryptoIoError::UnknownAlgorithm(_)
+            | CryptoIoError::InvalidMetadata
+            | CryptoIoError::ManifestMismatch { .. }
+            | CryptoIoError::Empty => IoErrorKind::InvalidData,
+            CryptoIoError::Truncated => IoErrorKind::UnexpectedEof,
+            CryptoIoError::InvalidKeyLen { .. }
+            | CryptoIoError::InvalidIvLen { .. }
+            | CryptoIoError::InvalidTagLen { .. }
+            | CryptoIoError::NonceReuse => IoErrorKind::InvalidInput,
+        }
+    }
+
+    fn try_clone(&self) -> Option<Self> {
+        Some(match self {
+            CryptoIoError::Io(_) => return None,
+            CryptoIoError::OpenSsl(e) => CryptoIoError::OpenSsl(e.clone()),
+            CryptoIoError::Decrypt { offset, error } => CryptoIoError::Decrypt {
+                offset: *offset,
+                error: error.clone(),
+            },
+            CryptoIoError::BadTag => CryptoIoError::BadTag,
+            CryptoIoError::BadPadding => CryptoIoError::BadPadding,
+            CryptoIoError::KeyMismatch => CryptoIoError::KeyMismatch,
+            CryptoIoError::Truncated => CryptoIoError::Truncated,
+            CryptoIoError::InvalidKeyLen { expected, actual } => CryptoIoError::InvalidKeyLen {
+                expected: *expected,
+                actual: *actual,
+            },
+            CryptoIoError::InvalidIvLen { expected, actual } => CryptoIoError::InvalidIvLen {
+                expected: *expected,
+                actual: *actual,
+            },
+            CryptoIoError::InvalidTagLen { min, max, actual } => CryptoIoError::InvalidTagLen {
+                min: *min,
+                max: *max,
+                actual: *actual,
+            },
+            CryptoIoError::TagLenMismatch { expected, actual } => CryptoIoError::TagLenMismatch {
+                expected: *expected,
+                actual: *actual,
+            },
+            CryptoIoError::NonceReuse => CryptoIoError::NonceReuse,
+            CryptoIoError::UnsupportedVersion { min, max, actual } => {
+                CryptoIoError::UnsupportedVersion {
+                    min: *min,
+                    max: *max,
+                    actual: *actual,
+                }
+            }
+            CryptoIoError::UnknownCompression(id) => CryptoIoError::UnknownCompression(*id),
+            CryptoIoError::UnknownAlgorithm(id) => CryptoIoError::UnknownAlgorithm(*id),
+            CryptoIoError::InvalidMetadata => CryptoIoError::InvalidMetadata,
+            CryptoIoError::ManifestMismatch { chunk } => {
+                CryptoIoError::ManifestMismatch { chunk: *chunk }
+            }
+            CryptoIoError::Empty => CryptoIoError::Empty,
+            CryptoIoError::Finalized => CryptoIoError::Finalized,
+            CryptoIoError::RecordLimit => CryptoIoError::RecordLimit,
+            CryptoIoError::Poisoned => CryptoIoError::Poisoned,
+            CryptoIoError::Closed => CryptoIoError::Closed,
+            CryptoIoError::At {
+                plaintext_offset,
+                ciphertext_offset,
+                error,
+            } => CryptoIoError::At {
+                plaintext_offset: *plaintext_offset,
+                ciphertext_offset: *ciphertext_offset,
+                error: Box::new(error.try_clone()?),
+            },
+        })
+    }
+
+    // a backend error during decryption, located at ciphertext `offset`
+    pub(crate) fn at_offset(self, offset: u64) -> Self {
+        match self {
+            CryptoIoError::OpenSsl(error) => CryptoIoError::Decrypt { offset, error },
+            e => e,
+        }
+    }
+
+    pub(crate) fn init(cipher: Cipher, key: &[u8], e: ErrorStack) -> Self {
+        if key.len() != cipher.key_len() {
+            return CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_len(),
+                actual: key.len(),
+            };
+        }
+        CryptoIoError::OpenSsl(e)
+    }
+}
+
+impl fmt::Display for CryptoIoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CryptoIoError::Io(e) => write!(f, "{}", e),
+            CryptoIoError::OpenSsl(e) => write!(f, "{}", e),
+            CryptoIoError::Decrypt { offset, error } => {
+                write!(f, "decryption failed at offset {}: {}", offset, error)
+            }
+            CryptoIoError::BadTag => write!(f, "authentication failed"),
+            CryptoIoError::BadPadding => write!(f, "bad padding"),
+            CryptoIoError::KeyMismatch => write!(f, "key commitment mismatch"),
+            CryptoIoError::Truncated => write!(f, "ciphertext truncated"),
+            CryptoIoError::InvalidKeyLen { expected, actual } => write!(
+                f,
+                "invalid key length: expected {} bytes, got {}",
+                expected, actual
+            ),
+            CryptoIoError::InvalidIvLen { expected, actual } => write!(
+                f,
+                "invalid IV length: expected {} bytes, got {}",
+                expected, actual
+            ),
+            CryptoIoError::InvalidTagLen { min, max, actual } if min == max => write!(
+                f,
+                "invalid tag length: expected {} bytes, got {}",
+                min, actual
+            ),
+            CryptoIoError::InvalidTagLen { min, max, actual } => write!(
+                f,
+                "invalid tag length: expected {} to {} bytes, got {}",
+                min, max, actual
+            ),
+            CryptoIoError::TagLenMismatch { expected, actual } => {
+                write!(f, "stream uses {}-byte tags, expected {}", actual, expected)
+            }
+            CryptoIoError::NonceReuse => write!(f, "IV reused with the same key"),
+            CryptoIoError::UnsupportedVersion { min, max, actual } => write!(
+                f,
+                "peer uses protocol version {}, expected {} to {}",
+                actual, min, max
+            ),
+            CryptoIoError::UnknownCompression(id) => {
+                write!(f, "unknown or unsupported compression algorithm {}", id)
+            }
+            CryptoIoError::UnknownAlgorithm(id) => write!(f, "unknown cipher algorithm {}", id),
+            CryptoIoError::InvalidMetadata => write!(f, "malformed stream metadata"),
+            CryptoIoError::ManifestMismatch { chunk } => {
+                write!(
+                    f,
+                    "ciphertext does not match its manifest in chunk {}",
+                    chunk
+                )
+            }
+            CryptoIoError::Empty => write!(f, "stream holds no plaintext"),
+            CryptoIoError::Finalized => write!(f, "stream already finalized"),
+            CryptoIoError::RecordLimit => write!(f, "record numbers exhausted under this key"),
+            CryptoIoError::Poisoned => write!(f, "stream unusable after an earlier error"),
+            CryptoIoError::Closed => write!(f, "stream already shut down"),
+            CryptoIoError::At {
+                plaintext_offset,
+                ciphertext_offset,
+                error,
+            } => write!(
+                f,
+                "{} (at plaintext offset {}, ciphertext offset {})",
+                error, plaintext_offset, ciphertext_offset
+            ),
+        }
+    }
+}
+
+impl Error for CryptoIoError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            CryptoIoError::Io(e) => Some(e),
+            CryptoIoError::OpenSsl(e) => Some(e),
+            CryptoIoError::Decrypt { error, .. } => Some(error),
+            CryptoIoError::At { error, .. } => error.source(),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) fn openssl_err(e: ErrorStack) -> IoError {
+    CryptoIoError::OpenSsl(e).into()
+}
+
+impl From<IoError> for CryptoIoError {
+    fn from(e: IoError) -> Self {
+        CryptoIoError::Io(e)
+    }
+}
+
+impl From<ErrorStack> for CryptoIoError {
+    fn from(e: ErrorStack) -> Self {
+        CryptoIoError::OpenSsl(e)
+    }
+}
+
+impl From<CryptoIoError> for IoError {
+    fn from(e: CryptoIoError) -> Self {
+        match e {
+            CryptoIoError::Io(e) => e,
+            e => IoError::new(e.kind(), e),
+        }
+    }
+}
+
+fn at(e: IoError, plaintext_offset: u64, ciphertext_offset: u64) -> IoError {
+    let error = if CryptoIoError::from_io(&e).is_some() {
+        e.into_inner().unwrap().downcast().unwrap()
+    } else {
+        Box::new(CryptoIoError::Io(e))
+    };
+    if let CryptoIoError::At { .. } = *error {
+        return (*error).into();
+    }
+    CryptoIoError::At {
+        plaintext_offset,
+        ciphertext_offset,
+        error,
+    }
+    .into()
+}
+
+fn is_transient(e: &IoError) -> bool {
+    match e.kind() {
+        IoErrorKind::Interrupted | IoErrorKind::WouldBlock | IoErrorKind::TimedOut => {
+            CryptoIoError::from_io(e).is_none()
+        }
+        _ => false,
+    }
+}
+
+// io::Error is not Clone, so the copy kept for `take_error` preserves the typed error where there
+// is one and otherwise only the kind and message
+fn duplicate(e: &IoError) -> IoError {
+    match CryptoIoError::from_io(e).and_then(CryptoIoError::try_clone) {
+        Some(e) => e.into(),
+        None => IoError::new(e.kind(), e.to_string()),
+    }
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Poison {
+    poisoned: bool,
+    error: Option<IoError>,
+}
+impl Poison {
+    pub(crate) fn check(&self) -> IoResult<()> {
+        if self.poisoned {
+            Err(CryptoIoError::Poisoned.into())
+        } else {
+            Ok(())
+        }
+    }
+
+    // Poisons on error, attaching the stream offsets the adapter had reached. Transient errors
+    // from the inner reader or writer are passed on as they are: the adapters only call it
+    // before changing any state of their own, keeping buffered ciphertext, so the same call can
+    // simply be made again.
+    pub(crate) fn track<T>(
+        &mut self,
+        res: Poll<IoResult<T>>,
+        plaintext_offset: u64,
+        ciphertext_offset: u64,
+    ) -> Poll<IoResult<T>> {
+        match res {
+            Poll::Ready(Err(e)) if is_transient(&e) => Poll::Ready(Err(e)),
+            Poll::Ready(Err(e)) => {
+                let e = at(e, plaintext_offset, ciphertext_offset);
+                self.poisoned = true;
+                self.error = Some(duplicate(&e));
+                Poll::Ready(Err(e))
+            }
+            res => res,
+        }
+    }
+
+    pub(crate) fn is_poisoned(&self) -> bool {
+        self.poisoned
+    }
+
+    pub(crate) fn take_error(&mut self) -> Option<IoError> {
+        self.error.take()
+    }
+}
@@ -0,0 +1,306 @@
+use std::fmt;
+use std::sync::Arc;
+
+use openssl::symm::Cipher;
+use tokio::io::AsyncRead;
+use zeroize::Zeroizing;
+
+use crate::mac::{hkdf_expand, hkdf_extract};
+use crate::rng::fill_random;
+use crate::{read_prefix, CryptoIoError, DecryptReader, EncryptCore, EncryptWriter};
+
+const SALT_LEN: usize = 32;
+const LABEL: &[u8] = b"tokio-openssl-symm factory v1 ";
+const STREAM_LABEL: &[u8] = b"tokio-openssl-symm stream keys v1 ";
+
+type Secret = Zeroizing<Vec<u8>>;
+
+// Holds a cipher and master key, and mints a writer with its own derived key and IV for every
+// connection or message, so nothing encrypted under it ever shares a key and IV. Each stream
+// starts with the random salt its key was derived from, followed by the usual stream, and
+// `reader` derives the same key from it. Clones share the key, so one can sit in server state or
+// each pooled connection.
+#[derive(Clone)]
+pub struct CipherFactory {
+    inner: Arc<Inner>,
+}
+struct Inner {
+    cipher: Cipher,
+    master_key: Zeroizing<Vec<u8>>,
+}
+impl CipherFactory {
+    // `master_key` must be at least as long as a key for `cipher`
+    pub fn new(cipher: Cipher, master_key: &[u8]) -> Result<Self, CryptoIoError> {
+        if master_key.len() < cipher.key_len() {
+            return Err(CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_len(),
+                actual: master_key.len(),
+            });
+        }
+        Ok(CipherFactory {
+            inner: Arc::new(Inner {
+                cipher,
+                master_key: Zeroizing::new(master_key.to_vec()),
+            }),
+        })
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.inner.cipher
+    }
+
+    // the key and IV for the stream with this salt
+    fn derive(&self, salt: &[u8]) -> Result<(Secret, Option<Secret>), CryptoIoError> {
+        let cipher = self.inner.cipher;
+        let nid = (cipher.nid().as_raw() as u32).to_be_bytes();
+        let prk = hkdf_extract(salt, &[&self.inner.master_key])?;
+        let key = hkdf_expand(&prk, &[LABEL, &nid, b" key"], cipher.key_len())?;
+        let iv = match cipher.iv_len() {
+            Some(len) => Some(hkdf_expand(&prk, &[LABEL, &nid, b" iv"], len)?),
+            None => None,
+        };
+        Ok((key, iv))
+    }
+
+    pub fn writer<W>(&self, writer: W) -> Result<EncryptWriter<W>, CryptoIoError> {
+        let mut salt = [0; SALT_LEN];
+        fill_random(&mut salt)?;
+        let (key, iv) = self.derive(&salt)?;
+        let core = EncryptCore::new(self.inner.cipher, &key, iv.as_deref().map(|iv| &iv[..]))?
+            .with_prefix(&salt);
+        Ok(EncryptWriter::with_core(writer, core))
+    }
+
+    // reads the salt from the start of a stream written by `writer`
+    pub async fn reader<R>(&self, mut reader: R) -> Result<DecryptReader<R>, CryptoIoError>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let mut salt = [0; SALT_LEN];
+        read_prefix(&mut reader, &mut salt).await?;
+        let (key, iv) = self.derive(&salt)?;
+        DecryptReader::new(
+            reader,
+            self.inner.cipher,
+            &key,
+            iv.as_deref().map(|iv| &iv[..]),
+        )
+    }
+
+    // `derive_stream_keys` under this factory's master key and cipher
+    pub fn stream_keys(&self, context: &[u8], role: Role) -> Result<StreamKeys, CryptoIoError> {
+        derive_stream_keys(&self.inner.master_key, context, self.inner.cipher, role)
+    }
+}
+
+// which end of a connection the keys are for: the client sends under the keys the server
+// receives under, and vice versa
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Role {
+    Client,
+    Server,
+}
+
+// the keys and IVs for one end of a connection, from `derive_stream_keys`
+pub struct StreamKeys {
+    cipher: Cipher,
+    send_key: Secret,
+    send_iv: Option<Secret>,
+    receive_key: Secret,
+    receive_iv: Option<Secret>,
+}
+impl StreamKeys {
+    pub fn cipher(&self) -> Cipher {
+        self.cipher
+    }
+
+    pub fn send_key(&self) -> &[u8] {
+        &self.send_key
+    }
+
+    pub fn send_iv(&self) -> Option<&[u8]> {
+        self.send_iv.as_deref().map(|iv| &iv[..])
+    }
+
+    pub fn receive_key(&self) -> &[u8] {
+        &self.receive_key
+    }
+
+    pub fn receive_iv(&self) -> Option<&[u8]> {
+        self.receive_iv.as_deref().map(|iv| &iv[..])
+    }
+
+    // encrypts what is written to `stream` and decrypts what is read from it
+    #[cfg(feature = "duplex")]
+    pub fn encrypted_stream<S>(&self, stream: S) -> Result<crate::EncryptedStream<S>, CryptoIoError>
+    where
+        S: tokio::io::AsyncRead + tokio::io::AsyncWrite,
+    {
+        crate::EncryptedStream::new(
+            stream,
+            self.cipher,
+            self.send_key(),
+            self.send_iv(),
+            self.receive_key(),
+            self.receive_iv(),
+        )
+    }
+}
+
+impl fmt::Debug for StreamKeys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StreamKeys")
+            .field("cipher", &crate::telemetry::cipher_name(self.cipher))
+            .finish_non_exhaustive()
+    }
+}
+
+// Derives a key and IV for each direction of the connection named by `context` (a connection
+// ID, say, unique under `master`) with HKDF-SHA256, so a proxy multiplexing many clients under
+// one master secret never reuses a key and IV across connections or directions.
+pub fn derive_stream_keys(
+    master: &[u8],
+    context: &[u8],
+    cipher: Cipher,
+    role: Role,
+) -> Result<StreamKeys, CryptoIoError> {
+    let prk = hkdf_extract(&[], &[master])?;
+    let nid = (cipher.nid().as_raw() as u32).to_be_bytes();
+    let context_len = (context.len() as u64).to_be_bytes();
+    let derive = |direction: &[u8]| -> Result<(Secret, Option<Secret>), CryptoIoError> {
+        let info =
+            |label: &'static [u8]| [STREAM_LABEL, &nid, &context_len, context, direction, label];
+        let key = hkdf_expand(&prk, &info(b" key"), cipher.key_len())?;
+        let iv = match cipher.iv_len() {
+            Some(len) => Some(hkdf_expand(&prk, &info(b" iv"), len)?),
+            None => None,
+        };
+        Ok((key, iv))
+    };
+    let (send, receive): (&[u8], &[u8]) = match role {
+        Role::Client => (b"client to server", b"server to client"),
+        Role::Server => (b"server to client", b"client to server"),
+    };
+    let (send_key, send_iv) = derive(send)?;
+    let (receive_key, receive_iv) = derive(receive)?;
+    Ok(StreamKeys {
+        cipher,
+        send_key,
+        send_iv,
+        receive_key,
+        receive_iv,
+    })
+}
+
+impl fmt::Debug for CipherFactory {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CipherFactory")
+            .field("cipher", &crate::telemetry::cipher_name(self.inner.cipher))
+            .field("master_key", &"<redacted>")
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Cipher;
+
+    use super::{derive_stream_keys, CipherFactory, Role};
+    use crate::testing::{block_on, read_to_end, sample, shutdown, write_all};
+    use crate::CryptoIoError;
+
+    fn round_trip(factory: &CipherFactory, plaintext: &[u8], without_header: bool) -> Vec<u8> {
+        let mut ciphertext = Vec::new();
+        block_on(async {
+            let mut writer = factory.writer(&mut ciphertext).unwrap();
+            if without_header {
+                writer = writer.without_header();
+            }
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        block_on(async {
+            let mut reader = factory.reader(&ciphertext[..]).await.unwrap();
+            if without_header {
+                reader = reader.without_header();
+            }
+            read_to_end(&mut reader).await.unwrap()
+        })
+    }
+
+    #[test]
+    fn writer_reader_round_trip() {
+        let plaintext = sample(5000);
+        for cipher in [Cipher::aes_256_gcm(), Cipher::aes_256_cbc()] {
+            let factory = CipherFactory::new(cipher, &[7; 32]).unwrap();
+            for without_header in [false, true] {
+                assert_eq!(round_trip(&factory, &plaintext, without_header), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn streams_differ() {
+        let factory = CipherFactory::new(Cipher::aes_256_gcm(), &[7; 32]).unwrap();
+        let encrypt = || {
+            let mut ciphertext = Vec::new();
+            block_on(async {
+                let mut writer = factory.writer(&mut ciphertext).unwrap();
+                write_all(&mut writer, b"same").await.unwrap();
+                shutdown(&mut writer).await.unwrap();
+            });
+            ciphertext
+        };
+        assert_ne!(encrypt(), encrypt());
+    }
+
+    #[test]
+    fn short_master_key() {
+        let cipher = Cipher::aes_256_gcm();
+        assert!(matches!(
+            CipherFactory::new(cipher, &[7; 16]),
+            Err(CryptoIoError::InvalidKeyLen {
+                expected: 32,
+                actual: 16
+            })
+        ));
+        let factory = CipherFactory::new(cipher, &[7; 32]).unwrap();
+        assert!(!format!("{:?}", factory).contains("7, 7"));
+    }
+
+    #[test]
+    fn stream_keys_pair_up() {
+        let cipher = Cipher::aes_256_gcm();
+        let client = derive_stream_keys(&[7; 32], b"conn 1", cipher, Role::Client).unwrap();
+        let server = derive_stream_keys(&[7; 32], b"conn 1", cipher, Role::Server).unwrap();
+        assert_eq!(client.send_key(), server.receive_key());
+        assert_eq!(client.send_iv(), server.receive_iv());
+        assert_eq!(client.receive_key(), server.send_key());
+        assert_eq!(client.receive_iv(), server.send_iv());
+        assert_ne!(client.send_key(), client.receive_key());
+        assert_eq!(client.send_key().len(), 32);
+        assert_eq!(client.send_iv().unwrap().len(), 12);
+
+        // another connection, master or cipher gets other keys
+        let others = [
+            derive_stream_keys(&[7; 32], b"conn 2", cipher, Role::Client).unwrap(),
+            derive_stream_keys(&[8; 32], b"conn 1", cipher, Role::Client).unwrap(),
+            derive_stream_keys(
+                &[7; 32],
+                b"conn 1",
+                Cipher::chacha20_poly1305(),
+                Role::Client,
+            )
+            .unwrap(),
+        ];
+        for other in &others {
+            assert_ne!(other.send_key(), client.send_key());
+            assert_ne!(other.send_iv(), client.send_iv());
+        }
+
+        let factory = CipherFactory::new(cipher, &[7; 32]).unwrap();
+        let keys = factory.stream_keys(b"conn 1", Role::Client).unwrap();
+        assert_eq!(keys.send_key(), client.send_key());
+        assert!(!format!("{:?}", keys).contains("send_key"));
+    }
+}
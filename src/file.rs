@@ -0,0 +1,972 @@
+use std::collections::VecDeque;
+use std::future::poll_fn;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult, SeekFrom};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use openssl::symm::{encrypt_aead, Cipher, Crypter, Mode};
+use tokio::fs::File;
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+use zeroize::Zeroizing;
+
+use crate::cipher::tag_len_range;
+use crate::error::Poison;
+use crate::mac::{hkdf_expand, hkdf_extract};
+use crate::rng::fill_random;
+use crate::telemetry::{cipher_name, record_tag_failure};
+use crate::{read_prefix, CryptoIoError};
+
+// plaintext bytes per block
+const BLOCK_LEN: u64 = 4096;
+// the random ID the file starts with, bound into every block
+const FILE_ID_LEN: usize = 16;
+const FILE_KEY_LABEL: &[u8] = b"tokio-openssl-symm file key";
+const ZEROS: [u8; 1024] = [0; 1024];
+
+// where the inner file is in loading or storing the block at this stored offset
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Idle,
+    Seeking(u64),
+    // bytes read or written so far
+    Transferring(u64, usize),
+}
+impl Phase {
+    fn offset(self) -> Option<u64> {
+        match self {
+            Phase::Idle => None,
+            Phase::Seeking(offset) | Phase::Transferring(offset, _) => Some(offset),
+        }
+    }
+}
+
+// when changed blocks in the cache reach the file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WritePolicy {
+    // when they are evicted, or on flush
+    WriteBack,
+    // before each write returns, though the inner file may still buffer them until flushed
+    WriteThrough,
+}
+
+struct CachedBlock {
+    index: u64,
+    data: Zeroizing<Vec<u8>>,
+    dirty: bool,
+}
+
+// A file encrypted in independent 4 KiB blocks under an AEAD cipher, readable, writable and
+// seekable in place like the plaintext file it stands for. The file starts with a random 16-byte
+// file ID, and each block is stored as a fresh random nonce, the ciphertext and the tag, with the
+// file ID and block index as associated data, so blocks cannot be moved around within a file or
+// between files under the same key. That is all the integrity there is: nothing binds the file's
+// overall length, so truncation to a block boundary goes unnoticed, and nothing records which
+// version of a block is current, so anyone who can write the file can roll any block (or the
+// whole file) back to an earlier version of itself undetected. Every write of a block draws a new
+// random nonce, and random 96-bit nonces stay safe for about 2^32 writes under one key, so blocks
+// are sealed under a subkey derived from the key and the file ID: the limit is per file rather
+// than per key, and a file rewritten anywhere near that often should be copied to a new one.
+// Decrypted blocks are kept in a small LRU cache (one block unless `with_cache` says otherwise),
+// and with `WritePolicy::WriteBack` writes only reach the file when their block is evicted or on
+// flush, so flush or shut down before dropping.
+pub struct EncryptedFile<F = File> {
+    inner: F,
+    file_id: [u8; FILE_ID_LEN],
+    cipher: Cipher,
+    key: Zeroizing<Vec<u8>>,
+    // plaintext length and position
+    len: u64,
+    pos: u64,
+    // least recently used first
+    cache: VecDeque<CachedBlock>,
+    capacity: usize,
+    policy: WritePolicy,
+    sparse: bool,
+    disk: Vec<u8>,
+    phase: Phase,
+    poison: Poison,
+}
+impl<F> EncryptedFile<F>
+where
+    F: AsyncRead + AsyncWrite + AsyncSeek + Unpin,
+{
+    // opens `inner` (a `tokio::fs::File`, normally) for reading and writing, where an empty file
+    // stands for an empty plaintext and has a new file ID written to it
+    pub async fn open(mut inner: F, cipher: Cipher, key: &[u8]) -> Result<Self, CryptoIoError> {
+        if tag_len_range(cipher).1 == 0 {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "encrypted files need an AEAD cipher",
+            )
+            .into());
+        }
+        if key.len() != cipher.key_len() {
+            return Err(CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_len(),
+                actual: key.len(),
+            });
+        }
+        poll_fn(|cx| Pin::new(&mut inner).start_seek(cx, SeekFrom::End(0))).await?;
+        let disk_len = poll_fn(|cx| Pin::new(&mut inner).poll_complete(cx)).await?;
+        let mut file_id = [0; FILE_ID_LEN];
+        if disk_len == 0 {
+            fill_random(&mut file_id)?;
+            let mut written = 0;
+            while written < FILE_ID_LEN {
+                match poll_fn(|cx| Pin::new(&mut inner).poll_write(cx, &file_id[written..])).await?
+                {
+                    0 => {
+                        return Err(
+                            IoError::new(IoErrorKind::WriteZero, "failed to write file ID").into(),
+                        )
+                    }
+                    n => written += n,
+                }
+            }
+        } else {
+            poll_fn(|cx| Pin::new(&mut inner).start_seek(cx, SeekFrom::Start(0))).await?;
+            poll_fn(|cx| Pin::new(&mut inner).poll_complete(cx)).await?;
+            read_prefix(&mut inner, &mut file_id).await?;
+        }
+        let body_len = disk_len.saturating_sub(FILE_ID_LEN as u64);
+        let mut file = EncryptedFile {
+            inner,
+            file_id,
+            cipher,
+            key: file_key(key, &file_id)?,
+            len: 0,
+            pos: 0,
+            cache: VecDeque::new(),
+            capacity: 1,
+            policy: WritePolicy::WriteBack,
+            sparse: false,
+            disk: Vec::new(),
+            phase: Phase::Idle,
+            poison: Poison::default(),
+        };
+        let stored = file.stored_len(0);
+        let partial = body_len % stored;
+        if partial != 0 && partial <= file.overhead() as u64 {
+            return Err(CryptoIoError::Truncated);
+        }
+        file.len = body_len / stored * BLOCK_LEN + partial.saturating_sub(file.overhead() as u64);
+        Ok(file)
+    }
+}
+
+impl<F> EncryptedFile<F> {
+    // keeps up to `capacity` decrypted blocks, so repeated small reads and writes around the same
+    // offsets don't decrypt and encrypt the same blocks over and over
+    pub fn with_cache(mut self, capacity: usize, policy: WritePolicy) -> Self {
+        self.capacity = capacity.max(1);
+        self.policy = policy;
+        self
+    }
+
+    // For sparse outputs such as VM images: a write past the end leaves the whole blocks it skips
+    // unwritten, as holes in the inner file, rather than encrypting zeros into them, and a block
+    // stored as all zeros reads back as zeros. That means anyone who can write the file can also
+    // zero out blocks undetected, so only enable it where that is acceptable.
+    pub fn sparse(mut self, sparse: bool) -> Self {
+        self.sparse = sparse;
+        self
+    }
+
+    // the length of the plaintext
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn get_ref(&self) -> &F {
+        &self.inner
+    }
+
+    fn overhead(&self) -> usize {
+        self.cipher.iv_len().unwrap_or(0) + tag_len_range(self.cipher).1
+    }
+
+    // the plaintext bytes in block `index`
+    fn block_len(&self, index: u64) -> usize {
+        self.len.saturating_sub(index * BLOCK_LEN).min(BLOCK_LEN) as usize
+    }
+
+    // the stored length of block `index`, or of a full block if it is past the end
+    fn stored_len(&self, index: u64) -> u64 {
+        let len = match self.block_len(index) {
+            0 => BLOCK_LEN as usize,
+            len => len,
+        };
+        (len + self.overhead()) as u64
+    }
+
+    fn stored_offset(&self, index: u64) -> u64 {
+        FILE_ID_LEN as u64 + index * (BLOCK_LEN + self.overhead() as u64)
+    }
+
+    // what each block is authenticated together with
+    fn aad(&self, index: u64) -> [u8; FILE_ID_LEN + 8] {
+        let mut aad = [0; FILE_ID_LEN + 8];
+        aad[..FILE_ID_LEN].copy_from_slice(&self.file_id);
+        aad[FILE_ID_LEN..].copy_from_slice(&index.to_be_bytes());
+        aad
+    }
+
+    fn find(&self, index: u64) -> Option<usize> {
+        self.cache.iter().position(|block| block.index == index)
+    }
+
+    // encrypts cached block `i` into `disk`
+    fn seal(&mut self, i: usize) -> Result<(), CryptoIoError> {
+        let aad = self.aad(self.cache[i].index);
+        let block = &self.cache[i];
+        let iv_len = self.cipher.iv_len().unwrap_or(0);
+        let mut nonce = vec![0; iv_len];
+        fill_random(&mut nonce)?;
+        let mut tag = vec![0; tag_len_range(self.cipher).1];
+        let ciphertext = encrypt_aead(
+            self.cipher,
+            &self.key,
+            Some(&nonce),
+            &aad,
+            &block.data,
+            &mut tag,
+        )?;
+        self.disk.clear();
+        self.disk.extend_from_slice(&nonce);
+        self.disk.extend_from_slice(&ciphertext);
+        self.disk.extend_from_slice(&tag);
+        Ok(())
+    }
+
+    // decrypts block `index` from `disk`, zeroing whatever was decrypted if the tag is wrong
+    fn open_block(&self, index: u64) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+        let iv_len = self.cipher.iv_len().unwrap_or(0);
+        let tag_at = self.disk.len() - tag_len_range(self.cipher).1;
+        let (nonce, rest) = self.disk.split_at(iv_len);
+        let (ciphertext, tag) = rest.split_at(tag_at - iv_len);
+        let mut crypter = Crypter::new(self.cipher, Mode::Decrypt, &self.key, Some(nonce))?;
+        crypter.aad_update(&self.aad(index))?;
+        let mut plaintext = Zeroizing::new(vec![0; ciphertext.len() + self.cipher.block_size()]);
+        let count = crypter.update(ciphertext, &mut plaintext)?;
+        crypter.set_tag(tag)?;
+        match crypter.finalize(&mut plaintext[count..]) {
+            Ok(n) => {
+                plaintext.truncate(count + n);
+                Ok(plaintext)
+            }
+            Err(_) => {
+                event!(
+                    tracing::Level::ERROR,
+                    block = index,
+                    "block failed to authenticate"
+                );
+                record_tag_failure(cipher_name(self.cipher));
+                Err(CryptoIoError::BadTag)
+            }
+        }
+    }
+
+    // after a block fails to authenticate the file is poisoned and every later call fails with
+    // `BrokenPipe`, so nothing more is read from (or written to) a tampered file; this returns the
+    // error that caused it
+    pub fn take_error(&mut self) -> Option<IoError> {
+        self.poison.take_error()
+    }
+}
+
+impl<F> EncryptedFile<F>
+where
+    F: AsyncRead + AsyncWrite + AsyncSeek,
+{
+    // self must be pinned
+    unsafe fn poll_seek_inner(&mut self, cx: &mut Context<'_>, offset: u64) -> Poll<IoResult<()>> {
+        // left over from a read or write that was given up on
+        if self.phase.offset() != Some(offset) {
+            self.phase = Phase::Idle;
+        }
+        if self.phase == Phase::Idle {
+            match Pin::new_unchecked(&mut self.inner).start_seek(cx, SeekFrom::Start(offset)) {
+                Poll::Ready(Ok(())) => self.phase = Phase::Seeking(offset),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        if self.phase == Phase::Seeking(offset) {
+            match Pin::new_unchecked(&mut self.inner).poll_complete(cx) {
+                Poll::Ready(Ok(_)) => self.phase = Phase::Transferring(offset, 0),
+                Poll::Ready(Err(e)) => {
+                    self.phase = Phase::Idle;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // self must be pinned; writes cached block `i` back if it has changed
+    unsafe fn poll_store(&mut self, cx: &mut Context<'_>, i: usize) -> Poll<IoResult<()>> {
+        if !self.cache[i].dirty {
+            return Poll::Ready(Ok(()));
+        }
+        let offset = self.stored_offset(self.cache[i].index);
+        if self.phase.offset() != Some(offset) {
+            self.phase = Phase::Idle;
+            self.seal(i)?;
+        }
+        match self.poll_seek_inner(cx, offset) {
+            Poll::Ready(Ok(())) => (),
+            a => return a,
+        }
+        while let Phase::Transferring(_, done) = self.phase {
+            if done == self.disk.len() {
+                break;
+            }
+            match Pin::new_unchecked(&mut self.inner).poll_write(cx, &self.disk[done..]) {
+                Poll::Ready(Ok(0)) => {
+                    self.phase = Phase::Idle;
+                    return Poll::Ready(Err(IoError::new(
+                        IoErrorKind::WriteZero,
+                        "failed to write block",
+                    )));
+                }
+                Poll::Ready(Ok(n)) => self.phase = Phase::Transferring(offset, done + n),
+                Poll::Ready(Err(e)) => {
+                    self.phase = Phase::Idle;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.phase = Phase::Idle;
+        self.cache[i].dirty = false;
+        Poll::Ready(Ok(()))
+    }
+
+    // self must be pinned
+    unsafe fn poll_store_all(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        for i in 0..self.cache.len() {
+            match self.poll_store(cx, i) {
+                Poll::Ready(Ok(())) => (),
+                a => return a,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    // self must be pinned; moves block `index` to the back of the cache, evicting the least
+    // recently used block to make room and reading it in unless it is past the end
+    unsafe fn poll_block(&mut self, cx: &mut Context<'_>, index: u64) -> Poll<IoResult<()>> {
+        if let Some(i) = self.find(index) {
+            let block = self.cache.remove(i).unwrap();
+            self.cache.push_back(block);
+            return Poll::Ready(Ok(()));
+        }
+        while self.cache.len() >= self.capacity {
+            match self.poll_store(cx, 0) {
+                Poll::Ready(Ok(())) => (),
+                a => return a,
+            }
+            self.cache.pop_front();
+        }
+        if self.block_len(index) == 0 {
+            self.cache.push_back(CachedBlock {
+                index,
+                data: Zeroizing::new(Vec::new()),
+                dirty: false,
+            });
+            return Poll::Ready(Ok(()));
+        }
+        let offset = self.stored_offset(index);
+        match self.poll_seek_inner(cx, offset) {
+            Poll::Ready(Ok(())) => (),
+            a => return a,
+        }
+        let stored = self.stored_len(index) as usize;
+        self.disk.resize(stored, 0);
+        while let Phase::Transferring(_, done) = self.phase {
+            if done == stored {
+                break;
+            }
+            match Pin::new_unchecked(&mut self.inner).poll_read(cx, &mut self.disk[done..]) {
+                // a hole the file does not reach past yet, while later blocks are only cached
+                Poll::Ready(Ok(0)) if self.sparse => {
+                    self.disk[done..].iter_mut().for_each(|b| *b = 0);
+                    self.phase = Phase::Transferring(offset, stored);
+                }
+                Poll::Ready(Ok(0)) => {
+                    self.phase = Phase::Idle;
+                    return Poll::Ready(Err(CryptoIoError::Truncated.into()));
+                }
+                Poll::Ready(Ok(n)) => self.phase = Phase::Transferring(offset, done + n),
+                Poll::Ready(Err(e)) => {
+                    self.phase = Phase::Idle;
+                    return Poll::Ready(Err(e));
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        self.phase = Phase::Idle;
+        let data = if self.sparse && self.disk.iter().all(|&b| b == 0) {
+            Zeroizing::new(vec![0; self.block_len(index)])
+        } else {
+            match self.open_block(index) {
+                Ok(a) => a,
+                Err(e) => {
+                    let res = Poll::Ready(Err(e.into()));
+                    return self.poison.track(res, index * BLOCK_LEN, offset);
+                }
+            }
+        };
+        self.cache.push_back(CachedBlock {
+            index,
+            data,
+            dirty: false,
+        });
+        Poll::Ready(Ok(()))
+    }
+
+    // self must be pinned; `at` must not be past the end
+    unsafe fn poll_write_at(
+        &mut self,
+        cx: &mut Context<'_>,
+        at: u64,
+        data: &[u8],
+    ) -> Poll<IoResult<usize>> {
+        let index = at / BLOCK_LEN;
+        match self.poll_block(cx, index) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        let offset = (at - index * BLOCK_LEN) as usize;
+        let n = data.len().min(BLOCK_LEN as usize - offset);
+        let block = self.cache.back_mut().unwrap();
+        // leaves the block alone when a write that was still being written through is retried
+        if block.data.get(offset..offset + n) != Some(&data[..n]) {
+            if block.data.len() < offset + n {
+                block.data.resize(offset + n, 0);
+            }
+            block.data[offset..offset + n].copy_from_slice(&data[..n]);
+            block.dirty = true;
+            // a write-back of the old contents given up on part way must start over
+            self.phase = Phase::Idle;
+        }
+        self.len = self.len.max(at + n as u64);
+        if self.policy == WritePolicy::WriteThrough {
+            let last = self.cache.len() - 1;
+            match self.poll_store(cx, last) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl<F> AsyncRead for EncryptedFile<F>
+where
+    F: AsyncRead + AsyncWrite + AsyncSeek,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            if let Err(e) = inner.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            if inner.pos >= inner.len || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let index = inner.pos / BLOCK_LEN;
+            match inner.poll_block(cx, index) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            let block = &inner.cache.back().unwrap().data;
+            let offset = (inner.pos - index * BLOCK_LEN) as usize;
+            let n = buf.len().min(block.len() - offset);
+            buf[..n].copy_from_slice(&block[offset..offset + n]);
+            inner.pos += n as u64;
+            Poll::Ready(Ok(n))
+        }
+    }
+}
+
+impl<F> AsyncWrite for EncryptedFile<F>
+where
+    F: AsyncRead + AsyncWrite + AsyncSeek,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            if let Err(e) = inner.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            // a write past the end fills the gap with zeros first, or in a sparse file skips the
+            // whole blocks in it
+            while inner.len < inner.pos {
+                if inner.sparse && inner.len % BLOCK_LEN == 0 {
+                    inner.len = inner.len.max(inner.pos / BLOCK_LEN * BLOCK_LEN);
+                    if inner.len == inner.pos {
+                        break;
+                    }
+                }
+                let gap = (inner.pos - inner.len).min(ZEROS.len() as u64) as usize;
+                match inner.poll_write_at(cx, inner.len, &ZEROS[..gap]) {
+                    Poll::Ready(Ok(_)) => (),
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            match inner.poll_write_at(cx, inner.pos, buf) {
+                Poll::Ready(Ok(n)) => {
+                    inner.pos += n as u64;
+                    Poll::Ready(Ok(n))
+                }
+                a => a,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            if let Err(e) = inner.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            match inner.poll_store_all(cx) {
+                Poll::Ready(Ok(())) => (),
+                a => return a,
+            }
+            Pin::new_unchecked(&mut inner.inner).poll_flush(cx)
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            if let Err(e) = inner.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            match inner.poll_store_all(cx) {
+                Poll::Ready(Ok(())) => (),
+                a => return a,
+            }
+            Pin::new_unchecked(&mut inner.inner).poll_shutdown(cx)
+        }
+    }
+}
+
+impl<F> AsyncSeek for EncryptedFile<F>
+where
+    F: AsyncRead + AsyncWrite + AsyncSeek,
+{
+    // seeking only moves the plaintext position; nothing is read or written until the next
+    // read or write
+    fn start_seek(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        position: SeekFrom,
+    ) -> Poll<IoResult<()>> {
+        let inner = unsafe { self.get_unchecked_mut() };
+        let (base, offset) = match position {
+            SeekFrom::Start(n) => (n, 0),
+            SeekFrom::End(n) => (inner.len, n),
+            SeekFrom::Current(n) => (inner.pos, n),
+        };
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        };
+        match pos {
+            Some(pos) => {
+                inner.pos = pos;
+                Poll::Ready(Ok(()))
+            }
+            None => Poll::Ready(Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            ))),
+        }
+    }
+
+    fn poll_complete(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<IoResult<u64>> {
+        Poll::Ready(Ok(self.pos))
+    }
+}
+
+// the subkey of `key` that the blocks of the file with this ID are sealed under
+fn file_key(key: &[u8], file_id: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+    let prk = hkdf_extract(file_id, &[key])?;
+    Ok(hkdf_expand(&prk, &[FILE_KEY_LABEL], key.len())?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::io::{Cursor, SeekFrom};
+    use std::pin::Pin;
+
+    use openssl::symm::Cipher;
+    use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+
+    use super::{EncryptedFile, WritePolicy, BLOCK_LEN, FILE_ID_LEN};
+    use crate::testing::{block_on, read_to_end, sample, shutdown, write_all};
+    use crate::CryptoIoError;
+
+    const KEY: [u8; 32] = [9; 32];
+
+    fn cipher() -> Cipher {
+        Cipher::aes_256_gcm()
+    }
+
+    // the stored file for `plaintext`
+    fn create(plaintext: &[u8]) -> Vec<u8> {
+        block_on(async {
+            let mut file = EncryptedFile::open(Cursor::new(Vec::new()), cipher(), &KEY)
+                .await
+                .unwrap();
+            write_all(&mut file, plaintext).await.unwrap();
+            shutdown(&mut file).await.unwrap();
+            file.get_ref().get_ref().clone()
+        })
+    }
+
+    fn read(stored: Vec<u8>) -> Result<Vec<u8>, CryptoIoError> {
+        block_on(async {
+            let mut file = EncryptedFile::open(Cursor::new(stored), cipher(), &KEY).await?;
+            Ok(read_to_end(&mut file).await?)
+        })
+    }
+
+    fn is_bad_tag(result: Result<Vec<u8>, CryptoIoError>) -> bool {
+        match result {
+            Err(CryptoIoError::Io(e)) => matches!(
+                CryptoIoError::from_io(&e).map(|e| e.root()),
+                Some(CryptoIoError::BadTag)
+            ),
+            _ => false,
+        }
+    }
+
+    fn block_range(index: usize) -> std::ops::Range<usize> {
+        let stored = BLOCK_LEN as usize + 12 + 16;
+        let start = FILE_ID_LEN + index * stored;
+        start..start + stored
+    }
+
+    #[test]
+    fn round_trip() {
+        let plaintext = sample(3 * BLOCK_LEN as usize + 100);
+        assert_eq!(read(create(&plaintext)).unwrap(), plaintext);
+        assert_eq!(read(create(b"")).unwrap(), b"");
+        assert_eq!(create(b"").len(), FILE_ID_LEN);
+    }
+
+    #[test]
+    fn overwrite_in_place() {
+        let plaintext = sample(2 * BLOCK_LEN as usize);
+        let stored = block_on(async {
+            let mut file = EncryptedFile::open(Cursor::new(create(&plaintext)), cipher(), &KEY)
+                .await
+                .unwrap();
+            let at = SeekFrom::Start(BLOCK_LEN - 2);
+            poll_fn(|cx| Pin::new(&mut file).start_seek(cx, at))
+                .await
+                .unwrap();
+            write_all(&mut file, b"four").await.unwrap();
+            shutdown(&mut file).await.unwrap();
+            file.get_ref().get_ref().clone()
+        });
+        let mut expected = plaintext;
+        expected[BLOCK_LEN as usize - 2..][..4].copy_from_slice(b"four");
+        assert_eq!(read(stored).unwrap(), expected);
+    }
+
+    #[test]
+    fn tampered_block() {
+        let mut stored = create(&sample(5000));
+        stored[FILE_ID_LEN + 20] ^= 1;
+        assert!(is_bad_tag(read(stored)));
+    }
+
+    #[test]
+    fn swapped_blocks() {
+        let mut stored = create(&sample(2 * BLOCK_LEN as usize));
+        let first = stored[block_range(0)].to_vec();
+        let second = stored[block_range(1)].to_vec();
+        stored[block_range(0)].copy_from_slice(&second);
+        stored[block_range(1)].copy_from_slice(&first);
+        assert!(is_bad_tag(read(stored)));
+    }
+
+    // the file ID keeps a block from another file under the same key out
+    #[test]
+    fn block_from_another_file() {
+        let plaintext = sample(BLOCK_LEN as usize);
+        let mut stored = create(&plaintext);
+        let other = create(&plaintext);
+        stored[block_range(0)].copy_from_slice(&other[block_range(0)]);
+        assert!(is_bad_tag(read(stored)));
+    }
+
+    // each file has its own subkey, and finds it again from its ID
+    #[test]
+    fn per_file_keys() {
+        let open = |stored: Vec<u8>| {
+            block_on(EncryptedFile::open(Cursor::new(stored), cipher(), &KEY)).unwrap()
+        };
+        let stored = create(b"one");
+        let (first, again, other) = (open(stored.clone()), open(stored), open(create(b"one")));
+        assert_eq!(first.key, again.key);
+        assert_ne!(first.key, other.key);
+        assert_ne!(first.key[..], KEY[..]);
+    }
+
+    #[test]
+    fn truncated_header() {
+        let stored = create(&sample(100));
+        assert!(matches!(
+            read(stored[..FILE_ID_LEN - 1].to_vec()),
+            Err(CryptoIoError::Truncated)
+        ));
+    }
+
+    async fn seek<F: AsyncSeek + Unpin>(file: &mut F, pos: u64) {
+        let mut file = Pin::new(file);
+        poll_fn(|cx| file.as_mut().start_seek(cx, SeekFrom::Start(pos)))
+            .await
+            .unwrap();
+        poll_fn(|cx| file.as_mut().poll_complete(cx)).await.unwrap();
+    }
+
+    async fn read_len<R: AsyncRead + Unpin>(reader: &mut R, len: usize) -> Vec<u8> {
+        let mut out = vec![0; len];
+        let mut done = 0;
+        while done < len {
+            let n = poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut out[done..]))
+                .await
+                .unwrap();
+            if n == 0 {
+                break;
+            }
+            done += n;
+        }
+        out.truncate(done);
+        out
+    }
+
+    async fn flush<W: AsyncWrite + Unpin>(writer: &mut W) {
+        poll_fn(|cx| Pin::new(&mut *writer).poll_flush(cx))
+            .await
+            .unwrap();
+    }
+
+    // scattered reads and writes, some past the end, checked against a plain `Vec`
+    #[test]
+    fn cached_random_access() {
+        for &(capacity, policy) in &[
+            (1, WritePolicy::WriteBack),
+            (3, WritePolicy::WriteBack),
+            (3, WritePolicy::WriteThrough),
+        ] {
+            let mut expected = sample(5 * BLOCK_LEN as usize);
+            let stored = block_on(async {
+                let inner = Cursor::new(create(&expected));
+                let file = EncryptedFile::open(inner, cipher(), &KEY).await.unwrap();
+                let mut file = file.with_cache(capacity, policy);
+                let mut state = 7u64;
+                for step in 0..200 {
+                    state = state.wrapping_mul(6364136223846793005).wrapping_add(1);
+                    let pos = (state >> 33) as usize % (expected.len() + 100);
+                    let len = 1 + (state >> 13) as usize % 6000;
+                    seek(&mut file, pos as u64).await;
+                    if step % 3 == 0 {
+                        let data = vec![step as u8; len];
+                        write_all(&mut file, &data).await.unwrap();
+                        if expected.len() < pos + len {
+                            expected.resize(pos + len, 0);
+                        }
+                        expected[pos..pos + len].copy_from_slice(&data);
+                    } else {
+                        let end = (pos + len).min(expected.len());
+                        let read = read_len(&mut file, len).await;
+                        assert_eq!(read, &expected[pos.min(end)..end]);
+                    }
+                }
+                assert_eq!(file.len(), expected.len() as u64);
+                shutdown(&mut file).await.unwrap();
+                file.get_ref().get_ref().clone()
+            });
+            assert_eq!(read(stored).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn write_policies() {
+        let stored = create(&sample(2 * BLOCK_LEN as usize));
+        for &policy in &[WritePolicy::WriteBack, WritePolicy::WriteThrough] {
+            block_on(async {
+                let inner = Cursor::new(stored.clone());
+                let file = EncryptedFile::open(inner, cipher(), &KEY).await.unwrap();
+                let mut file = file.with_cache(4, policy);
+                write_all(&mut file, b"changed").await.unwrap();
+                let written = file.get_ref().get_ref() != &stored;
+                assert_eq!(written, policy == WritePolicy::WriteThrough);
+                flush(&mut file).await;
+                assert_ne!(file.get_ref().get_ref(), &stored);
+            });
+        }
+    }
+
+    // a block evicted to make room is written back on the way out
+    #[test]
+    fn eviction_writes_back() {
+        let stored = create(&sample(3 * BLOCK_LEN as usize));
+        block_on(async {
+            let inner = Cursor::new(stored.clone());
+            let file = EncryptedFile::open(inner, cipher(), &KEY).await.unwrap();
+            let mut file = file.with_cache(2, WritePolicy::WriteBack);
+            write_all(&mut file, b"first").await.unwrap();
+            seek(&mut file, BLOCK_LEN).await;
+            read_len(&mut file, 1).await;
+            assert_eq!(file.get_ref().get_ref(), &stored);
+            seek(&mut file, 2 * BLOCK_LEN).await;
+            read_len(&mut file, 1).await;
+            let after = file.get_ref().get_ref().clone();
+            assert_ne!(after[block_range(0)], stored[block_range(0)]);
+            assert_eq!(after[block_range(1)], stored[block_range(1)]);
+        });
+    }
+
+    // writes "tail" three blocks and a bit past the start of a new file
+    fn write_past_end(sparse: bool) -> Vec<u8> {
+        block_on(async {
+            let inner = Cursor::new(Vec::new());
+            let file = EncryptedFile::open(inner, cipher(), &KEY).await.unwrap();
+            let mut file = file.sparse(sparse);
+            seek(&mut file, 3 * BLOCK_LEN + 10).await;
+            write_all(&mut file, b"tail").await.unwrap();
+            shutdown(&mut file).await.unwrap();
+            file.get_ref().get_ref().clone()
+        })
+    }
+
+    fn read_sparse(stored: Vec<u8>) -> Result<Vec<u8>, CryptoIoError> {
+        block_on(async {
+            let inner = Cursor::new(stored);
+            let file = EncryptedFile::open(inner, cipher(), &KEY).await?;
+            Ok(read_to_end(&mut file.sparse(true)).await?)
+        })
+    }
+
+    #[test]
+    fn sparse_holes() {
+        let mut expected = vec![0; 3 * BLOCK_LEN as usize + 10];
+        expected.extend_from_slice(b"tail");
+        let sparse = write_past_end(true);
+        let dense = write_past_end(false);
+        assert_eq!(sparse.len(), dense.len());
+        // the skipped blocks are left as holes, and the block written to is still encrypted
+        let holes = block_range(0).start..block_range(2).end;
+        assert!(sparse[holes.clone()].iter().all(|&b| b == 0));
+        assert!(dense[holes].iter().any(|&b| b != 0));
+        assert!(sparse[block_range(3).start..].iter().any(|&b| b != 0));
+        assert_eq!(read_sparse(sparse.clone()).unwrap(), expected);
+        assert_eq!(read_sparse(dense.clone()).unwrap(), expected);
+        assert_eq!(read(dense).unwrap(), expected);
+        // only a sparse reader takes a hole for zeros
+        assert!(read(sparse).is_err());
+    }
+
+    // the documented cost: in a sparse file, zeroing a stored block goes unnoticed
+    #[test]
+    fn zeroed_block() {
+        let plaintext = sample(2 * BLOCK_LEN as usize);
+        let mut stored = create(&plaintext);
+        stored[block_range(0)].iter_mut().for_each(|b| *b = 0);
+        assert!(is_bad_tag(read(stored.clone())));
+        let mut expected = plaintext;
+        expected[..BLOCK_LEN as usize]
+            .iter_mut()
+            .for_each(|b| *b = 0);
+        assert_eq!(read_sparse(stored).unwrap(), expected);
+    }
+
+    // a hole at the end of the file, where the inner file stops short, when only a later
+    // block's write is still cached
+    #[test]
+    fn sparse_hole_before_cached_block() {
+        block_on(async {
+            let inner = Cursor::new(Vec::new());
+            let file = EncryptedFile::open(inner, cipher(), &KEY).await.unwrap();
+            let mut file = file.sparse(true).with_cache(2, WritePolicy::WriteBack);
+            seek(&mut file, 2 * BLOCK_LEN).await;
+            write_all(&mut file, b"tail").await.unwrap();
+            seek(&mut file, 0).await;
+            let read = read_len(&mut file, 2 * BLOCK_LEN as usize + 4).await;
+            assert!(read[..2 * BLOCK_LEN as usize].iter().all(|&b| b == 0));
+            assert_eq!(&read[2 * BLOCK_LEN as usize..], b"tail");
+        });
+    }
+
+    // the first bad block stops the file: nothing after it is handed out, and nothing more is
+    // read from or written to the inner file
+    #[test]
+    fn poisoned_by_bad_block() {
+        let mut stored = create(&sample(3 * BLOCK_LEN as usize));
+        stored[block_range(1).start + 20] ^= 1;
+        block_on(async {
+            let inner = Cursor::new(stored.clone());
+            let mut file = EncryptedFile::open(inner, cipher(), &KEY).await.unwrap();
+            let read = read_len(&mut file, BLOCK_LEN as usize).await;
+            assert_eq!(read, &sample(BLOCK_LEN as usize)[..]);
+            let mut buf = [0; 10];
+            let err = poll_fn(|cx| Pin::new(&mut file).poll_read(cx, &mut buf))
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                CryptoIoError::from_io(&err).map(|e| e.root()),
+                Some(CryptoIoError::BadTag)
+            ));
+            assert!(file.cache.iter().all(|block| block.index != 1));
+            let position = file.get_ref().position();
+
+            let err = poll_fn(|cx| Pin::new(&mut file).poll_read(cx, &mut buf))
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                CryptoIoError::from_io(&err),
+                Some(CryptoIoError::Poisoned)
+            ));
+            seek(&mut file, 0).await;
+            assert!(write_all(&mut file, b"more").await.is_err());
+            assert!(poll_fn(|cx| Pin::new(&mut file).poll_flush(cx))
+                .await
+                .is_err());
+            assert!(shutdown(&mut file).await.is_err());
+            assert_eq!(file.get_ref().position(), position);
+            assert_eq!(file.get_ref().get_ref(), &stored);
+
+            let err = file.take_error().unwrap();
+            let err = CryptoIoError::from_io(&err).unwrap();
+            assert!(matches!(err.root(), CryptoIoError::BadTag));
+            let stored_offset = block_range(1).start as u64;
+            assert_eq!(err.offsets(), Some((BLOCK_LEN, stored_offset)));
+        });
+    }
+}
@@ -0,0 +1,169 @@
+use std::future::poll_fn;
+use std::io::Result as IoResult;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::AsyncWrite;
+use tokio::runtime::Handle;
+
+use crate::{EncryptWriter, OpensslBackend, SymmetricBackend, WriteState};
+
+// An `EncryptWriter` that, if dropped before it has been shut down, spawns a task on `handle` to
+// finalize the stream and flush and shut down the inner writer, logging the outcome, rather than
+// losing the final block and tag. This is a safety net for call sites that forget `shutdown`
+// (or return early on some other error), not a substitute for it: nothing waits for the task,
+// so whatever reads the stream may still see it incomplete, and errors only reach the log. A
+// poisoned writer is dropped as it is, having nothing trustworthy to finish.
+pub struct FinalizeOnDrop<W, B = OpensslBackend>
+where
+    W: AsyncWrite + Send + 'static,
+    B: SymmetricBackend + Send + 'static,
+{
+    // boxed so it can be moved into the task whatever `W` is; None once handed back
+    writer: Option<Pin<Box<EncryptWriter<W, B>>>>,
+    handle: Handle,
+}
+impl<W, B> FinalizeOnDrop<W, B>
+where
+    W: AsyncWrite + Send + 'static,
+    B: SymmetricBackend + Send + 'static,
+{
+    pub fn new(writer: EncryptWriter<W, B>, handle: Handle) -> Self {
+        FinalizeOnDrop {
+            writer: Some(Box::pin(writer)),
+            handle,
+        }
+    }
+
+    fn writer(&mut self) -> Pin<&mut EncryptWriter<W, B>> {
+        self.writer.as_mut().unwrap().as_mut()
+    }
+
+    pub fn get_ref(&self) -> &EncryptWriter<W, B> {
+        self.writer.as_ref().unwrap()
+    }
+
+    // gives the writer back, no longer finalized on drop
+    pub fn into_inner(mut self) -> Pin<Box<EncryptWriter<W, B>>> {
+        self.writer.take().unwrap()
+    }
+}
+
+impl<W, B> Drop for FinalizeOnDrop<W, B>
+where
+    W: AsyncWrite + Send + 'static,
+    B: SymmetricBackend + Send + 'static,
+{
+    fn drop(&mut self) {
+        let mut writer = match self.writer.take() {
+            Some(a) => a,
+            None => return,
+        };
+        if writer.state == WriteState::Closed || writer.poison.is_poisoned() {
+            return;
+        }
+        event!(
+            tracing::Level::WARN,
+            plaintext_bytes = writer.core.plaintext_bytes(),
+            "dropped without shutdown, finalizing in the background"
+        );
+        self.handle.spawn(async move {
+            match poll_fn(|cx| writer.as_mut().poll_shutdown(cx)).await {
+                Ok(()) => {
+                    event!(
+                        tracing::Level::INFO,
+                        ciphertext_bytes = writer.core.ciphertext_bytes(),
+                        "finalized in the background"
+                    );
+                }
+                Err(_e) => {
+                    event!(
+                        tracing::Level::ERROR,
+                        error = %_e,
+                        "background finalize failed"
+                    );
+                }
+            }
+        });
+    }
+}
+
+impl<W, B> AsyncWrite for FinalizeOnDrop<W, B>
+where
+    W: AsyncWrite + Send + 'static,
+    B: SymmetricBackend + Send + 'static,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        self.get_mut().writer().poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        self.get_mut().writer().poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        self.get_mut().writer().poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use openssl::symm::Cipher;
+    use tokio::io::AsyncWrite;
+
+    use crate::testing::{block_on, key_iv, read_to_end, sample, write_all};
+    use crate::{DecryptReader, EncryptWriter};
+
+    // a Vec shared with the test, since the writer is moved into the background task
+    #[derive(Clone, Default)]
+    struct Shared(Arc<Mutex<Vec<u8>>>);
+    impl AsyncWrite for Shared {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<std::io::Result<usize>> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Poll::Ready(Ok(buf.len()))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn finalizes_dropped_writer() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(10_000);
+        let out = Shared::default();
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap();
+        let handle = runtime.handle().clone();
+        let mut writer = EncryptWriter::new(out.clone(), cipher, &key, iv.as_deref())
+            .unwrap()
+            .finalize_on_drop(handle);
+        block_on(write_all(&mut writer, &plaintext)).unwrap();
+        drop(writer);
+        // runs the spawned finalize task
+        runtime.block_on(tokio::task::yield_now());
+
+        let ciphertext = out.0.lock().unwrap().clone();
+        let decrypted = block_on(async {
+            let mut reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref())?;
+            read_to_end(&mut reader).await
+        });
+        assert_eq!(decrypted.unwrap(), plaintext);
+    }
+}
@@ -0,0 +1,220 @@
+use std::io::Error as IoError;
+
+use aes_gcm_siv::aead::{AeadInPlace, KeyInit};
+use aes_gcm_siv::{Aes128GcmSiv, Aes256GcmSiv, Nonce, Tag};
+use openssl::symm::Mode;
+use zeroize::Zeroize;
+
+use crate::{CryptoIoError, SymmetricBackend};
+
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+enum Aead {
+    Aes128(Box<Aes128GcmSiv>),
+    Aes256(Box<Aes256GcmSiv>),
+}
+
+// AES-GCM-SIV (RFC 8452) from RustCrypto, as OpenSSL only gained it in 3.2. Reusing a nonce only
+// reveals whether two messages were identical, instead of breaking the key as with GCM.
+// SIV modes make two passes over the message: the tag is derived from the whole plaintext and
+// then used to encrypt it, and decryption can only be checked once all of it is in. So nothing
+// comes out until `finalize`, and the whole message is held in memory; use this for messages
+// rather than unbounded streams.
+pub struct AesGcmSivBackend {
+    aead: Aead,
+    mode: Mode,
+    nonce: [u8; NONCE_LEN],
+    buf: Vec<u8>,
+    // computed when encrypting, expected when decrypting
+    tag: [u8; TAG_LEN],
+}
+impl AesGcmSivBackend {
+    // `key` is 16 bytes for AES-128-GCM-SIV or 32 for AES-256-GCM-SIV
+    pub fn new(mode: Mode, key: &[u8], nonce: &[u8]) -> Result<Self, CryptoIoError> {
+        let aead = match key.len() {
+            16 => Aead::Aes128(Box::new(Aes128GcmSiv::new_from_slice(key).unwrap())),
+            32 => Aead::Aes256(Box::new(Aes256GcmSiv::new_from_slice(key).unwrap())),
+            actual => {
+                return Err(CryptoIoError::InvalidKeyLen {
+                    expected: 32,
+                    actual,
+                })
+            }
+        };
+        if nonce.len() != NONCE_LEN {
+            return Err(CryptoIoError::InvalidIvLen {
+                expected: NONCE_LEN,
+                actual: nonce.len(),
+            });
+        }
+        let mut res = AesGcmSivBackend {
+            aead,
+            mode,
+            nonce: [0; NONCE_LEN],
+            buf: Vec::new(),
+            tag: [0; TAG_LEN],
+        };
+        res.nonce.copy_from_slice(nonce);
+        Ok(res)
+    }
+}
+
+impl SymmetricBackend for AesGcmSivBackend {
+    fn update(&mut self, input: &[u8], _output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        self.buf.extend_from_slice(input);
+        Ok(0)
+    }
+
+    fn finalize(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        let nonce = Nonce::from_slice(&self.nonce);
+        let buf = &mut self.buf;
+        match self.mode {
+            Mode::Encrypt => {
+                let tag = match &self.aead {
+                    Aead::Aes128(a) => a.encrypt_in_place_detached(nonce, &[], buf),
+                    Aead::Aes256(a) => a.encrypt_in_place_detached(nonce, &[], buf),
+                }
+                .map_err(|_| IoError::other("message too long for AES-GCM-SIV"))?;
+                self.tag.copy_from_slice(&tag);
+            }
+            Mode::Decrypt => {
+                let tag = Tag::from_slice(&self.tag);
+                match &self.aead {
+                    Aead::Aes128(a) => a.decrypt_in_place_detached(nonce, &[], buf, tag),
+                    Aead::Aes256(a) => a.decrypt_in_place_detached(nonce, &[], buf, tag),
+                }
+                .map_err(|_| CryptoIoError::BadTag)?;
+            }
+        }
+        let len = buf.len();
+        output[..len].copy_from_slice(buf);
+        buf.zeroize();
+        Ok(len)
+    }
+
+    fn block_size(&self) -> usize {
+        1
+    }
+
+    fn finalize_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn name(&self) -> &'static str {
+        match self.aead {
+            Aead::Aes128(_) => "AES-128-GCM-SIV",
+            Aead::Aes256(_) => "AES-256-GCM-SIV",
+        }
+    }
+
+    fn tag_len(&self) -> usize {
+        TAG_LEN
+    }
+
+    fn get_tag(&mut self, tag: &mut [u8]) -> Result<(), CryptoIoError> {
+        tag.copy_from_slice(&self.tag[..tag.len()]);
+        Ok(())
+    }
+
+    fn set_tag(&mut self, tag: &[u8]) -> Result<(), CryptoIoError> {
+        if tag.len() != TAG_LEN {
+            return Err(CryptoIoError::InvalidTagLen {
+                min: TAG_LEN,
+                max: TAG_LEN,
+                actual: tag.len(),
+            });
+        }
+        self.tag.copy_from_slice(tag);
+        Ok(())
+    }
+}
+
+impl Drop for AesGcmSivBackend {
+    fn drop(&mut self) {
+        self.buf.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Mode;
+
+    use super::AesGcmSivBackend;
+    use crate::testing::{block_on, hex, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter, SymmetricBackend};
+
+    fn seal(key: &[u8], nonce: &[u8], plaintext: &[u8]) -> (Vec<u8>, [u8; 16]) {
+        let mut backend = AesGcmSivBackend::new(Mode::Encrypt, key, nonce).unwrap();
+        assert_eq!(backend.update(plaintext, &mut []).unwrap(), 0);
+        let mut out = vec![0; backend.finalize_len()];
+        let len = backend.finalize(&mut out).unwrap();
+        out.truncate(len);
+        let mut tag = [0; 16];
+        backend.get_tag(&mut tag).unwrap();
+        (out, tag)
+    }
+
+    // RFC 8452 C.1, without associated data
+    #[test]
+    fn rfc_8452_vectors() {
+        let key = hex("01000000000000000000000000000000");
+        let nonce = hex("030000000000000000000000");
+        let (ciphertext, tag) = seal(&key, &nonce, &[]);
+        assert!(ciphertext.is_empty());
+        assert_eq!(tag[..], hex("dc20e2d83f25705bb49e439eca56de25")[..]);
+        let (ciphertext, tag) = seal(&key, &nonce, &hex("0100000000000000"));
+        assert_eq!(ciphertext, hex("b5d839330ac7b786"));
+        assert_eq!(tag[..], hex("578782fff6013b815b287c22493a364c")[..]);
+    }
+
+    fn stream(key: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        block_on(async {
+            let backend = AesGcmSivBackend::new(Mode::Encrypt, key, &[3; 12]).unwrap();
+            let mut writer = EncryptWriter::with_backend(&mut out, backend);
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    #[test]
+    fn round_trip_and_tampering() {
+        let key = [9; 32];
+        let plaintext = sample(10_000);
+        let mut ciphertext = stream(&key, &plaintext);
+        // a reused nonce only shows that the plaintexts were the same
+        assert_eq!(stream(&key, &plaintext), ciphertext);
+        let open = |ciphertext: &[u8]| {
+            let backend = AesGcmSivBackend::new(Mode::Decrypt, &key, &[3; 12]).unwrap();
+            let mut reader = DecryptReader::with_backend(ciphertext, backend);
+            block_on(read_to_end(&mut reader))
+        };
+        assert_eq!(open(&ciphertext).unwrap(), plaintext);
+        ciphertext[5000] ^= 1;
+        let e = open(&ciphertext).unwrap_err();
+        let root = CryptoIoError::from_io(&e).map(|e| e.root());
+        assert!(matches!(root, Some(CryptoIoError::BadTag)));
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        let res = AesGcmSivBackend::new(Mode::Encrypt, &[0; 24], &[0; 12]);
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidKeyLen {
+                expected: 32,
+                actual: 24
+            })
+        ));
+        let res = AesGcmSivBackend::new(Mode::Encrypt, &[0; 16], &[0; 16]);
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidIvLen {
+                expected: 12,
+                actual: 16
+            })
+        ));
+    }
+}
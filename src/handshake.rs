@@ -0,0 +1,865 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+use openssl::memcmp;
+use openssl::pkey::{Id, PKey};
+use openssl::symm::Cipher;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use zeroize::Zeroizing;
+
+use crate::cipher::tag_len_range;
+use crate::hpke::dh;
+use crate::mac::{hkdf_expand, hkdf_extract};
+use crate::rng::fill_random;
+use crate::{CryptoIoError, EncryptedStream};
+
+// A minimal handshake for standing up an `EncryptedStream` between two services that share a
+// key, without certificates:
+//
+//   client -> server: lowest and highest accepted protocol version (1 byte each),
+//                     client nonce (32 bytes)
+//   server -> client: chosen version (1 byte), server nonce (32 bytes),
+//                     server confirmation (32 bytes)
+//   client -> server: client confirmation (32 bytes)
+//
+// The server picks the highest version both sides accept. If there is none it replies with just
+// its own highest version and hangs up, so the client can report what the server wanted. The
+// offer and the choice are mixed into the session key, so a man in the middle who rewrites the
+// offer to force an older version fails the confirmations.
+//
+// Both sides derive a session key with HKDF-SHA256 from the pre-shared key and both nonces, and
+// from it a key and IV per direction, so no two sessions encrypt under the same key and IV. The
+// confirmations are HMACs under the session key, so a wrong key fails the handshake with
+// `KeyMismatch` rather than surfacing later as a bad tag. There is no forward secrecy: anyone who
+// later learns the pre-shared key can decrypt recorded sessions.
+//
+// The pre-shared key must be a full-entropy key (e.g. 32 random bytes), never a password: the
+// server sends its confirmation before the client has proven anything, so anyone who connects
+// gets a value to test guesses at the key against offline.
+//
+// Only AEAD ciphers are accepted: the `EncryptedStream` the handshake returns seals the traffic in
+// records under them, each authenticated before any of it is read.
+
+const NONCE_LEN: usize = 32;
+const CONFIRM_LEN: usize = 32;
+const PSK_LABEL: &[u8] = b"tokio-openssl-symm handshake v1 ";
+const X25519_LABEL: &[u8] = b"tokio-openssl-symm x25519 handshake v1 ";
+
+// the newest handshake protocol version this crate speaks
+pub const PROTOCOL_VERSION: u8 = 1;
+
+// Which protocol versions a side accepts. The default takes anything this crate speaks; once
+// every peer has been upgraded, raising the minimum stops an old or tampered peer from dragging a
+// connection down to an older version.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionPolicy {
+    min: u8,
+    max: u8,
+}
+impl VersionPolicy {
+    // `min` and any newer version this crate speaks. A policy that only allows versions newer
+    // than `PROTOCOL_VERSION` never completes a handshake.
+    pub fn at_least(min: u8) -> Self {
+        VersionPolicy {
+            min,
+            max: PROTOCOL_VERSION,
+        }
+    }
+
+    // `version` and nothing else, e.g. to hold back an upgrade until every peer has it
+    pub fn only(version: u8) -> Self {
+        VersionPolicy {
+            min: version,
+            max: version,
+        }
+    }
+
+    pub fn min(&self) -> u8 {
+        self.min
+    }
+
+    pub fn max(&self) -> u8 {
+        self.max
+    }
+
+    // the sent offer, limited to the versions this crate speaks
+    fn offer(&self) -> [u8; 2] {
+        [self.min.max(1), self.max.min(PROTOCOL_VERSION)]
+    }
+
+    fn check(&self, version: u8) -> Result<(), CryptoIoError> {
+        let [min, max] = self.offer();
+        if version < min || version > max {
+            event!(
+                tracing::Level::ERROR,
+                version,
+                "unsupported handshake version"
+            );
+            return Err(CryptoIoError::UnsupportedVersion {
+                min,
+                max,
+                actual: version,
+            });
+        }
+        Ok(())
+    }
+}
+impl Default for VersionPolicy {
+    fn default() -> Self {
+        VersionPolicy::at_least(1)
+    }
+}
+
+fn check_cipher(cipher: Cipher) -> Result<(), CryptoIoError> {
+    if tag_len_range(cipher).1 == 0 {
+        return Err(IoError::new(
+            IoErrorKind::InvalidInput,
+            "the handshake needs an AEAD cipher",
+        )
+        .into());
+    }
+    Ok(())
+}
+
+// version 1 is the only one so far; a later version picks its own labels, KDF or framing here
+fn label(version: u8, x25519: bool) -> &'static [u8] {
+    debug_assert_eq!(version, 1);
+    if x25519 {
+        X25519_LABEL
+    } else {
+        PSK_LABEL
+    }
+}
+
+// the client's offer followed by the server's choice
+type Transcript = [u8; 3];
+
+async fn client_offer<S>(
+    stream: &mut S,
+    policy: VersionPolicy,
+    nonce: &[u8],
+) -> Result<[u8; 2], CryptoIoError>
+where
+    S: AsyncWrite + Unpin,
+{
+    let offer = policy.offer();
+    stream.write_all(&[&offer[..], nonce].concat()).await?;
+    stream.flush().await?;
+    Ok(offer)
+}
+
+async fn client_version<S>(
+    stream: &mut S,
+    policy: VersionPolicy,
+    offer: [u8; 2],
+) -> Result<Transcript, CryptoIoError>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut version = [0];
+    stream.read_exact(&mut version).await?;
+    policy.check(version[0])?;
+    Ok([offer[0], offer[1], version[0]])
+}
+
+// reads the client's offer and picks a version, or tells the client which it would have wanted
+async fn server_version<S>(
+    stream: &mut S,
+    policy: VersionPolicy,
+) -> Result<Transcript, CryptoIoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut offer = [0; 2];
+    stream.read_exact(&mut offer).await?;
+    let [min, max] = policy.offer();
+    let version = offer[1].min(max);
+    if version < offer[0] || version < min {
+        // read the rest of the first message so closing doesn't reset the connection before the
+        // client sees the reply
+        stream.read_exact(&mut [0; NONCE_LEN]).await?;
+        stream.write_all(&[max.max(min)]).await?;
+        stream.flush().await?;
+        return Err(CryptoIoError::UnsupportedVersion {
+            min,
+            max,
+            actual: offer[1],
+        });
+    }
+    Ok([offer[0], offer[1], version])
+}
+
+struct Session {
+    label: &'static [u8],
+    transcript: Transcript,
+    // the client's then the server's static public key, with `Auth::StaticKeys`
+    static_keys: Vec<u8>,
+    prk: Zeroizing<Vec<u8>>,
+    cipher: Cipher,
+}
+impl Session {
+    // `client_nonce` and `server_nonce` are the random values each side sent
+    fn new(
+        transcript: Transcript,
+        x25519: bool,
+        ikm: &[&[u8]],
+        cipher: Cipher,
+        client_nonce: &[u8],
+        server_nonce: &[u8],
+    ) -> Result<Self, CryptoIoError> {
+        let salt = [client_nonce, server_nonce].concat();
+        Ok(Session {
+            label: label(transcript[2], x25519),
+            transcript,
+            static_keys: Vec::new(),
+            prk: hkdf_extract(&salt, ikm)?,
+            cipher,
+        })
+    }
+
+    // binds both sides' static public keys into every derived key, so each side knows which pair
+    // of identities the session belongs to
+    fn with_static_keys(mut self, client: &[u8], server: &[u8]) -> Self {
+        self.static_keys = [client, server].concat();
+        self
+    }
+
+    fn expand(&self, label: &[u8], len: usize) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+        let nid = (self.cipher.nid().as_raw() as u32).to_be_bytes();
+        let info = [self.label, &self.transcript, &self.static_keys, label, &nid];
+        Ok(hkdf_expand(&self.prk, &info, len)?)
+    }
+
+    fn confirmation(&self, role: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+        self.expand(&[role, b" finished"].concat(), CONFIRM_LEN)
+    }
+
+    fn check(&self, role: &[u8], confirmation: &[u8]) -> Result<(), CryptoIoError> {
+        if !memcmp::eq(&self.confirmation(role)?, confirmation) {
+            event!(tracing::Level::ERROR, "handshake confirmation mismatch");
+            return Err(CryptoIoError::KeyMismatch);
+        }
+        Ok(())
+    }
+
+    fn stream<S>(
+        &self,
+        stream: S,
+        write: &[u8],
+        read: &[u8],
+    ) -> Result<EncryptedStream<S>, CryptoIoError>
+    where
+        S: AsyncRead + AsyncWrite,
+    {
+        let key_len = self.cipher.key_len();
+        let iv_len = self.cipher.iv_len().unwrap_or(0);
+        let write_key = self.expand(&[write, b" key"].concat(), key_len)?;
+        let write_iv = self.expand(&[write, b" iv"].concat(), iv_len)?;
+        let read_key = self.expand(&[read, b" key"].concat(), key_len)?;
+        let read_iv = self.expand(&[read, b" iv"].concat(), iv_len)?;
+        let (write_iv, read_iv) = match self.cipher.iv_len() {
+            Some(_) => (Some(&write_iv[..]), Some(&read_iv[..])),
+            None => (None, None),
+        };
+        EncryptedStream::new(
+            stream,
+            self.cipher,
+            &write_key,
+            write_iv,
+            &read_key,
+            read_iv,
+        )
+    }
+}
+
+pub async fn client<S>(
+    mut stream: S,
+    psk: &[u8],
+    cipher: Cipher,
+    policy: VersionPolicy,
+) -> Result<EncryptedStream<S>, CryptoIoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    check_cipher(cipher)?;
+    let mut client_nonce = [0; NONCE_LEN];
+    fill_random(&mut client_nonce)?;
+    let offer = client_offer(&mut stream, policy, &client_nonce).await?;
+    let transcript = client_version(&mut stream, policy, offer).await?;
+    let mut reply = [0; NONCE_LEN + CONFIRM_LEN];
+    stream.read_exact(&mut reply).await?;
+    let (server_nonce, confirmation) = reply.split_at(NONCE_LEN);
+    let session = Session::new(
+        transcript,
+        false,
+        &[psk],
+        cipher,
+        &client_nonce,
+        server_nonce,
+    )?;
+    session.check(b"server", confirmation)?;
+    stream.write_all(&session.confirmation(b"client")?).await?;
+    stream.flush().await?;
+    session.stream(stream, b"client", b"server")
+}
+
+pub async fn server<S>(
+    mut stream: S,
+    psk: &[u8],
+    cipher: Cipher,
+    policy: VersionPolicy,
+) -> Result<EncryptedStream<S>, CryptoIoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    check_cipher(cipher)?;
+    let transcript = server_version(&mut stream, policy).await?;
+    let mut client_nonce = [0; NONCE_LEN];
+    stream.read_exact(&mut client_nonce).await?;
+    let mut server_nonce = [0; NONCE_LEN];
+    fill_random(&mut server_nonce)?;
+    let session = Session::new(
+        transcript,
+        false,
+        &[psk],
+        cipher,
+        &client_nonce,
+        &server_nonce,
+    )?;
+    let confirmation = session.confirmation(b"server")?;
+    let reply = [&transcript[2..], &server_nonce[..], &confirmation].concat();
+    stream.write_all(&reply).await?;
+    stream.flush().await?;
+    let mut confirmation = [0; CONFIRM_LEN];
+    stream.read_exact(&mut confirmation).await?;
+    session.check(b"client", &confirmation)?;
+    session.stream(stream, b"server", b"client")
+}
+
+// How the X25519 handshake authenticates the peer. Without authentication it still gives
+// forward secrecy against a passive eavesdropper, but an active man in the middle can run a
+// handshake with each side.
+#[derive(Clone, Copy)]
+pub enum Auth<'a> {
+    None,
+    // both sides know the same pre-shared key, which is mixed into the session key. As with
+    // `client`/`server`, it must be a full-entropy key rather than a password: a peer knows its
+    // own ephemeral key, so the other side's confirmation lets it test guesses offline.
+    Psk(&'a [u8]),
+    // each side has a static X25519 key pair (as raw keys, e.g. from `hpke_keypair`) and knows
+    // the other's public key; both public keys are bound into the session keys
+    StaticKeys {
+        private_key: &'a [u8],
+        peer_public_key: &'a [u8],
+    },
+}
+
+// The same exchange as `client`/`server`, but the nonces are ephemeral X25519 public keys and the
+// session key comes from their shared secret, so recorded sessions stay secret even if the
+// long-term keys later leak. With `Auth::StaticKeys`, each side's static key is combined with the
+// other's ephemeral key as well, so only the holders of the static private keys can complete the
+// handshake.
+pub async fn client_x25519<S>(
+    mut stream: S,
+    auth: Auth<'_>,
+    cipher: Cipher,
+    policy: VersionPolicy,
+) -> Result<EncryptedStream<S>, CryptoIoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    check_cipher(cipher)?;
+    let ephemeral = PKey::generate_x25519()?;
+    let client_public = ephemeral.raw_public_key()?;
+    let offer = client_offer(&mut stream, policy, &client_public).await?;
+    let transcript = client_version(&mut stream, policy, offer).await?;
+    let mut reply = [0; NONCE_LEN + CONFIRM_LEN];
+    stream.read_exact(&mut reply).await?;
+    let (server_public, confirmation) = reply.split_at(NONCE_LEN);
+    let mut ikm = vec![dh(&ephemeral, server_public)?];
+    let mut static_keys = None;
+    match auth {
+        Auth::None => (),
+        Auth::Psk(psk) => ikm.push(Zeroizing::new(psk.to_vec())),
+        Auth::StaticKeys {
+            private_key,
+            peer_public_key,
+        } => {
+            let private = PKey::private_key_from_raw_bytes(private_key, Id::X25519)?;
+            ikm.push(dh(&private, server_public)?);
+            ikm.push(dh(&ephemeral, peer_public_key)?);
+            static_keys = Some((private.raw_public_key()?, peer_public_key));
+        }
+    }
+    let ikm: Vec<&[u8]> = ikm.iter().map(|a| &a[..]).collect();
+    let mut session = Session::new(
+        transcript,
+        true,
+        &ikm,
+        cipher,
+        &client_public,
+        server_public,
+    )?;
+    if let Some((own, peer)) = &static_keys {
+        session = session.with_static_keys(own, peer);
+    }
+    session.check(b"server", confirmation)?;
+    stream.write_all(&session.confirmation(b"client")?).await?;
+    stream.flush().await?;
+    session.stream(stream, b"client", b"server")
+}
+
+pub async fn server_x25519<S>(
+    mut stream: S,
+    auth: Auth<'_>,
+    cipher: Cipher,
+    policy: VersionPolicy,
+) -> Result<EncryptedStream<S>, CryptoIoError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    check_cipher(cipher)?;
+    let transcript = server_version(&mut stream, policy).await?;
+    let mut client_public = [0; NONCE_LEN];
+    stream.read_exact(&mut client_public).await?;
+    let ephemeral = PKey::generate_x25519()?;
+    let server_public = ephemeral.raw_public_key()?;
+    let mut ikm = vec![dh(&ephemeral, &client_public)?];
+    let mut static_keys = None;
+    match auth {
+        Auth::None => (),
+        Auth::Psk(psk) => ikm.push(Zeroizing::new(psk.to_vec())),
+        Auth::StaticKeys {
+            private_key,
+            peer_public_key,
+        } => {
+            let private = PKey::private_key_from_raw_bytes(private_key, Id::X25519)?;
+            ikm.push(dh(&ephemeral, peer_public_key)?);
+            ikm.push(dh(&private, &client_public)?);
+            static_keys = Some((peer_public_key, private.raw_public_key()?));
+        }
+    }
+    let ikm: Vec<&[u8]> = ikm.iter().map(|a| &a[..]).collect();
+    let mut session = Session::new(
+        transcript,
+        true,
+        &ikm,
+        cipher,
+        &client_public,
+        &server_public,
+    )?;
+    if let Some((peer, own)) = &static_keys {
+        session = session.with_static_keys(peer, own);
+    }
+    let confirmation = session.confirmation(b"server")?;
+    let reply = [&transcript[2..], &server_public[..], &confirmation].concat();
+    stream.write_all(&reply).await?;
+    stream.flush().await?;
+    let mut confirmation = [0; CONFIRM_LEN];
+    stream.read_exact(&mut confirmation).await?;
+    session.check(b"client", &confirmation)?;
+    session.stream(stream, b"server", b"client")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Result as IoResult;
+    use std::pin::Pin;
+    use std::sync::{Arc, Mutex};
+    use std::task::{Context, Poll};
+
+    use openssl::symm::Cipher;
+    use tokio::io::{self, AsyncRead, AsyncWrite, DuplexStream};
+
+    use super::{
+        client, client_x25519, server, server_x25519, Auth, Session, VersionPolicy,
+        PROTOCOL_VERSION,
+    };
+    use crate::testing::{block_on, join, read_to_end, shutdown, write_all};
+    use crate::{hpke_keypair, CryptoIoError, EncryptedStream};
+
+    // one end of the pipe, recording what is written through it and optionally flipping the byte
+    // at one offset, as a man in the middle would
+    struct Tap {
+        inner: DuplexStream,
+        written: Arc<Mutex<Vec<u8>>>,
+        flip: Option<usize>,
+    }
+    impl Tap {
+        fn new(inner: DuplexStream, flip: Option<usize>) -> (Self, Arc<Mutex<Vec<u8>>>) {
+            let written = Arc::new(Mutex::new(Vec::new()));
+            let tap = Tap {
+                inner,
+                written: written.clone(),
+                flip,
+            };
+            (tap, written)
+        }
+    }
+    impl AsyncRead for Tap {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<IoResult<usize>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+    impl AsyncWrite for Tap {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<IoResult<usize>> {
+            let start = self.written.lock().unwrap().len();
+            let mut data = buf.to_vec();
+            if let Some(at) = self.flip.and_then(|at| at.checked_sub(start)) {
+                if let Some(byte) = data.get_mut(at) {
+                    *byte ^= 1;
+                }
+            }
+            let n = match Pin::new(&mut self.inner).poll_write(cx, &data) {
+                Poll::Ready(Ok(n)) => n,
+                res => return res,
+            };
+            self.written.lock().unwrap().extend_from_slice(&data[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    // the client sends `request` and the server answers with `response`, each then hanging up
+    async fn exchange(
+        client: Result<EncryptedStream<impl AsyncRead + AsyncWrite>, CryptoIoError>,
+        server: Result<EncryptedStream<impl AsyncRead + AsyncWrite>, CryptoIoError>,
+    ) -> (Vec<u8>, Vec<u8>) {
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        write_all(&mut client, b"request").await.unwrap();
+        shutdown(&mut client).await.unwrap();
+        let request = read_to_end(&mut server).await.unwrap();
+        write_all(&mut server, b"response").await.unwrap();
+        shutdown(&mut server).await.unwrap();
+        (request, read_to_end(&mut client).await.unwrap())
+    }
+
+    #[test]
+    fn psk_handshake() {
+        let policy = VersionPolicy::default();
+        for &cipher in &[
+            Cipher::aes_256_gcm(),
+            Cipher::chacha20_poly1305(),
+            Cipher::aes_128_gcm(),
+        ] {
+            let (a, b) = io::duplex(1024);
+            let (client, server) = join(
+                client(a, b"psk", cipher, policy),
+                server(b, b"psk", cipher, policy),
+            );
+            let (request, response) = block_on(exchange(client, server));
+            assert_eq!(request, b"request");
+            assert_eq!(response, b"response");
+        }
+    }
+
+    // refused before anything is sent
+    #[test]
+    fn non_aead_refused() {
+        let policy = VersionPolicy::default();
+        for &cipher in &[Cipher::aes_128_ctr(), Cipher::aes_256_cbc()] {
+            let (a, b) = io::duplex(1024);
+            let (a, client_written) = Tap::new(a, None);
+            let (b, server_written) = Tap::new(b, None);
+            let (client, server) = join(
+                client(a, b"psk", cipher, policy),
+                server(b, b"psk", cipher, policy),
+            );
+            let (a, b) = io::duplex(1024);
+            let (a, x25519_client_written) = Tap::new(a, None);
+            let (b, x25519_server_written) = Tap::new(b, None);
+            let (x25519_client, x25519_server) = join(
+                client_x25519(a, Auth::None, cipher, policy),
+                server_x25519(b, Auth::None, cipher, policy),
+            );
+            for res in [
+                client.map(drop),
+                server.map(drop),
+                x25519_client.map(drop),
+                x25519_server.map(drop),
+            ] {
+                let kind = res.unwrap_err().kind();
+                assert_eq!(kind, std::io::ErrorKind::InvalidInput);
+            }
+            assert!(client_written.lock().unwrap().is_empty());
+            assert!(server_written.lock().unwrap().is_empty());
+            assert!(x25519_client_written.lock().unwrap().is_empty());
+            assert!(x25519_server_written.lock().unwrap().is_empty());
+        }
+    }
+
+    #[test]
+    fn wrong_psk() {
+        let (a, b) = io::duplex(1024);
+        let cipher = Cipher::aes_256_gcm();
+        let policy = VersionPolicy::default();
+        let (client, server) = join(
+            client(a, b"psk", cipher, policy),
+            server(b, b"other", cipher, policy),
+        );
+        assert!(matches!(client, Err(CryptoIoError::KeyMismatch)));
+        // the client hangs up instead of confirming
+        assert!(server.is_err());
+    }
+
+    // both ends choose their own random nonce, so sessions under the same key never repeat a key
+    // and IV
+    #[test]
+    fn sessions_differ() {
+        let cipher = Cipher::aes_256_gcm();
+        let policy = VersionPolicy::default();
+        let mut sent = Vec::new();
+        for _ in 0..2 {
+            let (a, b) = io::duplex(1024);
+            let (a, written) = Tap::new(a, None);
+            let (client, server) = join(
+                client(a, b"psk", cipher, policy),
+                server(b, b"psk", cipher, policy),
+            );
+            let (mut client, _server) = (client.unwrap(), server.unwrap());
+            block_on(write_all(&mut client, &[0; 32])).unwrap();
+            block_on(shutdown(&mut client)).unwrap();
+            // past the offer, nonce and confirmation
+            sent.push(written.lock().unwrap()[66..].to_vec());
+        }
+        // one final record: its header, the ciphertext and the tag
+        assert_eq!(sent[0].len(), 5 + 32 + 16);
+        assert_ne!(sent[0], sent[1]);
+    }
+
+    // the traffic is sealed in records, each checked before any of it is read
+    #[test]
+    fn tampered_traffic() {
+        let cipher = Cipher::aes_256_gcm();
+        let policy = VersionPolicy::default();
+        let (a, b) = io::duplex(1024);
+        let (a, _) = Tap::new(a, Some(66 + 5));
+        let (client, server) = join(
+            client(a, b"psk", cipher, policy),
+            server(b, b"psk", cipher, policy),
+        );
+        let (mut client, mut server) = (client.unwrap(), server.unwrap());
+        block_on(write_all(&mut client, b"request")).unwrap();
+        block_on(shutdown(&mut client)).unwrap();
+        let err = block_on(read_to_end(&mut server)).unwrap_err();
+        assert!(matches!(
+            CryptoIoError::from_io(&err).map(|e| e.root()),
+            Some(CryptoIoError::BadTag)
+        ));
+    }
+
+    #[test]
+    fn x25519_handshake() {
+        let cipher = Cipher::chacha20_poly1305();
+        let policy = VersionPolicy::default();
+        let (client_private, client_public) = hpke_keypair().unwrap();
+        let (server_private, server_public) = hpke_keypair().unwrap();
+        let client_auth = Auth::StaticKeys {
+            private_key: &client_private,
+            peer_public_key: &server_public,
+        };
+        let server_auth = Auth::StaticKeys {
+            private_key: &server_private,
+            peer_public_key: &client_public,
+        };
+        for &(client_auth, server_auth) in &[
+            (Auth::None, Auth::None),
+            (Auth::Psk(b"psk"), Auth::Psk(b"psk")),
+            (client_auth, server_auth),
+        ] {
+            let (a, b) = io::duplex(1024);
+            let (client, server) = join(
+                client_x25519(a, client_auth, cipher, policy),
+                server_x25519(b, server_auth, cipher, policy),
+            );
+            let (request, response) = block_on(exchange(client, server));
+            assert_eq!(request, b"request");
+            assert_eq!(response, b"response");
+        }
+    }
+
+    fn x25519_fails(client_auth: Auth<'_>, server_auth: Auth<'_>) {
+        let cipher = Cipher::aes_256_gcm();
+        let policy = VersionPolicy::default();
+        let (a, b) = io::duplex(1024);
+        let (client, server) = join(
+            client_x25519(a, client_auth, cipher, policy),
+            server_x25519(b, server_auth, cipher, policy),
+        );
+        assert!(matches!(client, Err(CryptoIoError::KeyMismatch)));
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn x25519_wrong_psk() {
+        x25519_fails(Auth::Psk(b"psk"), Auth::Psk(b"other"));
+        x25519_fails(Auth::Psk(b"psk"), Auth::None);
+    }
+
+    // a server that doesn't hold the private key the client expects can't confirm, and neither
+    // can one that accepts a client other than the one it expects
+    #[test]
+    fn x25519_wrong_static_key() {
+        let (client_private, client_public) = hpke_keypair().unwrap();
+        let (server_private, server_public) = hpke_keypair().unwrap();
+        let (other_private, other_public) = hpke_keypair().unwrap();
+        x25519_fails(
+            Auth::StaticKeys {
+                private_key: &client_private,
+                peer_public_key: &server_public,
+            },
+            Auth::StaticKeys {
+                private_key: &other_private,
+                peer_public_key: &client_public,
+            },
+        );
+        x25519_fails(
+            Auth::StaticKeys {
+                private_key: &client_private,
+                peer_public_key: &server_public,
+            },
+            Auth::StaticKeys {
+                private_key: &server_private,
+                peer_public_key: &other_public,
+            },
+        );
+    }
+
+    // which static keys the session is between, and in which role, goes into every derived key
+    #[test]
+    fn static_keys_are_bound() {
+        let confirmation = |static_keys: Option<(&[u8], &[u8])>| {
+            let mut session = Session::new(
+                [1, 1, 1],
+                true,
+                &[b"ikm"],
+                Cipher::aes_256_gcm(),
+                &[1; 32],
+                &[2; 32],
+            )
+            .unwrap();
+            if let Some((client, server)) = static_keys {
+                session = session.with_static_keys(client, server);
+            }
+            session.confirmation(b"server").unwrap()
+        };
+        let (a, b) = ([3; 32], [4; 32]);
+        assert_ne!(confirmation(None), confirmation(Some((&a, &b))));
+        assert_ne!(confirmation(Some((&a, &b))), confirmation(Some((&b, &a))));
+    }
+
+    // the PSK and X25519 handshakes derive their keys under different labels
+    #[test]
+    fn psk_and_x25519_differ() {
+        let cipher = Cipher::aes_256_gcm();
+        let policy = VersionPolicy::default();
+        let (a, b) = io::duplex(1024);
+        let (client, server) = join(
+            client(a, b"psk", cipher, policy),
+            server_x25519(b, Auth::Psk(b"psk"), cipher, policy),
+        );
+        assert!(matches!(client, Err(CryptoIoError::KeyMismatch)));
+        assert!(server.is_err());
+    }
+
+    #[test]
+    fn policies() {
+        assert_eq!(VersionPolicy::default(), VersionPolicy::at_least(1));
+        assert_eq!(VersionPolicy::at_least(1).max(), PROTOCOL_VERSION);
+        assert_eq!(VersionPolicy::only(3).min(), 3);
+        assert_eq!(VersionPolicy::only(3).max(), 3);
+    }
+
+    fn negotiate(
+        client_policy: VersionPolicy,
+        server_policy: VersionPolicy,
+    ) -> (CryptoIoError, CryptoIoError) {
+        let cipher = Cipher::aes_256_gcm();
+        let (a, b) = io::duplex(1024);
+        let (client, server) = join(
+            client(a, b"psk", cipher, client_policy),
+            server(b, b"psk", cipher, server_policy),
+        );
+        (client.unwrap_err(), server.unwrap_err())
+    }
+
+    // the client learns which version the server wanted
+    #[test]
+    fn server_wants_newer() {
+        let (client, server) = negotiate(VersionPolicy::default(), VersionPolicy::only(2));
+        assert!(matches!(
+            client,
+            CryptoIoError::UnsupportedVersion {
+                min: 1,
+                max: 1,
+                actual: 2
+            }
+        ));
+        assert!(matches!(
+            server,
+            CryptoIoError::UnsupportedVersion { actual: 1, .. }
+        ));
+    }
+
+    #[test]
+    fn client_wants_newer() {
+        let (client, server) = negotiate(VersionPolicy::at_least(2), VersionPolicy::default());
+        assert!(matches!(
+            client,
+            CryptoIoError::UnsupportedVersion {
+                min: 2,
+                actual: 1,
+                ..
+            }
+        ));
+        assert!(matches!(server, CryptoIoError::UnsupportedVersion { .. }));
+    }
+
+    // a man in the middle who rewrites the offer gets a version both sides accept, but the
+    // confirmations cover the offer as sent
+    #[test]
+    fn rewritten_offer() {
+        let cipher = Cipher::aes_256_gcm();
+        let policy = VersionPolicy::default();
+        let (a, b) = io::duplex(1024);
+        let (a, _) = Tap::new(a, Some(0));
+        let (client, server) = join(
+            client(a, b"psk", cipher, policy),
+            server(b, b"psk", cipher, policy),
+        );
+        assert!(matches!(client, Err(CryptoIoError::KeyMismatch)));
+        assert!(server.is_err());
+    }
+
+    // a forged choice outside the client's policy is refused before any key is derived
+    #[test]
+    fn rewritten_choice() {
+        let cipher = Cipher::aes_256_gcm();
+        let policy = VersionPolicy::default();
+        let (a, b) = io::duplex(1024);
+        let (b, _) = Tap::new(b, Some(0));
+        let (client, server) = join(
+            client(a, b"psk", cipher, policy),
+            server(b, b"psk", cipher, policy),
+        );
+        assert!(matches!(
+            client,
+            Err(CryptoIoError::UnsupportedVersion { actual: 0, .. })
+        ));
+        assert!(server.is_err());
+    }
+}
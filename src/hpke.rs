@@ -0,0 +1,296 @@
+use openssl::derive::Deriver;
+use openssl::error::ErrorStack;
+use openssl::nid::Nid;
+use openssl::pkey::{Id, PKey};
+use openssl::symm::Cipher;
+use tokio::io::AsyncRead;
+use zeroize::Zeroizing;
+
+use crate::mac::{hkdf_expand, hkdf_extract};
+use crate::{read_prefix, CryptoIoError, DecryptReader, EncryptCore, EncryptWriter};
+
+// DHKEM(X25519, HKDF-SHA256) and HKDF-SHA256
+const KEM_ID: u16 = 0x0020;
+const KDF_ID: u16 = 0x0001;
+const ENC_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const MODE_BASE: u8 = 0;
+
+type Secret = Zeroizing<Vec<u8>>;
+
+fn aead_id(cipher: Cipher) -> Result<(u16, usize), CryptoIoError> {
+    match cipher.nid() {
+        Nid::AES_128_GCM => Ok((0x0001, 16)),
+        Nid::AES_256_GCM => Ok((0x0002, 32)),
+        Nid::CHACHA20_POLY1305 => Ok((0x0003, 32)),
+        _ => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "HPKE streams use AES-128-GCM, AES-256-GCM or ChaCha20-Poly1305",
+        )
+        .into()),
+    }
+}
+
+fn labeled_extract(
+    suite_id: &[u8],
+    salt: &[u8],
+    label: &[u8],
+    ikm: &[u8],
+) -> Result<Secret, ErrorStack> {
+    hkdf_extract(salt, &[b"HPKE-v1", suite_id, label, ikm])
+}
+
+fn labeled_expand(
+    suite_id: &[u8],
+    prk: &[u8],
+    label: &[u8],
+    info: &[u8],
+    len: usize,
+) -> Result<Secret, ErrorStack> {
+    let len_bytes = (len as u16).to_be_bytes();
+    hkdf_expand(prk, &[&len_bytes, b"HPKE-v1", suite_id, label, info], len)
+}
+
+fn shared_secret(dh: &[u8], enc: &[u8], public: &[u8]) -> Result<Secret, ErrorStack> {
+    let mut suite_id = b"KEM".to_vec();
+    suite_id.extend_from_slice(&KEM_ID.to_be_bytes());
+    let prk = labeled_extract(&suite_id, b"", b"eae_prk", dh)?;
+    labeled_expand(
+        &suite_id,
+        &prk,
+        b"shared_secret",
+        &[enc, public].concat(),
+        32,
+    )
+}
+
+// the base mode key schedule: the AEAD key and nonce for sequence number 0
+fn key_schedule(
+    cipher: Cipher,
+    shared_secret: &[u8],
+    info: &[u8],
+) -> Result<(Secret, Secret), CryptoIoError> {
+    let (aead_id, key_len) = aead_id(cipher)?;
+    let mut suite_id = b"HPKE".to_vec();
+    suite_id.extend_from_slice(&KEM_ID.to_be_bytes());
+    suite_id.extend_from_slice(&KDF_ID.to_be_bytes());
+    suite_id.extend_from_slice(&aead_id.to_be_bytes());
+    let psk_id_hash = labeled_extract(&suite_id, b"", b"psk_id_hash", b"")?;
+    let info_hash = labeled_extract(&suite_id, b"", b"info_hash", info)?;
+    let context = [&[MODE_BASE][..], &psk_id_hash, &info_hash].concat();
+    let secret = labeled_extract(&suite_id, shared_secret, b"secret", b"")?;
+    let key = labeled_expand(&suite_id, &secret, b"key", &context, key_len)?;
+    let nonce = labeled_expand(&suite_id, &secret, b"base_nonce", &context, NONCE_LEN)?;
+    Ok((key, nonce))
+}
+
+pub(crate) fn dh(
+    private: &PKey<openssl::pkey::Private>,
+    public: &[u8],
+) -> Result<Secret, ErrorStack> {
+    let public = PKey::public_key_from_raw_bytes(public, Id::X25519)?;
+    let mut deriver = Deriver::new(private)?;
+    deriver.set_peer(&public)?;
+    Ok(Zeroizing::new(deriver.derive_to_vec()?))
+}
+
+// a fresh X25519 key pair for receiving HPKE streams, as raw (private, public) keys
+pub fn hpke_keypair() -> Result<(Secret, Vec<u8>), CryptoIoError> {
+    let key = PKey::generate_x25519()?;
+    Ok((
+        Zeroizing::new(key.raw_private_key()?),
+        key.raw_public_key()?,
+    ))
+}
+
+// HPKE (RFC 9180) in base mode with DHKEM(X25519, HKDF-SHA256), HKDF-SHA256 and `cipher`, which
+// must be AES-128-GCM, AES-256-GCM or ChaCha20-Poly1305. The stream is the encapsulated key
+// followed by the ciphertext and tag, with no tag length byte: exactly a single-shot HPKE `Seal`
+// with empty associated data, so other HPKE implementations can open it.
+impl<W> EncryptWriter<W> {
+    pub fn hpke_seal(
+        writer: W,
+        cipher: Cipher,
+        recipient_public_key: &[u8],
+        info: &[u8],
+    ) -> Result<Self, CryptoIoError> {
+        let ephemeral = PKey::generate_x25519()?;
+        let enc = ephemeral.raw_public_key()?;
+        let dh = dh(&ephemeral, recipient_public_key)?;
+        let shared_secret = shared_secret(&dh, &enc, recipient_public_key)?;
+        let (key, nonce) = key_schedule(cipher, &shared_secret, info)?;
+        let core = EncryptCore::new(cipher, &key, Some(&nonce))?
+            .without_header()
+            .with_prefix(&enc);
+        Ok(Self::with_core(writer, core))
+    }
+}
+
+impl<R> DecryptReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    // opens a stream written by `EncryptWriter::hpke_seal` (or any HPKE single-shot `Seal` with
+    // the same suite and empty associated data) with the recipient's raw X25519 private key
+    pub async fn hpke_open(
+        mut reader: R,
+        cipher: Cipher,
+        recipient_private_key: &[u8],
+        info: &[u8],
+    ) -> Result<Self, CryptoIoError> {
+        let private = PKey::private_key_from_raw_bytes(recipient_private_key, Id::X25519)?;
+        let mut enc = [0; ENC_LEN];
+        read_prefix(&mut reader, &mut enc).await?;
+        let dh = dh(&private, &enc)?;
+        let shared_secret = shared_secret(&dh, &enc, &private.raw_public_key()?)?;
+        let (key, nonce) = key_schedule(cipher, &shared_secret, info)?;
+        Ok(Self::new(reader, cipher, &key, Some(&nonce))?.without_header())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Result as IoResult;
+
+    use openssl::pkey::{Id, PKey};
+    use openssl::symm::{encrypt_aead, Cipher};
+
+    use super::{dh, key_schedule, shared_secret};
+    use crate::testing::{block_on, hex, read_to_end, sample, shutdown, write_all};
+    use crate::{hpke_keypair, CryptoIoError, DecryptReader, EncryptWriter};
+
+    // RFC 9180 appendix A.1.1: DHKEM(X25519, HKDF-SHA256), HKDF-SHA256, AES-128-GCM, base mode
+    const INFO: &str = "4f6465206f6e2061204772656369616e2055726e";
+    const SK_E: &str = "52c4a758a802cd8b936eceea314432798d5baf2d7e9235dc084ab1b9cfa2f736";
+    const SK_R: &str = "4612c550263fc8ad58375df3f557aac531d26850903e55a9f23f21d8534e8ac8";
+    const ENC: &str = "37fda3567bdbd628e88668c3c8d7e97d1d1253b6d4ea6d44c150f741f1bf4431";
+    const SHARED_SECRET: &str = "fe0e18c9f024ce43799ae393c7e8fe8fce9d218875e8227b0187c04e7d2ea1fc";
+    const KEY: &str = "4531685d41d65f03dc48f6b8302c05b0";
+    const BASE_NONCE: &str = "56d890e5accaaf011cff4b7d";
+    // "Beauty is truth, truth beauty", sealed with sequence number 0 and associated data "Count-0"
+    const PT: &str = "4265617574792069732074727574682c20747275746820626561757479";
+    const AAD: &str = "436f756e742d30";
+    const CT: &str = "f938558b5d72f1a23810b4be2ab4f84331acc02fc97babc53a52ae8218a355a96d8770ac83d07bea87e13c512a";
+
+    fn public_key(private: &[u8]) -> Vec<u8> {
+        let key = PKey::private_key_from_raw_bytes(private, Id::X25519).unwrap();
+        key.raw_public_key().unwrap()
+    }
+
+    fn seal(cipher: Cipher, public: &[u8], info: &[u8], plaintext: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::hpke_seal(&mut out, cipher, public, info).unwrap();
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn open(cipher: Cipher, private: &[u8], info: &[u8], ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        block_on(async {
+            let mut reader = DecryptReader::hpke_open(ciphertext, cipher, private, info).await?;
+            read_to_end(&mut reader).await
+        })
+    }
+
+    fn is_bad_tag(result: IoResult<Vec<u8>>) -> bool {
+        match result {
+            Err(e) => matches!(
+                CryptoIoError::from_io(&e).map(|e| e.root()),
+                Some(CryptoIoError::BadTag)
+            ),
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn rfc_9180_key_schedule() {
+        let ephemeral = PKey::private_key_from_raw_bytes(&hex(SK_E), Id::X25519).unwrap();
+        let enc = ephemeral.raw_public_key().unwrap();
+        assert_eq!(enc, hex(ENC));
+        let public = public_key(&hex(SK_R));
+        let dh = dh(&ephemeral, &public).unwrap();
+        let shared_secret = shared_secret(&dh, &enc, &public).unwrap();
+        assert_eq!(&shared_secret[..], &hex(SHARED_SECRET)[..]);
+        let (key, nonce) = key_schedule(Cipher::aes_128_gcm(), &shared_secret, &hex(INFO)).unwrap();
+        assert_eq!(&key[..], &hex(KEY)[..]);
+        assert_eq!(&nonce[..], &hex(BASE_NONCE)[..]);
+
+        let mut tag = [0; 16];
+        let cipher = Cipher::aes_128_gcm();
+        let ct = encrypt_aead(cipher, &key, Some(&nonce), &hex(AAD), &hex(PT), &mut tag).unwrap();
+        assert_eq!([ct, tag.to_vec()].concat(), hex(CT));
+    }
+
+    // a single-shot Seal by another implementation, with empty associated data
+    #[test]
+    fn opens_rfc_9180_seal() {
+        let cipher = Cipher::aes_128_gcm();
+        let mut tag = [0; 16];
+        let ct = encrypt_aead(
+            cipher,
+            &hex(KEY),
+            Some(&hex(BASE_NONCE)),
+            &[],
+            &hex(PT),
+            &mut tag,
+        );
+        let stream = [hex(ENC), ct.unwrap(), tag.to_vec()].concat();
+        let plaintext = open(cipher, &hex(SK_R), &hex(INFO), &stream).unwrap();
+        assert_eq!(plaintext, hex(PT));
+    }
+
+    #[test]
+    fn round_trip() {
+        let plaintext = sample(10_000);
+        let (private, public) = hpke_keypair().unwrap();
+        for &cipher in &[
+            Cipher::aes_128_gcm(),
+            Cipher::aes_256_gcm(),
+            Cipher::chacha20_poly1305(),
+        ] {
+            let ciphertext = seal(cipher, &public, b"info", &plaintext);
+            assert_eq!(ciphertext.len(), 32 + plaintext.len() + 16);
+            assert_eq!(
+                open(cipher, &private, b"info", &ciphertext).unwrap(),
+                plaintext
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_recipient_or_info() {
+        let cipher = Cipher::aes_256_gcm();
+        let (private, public) = hpke_keypair().unwrap();
+        let ciphertext = seal(cipher, &public, b"info", b"hello");
+        let (other, _) = hpke_keypair().unwrap();
+        assert!(is_bad_tag(open(cipher, &other, b"info", &ciphertext)));
+        assert!(is_bad_tag(open(cipher, &private, b"other", &ciphertext)));
+    }
+
+    #[test]
+    fn tampered() {
+        let cipher = Cipher::chacha20_poly1305();
+        let (private, public) = hpke_keypair().unwrap();
+        let ciphertext = seal(cipher, &public, b"", b"hello");
+        for &at in &[0, 32, ciphertext.len() - 1] {
+            let mut tampered = ciphertext.clone();
+            tampered[at] ^= 1;
+            assert!(
+                open(cipher, &private, b"", &tampered).is_err(),
+                "byte {}",
+                at
+            );
+        }
+        assert!(open(cipher, &private, b"", &ciphertext[..20]).is_err());
+    }
+
+    #[test]
+    fn unsupported_cipher() {
+        let (_, public) = hpke_keypair().unwrap();
+        let result =
+            EncryptWriter::hpke_seal(Vec::<u8>::new(), Cipher::aes_256_ctr(), &public, b"");
+        assert!(result.is_err());
+    }
+}
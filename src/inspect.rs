@@ -0,0 +1,212 @@
+use std::io::Result as IoResult;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+// Pass-through adapters that show every byte going by to a callback, e.g. for audit logging or
+// content scanning. Wrapped around an `EncryptWriter` or `DecryptReader` they see plaintext;
+// wrapped around the writer or reader underneath one they see ciphertext. Returning an error from
+// the callback fails the write or read, which lets a scanner stop the pipeline. To copy the data
+// to a secondary `std::io::Write`, pass `|b| log.write_all(b)`.
+
+// calls `inspect` with the bytes the inner writer has accepted, so each byte is seen exactly once
+// even across partial writes; a failing callback reports an error after those bytes were written
+pub struct InspectWriter<W, F> {
+    writer: W,
+    inspect: F,
+}
+impl<W, F> InspectWriter<W, F>
+where
+    F: FnMut(&[u8]) -> IoResult<()>,
+{
+    pub fn new(writer: W, inspect: F) -> Self {
+        InspectWriter { writer, inspect }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W, F> AsyncWrite for InspectWriter<W, F>
+where
+    W: AsyncWrite,
+    F: FnMut(&[u8]) -> IoResult<()>,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            match Pin::new_unchecked(&mut inner.writer).poll_write(cx, buf) {
+                Poll::Ready(Ok(n)) => match (inner.inspect)(&buf[..n]) {
+                    Ok(()) => Poll::Ready(Ok(n)),
+                    Err(e) => Poll::Ready(Err(e)),
+                },
+                a => a,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe { Pin::new_unchecked(&mut self.get_unchecked_mut().writer).poll_flush(cx) }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe { Pin::new_unchecked(&mut self.get_unchecked_mut().writer).poll_shutdown(cx) }
+    }
+}
+
+// calls `inspect` with each chunk read, before it is returned to the caller
+pub struct InspectReader<R, F> {
+    reader: R,
+    inspect: F,
+}
+impl<R, F> InspectReader<R, F>
+where
+    F: FnMut(&[u8]) -> IoResult<()>,
+{
+    pub fn new(reader: R, inspect: F) -> Self {
+        InspectReader { reader, inspect }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R, F> AsyncRead for InspectReader<R, F>
+where
+    R: AsyncRead,
+    F: FnMut(&[u8]) -> IoResult<()>,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            match Pin::new_unchecked(&mut inner.reader).poll_read(cx, buf) {
+                Poll::Ready(Ok(n)) => match (inner.inspect)(&buf[..n]) {
+                    Ok(()) => Poll::Ready(Ok(n)),
+                    Err(e) => Poll::Ready(Err(e)),
+                },
+                a => a,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use openssl::symm::Cipher;
+    use tokio::io::AsyncWrite;
+
+    use super::{InspectReader, InspectWriter};
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::{DecryptReader, EncryptWriter};
+
+    // accepts at most 7 bytes per write
+    struct Trickle(Vec<u8>);
+    impl AsyncWrite for Trickle {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<IoResult<usize>> {
+            let n = buf.len().min(7);
+            self.0.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn sees_plaintext_and_ciphertext() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(1000);
+        let (mut plain_seen, mut cipher_seen) = (Vec::new(), Vec::new());
+        let ciphertext = block_on(async {
+            let inner = InspectWriter::new(Trickle(Vec::new()), |b: &[u8]| {
+                cipher_seen.extend_from_slice(b);
+                Ok(())
+            });
+            let writer = EncryptWriter::new(inner, cipher, &key, iv.as_deref()).unwrap();
+            let mut writer = InspectWriter::new(writer, |b: &[u8]| {
+                plain_seen.extend_from_slice(b);
+                Ok(())
+            });
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+            // EncryptWriter has no into_inner, so compare with a fresh encryption
+            let mut expected = Vec::new();
+            let mut writer =
+                EncryptWriter::new(&mut expected, cipher, &key, iv.as_deref()).unwrap();
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+            expected
+        });
+        assert_eq!(plain_seen, plaintext);
+        assert_eq!(cipher_seen, ciphertext);
+
+        let mut seen = Vec::new();
+        let decrypted = block_on(async {
+            let reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref()).unwrap();
+            let mut reader = InspectReader::new(reader, |b: &[u8]| {
+                seen.extend_from_slice(b);
+                Ok(())
+            });
+            read_to_end(&mut reader).await
+        });
+        assert_eq!(decrypted.unwrap(), plaintext);
+        assert_eq!(seen, plaintext);
+    }
+
+    #[test]
+    fn callback_errors_stop_the_pipeline() {
+        let scan = |b: &[u8]| {
+            if b.contains(&0xff) {
+                return Err(IoError::new(IoErrorKind::InvalidData, "rejected"));
+            }
+            Ok(())
+        };
+        let mut writer = InspectWriter::new(Vec::new(), scan);
+        block_on(write_all(&mut writer, b"fine")).unwrap();
+        let e = block_on(write_all(&mut writer, &[1, 0xff])).unwrap_err();
+        assert_eq!(e.kind(), IoErrorKind::InvalidData);
+        // the bytes were written before the callback saw them
+        assert_eq!(writer.get_ref(), b"fine\x01\xff");
+
+        let mut reader = InspectReader::new(&[1, 2, 0xff][..], scan);
+        let e = block_on(read_to_end(&mut reader)).unwrap_err();
+        assert_eq!(e.kind(), IoErrorKind::InvalidData);
+    }
+}
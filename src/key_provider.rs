@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::future::Future;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+use openssl::symm::Cipher;
+use zeroize::Zeroizing;
+
+use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+// names a key held by a `KeyProvider`
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct KeyId(Vec<u8>);
+impl KeyId {
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+}
+impl From<&str> for KeyId {
+    fn from(id: &str) -> Self {
+        KeyId(id.as_bytes().to_vec())
+    }
+}
+impl From<&[u8]> for KeyId {
+    fn from(id: &[u8]) -> Self {
+        KeyId(id.to_vec())
+    }
+}
+impl From<Vec<u8>> for KeyId {
+    fn from(id: Vec<u8>) -> Self {
+        KeyId(id)
+    }
+}
+impl fmt::Debug for KeyId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match std::str::from_utf8(&self.0) {
+            Ok(id) => write!(f, "KeyId({:?})", id),
+            Err(_) => write!(f, "KeyId({:02x?})", self.0),
+        }
+    }
+}
+
+// Resolves key IDs to key bytes, asynchronously so that a KMS, Vault or database lookup doesn't
+// block the reactor. Implementations can be written with `async fn key_for`.
+pub trait KeyProvider {
+    fn key_for(
+        &self,
+        key_id: &KeyId,
+    ) -> impl Future<Output = Result<Zeroizing<Vec<u8>>, CryptoIoError>> + Send;
+}
+
+// a fixed set of keys, e.g. for tests or keys loaded at startup
+impl KeyProvider for HashMap<KeyId, Vec<u8>> {
+    async fn key_for(&self, key_id: &KeyId) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+        match self.get(key_id) {
+            Some(key) => Ok(Zeroizing::new(key.clone())),
+            None => {
+                Err(IoError::new(IoErrorKind::NotFound, format!("no key for {:?}", key_id)).into())
+            }
+        }
+    }
+}
+
+// The stream format doesn't record which key it was written under, so the caller says which one
+// to fetch.
+impl<W> EncryptWriter<W> {
+    pub async fn from_provider<P>(
+        writer: W,
+        cipher: Cipher,
+        provider: &P,
+        key_id: &KeyId,
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError>
+    where
+        P: KeyProvider,
+    {
+        Self::new(writer, cipher, &provider.key_for(key_id).await?, iv)
+    }
+}
+
+impl<R> DecryptReader<R> {
+    pub async fn from_provider<P>(
+        reader: R,
+        cipher: Cipher,
+        provider: &P,
+        key_id: &KeyId,
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError>
+    where
+        P: KeyProvider,
+    {
+        Self::new(reader, cipher, &provider.key_for(key_id).await?, iv)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::io::ErrorKind as IoErrorKind;
+
+    use openssl::symm::Cipher;
+
+    use super::{KeyId, KeyProvider};
+    use crate::testing::{block_on, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+    fn provider() -> HashMap<KeyId, Vec<u8>> {
+        let mut keys = HashMap::new();
+        keys.insert(KeyId::from("current"), vec![1; 32]);
+        keys.insert(KeyId::from("previous"), vec![2; 32]);
+        keys
+    }
+
+    #[test]
+    fn resolves_keys() {
+        let keys = provider();
+        let key = block_on(keys.key_for(&"previous".into())).unwrap();
+        assert_eq!(&key[..], &[2; 32]);
+        let res = block_on(keys.key_for(&"missing".into()));
+        assert!(matches!(res, Err(CryptoIoError::Io(e)) if e.kind() == IoErrorKind::NotFound));
+    }
+
+    #[test]
+    fn adapters_from_provider() {
+        let cipher = Cipher::aes_256_gcm();
+        let (keys, id, iv) = (provider(), KeyId::from("current"), [3; 12]);
+        let plaintext = sample(1000);
+        let mut ciphertext = Vec::new();
+        block_on(async {
+            let mut writer =
+                EncryptWriter::from_provider(&mut ciphertext, cipher, &keys, &id, Some(&iv))
+                    .await
+                    .unwrap();
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        let decrypted = block_on(async {
+            let mut reader =
+                DecryptReader::from_provider(&ciphertext[..], cipher, &keys, &id, Some(&iv))
+                    .await
+                    .unwrap();
+            read_to_end(&mut reader).await
+        });
+        assert_eq!(decrypted.unwrap(), plaintext);
+        let mut reader = DecryptReader::new(&ciphertext[..], cipher, &[1; 32], Some(&iv)).unwrap();
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn key_id_debug() {
+        assert_eq!(
+            format!("{:?}", KeyId::from("current")),
+            "KeyId(\"current\")"
+        );
+        assert_eq!(
+            format!("{:?}", KeyId::from(vec![0xff, 1])),
+            "KeyId([ff, 01])"
+        );
+    }
+}
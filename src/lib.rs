@@ -1,22 +1,218 @@
-use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::fmt;
+use std::future::poll_fn;
+use std::io::{Error as IoError, Result as IoResult};
 use std::pin::Pin;
 use std::task::Context;
 use std::task::Poll;
+#[cfg(feature = "timeout")]
+use std::time::Duration;
 
 use openssl::{
-    error::ErrorStack,
-    symm::{Cipher, Crypter, Mode},
+    hash::MessageDigest,
+    memcmp,
+    symm::{Cipher, Mode},
 };
 use tokio::io::AsyncRead;
 use tokio::io::AsyncWrite;
 
-pub struct EncryptWriter<W> {
-    cipher: Cipher,
+#[macro_use]
+mod telemetry;
+
+#[cfg(all(unix, feature = "secure-memory"))]
+mod secure;
+#[cfg(all(unix, feature = "secure-memory"))]
+pub use secure::LockedKey;
+
+#[cfg(feature = "secrecy")]
+mod secret;
+
+#[cfg(feature = "keyring")]
+mod os_keyring;
+#[cfg(feature = "keyring")]
+pub use os_keyring::keyring_key;
+
+#[cfg(feature = "pkcs11")]
+mod pkcs11;
+#[cfg(feature = "pkcs11")]
+pub use pkcs11::Pkcs11Provider;
+
+#[cfg(all(unix, feature = "tpm2"))]
+mod tpm2;
+#[cfg(all(unix, feature = "tpm2"))]
+pub use tpm2::Tpm2Unsealer;
+
+#[cfg(feature = "vault")]
+mod vault;
+#[cfg(feature = "vault")]
+pub use vault::VaultTransit;
+
+#[cfg(any(feature = "flate2", feature = "zstd"))]
+mod compress;
+#[cfg(any(feature = "flate2", feature = "zstd"))]
+pub use compress::{CompressWriter, Compression, DecompressReader};
+
+#[cfg(feature = "rustcrypto")]
+mod rustcrypto;
+#[cfg(feature = "rustcrypto")]
+pub use rustcrypto::{RustCryptoBackend, RustCryptoCipher};
+
+#[cfg(feature = "aes-gcm-siv")]
+mod gcm_siv;
+#[cfg(feature = "aes-gcm-siv")]
+pub use gcm_siv::AesGcmSivBackend;
+
+#[cfg(feature = "aws-lc-rs")]
+mod aws_lc;
+#[cfg(feature = "aws-lc-rs")]
+pub use aws_lc::{AwsLcBackend, AwsLcCipher};
+
+#[cfg(all(target_os = "linux", feature = "af-alg"))]
+mod af_alg;
+#[cfg(all(target_os = "linux", feature = "af-alg"))]
+pub use af_alg::{AfAlgBackend, AfAlgCipher};
+
+#[cfg(feature = "duplex")]
+mod duplex;
+#[cfg(feature = "duplex")]
+pub use duplex::{encrypted_duplex, tunnel, EncryptedStream, TunnelStats};
+
+#[cfg(feature = "file")]
+mod file;
+#[cfg(feature = "file")]
+pub use file::{EncryptedFile, WritePolicy};
+
+#[cfg(feature = "finalize-on-drop")]
+mod finalize;
+#[cfg(feature = "finalize-on-drop")]
+pub use finalize::FinalizeOnDrop;
+
+// pre-shared key handshakes that set up an `EncryptedStream` over any socket
+#[cfg(feature = "handshake")]
+pub mod handshake;
+
+#[cfg(feature = "manifest")]
+mod manifest;
+#[cfg(feature = "manifest")]
+pub use manifest::{encrypt_with_manifest, verify_manifest, Manifest};
+
+#[cfg(feature = "mmap")]
+mod mmap;
+#[cfg(feature = "mmap")]
+pub use mmap::{decrypt_mmap, encrypt_mmap, process_mmap};
+
+#[cfg(feature = "shaping")]
+mod shaping;
+#[cfg(feature = "shaping")]
+pub use shaping::{shape_traffic, UnshapeReader};
+
+#[cfg(feature = "tungstenite")]
+mod websocket;
+#[cfg(feature = "tungstenite")]
+pub use websocket::EncryptedWebSocket;
+
+// deterministic, insecure helpers for testing code built on this crate
+#[cfg(feature = "test-util")]
+mod test_util;
+#[cfg(feature = "test-util")]
+pub use test_util::{SeededIvs, XorBackend};
+
+// no encryption at all, for measuring and debugging pipelines
+#[cfg(feature = "null-cipher")]
+mod null;
+#[cfg(feature = "null-cipher")]
+pub use null::NullBackend;
+
+// requires linking OpenSSL 3.0 or newer
+#[cfg(feature = "openssl3")]
+mod provider;
+#[cfg(feature = "openssl3")]
+pub use provider::ProviderBackend;
+#[cfg(feature = "openssl3")]
+mod siv;
+#[cfg(feature = "openssl3")]
+pub use siv::AesSiv;
+
+mod algorithm;
+mod backend;
+mod bench;
+mod cipher;
+mod convergent;
+mod core;
+mod dedup;
+mod digest;
+mod error;
+mod factory;
+mod hpke;
+mod inspect;
+mod key_provider;
+mod mac;
+mod message;
+mod metadata;
+mod multipart;
+mod negotiate;
+mod nonce_guard;
+mod padding;
+mod parts;
+#[cfg(feature = "duplex")]
+mod record;
+mod reencrypt;
+mod rng;
+mod self_test;
+mod tls;
+mod verify;
+
+// helpers for the unit tests; not every feature set uses all of them
+#[cfg(test)]
+#[allow(dead_code)]
+mod testing;
+
+pub use algorithm::Algorithm;
+pub use backend::{OpensslBackend, SymmetricBackend};
+pub use bench::{fastest_aead, measure_throughput, Throughput};
+use cipher::tag_len_range;
+pub use cipher::{best_aead, has_aes_acceleration};
+pub use convergent::{ConvergentBlob, ConvergentCipher};
+pub use core::{DecryptCore, EncryptCore, Footer, TagPlacement};
+pub use dedup::EqualityRevealingCipher;
+pub use error::CryptoIoError;
+use error::Poison;
+pub use factory::{derive_stream_keys, CipherFactory, Role, StreamKeys};
+pub use hpke::hpke_keypair;
+pub use inspect::{InspectReader, InspectWriter};
+pub use key_provider::{KeyId, KeyProvider};
+use mac::{key_commitment, KEY_COMMITMENT_LEN};
+
+pub use mac::{MacReader, MacWriter};
+pub use message::MessageCipher;
+pub use metadata::Metadata;
+pub use multipart::{MultipartCipher, PartDecryptor};
+pub use negotiate::{body_scheme, negotiate, MaybeDecrypted, MaybeEncrypted, Negotiated, IDENTITY};
+pub use nonce_guard::{check_nonce, disable_nonce_guard, enable_nonce_guard};
+pub use padding::{PadWriter, Padding, UnpadReader};
+pub use parts::{encrypt_to_parts, PartSink, PartWriter};
+pub use reencrypt::reencrypt;
+pub use rng::{reset_random_source, set_random_source, RandomSource};
+pub use self_test::{self_test, self_test_with, SelfTestOutcome, SelfTestReport, SelfTestResult};
+pub use tls::tls_stream_keys;
+pub use verify::VerifyReader;
+
+// how far an `EncryptWriter` has got with shutting down; once it has started, writes fail with
+// `Closed` every time, without poisoning the writer, so the shutdown itself can still be retried
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum WriteState {
+    Open,
+    ShuttingDown,
+    Closed,
+}
+
+pub struct EncryptWriter<W, B = OpensslBackend> {
     writer: W,
-    crypter: Crypter,
-    written: usize,
-    buf: Vec<u8>,
-    is_finalized: bool,
+    core: EncryptCore<B>,
+    limit: Option<u64>,
+    state: WriteState,
+    poison: Poison,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 impl<W> EncryptWriter<W> {
     pub fn new(
@@ -24,102 +220,432 @@ impl<W> EncryptWriter<W> {
         cipher: Cipher,
         key: &[u8],
         iv: Option<&[u8]>,
-    ) -> Result<Self, ErrorStack> {
-        Ok(EncryptWriter {
-            cipher,
+    ) -> Result<Self, CryptoIoError> {
+        let backend = OpensslBackend::new(cipher, Mode::Encrypt, key, iv)?;
+        check_nonce(key, iv)?;
+        Ok(Self::with_backend(writer, backend))
+    }
+
+    // A writer for this stream again from the start, under the key and IV kept by this one, e.g.
+    // to resume an interrupted upload: the same plaintext gives the same ciphertext, so what was
+    // already sent can be skipped. Nothing else may be encrypted with it. Options set with the
+    // `with_*` methods are not carried over.
+    pub fn restart<V>(&self, writer: V) -> Result<EncryptWriter<V>, CryptoIoError> {
+        Ok(EncryptWriter::with_backend(
             writer,
-            crypter: Crypter::new(cipher, Mode::Encrypt, key, iv)?,
-            written: 0,
-            buf: Vec::new(),
-            is_finalized: false,
-        })
+            self.core.backend().restarted()?,
+        ))
+    }
+
+    // a writer for a new stream under the same key and a fresh `iv`, for which the same goes
+    pub fn next_stream<V>(&self, writer: V, iv: &[u8]) -> Result<EncryptWriter<V>, CryptoIoError> {
+        let backend = self.core.backend().next_stream(Some(iv))?;
+        Ok(EncryptWriter::with_backend(writer, backend))
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.core.backend().cipher()
+    }
+
+    pub fn key_len(&self) -> usize {
+        self.cipher().key_len()
+    }
+
+    pub fn iv_len(&self) -> Option<usize> {
+        self.cipher().iv_len()
+    }
+}
+
+impl<W, B> EncryptWriter<W, B>
+where
+    B: SymmetricBackend,
+{
+    // encrypts through `backend`, which must already be keyed for encryption
+    pub fn with_backend(writer: W, backend: B) -> Self {
+        Self::with_core(writer, EncryptCore::with_backend(backend))
+    }
+
+    // writes the ciphertext produced by `core` to `writer`
+    pub fn with_core(writer: W, core: EncryptCore<B>) -> Self {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("encrypt_writer", cipher = core.name());
+        enter_span!(span);
+        event!(tracing::Level::DEBUG, "constructed");
+        EncryptWriter {
+            writer,
+            core,
+            limit: None,
+            state: WriteState::Open,
+            poison: Poison::default(),
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+
+    // the exact length of the stream written for `plaintext_len` bytes of plaintext, including
+    // any padding, key commitment and MAC, e.g. for a Content-Length header
+    pub fn ciphertext_len_for(&self, plaintext_len: u64) -> u64 {
+        self.core.ciphertext_len_for(plaintext_len)
+    }
+
+    // the most plaintext a stream of `ciphertext_len` bytes can hold
+    pub fn max_plaintext_len_for(&self, ciphertext_len: u64) -> u64 {
+        self.core.max_plaintext_len_for(ciphertext_len)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.core.backend().block_size()
+    }
+
+    // whether the backend authenticates the stream with a tag of its own
+    pub fn is_aead(&self) -> bool {
+        self.core.backend().tag_len() > 0
+    }
+}
+
+impl<W, B> EncryptWriter<W, B> {
+    // true once `shutdown` has finalized the cipher (the final block may still be unflushed)
+    pub fn is_finalized(&self) -> bool {
+        self.core.is_finalized()
+    }
+
+    // after any error the writer is poisoned and every later call fails with `BrokenPipe`, except
+    // `Interrupted`, `WouldBlock` and `TimedOut` from the inner writer, which leave everything
+    // buffered to be retried on the next call; this returns the error that caused it
+    pub fn take_error(&mut self) -> Option<IoError> {
+        self.poison.take_error()
+    }
+
+    // panic if dropped before `shutdown` finalized the stream, instead of only logging it
+    pub fn panic_on_unfinalized(mut self, panic: bool) -> Self {
+        self.core = self.core.panic_on_unfinalized(panic);
+        self
+    }
+
+    // Shutting down without having written anything still writes a complete stream (header,
+    // padding, tag, MAC, as the cipher and options call for) that decrypts to nothing. With
+    // `reject` set, shutdown fails with `Empty` instead.
+    pub fn reject_empty(mut self, reject: bool) -> Self {
+        self.core = self.core.reject_empty(reject);
+        self
+    }
+
+    // appends an HMAC-SHA256 of the ciphertext on shutdown; must be set before any data is written
+    pub fn with_hmac(mut self, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_hmac(key)?;
+        Ok(self)
+    }
+
+    // like `with_hmac`, but authenticates with CMAC under `mac_cipher` (e.g. AES-256-CBC)
+    pub fn with_cmac(mut self, mac_cipher: Cipher, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_cmac(mac_cipher, key)?;
+        Ok(self)
+    }
+
+    // hashes the plaintext as it is written; the result is available from `digest` after shutdown
+    pub fn with_digest(mut self, md: MessageDigest) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_digest(md)?;
+        Ok(self)
+    }
+
+    pub fn digest(&self) -> Option<&[u8]> {
+        self.core.digest()
+    }
+
+    // ends the plaintext with a `Footer` recording its length and SHA-256, checked by
+    // `DecryptReader::with_footer`; must be set before any data is written
+    pub fn with_footer(mut self) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_footer()?;
+        Ok(self)
+    }
+
+    // prefixes the stream with a commitment to `key` (which must be the key passed to `new`), so
+    // a reader can reject the wrong key before decrypting anything; must be set before any data
+    // is written. The commitment is the same for every stream under `key`, so it shows which
+    // streams share a key.
+    pub fn with_key_commitment(mut self, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_key_commitment(key)?;
+        Ok(self)
+    }
+
+    // where the AEAD tag goes; with `TagPlacement::First` nothing reaches the inner writer until
+    // shutdown. Must be set before any data is written.
+    pub fn with_tag_placement(mut self, placement: TagPlacement) -> Self {
+        self.core = self.core.with_tag_placement(placement);
+        self
+    }
+
+    // writes `metadata` after the header, bound into the AEAD tag or MAC, for the reader to get
+    // from `DecryptReader::metadata`; must be set after any MAC and before any data is written
+    pub fn with_metadata(mut self, metadata: &Metadata) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_metadata(metadata)?;
+        Ok(self)
+    }
+
+    // leaves out the tag length byte, for formats without a header
+    pub fn without_header(mut self) -> Self {
+        self.core = self.core.without_header();
+        self
+    }
+
+    // accepts at most `limit` bytes of plaintext, like `take` on a reader. The write that reaches
+    // the limit is cut short and finalizes the cipher, so the final block and tag are queued
+    // (written out by the next flush or shutdown); later writes return 0.
+    pub fn with_limit(mut self, limit: u64) -> Self {
+        self.limit = Some(limit);
+        self
+    }
+
+    // compresses everything written before encrypting it, recording the algorithm in the stream
+    #[cfg(any(feature = "flate2", feature = "zstd"))]
+    pub fn compressed(
+        self,
+        compression: Compression,
+    ) -> Result<CompressWriter<Self>, CryptoIoError> {
+        CompressWriter::new(self, compression)
+    }
+
+    // pads the plaintext on shutdown so the ciphertext length only reveals its size class
+    pub fn padded(self, padding: Padding) -> PadWriter<Self> {
+        PadWriter::new(self, padding)
+    }
+
+    // finalizes the stream in a task on `handle` if it is dropped without being shut down
+    #[cfg(feature = "finalize-on-drop")]
+    pub fn finalize_on_drop(self, handle: tokio::runtime::Handle) -> FinalizeOnDrop<W, B>
+    where
+        W: AsyncWrite + Send + 'static,
+        B: SymmetricBackend + Send + 'static,
+    {
+        FinalizeOnDrop::new(self, handle)
+    }
+
+    // the AEAD tag, once shutdown has finalized the cipher
+    pub fn tag(&self) -> Option<&[u8]> {
+        self.core.tag()
+    }
+}
+
+impl<W, B> fmt::Debug for EncryptWriter<W, B>
+where
+    B: SymmetricBackend,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptWriter")
+            .field("cipher", &self.core.name())
+            .field("crypter", &"<redacted>")
+            .field("plaintext_bytes", &self.core.plaintext_bytes())
+            .field("ciphertext_bytes", &self.core.ciphertext_bytes())
+            .field("buffered", &self.core.ciphertext().len())
+            .field("is_finalized", &self.core.is_finalized())
+            .field("state", &self.state)
+            .field("poisoned", &self.poison.is_poisoned())
+            .field("authenticated", &self.core.is_authenticated())
+            .finish_non_exhaustive()
     }
 }
 
-impl<W> EncryptWriter<W>
+impl<W, B> EncryptWriter<W, B>
 where
     W: AsyncWrite,
+    B: SymmetricBackend,
 {
     // self must be pinned
     unsafe fn poll_write_buf(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
-        while self.written < self.buf.len() {
-            match Pin::new_unchecked(&mut self.writer).poll_write(cx, &self.buf[self.written..]) {
-                Poll::Ready(Ok(n)) => {
-                    self.written += n;
+        while !self.core.ciphertext().is_empty() {
+            match Pin::new_unchecked(&mut self.writer).poll_write(cx, self.core.ciphertext()) {
+                Poll::Ready(Ok(n)) => self.core.consume(n),
+                Poll::Ready(Err(e)) => {
+                    event!(tracing::Level::ERROR, error = %e, "inner write failed");
+                    return Poll::Ready(Err(e));
                 }
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
                 Poll::Pending => return Poll::Pending,
             }
         }
-        self.written = 0;
-        self.buf.clear();
         Poll::Ready(Ok(()))
     }
+
+    // self must be pinned
+    unsafe fn write_impl(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        // a zero-byte write is a no-op: it neither flushes nor waits on the inner writer
+        if buf.is_empty() && !self.core.is_finalized() {
+            return Poll::Ready(Ok(0));
+        }
+        match self.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        let buf = match self.limit {
+            Some(limit) => {
+                let remaining = limit.saturating_sub(self.core.plaintext_bytes());
+                if remaining == 0 {
+                    return Poll::Ready(Ok(0));
+                }
+                &buf[..(buf.len() as u64).min(remaining) as usize]
+            }
+            None => buf,
+        };
+        if let Err(e) = self.core.push_plaintext(buf) {
+            return Poll::Ready(Err(e.into()));
+        }
+        if self.limit == Some(self.core.plaintext_bytes()) {
+            if let Err(e) = self.core.finish() {
+                return Poll::Ready(Err(e.into()));
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    // self must be pinned
+    unsafe fn flush_impl(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        match self.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        Pin::new_unchecked(&mut self.writer).poll_flush(cx)
+    }
+
+    // self must be pinned
+    unsafe fn shutdown_impl(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        if self.state == WriteState::Closed {
+            return Poll::Ready(Ok(()));
+        }
+        self.state = WriteState::ShuttingDown;
+        if let Err(e) = self.core.finish() {
+            return Poll::Ready(Err(e.into()));
+        }
+        match self.poll_write_buf(cx) {
+            Poll::Ready(Ok(())) => (),
+            Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+            Poll::Pending => return Poll::Pending,
+        }
+        match Pin::new_unchecked(&mut self.writer).poll_shutdown(cx) {
+            Poll::Ready(Ok(())) => {
+                self.state = WriteState::Closed;
+                Poll::Ready(Ok(()))
+            }
+            a => a,
+        }
+    }
 }
 
-impl<W> AsyncWrite for EncryptWriter<W>
+impl<W, B> AsyncWrite for EncryptWriter<W, B>
 where
     W: AsyncWrite,
+    B: SymmetricBackend,
 {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
         unsafe {
             let inner = self.get_unchecked_mut();
-            match inner.poll_write_buf(cx) {
-                Poll::Ready(Ok(())) => (),
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                Poll::Pending => return Poll::Pending,
+            enter_span!(inner.span);
+            if let Err(e) = inner.poison.check() {
+                return Poll::Ready(Err(e));
             }
-            inner.buf.resize(buf.len() + inner.cipher.block_size(), 0);
-            let len = match inner.crypter.update(buf, &mut inner.buf) {
-                Ok(a) => a,
-                Err(e) => return Poll::Ready(Err(IoError::new(IoErrorKind::Other, e))),
-            };
-            inner.buf.truncate(len);
-            Poll::Ready(Ok(buf.len()))
+            if inner.state != WriteState::Open {
+                return Poll::Ready(Err(CryptoIoError::Closed.into()));
+            }
+            let res = inner.write_impl(cx, buf);
+            inner.poison.track(
+                res,
+                inner.core.plaintext_bytes(),
+                inner.core.ciphertext_bytes(),
+            )
         }
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
         unsafe {
             let inner = self.get_unchecked_mut();
-            match inner.poll_write_buf(cx) {
-                Poll::Ready(Ok(())) => (),
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                Poll::Pending => return Poll::Pending,
+            enter_span!(inner.span);
+            if let Err(e) = inner.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            if inner.state == WriteState::Closed {
+                return Poll::Ready(Ok(()));
             }
-            Pin::new_unchecked(&mut inner.writer).poll_flush(cx)
+            let res = inner.flush_impl(cx);
+            inner.poison.track(
+                res,
+                inner.core.plaintext_bytes(),
+                inner.core.ciphertext_bytes(),
+            )
         }
     }
 
     fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
         unsafe {
             let inner = self.get_unchecked_mut();
-            if !inner.is_finalized {
-                let init_len = inner.buf.len();
-                inner.buf.resize(init_len + inner.cipher.block_size(), 0);
-                let finalize_count = match inner.crypter.finalize(&mut inner.buf[init_len..]) {
-                    Ok(a) => a,
-                    Err(e) => return Poll::Ready(Err(IoError::new(IoErrorKind::Other, e))),
-                };
-                inner.buf.truncate(init_len + finalize_count);
-                inner.is_finalized = true;
-            }
-            match inner.poll_write_buf(cx) {
-                Poll::Ready(Ok(())) => (),
-                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                Poll::Pending => return Poll::Pending,
+            enter_span!(inner.span);
+            if let Err(e) = inner.poison.check() {
+                return Poll::Ready(Err(e));
             }
-            Pin::new_unchecked(&mut inner.writer).poll_shutdown(cx)
+            let res = inner.shutdown_impl(cx);
+            inner.poison.track(
+                res,
+                inner.core.plaintext_bytes(),
+                inner.core.ciphertext_bytes(),
+            )
         }
     }
 }
 
-pub struct DecryptReader<R> {
-    cipher: Cipher,
+#[cfg(feature = "timeout")]
+impl<W, B> EncryptWriter<W, B>
+where
+    W: AsyncWrite + Unpin,
+    B: SymmetricBackend + Unpin,
+{
+    // Finalizes, flushes and shuts down like `shutdown`, but gives up after `timeout`, e.g. for a
+    // server draining connections that cannot wait on slow peers forever. Returns None once the
+    // stream has been shut down. On the deadline it returns the ciphertext the inner writer has
+    // not accepted yet (possibly none, if only the inner shutdown was outstanding), which is then
+    // the caller's to send or drop, and leaves the inner writer as it is.
+    pub async fn shutdown_timeout(&mut self, timeout: Duration) -> IoResult<Option<Vec<u8>>> {
+        let shutdown = poll_fn(|cx| Pin::new(&mut *self).poll_shutdown(cx));
+        match tokio::time::timeout(timeout, shutdown).await {
+            Ok(res) => res.map(|()| None),
+            Err(_) => {
+                let rest = self.core.take_ciphertext();
+                event!(
+                    tracing::Level::WARN,
+                    unsent = rest.len(),
+                    "shutdown timed out"
+                );
+                Ok(Some(rest))
+            }
+        }
+    }
+}
+
+const TO_END_CHUNK_LEN: usize = 64 * 1024;
+// small, so little of the plaintext after the metadata is read ahead
+const METADATA_CHUNK_LEN: usize = 512;
+
+// fills `buf` from `reader`, for fixed-length data ahead of the ciphertext; fails with `Truncated`
+// if the stream ends first
+pub(crate) async fn read_prefix<R>(reader: &mut R, buf: &mut [u8]) -> Result<(), CryptoIoError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut read = 0;
+    while read < buf.len() {
+        let n = poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut buf[read..])).await?;
+        if n == 0 {
+            return Err(CryptoIoError::Truncated);
+        }
+        read += n;
+    }
+    Ok(())
+}
+
+pub struct DecryptReader<R, B = OpensslBackend> {
     reader: R,
-    crypter: Crypter,
-    read: usize,
-    buf: Vec<u8>,
+    core: DecryptCore<B>,
+    poison: Poison,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
 }
 impl<R> DecryptReader<R> {
     pub fn new(
@@ -127,20 +653,411 @@ impl<R> DecryptReader<R> {
         cipher: Cipher,
         key: &[u8],
         iv: Option<&[u8]>,
-    ) -> Result<Self, ErrorStack> {
-        Ok(DecryptReader {
-            cipher,
+    ) -> Result<Self, CryptoIoError> {
+        let backend = OpensslBackend::new(cipher, Mode::Decrypt, key, iv)?;
+        Ok(Self::with_backend(reader, backend))
+    }
+
+    // a reader for this stream again from the start, under the key and IV kept by this one, e.g.
+    // to retry from a fresh connection after a failure; options set with the `with_*` methods
+    // are not carried over
+    pub fn restart<S>(&self, reader: S) -> Result<DecryptReader<S>, CryptoIoError> {
+        Ok(DecryptReader::with_backend(
+            reader,
+            self.core.backend().restarted()?,
+        ))
+    }
+
+    pub fn cipher(&self) -> Cipher {
+        self.core.backend().cipher()
+    }
+
+    pub fn key_len(&self) -> usize {
+        self.cipher().key_len()
+    }
+
+    pub fn iv_len(&self) -> Option<usize> {
+        self.cipher().iv_len()
+    }
+}
+
+impl<R> DecryptReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    // For streams written with `with_key_commitment` under one of several keys, such as across a
+    // key rotation with no key ID to say which: reads the header and commitment and decrypts
+    // under the first of `keys` it commits to, failing with `KeyMismatch` if none match.
+    pub async fn with_candidate_keys(
+        mut reader: R,
+        cipher: Cipher,
+        keys: &[&[u8]],
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError> {
+        let tag_header = (tag_len_range(cipher).1 > 0) as usize;
+        let mut header = vec![0; tag_header + KEY_COMMITMENT_LEN];
+        read_prefix(&mut reader, &mut header).await?;
+        for key in keys {
+            if memcmp::eq(&key_commitment(key)?, &header[tag_header..]) {
+                let mut core = DecryptCore::new(cipher, key, iv)?.with_key_commitment(key)?;
+                core.push_ciphertext(&header)?;
+                return Ok(Self::with_core(reader, core));
+            }
+        }
+        event!(
+            tracing::Level::ERROR,
+            candidates = keys.len(),
+            "no candidate key matches the commitment"
+        );
+        Err(CryptoIoError::KeyMismatch)
+    }
+}
+
+impl<R, B> DecryptReader<R, B>
+where
+    B: SymmetricBackend,
+{
+    // decrypts through `backend`, which must already be keyed for decryption
+    pub fn with_backend(reader: R, backend: B) -> Self {
+        Self::with_core(reader, DecryptCore::with_backend(backend))
+    }
+
+    // feeds the ciphertext read from `reader` to `core`
+    pub fn with_core(reader: R, core: DecryptCore<B>) -> Self {
+        #[cfg(feature = "tracing")]
+        let span = tracing::debug_span!("decrypt_reader", cipher = core.name());
+        enter_span!(span);
+        event!(tracing::Level::DEBUG, "constructed");
+        DecryptReader {
             reader,
-            crypter: Crypter::new(cipher, Mode::Decrypt, key, iv)?,
-            read: 0,
-            buf: Vec::new(),
+            core,
+            poison: Poison::default(),
+            #[cfg(feature = "tracing")]
+            span,
+        }
+    }
+
+    // the exact length of a stream holding `plaintext_len` bytes of plaintext
+    pub fn ciphertext_len_for(&self, plaintext_len: u64) -> u64 {
+        self.core.ciphertext_len_for(plaintext_len)
+    }
+
+    // the most plaintext a stream of `ciphertext_len` bytes can decrypt to, e.g. to pre-allocate
+    // the output
+    pub fn max_plaintext_len_for(&self, ciphertext_len: u64) -> u64 {
+        self.core.max_plaintext_len_for(ciphertext_len)
+    }
+
+    pub fn block_size(&self) -> usize {
+        self.core.backend().block_size()
+    }
+
+    // whether the backend authenticates the stream with a tag of its own
+    pub fn is_aead(&self) -> bool {
+        self.core.backend().tag_len() > 0
+    }
+}
+
+impl<R, B> DecryptReader<R, B> {
+    // An empty inner reader is `Truncated` for every cipher whose streams are never empty; an
+    // authentic stream holding no plaintext reads as an immediate EOF. With `reject` set, that
+    // fails with `Empty` instead.
+    pub fn reject_empty(mut self, reject: bool) -> Self {
+        self.core = self.core.reject_empty(reject);
+        self
+    }
+
+    // expects an HMAC-SHA256 trailer as written by `EncryptWriter::with_hmac`, and verifies it
+    // before the final block is released
+    pub fn with_hmac(mut self, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_hmac(key)?;
+        Ok(self)
+    }
+
+    // expects a CMAC trailer as written by `EncryptWriter::with_cmac`
+    pub fn with_cmac(mut self, mac_cipher: Cipher, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_cmac(mac_cipher, key)?;
+        Ok(self)
+    }
+
+    // hashes the decrypted output; the result is available from `digest` once EOF is reached
+    pub fn with_digest(mut self, md: MessageDigest) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_digest(md)?;
+        Ok(self)
+    }
+
+    pub fn digest(&self) -> Option<&[u8]> {
+        self.core.digest()
+    }
+
+    // expects the `Footer` written by `EncryptWriter::with_footer`, failing at EOF if the
+    // plaintext's length or digest does not match it
+    pub fn with_footer(mut self) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_footer()?;
+        Ok(self)
+    }
+
+    // the verified footer, once EOF has been reached
+    pub fn trailer(&self) -> Option<&Footer> {
+        self.core.trailer()
+    }
+
+    // expects the stream to start with a commitment to `key`, as written by
+    // `EncryptWriter::with_key_commitment`, and fails before decrypting if it does not match
+    pub fn with_key_commitment(mut self, key: &[u8]) -> Result<Self, CryptoIoError> {
+        self.core = self.core.with_key_commitment(key)?;
+        Ok(self)
+    }
+
+    // where to find the AEAD tag, which must match the writer
+    pub fn with_tag_placement(mut self, placement: TagPlacement) -> Self {
+        self.core = self.core.with_tag_placement(placement);
+        self
+    }
+
+    // expects the metadata block written by `EncryptWriter::with_metadata`
+    pub fn with_metadata(mut self) -> Self {
+        self.core = self.core.with_metadata();
+        self
+    }
+
+    // the metadata, once the first read (or `read_metadata`) has got past it; it is only known to
+    // be authentic once the stream has been read to the end
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.core.metadata()
+    }
+
+    // checks the stream against `tag`, as returned by `EncryptWriter::tag` with
+    // `TagPlacement::Detached`
+    pub fn with_detached_tag(mut self, tag: &[u8]) -> Self {
+        self.core = self.core.with_detached_tag(tag);
+        self
+    }
+
+    // expects no tag length byte, as written by `EncryptWriter::without_header`
+    pub fn without_header(mut self) -> Self {
+        self.core = self.core.without_header();
+        self
+    }
+
+    // decompresses what was written through `EncryptWriter::compressed`
+    #[cfg(any(feature = "flate2", feature = "zstd"))]
+    pub fn decompressed(self) -> DecompressReader<Self> {
+        DecompressReader::new(self)
+    }
+
+    // strips the padding added through `EncryptWriter::padded`
+    pub fn unpadded(self) -> UnpadReader<Self> {
+        UnpadReader::new(self)
+    }
+
+    // checks the stream without handing out its plaintext
+    pub fn verify_only(self) -> VerifyReader<R, B> {
+        VerifyReader::from_reader(self)
+    }
+
+    // after any error the reader is poisoned and every later read fails with `BrokenPipe`, except
+    // `Interrupted`, `WouldBlock` and `TimedOut` from the inner reader, which can be retried; this
+    // returns the error that caused it
+    pub fn take_error(&mut self) -> Option<IoError> {
+        self.poison.take_error()
+    }
+
+    // in strict mode (the default for padded and AEAD ciphers), a stream that ends mid-block or
+    // before its header, commitment, tag or MAC is complete fails with `UnexpectedEof` rather
+    // than being handed to the crypter
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.core = self.core.strict(strict);
+        self
+    }
+
+    // collects ciphertext from the inner reader until at least `bytes` (by default one block) are
+    // available before calling the crypter, for inner readers that deliver tiny fragments
+    pub fn coalesce(mut self, bytes: usize) -> Self {
+        self.core = self.core.coalesce(bytes);
+        self
+    }
+}
+
+impl<R, B> fmt::Debug for DecryptReader<R, B>
+where
+    B: SymmetricBackend,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DecryptReader")
+            .field("cipher", &self.core.name())
+            .field("crypter", &"<redacted>")
+            .field("plaintext_bytes", &self.core.plaintext_bytes())
+            .field("ciphertext_bytes", &self.core.ciphertext_bytes())
+            .field("buffered", &self.core.plaintext().len())
+            .field("is_finalized", &self.core.is_finalized())
+            .field("poisoned", &self.poison.is_poisoned())
+            .field("authenticated", &self.core.is_authenticated())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R, B> DecryptReader<R, B>
+where
+    R: AsyncRead,
+    B: SymmetricBackend,
+{
+    // self must be pinned; reads once from the inner reader into `buf` and passes the result on
+    // to the core
+    unsafe fn poll_fill(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<IoResult<()>> {
+        let res = match Pin::new_unchecked(&mut self.reader).poll_read(cx, buf) {
+            Poll::Ready(Ok(0)) => self.core.finish(),
+            Poll::Ready(Ok(n)) => self.core.push_ciphertext(&buf[..n]),
+            Poll::Ready(Err(e)) => {
+                event!(tracing::Level::ERROR, error = %e, "inner read failed");
+                return Poll::Ready(Err(e));
+            }
+            Poll::Pending => return Poll::Pending,
+        };
+        Poll::Ready(res.map_err(IoError::from))
+    }
+
+    // self must be pinned
+    unsafe fn read_impl(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        // the crypter may hold back (or we may withhold) everything read so far, so keep
+        // reading until there is plaintext to hand out or the stream is finished
+        while self.core.plaintext().is_empty() {
+            if self.core.is_finalized() {
+                return Poll::Ready(Ok(0));
+            }
+            match self.poll_fill(cx, buf) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        let src_buf = self.core.plaintext();
+        let len = src_buf.len().min(buf.len());
+        buf[..len].clone_from_slice(&src_buf[..len]);
+        self.core.consume(len);
+
+        Poll::Ready(Ok(len))
+    }
+
+    // self must be pinned
+    unsafe fn read_to_end_impl(
+        &mut self,
+        cx: &mut Context<'_>,
+        chunk: &mut [u8],
+        out: &mut Vec<u8>,
+    ) -> Poll<IoResult<()>> {
+        loop {
+            let available = self.core.plaintext();
+            out.extend_from_slice(available);
+            self.core.consume(available.len());
+            if self.core.is_finalized() {
+                return Poll::Ready(Ok(()));
+            }
+            match self.poll_fill(cx, chunk) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+}
+
+impl<R, B> DecryptReader<R, B>
+where
+    R: AsyncRead + Unpin,
+    B: SymmetricBackend + Unpin,
+{
+    // reads until the metadata block has been parsed, leaving the plaintext after it to be read
+    // as usual; returns None for a stream set up without `with_metadata`
+    pub async fn read_metadata(&mut self) -> IoResult<Option<&Metadata>> {
+        let mut chunk = [0; METADATA_CHUNK_LEN];
+        poll_fn(|cx| unsafe {
+            enter_span!(self.span);
+            if let Err(e) = self.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            let res = loop {
+                if !self.core.expects_metadata() || self.core.is_finalized() {
+                    break Poll::Ready(Ok(()));
+                }
+                match self.poll_fill(cx, &mut chunk) {
+                    Poll::Ready(Ok(())) => (),
+                    res => break res,
+                }
+            };
+            self.poison.track(
+                res,
+                self.core.plaintext_bytes(),
+                self.core.ciphertext_bytes(),
+            )
+        })
+        .await?;
+        Ok(self.core.metadata())
+    }
+
+    // discards the next `n` bytes of plaintext, e.g. to resume from an offset, decrypting in
+    // large chunks without copying anything out; returns how many were skipped, fewer only at
+    // the end of the stream. Everything skipped is still authenticated at the end as usual.
+    pub async fn skip(&mut self, n: u64) -> IoResult<u64> {
+        let mut remaining = n;
+        let mut chunk = vec![0; TO_END_CHUNK_LEN];
+        poll_fn(|cx| unsafe {
+            enter_span!(self.span);
+            if let Err(e) = self.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            let res = loop {
+                let available = (self.core.plaintext().len() as u64).min(remaining);
+                self.core.consume(available as usize);
+                remaining -= available;
+                if remaining == 0 || self.core.is_finalized() {
+                    break Poll::Ready(Ok(()));
+                }
+                match self.poll_fill(cx, &mut chunk) {
+                    Poll::Ready(Ok(())) => (),
+                    res => break res,
+                }
+            };
+            self.poison.track(
+                res,
+                self.core.plaintext_bytes(),
+                self.core.ciphertext_bytes(),
+            )
+        })
+        .await?;
+        Ok(n - remaining)
+    }
+
+    // decrypts the rest of the stream straight into `out`, reading the ciphertext in large chunks
+    // rather than through `poll_read` calls sized by the caller's buffer; returns the number of
+    // bytes appended
+    pub async fn decrypt_to_end(&mut self, out: &mut Vec<u8>) -> IoResult<usize> {
+        let start = out.len();
+        let mut chunk = vec![0; TO_END_CHUNK_LEN];
+        poll_fn(|cx| unsafe {
+            enter_span!(self.span);
+            if let Err(e) = self.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            let res = self.read_to_end_impl(cx, &mut chunk, out);
+            self.poison.track(
+                res,
+                self.core.plaintext_bytes(),
+                self.core.ciphertext_bytes(),
+            )
         })
+        .await?;
+        Ok(out.len() - start)
     }
 }
 
-impl<R> AsyncRead for DecryptReader<R>
+impl<R, B> AsyncRead for DecryptReader<R, B>
 where
     R: AsyncRead,
+    B: SymmetricBackend,
 {
     fn poll_read(
         self: Pin<&mut Self>,
@@ -149,40 +1066,724 @@ where
     ) -> Poll<IoResult<usize>> {
         unsafe {
             let inner = self.get_unchecked_mut();
+            enter_span!(inner.span);
+            if let Err(e) = inner.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            let res = inner.read_impl(cx, buf);
+            inner.poison.track(
+                res,
+                inner.core.plaintext_bytes(),
+                inner.core.ciphertext_bytes(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::future::poll_fn;
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+    use std::pin::Pin;
+    use std::rc::Rc;
+    use std::task::{Context, Poll};
+
+    use openssl::symm::{Cipher, Mode};
+    use tokio::io::{AsyncRead, AsyncWrite};
+
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::{
+        CryptoIoError, DecryptCore, DecryptReader, EncryptCore, EncryptWriter, OpensslBackend,
+        SymmetricBackend,
+    };
+
+    fn seal(cipher: Cipher, plaintext: &[u8]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::new(&mut out, cipher, &key, iv.as_deref()).unwrap();
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn open(cipher: Cipher, ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        let (key, iv) = key_iv(cipher);
+        let mut reader = DecryptReader::new(ciphertext, cipher, &key, iv.as_deref()).unwrap();
+        block_on(read_to_end(&mut reader))
+    }
+
+    fn is_truncated(result: IoResult<Vec<u8>>) -> bool {
+        match result {
+            Err(e) => e.kind() == IoErrorKind::UnexpectedEof,
+            Ok(_) => false,
+        }
+    }
+
+    // a padded stream cut anywhere short of a whole block is reported as EOF
+    #[test]
+    fn truncated_is_eof() {
+        let cipher = Cipher::aes_128_cbc();
+        let ciphertext = seal(cipher, &sample(100));
+        for &cut in &[0, 1, 17, ciphertext.len() - 1] {
+            let result = open(cipher, &ciphertext[..cut]);
+            assert!(is_truncated(result), "cut to {}", cut);
+        }
+    }
+
+    // a stream cipher is only strict when asked, and then catches a cut MAC
+    #[test]
+    fn truncated_mac_is_eof() {
+        let cipher = Cipher::aes_128_ctr();
+        let (key, iv) = key_iv(cipher);
+        let mut ciphertext = Vec::new();
+        block_on(async {
+            let writer = EncryptWriter::new(&mut ciphertext, cipher, &key, iv.as_deref());
+            let mut writer = writer.unwrap().with_hmac(&[7; 32]).unwrap();
+            write_all(&mut writer, &sample(100)).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        for &strict in &[false, true] {
+            let reader = DecryptReader::new(&ciphertext[..20], cipher, &key, iv.as_deref());
+            let reader = reader.unwrap().with_hmac(&[7; 32]).unwrap();
+            let e = block_on(read_to_end(&mut reader.strict(strict))).unwrap_err();
+            let kind = if strict {
+                IoErrorKind::UnexpectedEof
+            } else {
+                IoErrorKind::InvalidData
+            };
+            assert_eq!(e.kind(), kind);
+        }
+    }
+
+    #[test]
+    fn tampered() {
+        let cipher = Cipher::aes_128_cbc();
+        let mut ciphertext = seal(cipher, &sample(100));
+        *ciphertext.last_mut().unwrap() ^= 1;
+        let result = open(cipher, &ciphertext);
+        assert!(result.is_err());
+        assert!(!is_truncated(result));
+    }
 
-            let mut available = inner.buf.len() - inner.read;
-            if available == 0 {
-                inner.read = 0;
-                inner.buf.clear();
-                available = match Pin::new_unchecked(&mut inner.reader).poll_read(cx, buf) {
-                    Poll::Ready(Ok(0)) => {
-                        inner.buf.resize(inner.cipher.block_size(), 0);
-                        match inner.crypter.finalize(&mut inner.buf) {
-                            Ok(a) => a,
-                            Err(e) => return Poll::Ready(Err(IoError::new(IoErrorKind::Other, e))),
-                        }
+    #[test]
+    fn lenient_truncation() {
+        let cipher = Cipher::aes_128_cbc();
+        let (key, iv) = key_iv(cipher);
+        let ciphertext = seal(cipher, b"hello");
+        let reader = DecryptReader::new(&ciphertext[..5], cipher, &key, iv.as_deref());
+        let result = block_on(read_to_end(&mut reader.unwrap().strict(false)));
+        assert!(result.is_err());
+        assert!(!is_truncated(result));
+    }
+
+    #[test]
+    fn bad_padding_is_invalid_data() {
+        let cipher = Cipher::aes_256_cbc();
+        let mut ciphertext = seal(cipher, &sample(100));
+        assert_eq!(ciphertext.len(), 112);
+        *ciphertext.last_mut().unwrap() ^= 1;
+        let e = open(cipher, &ciphertext).unwrap_err();
+        assert_eq!(e.kind(), IoErrorKind::InvalidData);
+        let root = CryptoIoError::from_io(&e).map(|e| e.root());
+        assert!(matches!(
+            root,
+            Some(CryptoIoError::Decrypt { offset: 112, .. })
+        ));
+    }
+
+    // fails every `fail_every`th call with `kind`, passing the rest through
+    struct Flaky<T> {
+        inner: T,
+        kind: IoErrorKind,
+        fail_every: usize,
+        calls: usize,
+    }
+    impl<T> Flaky<T> {
+        fn new(inner: T, kind: IoErrorKind, fail_every: usize) -> Self {
+            Flaky {
+                inner,
+                kind,
+                fail_every,
+                calls: 0,
+            }
+        }
+
+        fn fails(&mut self) -> bool {
+            self.calls += 1;
+            self.calls % self.fail_every == 0
+        }
+    }
+    impl<T: AsyncRead + Unpin> AsyncRead for Flaky<T> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<IoResult<usize>> {
+            if self.fails() {
+                return Poll::Ready(Err(IoError::new(self.kind, "flaky")));
+            }
+            // small reads, so the stream takes several calls
+            let len = buf.len().min(7);
+            Pin::new(&mut self.inner).poll_read(cx, &mut buf[..len])
+        }
+    }
+    impl<T: AsyncWrite + Unpin> AsyncWrite for Flaky<T> {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<IoResult<usize>> {
+            if self.fails() {
+                return Poll::Ready(Err(IoError::new(self.kind, "flaky")));
+            }
+            let len = buf.len().min(7);
+            Pin::new(&mut self.inner).poll_write(cx, &buf[..len])
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    fn root(e: &IoError) -> Option<&CryptoIoError> {
+        CryptoIoError::from_io(e).map(|e| e.root())
+    }
+
+    #[test]
+    fn poisoned_after_bad_padding() {
+        let cipher = Cipher::aes_256_cbc();
+        let mut ciphertext = seal(cipher, &sample(100));
+        *ciphertext.last_mut().unwrap() ^= 1;
+        let (key, iv) = key_iv(cipher);
+        let mut reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref()).unwrap();
+        let e = block_on(read_to_end(&mut reader)).unwrap_err();
+        assert!(matches!(root(&e), Some(CryptoIoError::Decrypt { .. })));
+        let e = block_on(read_to_end(&mut reader)).unwrap_err();
+        assert_eq!(e.kind(), IoErrorKind::BrokenPipe);
+        assert!(matches!(root(&e), Some(CryptoIoError::Poisoned)));
+        let e = reader.take_error().unwrap();
+        assert!(matches!(root(&e), Some(CryptoIoError::Decrypt { .. })));
+        assert!(reader.take_error().is_none());
+    }
+
+    #[test]
+    fn poisoned_after_inner_error() {
+        let cipher = Cipher::aes_256_cbc();
+        let (key, iv) = key_iv(cipher);
+        let mut out = Vec::new();
+        let inner = Flaky::new(&mut out, IoErrorKind::ConnectionReset, 3);
+        let mut writer = EncryptWriter::new(inner, cipher, &key, iv.as_deref()).unwrap();
+        block_on(async {
+            write_all(&mut writer, &sample(100)).await.unwrap();
+            let e = shutdown(&mut writer).await.unwrap_err();
+            assert_eq!(e.kind(), IoErrorKind::ConnectionReset);
+            let e = write_all(&mut writer, b"more").await.unwrap_err();
+            assert!(matches!(root(&e), Some(CryptoIoError::Poisoned)));
+        });
+        let e = writer.take_error().unwrap();
+        assert_eq!(e.kind(), IoErrorKind::ConnectionReset);
+        assert!(writer.take_error().is_none());
+    }
+
+    // `Interrupted` and the like leave the adapter as it was, so retrying the call carries on
+    #[test]
+    fn transient_errors_are_retried() {
+        let cipher = Cipher::aes_256_cbc();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(100);
+        let mut ciphertext = Vec::new();
+        let inner = Flaky::new(&mut ciphertext, IoErrorKind::Interrupted, 3);
+        let mut writer = EncryptWriter::new(inner, cipher, &key, iv.as_deref()).unwrap();
+        block_on(async {
+            let mut data = &plaintext[..];
+            while !data.is_empty() {
+                match write_all(&mut writer, data).await {
+                    Ok(()) => data = &[],
+                    Err(e) => {
+                        assert_eq!(e.kind(), IoErrorKind::Interrupted);
+                        let written = writer.core.plaintext_bytes() as usize;
+                        data = &plaintext[written..];
+                    }
+                }
+            }
+            while let Err(e) = shutdown(&mut writer).await {
+                assert_eq!(e.kind(), IoErrorKind::Interrupted);
+            }
+        });
+        assert!(writer.take_error().is_none());
+        drop(writer);
+        assert_eq!(ciphertext, seal(cipher, &plaintext));
+
+        let inner = Flaky::new(&ciphertext[..], IoErrorKind::WouldBlock, 2);
+        let mut reader = DecryptReader::new(inner, cipher, &key, iv.as_deref()).unwrap();
+        let mut decrypted = Vec::new();
+        block_on(async {
+            let mut buf = [0; 1000];
+            loop {
+                match poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buf)).await {
+                    Ok(0) => break,
+                    Ok(n) => decrypted.extend_from_slice(&buf[..n]),
+                    Err(e) => assert_eq!(e.kind(), IoErrorKind::WouldBlock),
+                }
+            }
+        });
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn error_offsets() {
+        let cipher = Cipher::aes_256_cbc();
+        let mut ciphertext = seal(cipher, &sample(100));
+        *ciphertext.last_mut().unwrap() ^= 1;
+        // the last block is held back until the padding is checked
+        let e = open(cipher, &ciphertext).unwrap_err();
+        let offsets = CryptoIoError::from_io(&e).and_then(|e| e.offsets());
+        assert_eq!(offsets, Some((96, 112)));
+
+        let (key, iv) = key_iv(cipher);
+        let mut out = Vec::new();
+        let inner = Flaky::new(&mut out, IoErrorKind::ConnectionReset, 3);
+        let mut writer = EncryptWriter::new(inner, cipher, &key, iv.as_deref()).unwrap();
+        let e = block_on(async {
+            write_all(&mut writer, &sample(100)).await.unwrap();
+            shutdown(&mut writer).await.unwrap_err()
+        });
+        // two 7-byte writes got through before the third failed
+        let offsets = CryptoIoError::from_io(&e).and_then(|e| e.offsets());
+        assert_eq!(offsets, Some((100, 14)));
+    }
+
+    #[test]
+    fn decrypt_to_end() {
+        let cipher = Cipher::aes_256_cbc();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(100_000);
+        let ciphertext = seal(cipher, &plaintext);
+        let mut reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref()).unwrap();
+        let mut out = b"kept".to_vec();
+        let n = block_on(reader.decrypt_to_end(&mut out)).unwrap();
+        assert_eq!(n, plaintext.len());
+        assert_eq!(&out[..4], b"kept");
+        assert_eq!(&out[4..], &plaintext[..]);
+
+        drop(reader);
+        let mut tampered = ciphertext;
+        *tampered.last_mut().unwrap() ^= 1;
+        let mut reader = DecryptReader::new(&tampered[..], cipher, &key, iv.as_deref()).unwrap();
+        let e = block_on(reader.decrypt_to_end(&mut Vec::new())).unwrap_err();
+        assert!(matches!(root(&e), Some(CryptoIoError::Decrypt { .. })));
+    }
+
+    #[test]
+    fn skip() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(100_000);
+        let ciphertext = seal(cipher, &plaintext);
+        let mut reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref()).unwrap();
+        let mut head = [0; 10];
+        let n = block_on(poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut head))).unwrap();
+        assert_eq!(head[..n], plaintext[..n]);
+        assert_eq!(
+            block_on(reader.skip(70_000 - n as u64)).unwrap(),
+            70_000 - n as u64
+        );
+        assert_eq!(block_on(reader.skip(0)).unwrap(), 0);
+        assert_eq!(
+            block_on(read_to_end(&mut reader)).unwrap(),
+            &plaintext[70_000..]
+        );
+
+        // past the end, skipping stops there
+        let mut reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref()).unwrap();
+        assert_eq!(block_on(reader.skip(1_000_000)).unwrap(), 100_000);
+        assert!(block_on(read_to_end(&mut reader)).unwrap().is_empty());
+
+        // what is skipped is still authenticated
+        let mut tampered = ciphertext;
+        tampered[10_000] ^= 1;
+        let mut reader = DecryptReader::new(&tampered[..], cipher, &key, iv.as_deref()).unwrap();
+        let e = block_on(reader.skip(1_000_000)).unwrap_err();
+        assert!(matches!(root(&e), Some(CryptoIoError::BadTag)));
+    }
+
+    // records the length of each input to `update`
+    struct Counting {
+        backend: OpensslBackend,
+        updates: Rc<RefCell<Vec<usize>>>,
+    }
+    impl SymmetricBackend for Counting {
+        fn update(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError> {
+            self.updates.borrow_mut().push(input.len());
+            self.backend.update(input, output)
+        }
+
+        fn finalize(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError> {
+            self.backend.finalize(output)
+        }
+
+        fn block_size(&self) -> usize {
+            self.backend.block_size()
+        }
+    }
+
+    #[test]
+    fn coalesces_fragments() {
+        let cipher = Cipher::aes_256_ctr();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(1000);
+        let ciphertext = seal(cipher, &plaintext);
+        let updates = Rc::new(RefCell::new(Vec::new()));
+        let backend = Counting {
+            backend: OpensslBackend::new(cipher, Mode::Decrypt, &key, iv.as_deref()).unwrap(),
+            updates: updates.clone(),
+        };
+        // 7-byte reads that never fail
+        let inner = Flaky::new(&ciphertext[..], IoErrorKind::Other, usize::MAX);
+        let mut reader = DecryptReader::with_backend(inner, backend).coalesce(64);
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+        // whole reads add up to at least 64 bytes, except for the rest at the end of the stream
+        let updates = updates.borrow();
+        let (last, rest) = updates.split_last().unwrap();
+        assert!(rest.iter().all(|len| *len >= 64), "{:?}", rest);
+        assert_eq!(rest.iter().sum::<usize>() + last, 1000);
+    }
+
+    // the adapters only move bytes between the core and the inner reader or writer
+    #[test]
+    fn adapters_drive_the_core() {
+        let cipher = Cipher::chacha20_poly1305();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(10_000);
+        let mut core = EncryptCore::new(cipher, &key, iv.as_deref())
+            .unwrap()
+            .with_key_commitment(&key)
+            .unwrap();
+        core.push_plaintext(&plaintext).unwrap();
+        core.finish().unwrap();
+        let expected = core.take_ciphertext();
+
+        let core = EncryptCore::new(cipher, &key, iv.as_deref())
+            .unwrap()
+            .with_key_commitment(&key)
+            .unwrap();
+        let mut ciphertext = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::with_core(&mut ciphertext, core);
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        assert_eq!(ciphertext, expected);
+
+        let core = DecryptCore::new(cipher, &key, iv.as_deref())
+            .unwrap()
+            .with_key_commitment(&key)
+            .unwrap();
+        let mut reader = DecryptReader::with_core(&ciphertext[..], core);
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn candidate_keys() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(1000);
+        let mut ciphertext = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::new(&mut ciphertext, cipher, &key, iv.as_deref())
+                .unwrap()
+                .with_key_commitment(&key)
+                .unwrap();
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        let others = [vec![1; 32], vec![2; 32]];
+        let keys: [&[u8]; 3] = [&others[0], &key, &others[1]];
+        let decrypted = block_on(async {
+            let mut reader =
+                DecryptReader::with_candidate_keys(&ciphertext[..], cipher, &keys, iv.as_deref())
+                    .await
+                    .unwrap();
+            read_to_end(&mut reader).await
+        });
+        assert_eq!(decrypted.unwrap(), plaintext);
+
+        let keys: [&[u8]; 2] = [&others[0], &others[1]];
+        let res = block_on(DecryptReader::with_candidate_keys(
+            &ciphertext[..],
+            cipher,
+            &keys,
+            iv.as_deref(),
+        ));
+        assert!(matches!(res, Err(CryptoIoError::KeyMismatch)));
+    }
+
+    // a single `poll_write`, which may accept less than all of `buf`
+    async fn write<W: AsyncWrite + Unpin>(writer: &mut W, buf: &[u8]) -> IoResult<usize> {
+        poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, buf)).await
+    }
+
+    #[test]
+    fn write_limit() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(100);
+        let mut ciphertext = Vec::new();
+        let mut writer = EncryptWriter::new(&mut ciphertext, cipher, &key, iv.as_deref())
+            .unwrap()
+            .with_limit(60);
+        block_on(async {
+            assert_eq!(write(&mut writer, &plaintext[..40]).await.unwrap(), 40);
+            assert!(!writer.is_finalized());
+            // cut short at the limit, which finalizes the stream
+            assert_eq!(write(&mut writer, &plaintext[40..]).await.unwrap(), 20);
+            assert!(writer.is_finalized());
+            assert_eq!(write(&mut writer, &plaintext[60..]).await.unwrap(), 0);
+            shutdown(&mut writer).await.unwrap();
+        });
+        assert_eq!(open(cipher, &ciphertext).unwrap(), &plaintext[..60]);
+    }
+
+    #[test]
+    fn empty_streams() {
+        // a tag length byte and a tag, a padding block, and nothing at all
+        for (cipher, len) in [
+            (Cipher::aes_256_gcm(), 17),
+            (Cipher::aes_128_cbc(), 16),
+            (Cipher::aes_256_ctr(), 0),
+        ] {
+            let (key, iv) = key_iv(cipher);
+            let ciphertext = seal(cipher, &[]);
+            assert_eq!(ciphertext.len(), len);
+            assert!(open(cipher, &ciphertext).unwrap().is_empty());
+
+            // zero-byte writes return at once and add nothing
+            let mut out = Vec::new();
+            block_on(async {
+                let mut writer = EncryptWriter::new(&mut out, cipher, &key, iv.as_deref()).unwrap();
+                assert_eq!(write(&mut writer, &[]).await.unwrap(), 0);
+                shutdown(&mut writer).await.unwrap();
+            });
+            assert_eq!(out, ciphertext);
+
+            let writer = EncryptWriter::new(Vec::<u8>::new(), cipher, &key, iv.as_deref()).unwrap();
+            let mut writer = writer.reject_empty(true);
+            let e = block_on(shutdown(&mut writer)).unwrap_err();
+            assert!(matches!(root(&e), Some(CryptoIoError::Empty)));
+            assert_eq!(e.kind(), IoErrorKind::InvalidData);
+
+            let reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref()).unwrap();
+            let mut reader = reader.reject_empty(true);
+            let e = block_on(read_to_end(&mut reader)).unwrap_err();
+            assert!(matches!(root(&e), Some(CryptoIoError::Empty)));
+            let nonempty = seal(cipher, b"x");
+            let reader = DecryptReader::new(&nonempty[..], cipher, &key, iv.as_deref()).unwrap();
+            let mut reader = reader.reject_empty(true);
+            assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), b"x");
+        }
+
+        // no ciphertext at all is cut short where streams are never empty
+        for cipher in [Cipher::aes_256_gcm(), Cipher::aes_128_cbc()] {
+            let e = open(cipher, &[]).unwrap_err();
+            assert!(matches!(root(&e), Some(CryptoIoError::Truncated)));
+        }
+    }
+
+    #[test]
+    fn closed_after_shutdown() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let mut ciphertext = Vec::new();
+        let inner = Flaky::new(&mut ciphertext, IoErrorKind::Interrupted, 2);
+        let mut writer = EncryptWriter::new(inner, cipher, &key, iv.as_deref()).unwrap();
+        let is_closed = |res: IoResult<usize>| {
+            let e = res.unwrap_err();
+            e.kind() == IoErrorKind::BrokenPipe && matches!(root(&e), Some(CryptoIoError::Closed))
+        };
+        block_on(async {
+            write_all(&mut writer, b"hello").await.unwrap();
+            // a shutdown cut short refuses writes too, but can be retried
+            let e = shutdown(&mut writer).await.unwrap_err();
+            assert_eq!(e.kind(), IoErrorKind::Interrupted);
+            assert!(is_closed(write(&mut writer, b"more").await));
+            assert!(is_closed(write(&mut writer, &[]).await));
+            while let Err(e) = shutdown(&mut writer).await {
+                assert_eq!(e.kind(), IoErrorKind::Interrupted);
+            }
+            assert!(is_closed(write(&mut writer, b"more").await));
+            poll_fn(|cx| Pin::new(&mut writer).poll_flush(cx))
+                .await
+                .unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        assert!(writer.take_error().is_none());
+        assert_eq!(ciphertext, seal(cipher, b"hello"));
+    }
+
+    // every kind that can be retried, failing every other call, keeps all buffered data
+    #[test]
+    fn retries_each_transient_kind() {
+        let cipher = Cipher::aes_128_cbc();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(1000);
+        let expected = seal(cipher, &plaintext);
+        for kind in [
+            IoErrorKind::Interrupted,
+            IoErrorKind::WouldBlock,
+            IoErrorKind::TimedOut,
+        ] {
+            let mut ciphertext = Vec::new();
+            let inner = Flaky::new(&mut ciphertext, kind, 2);
+            let mut writer = EncryptWriter::new(inner, cipher, &key, iv.as_deref()).unwrap();
+            block_on(async {
+                let mut data = &plaintext[..];
+                while !data.is_empty() {
+                    match write(&mut writer, data).await {
+                        Ok(n) => data = &data[n..],
+                        Err(e) => assert_eq!(e.kind(), kind),
                     }
-                    Poll::Ready(Ok(n)) => {
-                        inner.buf.resize(n + inner.cipher.block_size(), 0);
-                        match inner.crypter.update(&buf[..n], &mut inner.buf) {
-                            Ok(a) => a,
-                            Err(e) => return Poll::Ready(Err(IoError::new(IoErrorKind::Other, e))),
-                        }
+                }
+                while let Err(e) = shutdown(&mut writer).await {
+                    assert_eq!(e.kind(), kind);
+                }
+            });
+            assert!(writer.take_error().is_none());
+            assert_eq!(ciphertext, expected);
+
+            let inner = Flaky::new(&expected[..], kind, 2);
+            let mut reader = DecryptReader::new(inner, cipher, &key, iv.as_deref()).unwrap();
+            let mut decrypted = Vec::new();
+            block_on(async {
+                let mut buf = [0; 100];
+                loop {
+                    match poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buf)).await {
+                        Ok(0) => break,
+                        Ok(n) => decrypted.extend_from_slice(&buf[..n]),
+                        Err(e) => assert_eq!(e.kind(), kind),
                     }
-                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
-                    Poll::Pending => return Poll::Pending,
-                };
-                inner.buf.truncate(available);
+                }
+            });
+            assert!(reader.take_error().is_none());
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    // accepts `room` bytes, then nothing ever again
+    #[cfg(feature = "timeout")]
+    struct Stuck {
+        room: usize,
+        written: Vec<u8>,
+    }
+    #[cfg(feature = "timeout")]
+    impl AsyncWrite for Stuck {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<IoResult<usize>> {
+            let n = buf.len().min(self.room - self.written.len());
+            if n == 0 {
+                return Poll::Pending;
             }
-            let src_buf = if buf.len() >= available {
-                &inner.buf[inner.read..]
-            } else {
-                &inner.buf[inner.read..(inner.read + buf.len())]
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Poll::Pending
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<IoResult<()>> {
+            Poll::Pending
+        }
+    }
+
+    #[cfg(feature = "timeout")]
+    #[test]
+    fn shutdown_timeout() {
+        use std::time::Duration;
+
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut out = Vec::new();
+            let mut writer = EncryptWriter::new(&mut out, cipher, &key, iv.as_deref()).unwrap();
+            write_all(&mut writer, b"hello").await.unwrap();
+            let rest = writer.shutdown_timeout(Duration::from_secs(10)).await;
+            assert!(rest.unwrap().is_none());
+            assert_eq!(out, seal(cipher, b"hello"));
+
+            // what didn't get out is handed back
+            let stuck = Stuck {
+                room: 10,
+                written: Vec::new(),
             };
-            buf[..src_buf.len()].clone_from_slice(src_buf);
-            inner.read += src_buf.len();
+            let mut writer = EncryptWriter::new(stuck, cipher, &key, iv.as_deref()).unwrap();
+            write_all(&mut writer, b"hello").await.unwrap();
+            let rest = writer.shutdown_timeout(Duration::from_millis(10)).await;
+            let rest = rest.unwrap().unwrap();
+            assert!(!rest.is_empty());
+            let mut ciphertext = writer.writer.written;
+            ciphertext.extend(rest);
+            assert_eq!(ciphertext, seal(cipher, b"hello"));
+        });
+    }
 
-            Poll::Ready(Ok(src_buf.len()))
+    #[test]
+    fn cipher_accessors() {
+        // (cipher, key length, IV length, block size, AEAD)
+        let ciphers = [
+            (Cipher::aes_128_cbc(), 16, Some(16), 16, false),
+            (Cipher::aes_256_ctr(), 32, Some(16), 1, false),
+            (Cipher::aes_256_gcm(), 32, Some(12), 1, true),
+            (Cipher::chacha20_poly1305(), 32, Some(12), 1, true),
+            (Cipher::aes_128_ecb(), 16, None, 16, false),
+        ];
+        for (cipher, key_len, iv_len, block_size, is_aead) in ciphers {
+            let (key, iv) = key_iv(cipher);
+            let writer = EncryptWriter::new(Vec::<u8>::new(), cipher, &key, iv.as_deref()).unwrap();
+            assert_eq!(writer.cipher().nid(), cipher.nid());
+            assert_eq!((writer.key_len(), writer.iv_len()), (key_len, iv_len));
+            assert_eq!(
+                (writer.block_size(), writer.is_aead()),
+                (block_size, is_aead)
+            );
+            let reader = DecryptReader::new(&b""[..], cipher, &key, iv.as_deref()).unwrap();
+            assert_eq!(reader.cipher().nid(), cipher.nid());
+            assert_eq!((reader.key_len(), reader.iv_len()), (key_len, iv_len));
+            assert_eq!(
+                (reader.block_size(), reader.is_aead()),
+                (block_size, is_aead)
+            );
         }
     }
+
+    // builder failures come back as the same CryptoIoError the constructors return
+    #[test]
+    fn builder_errors() {
+        let cipher = Cipher::aes_128_ctr();
+        let (key, iv) = key_iv(cipher);
+        let writer = EncryptWriter::new(Vec::<u8>::new(), cipher, &key, iv.as_deref()).unwrap();
+        assert!(matches!(
+            writer.with_cmac(Cipher::aes_128_cbc(), &[0; 3]),
+            Err(CryptoIoError::OpenSsl(_))
+        ));
+        let reader = DecryptReader::new(&b""[..], cipher, &key, iv.as_deref()).unwrap();
+        assert!(matches!(
+            reader.with_cmac(Cipher::aes_128_cbc(), &[0; 3]),
+            Err(CryptoIoError::OpenSsl(_))
+        ));
+    }
 }
@@ -0,0 +1,551 @@
+use std::io::{Error as IoError, Result as IoResult};
+use std::pin::Pin;
+use std::task::Context;
+use std::task::Poll;
+
+use openssl::error::ErrorStack;
+use openssl::md::Md;
+use openssl::md_ctx::MdCtx;
+use openssl::memcmp;
+use openssl::pkey::PKey;
+use openssl::symm::Cipher;
+use tokio::io::AsyncRead;
+use tokio::io::AsyncWrite;
+use zeroize::Zeroizing;
+
+use crate::error::{openssl_err, CryptoIoError};
+
+const KEY_COMMITMENT_LABEL: &[u8] = b"tokio-openssl-symm key commitment v2";
+pub(crate) const KEY_COMMITMENT_LEN: usize = 32;
+
+// Derived from `key` with HKDF-SHA256 under its own label, so the cipher key never keys anything
+// but the cipher. It depends on nothing but the key, which makes it a per-key fingerprint: it is
+// the same for every stream under that key, so anyone who sees the streams can tell which of them
+// share a key.
+pub(crate) fn key_commitment(key: &[u8]) -> Result<Vec<u8>, ErrorStack> {
+    let prk = hkdf_extract(&[], &[key])?;
+    let commitment = hkdf_expand(&prk, &[KEY_COMMITMENT_LABEL], KEY_COMMITMENT_LEN)?;
+    Ok(commitment.to_vec())
+}
+
+// HKDF-SHA256 (RFC 5869) extract and expand, with the input keying material and info given in
+// parts
+pub(crate) fn hkdf_extract(salt: &[u8], ikm: &[&[u8]]) -> Result<Zeroizing<Vec<u8>>, ErrorStack> {
+    // HKDF's default salt, which is also how HMAC pads an empty key
+    let salt = if salt.is_empty() { &[0; 32][..] } else { salt };
+    let mut mac = Mac::hmac_sha256(salt)?;
+    for part in ikm {
+        mac.update(part)?;
+    }
+    let mut res = Zeroizing::new(Vec::with_capacity(mac.len()));
+    mac.finish(&mut res)?;
+    Ok(res)
+}
+
+pub(crate) fn hkdf_expand(
+    prk: &[u8],
+    info: &[&[u8]],
+    len: usize,
+) -> Result<Zeroizing<Vec<u8>>, ErrorStack> {
+    let mut res = Zeroizing::new(Vec::with_capacity(len));
+    let mut block = Zeroizing::new(Vec::new());
+    let mut counter = 1u8;
+    while res.len() < len {
+        let mut mac = Mac::hmac_sha256(prk)?;
+        mac.update(&block)?;
+        for part in info {
+            mac.update(part)?;
+        }
+        mac.update(&[counter])?;
+        block.clear();
+        mac.finish(&mut block)?;
+        let take = (len - res.len()).min(block.len());
+        res.extend_from_slice(&block[..take]);
+        counter += 1;
+    }
+    Ok(res)
+}
+
+pub(crate) struct Mac {
+    ctx: MdCtx,
+    len: usize,
+}
+impl Mac {
+    pub(crate) fn hmac_sha256(key: &[u8]) -> Result<Self, ErrorStack> {
+        let pkey = PKey::hmac(key)?;
+        let mut ctx = MdCtx::new()?;
+        ctx.digest_sign_init(Some(Md::sha256()), &pkey)?;
+        Ok(Mac {
+            ctx,
+            len: Md::sha256().size(),
+        })
+    }
+
+    pub(crate) fn cmac(cipher: Cipher, key: &[u8]) -> Result<Self, ErrorStack> {
+        let pkey = PKey::cmac(&cipher, key)?;
+        let mut ctx = MdCtx::new()?;
+        ctx.digest_sign_init(None, &pkey)?;
+        Ok(Mac {
+            ctx,
+            len: cipher.block_size(),
+        })
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    pub(crate) fn update(&mut self, data: &[u8]) -> Result<(), ErrorStack> {
+        self.ctx.digest_sign_update(data)
+    }
+
+    pub(crate) fn finish(&mut self, out: &mut Vec<u8>) -> Result<(), ErrorStack> {
+        let init_len = out.len();
+        out.resize(init_len + self.len, 0);
+        let len = self.ctx.digest_sign_final(Some(&mut out[init_len..]))?;
+        out.truncate(init_len + len);
+        Ok(())
+    }
+
+    pub(crate) fn verify(&mut self, tag: &[u8]) -> Result<bool, ErrorStack> {
+        let mut expected = Vec::with_capacity(self.len);
+        self.finish(&mut expected)?;
+        Ok(expected.len() == tag.len() && memcmp::eq(&expected, tag))
+    }
+}
+
+fn finalize_once(mac: &mut Mac, tag: &mut Option<Vec<u8>>) -> Result<Vec<u8>, ErrorStack> {
+    if let Some(tag) = tag {
+        return Ok(tag.clone());
+    }
+    let mut res = Vec::with_capacity(mac.len());
+    mac.finish(&mut res)?;
+    *tag = Some(res.clone());
+    Ok(res)
+}
+
+fn finalized_error() -> IoError {
+    CryptoIoError::Finalized.into()
+}
+
+pub struct MacWriter<W> {
+    writer: W,
+    mac: Mac,
+    tag: Option<Vec<u8>>,
+}
+impl<W> MacWriter<W> {
+    pub fn new_hmac(writer: W, key: &[u8]) -> Result<Self, ErrorStack> {
+        Ok(MacWriter {
+            writer,
+            mac: Mac::hmac_sha256(key)?,
+            tag: None,
+        })
+    }
+
+    pub fn new_cmac(writer: W, cipher: Cipher, key: &[u8]) -> Result<Self, ErrorStack> {
+        Ok(MacWriter {
+            writer,
+            mac: Mac::cmac(cipher, key)?,
+            tag: None,
+        })
+    }
+
+    // finishes the MAC over everything written so far; later writes fail
+    pub fn finalize(&mut self) -> Result<Vec<u8>, ErrorStack> {
+        finalize_once(&mut self.mac, &mut self.tag)
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W> AsyncWrite for MacWriter<W>
+where
+    W: AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            if inner.tag.is_some() {
+                return Poll::Ready(Err(finalized_error()));
+            }
+            match Pin::new_unchecked(&mut inner.writer).poll_write(cx, buf) {
+                Poll::Ready(Ok(n)) => match inner.mac.update(&buf[..n]) {
+                    Ok(()) => Poll::Ready(Ok(n)),
+                    Err(e) => Poll::Ready(Err(openssl_err(e))),
+                },
+                a => a,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe { Pin::new_unchecked(&mut self.get_unchecked_mut().writer).poll_flush(cx) }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe { Pin::new_unchecked(&mut self.get_unchecked_mut().writer).poll_shutdown(cx) }
+    }
+}
+
+pub struct MacReader<R> {
+    reader: R,
+    mac: Mac,
+    tag: Option<Vec<u8>>,
+}
+impl<R> MacReader<R> {
+    pub fn new_hmac(reader: R, key: &[u8]) -> Result<Self, ErrorStack> {
+        Ok(MacReader {
+            reader,
+            mac: Mac::hmac_sha256(key)?,
+            tag: None,
+        })
+    }
+
+    pub fn new_cmac(reader: R, cipher: Cipher, key: &[u8]) -> Result<Self, ErrorStack> {
+        Ok(MacReader {
+            reader,
+            mac: Mac::cmac(cipher, key)?,
+            tag: None,
+        })
+    }
+
+    // finishes the MAC over everything read so far; later reads fail
+    pub fn finalize(&mut self) -> Result<Vec<u8>, ErrorStack> {
+        finalize_once(&mut self.mac, &mut self.tag)
+    }
+
+    pub fn verify(&mut self, tag: &[u8]) -> Result<bool, ErrorStack> {
+        let expected = self.finalize()?;
+        Ok(expected.len() == tag.len() && memcmp::eq(&expected, tag))
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R> AsyncRead for MacReader<R>
+where
+    R: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            if inner.tag.is_some() {
+                return Poll::Ready(Err(finalized_error()));
+            }
+            match Pin::new_unchecked(&mut inner.reader).poll_read(cx, buf) {
+                Poll::Ready(Ok(n)) => match inner.mac.update(&buf[..n]) {
+                    Ok(()) => Poll::Ready(Ok(n)),
+                    Err(e) => Poll::Ready(Err(openssl_err(e))),
+                },
+                a => a,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind as IoErrorKind, Result as IoResult};
+
+    use openssl::symm::Cipher;
+
+    use super::{key_commitment, Mac, MacReader, MacWriter};
+    use crate::testing::{block_on, hex, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+    fn mac(mut mac: Mac, data: &[u8]) -> Vec<u8> {
+        mac.update(data).unwrap();
+        let mut tag = Vec::new();
+        mac.finish(&mut tag).unwrap();
+        tag
+    }
+
+    // RFC 4231 test case 2
+    #[test]
+    fn hmac_sha256_vector() {
+        let tag = mac(
+            Mac::hmac_sha256(b"Jefe").unwrap(),
+            b"what do ya want for nothing?",
+        );
+        let expected = "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843";
+        assert_eq!(tag, hex(expected));
+        let mut hmac = Mac::hmac_sha256(b"Jefe").unwrap();
+        hmac.update(b"what do ya want for nothing?").unwrap();
+        assert!(hmac.verify(&hex(expected)).unwrap());
+        let mut hmac = Mac::hmac_sha256(b"Jefe").unwrap();
+        assert!(!hmac.verify(&hex(expected)[..31]).unwrap());
+    }
+
+    const MAC_KEY: &[u8] = b"a separate mac key";
+
+    fn encrypt(cipher: Cipher, plaintext: &[u8]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let mut out = Vec::new();
+        block_on(async {
+            let writer = EncryptWriter::new(&mut out, cipher, &key, iv.as_deref()).unwrap();
+            let mut writer = writer.with_hmac(MAC_KEY).unwrap();
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn decrypt(cipher: Cipher, mac_key: &[u8], ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        let (key, iv) = key_iv(cipher);
+        block_on(async {
+            let reader = DecryptReader::new(ciphertext, cipher, &key, iv.as_deref())?;
+            read_to_end(&mut reader.with_hmac(mac_key)?).await
+        })
+    }
+
+    fn is_bad_mac(result: IoResult<Vec<u8>>) -> bool {
+        match result {
+            Err(e) => {
+                e.kind() == IoErrorKind::InvalidData
+                    && matches!(
+                        CryptoIoError::from_io(&e).map(|e| e.root()),
+                        Some(CryptoIoError::BadTag)
+                    )
+            }
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn hmac_round_trip() {
+        let plaintext = sample(10_000);
+        // a full padding block for CBC, then the 32-byte trailer
+        for &(cipher, len) in &[
+            (Cipher::aes_256_cbc(), 10_000 + 16 + 32),
+            (Cipher::aes_256_ctr(), 10_000 + 32),
+        ] {
+            let ciphertext = encrypt(cipher, &plaintext);
+            assert_eq!(ciphertext.len(), len);
+            assert_eq!(decrypt(cipher, MAC_KEY, &ciphertext).unwrap(), plaintext);
+        }
+    }
+
+    // the MAC is checked before the final block is released, so tampering anywhere, even in
+    // the padding of a CBC stream, fails the MAC rather than the padding check
+    #[test]
+    fn hmac_tampered() {
+        for &cipher in &[Cipher::aes_256_cbc(), Cipher::aes_256_ctr()] {
+            let ciphertext = encrypt(cipher, &sample(1000));
+            for &at in &[0, 500, ciphertext.len() - 33, ciphertext.len() - 1] {
+                let mut tampered = ciphertext.clone();
+                tampered[at] ^= 1;
+                assert!(
+                    is_bad_mac(decrypt(cipher, MAC_KEY, &tampered)),
+                    "byte {}",
+                    at
+                );
+            }
+            assert!(is_bad_mac(decrypt(cipher, b"wrong key", &ciphertext)));
+        }
+    }
+
+    #[test]
+    fn hmac_truncated() {
+        let cipher = Cipher::aes_256_ctr();
+        let ciphertext = encrypt(cipher, &sample(1000));
+        for &cut in &[1, 32, 100] {
+            let result = decrypt(cipher, MAC_KEY, &ciphertext[..ciphertext.len() - cut]);
+            assert!(is_bad_mac(result), "cut by {}", cut);
+        }
+    }
+
+    // RFC 4493 examples 1 and 2
+    #[test]
+    fn aes_cmac_vectors() {
+        let key = hex("2b7e151628aed2a6abf7158809cf4f3c");
+        let cmac = || Mac::cmac(Cipher::aes_128_cbc(), &key).unwrap();
+        assert_eq!(mac(cmac(), b""), hex("bb1d6929e95937287fa37d129b756746"));
+        let message = hex("6bc1bee22e409f96e93d7e117393172a");
+        assert_eq!(
+            mac(cmac(), &message),
+            hex("070a16b46b4d4144f79bdd9dd04a287c")
+        );
+    }
+
+    #[test]
+    fn cmac_trailer() {
+        let cipher = Cipher::aes_256_ctr();
+        let (key, iv) = key_iv(cipher);
+        let mac_key = [7; 32];
+        let mac_cipher = Cipher::aes_256_cbc();
+        let plaintext = sample(1000);
+        let mut ciphertext = Vec::new();
+        block_on(async {
+            let writer = EncryptWriter::new(&mut ciphertext, cipher, &key, iv.as_deref()).unwrap();
+            let mut writer = writer.with_cmac(mac_cipher, &mac_key).unwrap();
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        assert_eq!(ciphertext.len(), plaintext.len() + 16);
+        let decrypt = |ciphertext: &[u8], mac_key: &[u8]| {
+            block_on(async {
+                let reader = DecryptReader::new(ciphertext, cipher, &key, iv.as_deref())?;
+                read_to_end(&mut reader.with_cmac(mac_cipher, mac_key)?).await
+            })
+        };
+        assert_eq!(decrypt(&ciphertext, &mac_key).unwrap(), plaintext);
+        assert!(is_bad_mac(decrypt(&ciphertext, &[8; 32])));
+        let mut tampered = ciphertext.clone();
+        tampered[10] ^= 1;
+        assert!(is_bad_mac(decrypt(&tampered, &mac_key)));
+    }
+
+    #[test]
+    fn pass_through_adapters() {
+        let data = sample(5000);
+        let mut out = Vec::new();
+        let tag = block_on(async {
+            let mut writer = MacWriter::new_hmac(&mut out, b"Jefe").unwrap();
+            write_all(&mut writer, &data).await.unwrap();
+            let tag = writer.finalize().unwrap();
+            // finalizing again gives the same tag, and nothing more can be written
+            assert_eq!(writer.finalize().unwrap(), tag);
+            let err = write_all(&mut writer, b"more").await.unwrap_err();
+            assert!(matches!(
+                CryptoIoError::from_io(&err),
+                Some(CryptoIoError::Finalized)
+            ));
+            tag
+        });
+        assert_eq!(out, data);
+        assert_eq!(tag, mac(Mac::hmac_sha256(b"Jefe").unwrap(), &data));
+
+        let mut reader = MacReader::new_hmac(&data[..], b"Jefe").unwrap();
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), data);
+        assert!(reader.verify(&tag).unwrap());
+        assert!(block_on(read_to_end(&mut reader)).is_err());
+
+        let mut reader = MacReader::new_hmac(&data[..4999], b"Jefe").unwrap();
+        block_on(read_to_end(&mut reader)).unwrap();
+        assert!(!reader.verify(&tag).unwrap());
+    }
+
+    #[test]
+    fn cmac_adapters() {
+        let cipher = Cipher::aes_128_cbc();
+        let key = hex("2b7e151628aed2a6abf7158809cf4f3c");
+        let message = hex("6bc1bee22e409f96e93d7e117393172a");
+        let mut writer = MacWriter::new_cmac(Vec::new(), cipher, &key).unwrap();
+        block_on(write_all(&mut writer, &message)).unwrap();
+        let tag = writer.finalize().unwrap();
+        assert_eq!(tag, hex("070a16b46b4d4144f79bdd9dd04a287c"));
+        let mut reader = MacReader::new_cmac(&message[..], cipher, &key).unwrap();
+        block_on(read_to_end(&mut reader)).unwrap();
+        assert!(reader.verify(&tag).unwrap());
+    }
+
+    fn commit(cipher: Cipher, plaintext: &[u8]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let mut out = Vec::new();
+        block_on(async {
+            let writer = EncryptWriter::new(&mut out, cipher, &key, iv.as_deref()).unwrap();
+            let mut writer = writer.with_key_commitment(&key).unwrap();
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn open_committed(cipher: Cipher, key: &[u8], ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        let (_, iv) = key_iv(cipher);
+        block_on(async {
+            let reader = DecryptReader::new(ciphertext, cipher, key, iv.as_deref())?;
+            read_to_end(&mut reader.with_key_commitment(key)?).await
+        })
+    }
+
+    fn is_key_mismatch(result: IoResult<Vec<u8>>) -> bool {
+        match result {
+            Err(e) => {
+                e.kind() == IoErrorKind::InvalidData
+                    && matches!(
+                        CryptoIoError::from_io(&e).map(|e| e.root()),
+                        Some(CryptoIoError::KeyMismatch)
+                    )
+            }
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn key_commitment_round_trip() {
+        let plaintext = sample(1000);
+        for &cipher in &[Cipher::aes_128_cbc(), Cipher::aes_128_ctr()] {
+            let (key, _) = key_iv(cipher);
+            let ciphertext = commit(cipher, &plaintext);
+            assert_eq!(ciphertext[..32], key_commitment(&key).unwrap()[..]);
+            assert_eq!(
+                open_committed(cipher, &key, &ciphertext).unwrap(),
+                plaintext
+            );
+        }
+    }
+
+    // the commitment depends on the key alone, so streams under another IV carry the same one
+    #[test]
+    fn key_commitment_is_per_key() {
+        let cipher = Cipher::aes_128_ctr();
+        let (key, _) = key_iv(cipher);
+        let mut out = Vec::new();
+        block_on(async {
+            let writer = EncryptWriter::new(&mut out, cipher, &key, Some(&[0x99; 16])).unwrap();
+            let mut writer = writer.with_key_commitment(&key).unwrap();
+            write_all(&mut writer, b"other stream").await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        assert_eq!(out[..32], commit(cipher, &sample(10))[..32]);
+        assert_ne!(
+            key_commitment(&key).unwrap(),
+            key_commitment(&[0x43; 16]).unwrap()
+        );
+    }
+
+    // the wrong key is caught from the commitment alone, before anything is decrypted
+    #[test]
+    fn key_commitment_mismatch() {
+        let cipher = Cipher::aes_128_ctr();
+        let (key, _) = key_iv(cipher);
+        let ciphertext = commit(cipher, &sample(1000));
+        let other = vec![0x43; key.len()];
+        assert!(is_key_mismatch(open_committed(
+            cipher,
+            &other,
+            &ciphertext[..32]
+        )));
+
+        // a flipped commitment byte, under the right key
+        let mut tampered = ciphertext.clone();
+        tampered[5] ^= 1;
+        assert!(is_key_mismatch(open_committed(cipher, &key, &tampered)));
+        assert!(open_committed(cipher, &key, &ciphertext[..20]).is_err());
+    }
+}
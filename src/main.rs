@@ -0,0 +1,527 @@
+use std::fs;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::process;
+
+use openssl::rand::rand_bytes;
+use openssl::symm::Mode;
+use tokio::fs::File;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio_openssl_symm::{
+    Algorithm, CryptoIoError, DecryptReader, EncryptWriter, Metadata, OpensslBackend,
+    SymmetricBackend,
+};
+
+const USAGE: &str = "\
+usage: tokio-openssl-symm (encrypt | decrypt) --key-file PATH [OPTIONS] [INPUT [OUTPUT]]
+
+Encrypts or decrypts INPUT to OUTPUT (standard input and output when omitted or `-`). The output
+of encrypt starts with a header recording the cipher, a random IV, the tag length and which of
+the options below were used, so decrypt needs only the keys.
+
+options:
+    --cipher NAME          the cipher to encrypt with, e.g. aes-256-gcm or chacha20-poly1305;
+                           for decrypt, the cipher the stream must use (otherwise whatever its
+                           header names is accepted)
+    --key-file PATH        file holding the raw key bytes
+    --tag-len N            AEAD tag length in bytes, for encrypt
+    --hmac-key-file PATH   add (or require and verify) an HMAC-SHA256 trailer under this key
+    --key-commitment       add (or require) a commitment to the key at the start
+
+Streams under a cipher other than an AEAD (aes-*-gcm, chacha20-poly1305) are only authenticated
+with --hmac-key-file; without it, anyone can alter them undetected.
+";
+
+// the container header: MAGIC, VERSION, the algorithm id, the flags, the tag length (0 for
+// ciphers other than AEADs) and the IV
+const MAGIC: &[u8; 4] = b"TOSS";
+const VERSION: u8 = 1;
+const FIXED_LEN: usize = MAGIC.len() + 4;
+const FLAG_HMAC: u8 = 1;
+const FLAG_KEY_COMMITMENT: u8 = 2;
+// the metadata entry the header is repeated in, so the tag or MAC covers it
+const HEADER_ENTRY: &str = "header";
+
+struct Args {
+    encrypt: bool,
+    algorithm: Option<Algorithm>,
+    key: Vec<u8>,
+    tag_len: Option<usize>,
+    hmac_key: Option<Vec<u8>>,
+    key_commitment: bool,
+    input: Option<String>,
+    output: Option<String>,
+}
+
+struct Header {
+    algorithm: Algorithm,
+    tag_len: usize,
+    hmac: bool,
+    key_commitment: bool,
+    iv: Vec<u8>,
+}
+impl Header {
+    fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.hmac {
+            flags |= FLAG_HMAC;
+        }
+        if self.key_commitment {
+            flags |= FLAG_KEY_COMMITMENT;
+        }
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&[VERSION, self.algorithm.id(), flags, self.tag_len as u8]);
+        header.extend_from_slice(&self.iv);
+        header
+    }
+
+    async fn read<R: AsyncRead + Unpin>(input: &mut R) -> Result<Self, CryptoIoError> {
+        let mut fixed = [0; FIXED_LEN];
+        read_exact(input, &mut fixed).await?;
+        let (magic, fixed) = fixed.split_at(MAGIC.len());
+        if magic != MAGIC {
+            return Err(malformed("not a tokio-openssl-symm stream"));
+        }
+        let (version, id, flags, tag_len) = (fixed[0], fixed[1], fixed[2], fixed[3] as usize);
+        if version != VERSION {
+            return Err(CryptoIoError::UnsupportedVersion {
+                min: VERSION,
+                max: VERSION,
+                actual: version,
+            });
+        }
+        let algorithm = Algorithm::from_id(id)?;
+        if flags & !(FLAG_HMAC | FLAG_KEY_COMMITMENT) != 0 {
+            return Err(malformed("unknown header flags"));
+        }
+        let mut iv = vec![0; algorithm.iv_len()];
+        read_exact(input, &mut iv).await?;
+        Ok(Header {
+            algorithm,
+            tag_len,
+            hmac: flags & FLAG_HMAC != 0,
+            key_commitment: flags & FLAG_KEY_COMMITMENT != 0,
+            iv,
+        })
+    }
+
+    fn metadata(&self) -> Metadata {
+        let mut metadata = Metadata::new();
+        metadata.insert(HEADER_ENTRY.to_owned(), self.encode());
+        metadata
+    }
+}
+
+fn invalid(msg: String) -> IoError {
+    IoError::new(IoErrorKind::InvalidInput, msg)
+}
+
+fn malformed(msg: &str) -> CryptoIoError {
+    IoError::new(IoErrorKind::InvalidData, msg).into()
+}
+
+async fn read_exact<R: AsyncRead + Unpin>(
+    input: &mut R,
+    buf: &mut [u8],
+) -> Result<(), CryptoIoError> {
+    match input.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == IoErrorKind::UnexpectedEof => Err(CryptoIoError::Truncated),
+        Err(e) => Err(e.into()),
+    }
+}
+
+fn parse_args() -> Result<Args, IoError> {
+    let mut args = std::env::args().skip(1);
+    let encrypt = match args.next().as_deref() {
+        Some("encrypt") => true,
+        Some("decrypt") => false,
+        _ => return Err(invalid("expected encrypt or decrypt".to_owned())),
+    };
+    let mut algorithm = None;
+    let mut key = None;
+    let mut tag_len = None;
+    let mut hmac_key = None;
+    let mut key_commitment = false;
+    let mut paths = Vec::new();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| invalid(format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "--cipher" => {
+                let name = value()?;
+                algorithm = Some(
+                    name.parse()
+                        .map_err(|_| invalid(format!("unknown cipher {}", name)))?,
+                );
+            }
+            "--key-file" => key = Some(fs::read(value()?)?),
+            "--tag-len" if encrypt => {
+                let len = value()?;
+                tag_len = Some(
+                    len.parse()
+                        .map_err(|_| invalid(format!("invalid tag length {}", len)))?,
+                );
+            }
+            "--hmac-key-file" => hmac_key = Some(fs::read(value()?)?),
+            "--key-commitment" => key_commitment = true,
+            "-h" | "--help" => {
+                print!("{}", USAGE);
+                process::exit(0);
+            }
+            a if a.starts_with("--") => return Err(invalid(format!("unknown option {}", a))),
+            _ => paths.push(arg),
+        }
+    }
+    if paths.len() > 2 {
+        return Err(invalid("too many arguments".to_owned()));
+    }
+    if encrypt && algorithm.is_none() {
+        return Err(invalid("--cipher is required".to_owned()));
+    }
+    let mut paths = paths.into_iter().map(|p| Some(p).filter(|p| p != "-"));
+    Ok(Args {
+        encrypt,
+        algorithm,
+        key: key.ok_or_else(|| invalid("--key-file is required".to_owned()))?,
+        tag_len,
+        hmac_key,
+        key_commitment,
+        input: paths.next().flatten(),
+        output: paths.next().flatten(),
+    })
+}
+
+fn backend(
+    algorithm: Algorithm,
+    mode: Mode,
+    key: &[u8],
+    iv: &[u8],
+    tag_len: Option<usize>,
+) -> Result<OpensslBackend, CryptoIoError> {
+    let backend = OpensslBackend::new(algorithm.cipher(), mode, key, Some(iv))?;
+    match tag_len {
+        Some(len) => backend.with_tag_len(len),
+        None => Ok(backend),
+    }
+}
+
+async fn encrypt<R, W>(args: &Args, mut input: R, mut output: W) -> Result<(), CryptoIoError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    // parse_args insists on --cipher for encrypt
+    let algorithm = args.algorithm.unwrap();
+    let mut iv = vec![0; algorithm.iv_len()];
+    rand_bytes(&mut iv)?;
+    let backend = backend(algorithm, Mode::Encrypt, &args.key, &iv, args.tag_len)?;
+    let header = Header {
+        algorithm,
+        tag_len: backend.tag_len(),
+        hmac: args.hmac_key.is_some(),
+        key_commitment: args.key_commitment,
+        iv,
+    };
+    output.write_all(&header.encode()).await?;
+    let mut writer = EncryptWriter::with_backend(output, backend).without_header();
+    if let Some(key) = &args.hmac_key {
+        writer = writer.with_hmac(key)?;
+    }
+    if args.key_commitment {
+        writer = writer.with_key_commitment(&args.key)?;
+    }
+    writer = writer.with_metadata(&header.metadata())?;
+    io::copy(&mut input, &mut writer).await?;
+    writer.shutdown().await?;
+    // tokio's File doesn't flush on shutdown
+    writer.flush().await?;
+    Ok(())
+}
+
+async fn decrypt<R, W>(args: &Args, mut input: R, mut output: W) -> Result<(), CryptoIoError>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let header = Header::read(&mut input).await?;
+    if matches!(args.algorithm, Some(a) if a != header.algorithm) {
+        return Err(malformed(&format!("the stream uses {}", header.algorithm)));
+    }
+    // the flags are only authenticated along with the rest of the stream, so what was asked for
+    // must be there rather than skipped when a (possibly altered) header leaves it out
+    if args.hmac_key.is_some() && !header.hmac {
+        return Err(malformed("the stream has no HMAC trailer"));
+    }
+    if args.key_commitment && !header.key_commitment {
+        return Err(malformed("the stream has no key commitment"));
+    }
+    let backend = backend(
+        header.algorithm,
+        Mode::Decrypt,
+        &args.key,
+        &header.iv,
+        Some(header.tag_len),
+    )?;
+    let mut reader = DecryptReader::with_backend(input, backend).without_header();
+    if header.hmac {
+        let key = args.hmac_key.as_ref().ok_or_else(|| {
+            invalid("the stream has an HMAC trailer; pass --hmac-key-file".to_owned())
+        })?;
+        reader = reader.with_hmac(key)?;
+    }
+    if header.key_commitment {
+        reader = reader.with_key_commitment(&args.key)?;
+    }
+    reader = reader.with_metadata();
+    io::copy(&mut reader, &mut output).await?;
+    if reader.metadata() != Some(&header.metadata()) {
+        return Err(malformed(
+            "the header differs from the one bound into the stream",
+        ));
+    }
+    output.flush().await?;
+    Ok(())
+}
+
+async fn run(args: &Args) -> Result<(), CryptoIoError> {
+    let input: Box<dyn AsyncRead + Unpin> = match &args.input {
+        Some(path) => Box::new(File::open(path).await?),
+        None => Box::new(io::stdin()),
+    };
+    let output: Box<dyn AsyncWrite + Unpin> = match &args.output {
+        Some(path) => Box::new(File::create(path).await?),
+        None => Box::new(io::stdout()),
+    };
+    let res = if args.encrypt {
+        encrypt(args, input, output).await
+    } else {
+        decrypt(args, input, output).await
+    };
+    // don't leave a partial (and for decryption, unauthenticated) file behind
+    if res.is_err() {
+        if let Some(path) = &args.output {
+            let _ = fs::remove_file(path);
+        }
+    }
+    res
+}
+
+#[tokio::main(basic_scheduler)]
+async fn main() {
+    let args = match parse_args() {
+        Ok(a) => a,
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(&args).await {
+        eprintln!("error: {}", e);
+        process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::ErrorKind as IoErrorKind;
+
+    use openssl::symm::Mode;
+    use tokio_openssl_symm::{Algorithm, CryptoIoError, DecryptReader};
+
+    use super::{
+        backend, decrypt, encrypt, Args, Header, FIXED_LEN, FLAG_HMAC, FLAG_KEY_COMMITMENT, MAGIC,
+    };
+
+    fn args(encrypt: bool) -> Args {
+        Args {
+            encrypt,
+            algorithm: Some(Algorithm::Aes256Gcm),
+            key: vec![1; 32],
+            tag_len: Some(12).filter(|_| encrypt),
+            hmac_key: Some(b"mac key".to_vec()),
+            key_commitment: true,
+            input: None,
+            output: None,
+        }
+    }
+
+    fn plaintext() -> Vec<u8> {
+        (0..100_000).map(|i| (i % 251) as u8).collect()
+    }
+
+    async fn seal(args: &Args) -> Vec<u8> {
+        let mut ciphertext = Vec::new();
+        encrypt(args, &plaintext()[..], &mut ciphertext)
+            .await
+            .unwrap();
+        ciphertext
+    }
+
+    async fn open(args: &Args, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoIoError> {
+        let mut decrypted = Vec::new();
+        decrypt(args, ciphertext, &mut decrypted).await?;
+        Ok(decrypted)
+    }
+
+    fn fails_with(
+        res: Result<Vec<u8>, CryptoIoError>,
+        expected: impl Fn(&CryptoIoError) -> bool,
+    ) -> bool {
+        match res {
+            Err(CryptoIoError::Io(e)) => match CryptoIoError::from_io(&e) {
+                Some(e) => expected(e.root()),
+                None => expected(&CryptoIoError::Io(e)),
+            },
+            Err(e) => expected(e.root()),
+            Ok(_) => false,
+        }
+    }
+
+    fn is_kind(kind: IoErrorKind) -> impl Fn(&CryptoIoError) -> bool {
+        move |e| e.kind() == kind
+    }
+
+    #[tokio::test]
+    async fn round_trip() {
+        let ciphertext = seal(&args(true)).await;
+        assert_eq!(open(&args(false), &ciphertext).await.unwrap(), plaintext());
+
+        // the header records everything decrypt needs besides the keys
+        let header = Header::read(&mut &ciphertext[..]).await.unwrap();
+        assert_eq!(header.algorithm, Algorithm::Aes256Gcm);
+        assert_eq!(header.tag_len, 12);
+        assert!(header.hmac && header.key_commitment);
+        assert_eq!(ciphertext[FIXED_LEN..FIXED_LEN + 12], header.iv[..]);
+        // drawn afresh for every stream
+        let again = Header::read(&mut &seal(&args(true)).await[..])
+            .await
+            .unwrap();
+        assert_ne!(again.iv, header.iv);
+
+        let mut plain = args(true);
+        plain.algorithm = Some(Algorithm::Aes128Ctr);
+        plain.key = vec![1; 16];
+        plain.tag_len = None;
+        plain.hmac_key = None;
+        plain.key_commitment = false;
+        let ciphertext = seal(&plain).await;
+        let header = Header::read(&mut &ciphertext[..]).await.unwrap();
+        assert_eq!((header.tag_len, header.hmac), (0, false));
+        plain.algorithm = None;
+        assert_eq!(open(&plain, &ciphertext).await.unwrap(), plaintext());
+    }
+
+    // the rest of the container is a headerless stream with the header as its metadata
+    #[tokio::test]
+    async fn library_reads_the_container() {
+        let ciphertext = seal(&args(true)).await;
+        let mut input = &ciphertext[..];
+        let header = Header::read(&mut input).await.unwrap();
+        let backend = backend(
+            header.algorithm,
+            Mode::Decrypt,
+            &[1; 32],
+            &header.iv,
+            Some(header.tag_len),
+        )
+        .unwrap();
+        let mut reader = DecryptReader::with_backend(input, backend)
+            .without_header()
+            .with_hmac(b"mac key")
+            .unwrap()
+            .with_key_commitment(&[1; 32])
+            .unwrap()
+            .with_metadata();
+        let mut decrypted = Vec::new();
+        tokio::io::copy(&mut reader, &mut decrypted).await.unwrap();
+        assert_eq!(decrypted, plaintext());
+        assert_eq!(reader.metadata(), Some(&header.metadata()));
+    }
+
+    #[tokio::test]
+    async fn wrong_key() {
+        let ciphertext = seal(&args(true)).await;
+        let mut wrong = args(false);
+        wrong.key = vec![3; 32];
+        assert!(fails_with(open(&wrong, &ciphertext).await, |e| matches!(
+            e,
+            CryptoIoError::KeyMismatch
+        )));
+    }
+
+    #[tokio::test]
+    async fn malformed_headers() {
+        let ciphertext = seal(&args(true)).await;
+        let with = |i: usize, byte: u8| {
+            let mut altered = ciphertext.clone();
+            altered[i] = byte;
+            altered
+        };
+        let invalid_data = is_kind(IoErrorKind::InvalidData);
+        assert!(fails_with(
+            open(&args(false), &with(0, b'X')).await,
+            &invalid_data
+        ));
+        assert!(fails_with(open(&args(false), &with(4, 2)).await, |e| {
+            matches!(e, CryptoIoError::UnsupportedVersion { actual: 2, .. })
+        }));
+        assert!(fails_with(open(&args(false), &with(5, 0)).await, |e| {
+            matches!(e, CryptoIoError::UnknownAlgorithm(0))
+        }));
+        assert!(fails_with(
+            open(&args(false), &with(6, 0x80)).await,
+            &invalid_data
+        ));
+        assert!(fails_with(open(&args(false), &with(7, 40)).await, |e| {
+            matches!(e, CryptoIoError::InvalidTagLen { actual: 40, .. })
+        }));
+        assert!(fails_with(
+            open(&args(false), &ciphertext[..FIXED_LEN + 3]).await,
+            |e| matches!(e, CryptoIoError::Truncated)
+        ));
+    }
+
+    // a header altered to leave out what decrypt was asked to check is refused, not skipped
+    #[tokio::test]
+    async fn downgrades() {
+        let ciphertext = seal(&args(true)).await;
+        let invalid_data = is_kind(IoErrorKind::InvalidData);
+        for &flag in &[FLAG_HMAC, FLAG_KEY_COMMITMENT] {
+            let mut altered = ciphertext.clone();
+            altered[MAGIC.len() + 2] &= !flag;
+            assert!(fails_with(
+                open(&args(false), &altered).await,
+                &invalid_data
+            ));
+        }
+
+        let mut pinned = args(false);
+        pinned.algorithm = Some(Algorithm::ChaCha20Poly1305);
+        assert!(fails_with(open(&pinned, &ciphertext).await, &invalid_data));
+
+        let mut no_hmac_key = args(false);
+        no_hmac_key.hmac_key = None;
+        assert!(fails_with(
+            open(&no_hmac_key, &ciphertext).await,
+            is_kind(IoErrorKind::InvalidInput)
+        ));
+    }
+
+    // the HMAC covers the ciphertext but not the IV, so the header repeated in the metadata is
+    // what catches an altered IV there
+    #[tokio::test]
+    async fn header_is_authenticated() {
+        let mut ctr = args(true);
+        ctr.algorithm = Some(Algorithm::Aes256Ctr);
+        ctr.tag_len = None;
+        let mut ciphertext = seal(&ctr).await;
+        ciphertext[FIXED_LEN] ^= 1;
+        ctr.encrypt = false;
+        assert!(fails_with(
+            open(&ctr, &ciphertext).await,
+            is_kind(IoErrorKind::InvalidData)
+        ));
+    }
+}
@@ -0,0 +1,363 @@
+use std::future::poll_fn;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use openssl::memcmp;
+use openssl::sha::Sha256;
+use serde_json::{json, Value};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::reencrypt::copy_zeroizing;
+use crate::{CryptoIoError, EncryptCore, EncryptWriter, KeyId, SymmetricBackend};
+
+const MANIFEST_VERSION: u64 = 1;
+const READ_CHUNK_LEN: usize = 64 * 1024;
+
+// A description of an encrypted blob, produced by `encrypt_with_manifest` and stored alongside
+// it, so the blob can later be checked with `verify_manifest` without the key. The ciphertext is
+// cut into chunks of `chunk_len` bytes (the last may be shorter), each recorded by its SHA-256,
+// which also says which part of a damaged blob to fetch again.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Manifest {
+    pub cipher: String,
+    pub key_id: Option<KeyId>,
+    pub chunk_len: u64,
+    pub chunks: Vec<[u8; 32]>,
+    pub plaintext_len: u64,
+    pub ciphertext_len: u64,
+}
+impl Manifest {
+    pub fn to_json(&self) -> String {
+        json!({
+            "version": MANIFEST_VERSION,
+            "cipher": self.cipher,
+            "key_id": self.key_id.as_ref().map(|id| to_hex(id.as_bytes())),
+            "chunk_len": self.chunk_len,
+            "chunks": self.chunks.iter().map(|c| to_hex(c)).collect::<Vec<_>>(),
+            "plaintext_len": self.plaintext_len,
+            "ciphertext_len": self.ciphertext_len,
+        })
+        .to_string()
+    }
+
+    pub fn from_json(json: &str) -> Result<Self, CryptoIoError> {
+        let bad = || IoError::new(IoErrorKind::InvalidData, "malformed manifest");
+        let value: Value = serde_json::from_str(json).map_err(|_| bad())?;
+        if value["version"].as_u64() != Some(MANIFEST_VERSION) {
+            return Err(
+                IoError::new(IoErrorKind::InvalidData, "unsupported manifest version").into(),
+            );
+        }
+        let key_id = match &value["key_id"] {
+            Value::Null => None,
+            id => Some(KeyId::from(
+                from_hex(id.as_str().ok_or_else(bad)?).ok_or_else(bad)?,
+            )),
+        };
+        let chunks = value["chunks"]
+            .as_array()
+            .ok_or_else(bad)?
+            .iter()
+            .map(|c| {
+                let digest = from_hex(c.as_str()?)?;
+                let mut chunk = [0; 32];
+                if digest.len() != chunk.len() {
+                    return None;
+                }
+                chunk.copy_from_slice(&digest);
+                Some(chunk)
+            })
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(bad)?;
+        let manifest = Manifest {
+            cipher: value["cipher"].as_str().ok_or_else(bad)?.to_owned(),
+            key_id,
+            chunk_len: value["chunk_len"]
+                .as_u64()
+                .filter(|&n| n > 0)
+                .ok_or_else(bad)?,
+            chunks,
+            plaintext_len: value["plaintext_len"].as_u64().ok_or_else(bad)?,
+            ciphertext_len: value["ciphertext_len"].as_u64().ok_or_else(bad)?,
+        };
+        if manifest.chunks.len() as u64 != manifest.ciphertext_len.div_ceil(manifest.chunk_len) {
+            return Err(bad().into());
+        }
+        Ok(manifest)
+    }
+}
+
+fn to_hex(data: &[u8]) -> String {
+    data.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(s.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+// cuts the bytes fed to it into chunks and hashes each
+struct ChunkDigests {
+    chunk_len: u64,
+    hasher: Sha256,
+    // bytes of the current chunk hashed so far
+    filled: u64,
+    chunks: Vec<[u8; 32]>,
+    total: u64,
+}
+impl ChunkDigests {
+    fn new(chunk_len: u64) -> Self {
+        ChunkDigests {
+            chunk_len,
+            hasher: Sha256::new(),
+            filled: 0,
+            chunks: Vec::new(),
+            total: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.total += data.len() as u64;
+        while !data.is_empty() {
+            let n = (self.chunk_len - self.filled).min(data.len() as u64) as usize;
+            self.hasher.update(&data[..n]);
+            self.filled += n as u64;
+            data = &data[n..];
+            if self.filled == self.chunk_len {
+                self.end_chunk();
+            }
+        }
+    }
+
+    fn end_chunk(&mut self) {
+        let hasher = std::mem::replace(&mut self.hasher, Sha256::new());
+        self.chunks.push(hasher.finish());
+        self.filled = 0;
+    }
+
+    fn finish(mut self) -> (Vec<[u8; 32]>, u64) {
+        if self.filled > 0 {
+            self.end_chunk();
+        }
+        (self.chunks, self.total)
+    }
+}
+
+// passes ciphertext through to `writer`, hashing what it accepts
+struct DigestWriter<W> {
+    writer: W,
+    digests: ChunkDigests,
+}
+
+impl<W> AsyncWrite for DigestWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let inner = self.get_mut();
+        match Pin::new(&mut inner.writer).poll_write(cx, buf) {
+            Poll::Ready(Ok(n)) => {
+                inner.digests.update(&buf[..n]);
+                Poll::Ready(Ok(n))
+            }
+            a => a,
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        Pin::new(&mut self.get_mut().writer).poll_shutdown(cx)
+    }
+}
+
+// Encrypts `reader` with `core` into `writer`, like `reencrypt` does, and returns a manifest of
+// the ciphertext in chunks of `chunk_len` bytes. `key_id` is only recorded, for finding the key
+// again later.
+pub async fn encrypt_with_manifest<R, W, B>(
+    mut reader: R,
+    core: EncryptCore<B>,
+    writer: W,
+    key_id: Option<&KeyId>,
+    chunk_len: u64,
+) -> IoResult<Manifest>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    B: SymmetricBackend + Unpin,
+{
+    assert!(chunk_len > 0, "chunk length must be nonzero");
+    let cipher = core.name().to_owned();
+    let writer = DigestWriter {
+        writer,
+        digests: ChunkDigests::new(chunk_len),
+    };
+    let mut writer = EncryptWriter::with_core(writer, core);
+    copy_zeroizing(&mut reader, &mut writer).await?;
+    poll_fn(|cx| Pin::new(&mut writer).poll_shutdown(cx)).await?;
+    let plaintext_len = writer.core.plaintext_bytes();
+    let (chunks, ciphertext_len) = writer.writer.digests.finish();
+    Ok(Manifest {
+        cipher,
+        key_id: key_id.cloned(),
+        chunk_len,
+        chunks,
+        plaintext_len,
+        ciphertext_len,
+    })
+}
+
+// Checks the ciphertext read from `reader` against `manifest`, failing with `ManifestMismatch`
+// naming the first chunk that differs, or `Truncated` if the blob is shorter than recorded. This
+// needs no key, so it says nothing about whether the manifest itself is authentic.
+pub async fn verify_manifest<R>(mut reader: R, manifest: &Manifest) -> Result<(), CryptoIoError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut digests = ChunkDigests::new(manifest.chunk_len);
+    let mut buf = vec![0; READ_CHUNK_LEN];
+    // chunks already compared
+    let mut checked = 0;
+    loop {
+        let n = poll_fn(|cx| Pin::new(&mut reader).poll_read(cx, &mut buf)).await?;
+        if n == 0 {
+            break;
+        }
+        digests.update(&buf[..n]);
+        check_chunks(&digests.chunks, &manifest.chunks, &mut checked)?;
+        if digests.total > manifest.ciphertext_len {
+            return Err(CryptoIoError::ManifestMismatch {
+                chunk: manifest.ciphertext_len / manifest.chunk_len,
+            });
+        }
+    }
+    let (chunks, total) = digests.finish();
+    if total < manifest.ciphertext_len {
+        return Err(CryptoIoError::Truncated);
+    }
+    check_chunks(&chunks, &manifest.chunks, &mut checked)
+}
+
+fn check_chunks(
+    actual: &[[u8; 32]],
+    expected: &[[u8; 32]],
+    checked: &mut usize,
+) -> Result<(), CryptoIoError> {
+    for (i, (a, e)) in actual.iter().zip(expected).enumerate().skip(*checked) {
+        if !memcmp::eq(a, e) {
+            return Err(CryptoIoError::ManifestMismatch { chunk: i as u64 });
+        }
+    }
+    *checked = actual.len();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::sha::sha256;
+    use openssl::symm::Cipher;
+
+    use super::{encrypt_with_manifest, verify_manifest, Manifest};
+    use crate::testing::{block_on, key_iv, read_to_end, sample};
+    use crate::{CryptoIoError, DecryptReader, EncryptCore, KeyId};
+
+    const CHUNK_LEN: u64 = 1000;
+
+    fn encrypt(plaintext: &[u8]) -> (Manifest, Vec<u8>) {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let core = EncryptCore::new(cipher, &key, iv.as_deref()).unwrap();
+        let mut out = Vec::new();
+        let key_id = KeyId::from("key-1");
+        let manifest = block_on(encrypt_with_manifest(
+            plaintext,
+            core,
+            &mut out,
+            Some(&key_id),
+            CHUNK_LEN,
+        ));
+        (manifest.unwrap(), out)
+    }
+
+    #[test]
+    fn describes_the_ciphertext() {
+        let plaintext = sample(4500);
+        let (manifest, ciphertext) = encrypt(&plaintext);
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let core = EncryptCore::new(cipher, &key, iv.as_deref()).unwrap();
+        assert_eq!(manifest.cipher, core.name());
+        assert_eq!(manifest.key_id, Some(KeyId::from("key-1")));
+        assert_eq!(manifest.plaintext_len, plaintext.len() as u64);
+        assert_eq!(manifest.ciphertext_len, ciphertext.len() as u64);
+        let chunks: Vec<_> = ciphertext.chunks(CHUNK_LEN as usize).map(sha256).collect();
+        assert_eq!(manifest.chunks, chunks);
+        block_on(verify_manifest(&ciphertext[..], &manifest)).unwrap();
+
+        let mut reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref()).unwrap();
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn json_round_trip() {
+        let (mut manifest, _) = encrypt(&sample(2500));
+        assert_eq!(Manifest::from_json(&manifest.to_json()).unwrap(), manifest);
+        manifest.key_id = None;
+        assert_eq!(Manifest::from_json(&manifest.to_json()).unwrap(), manifest);
+    }
+
+    #[test]
+    fn malformed_json() {
+        let (manifest, _) = encrypt(&sample(2500));
+        let json = manifest.to_json();
+        let mut missing_chunk = manifest.clone();
+        missing_chunk.chunks.pop();
+        let mut no_chunk_len = manifest.clone();
+        no_chunk_len.chunk_len = 0;
+        for bad in &[
+            "not json".to_owned(),
+            json.replace("\"version\":1", "\"version\":2"),
+            json.replace("\"key_id\":\"6b65792d31\"", "\"key_id\":\"6b65792d3\""),
+            missing_chunk.to_json(),
+            no_chunk_len.to_json(),
+        ] {
+            assert!(Manifest::from_json(bad).is_err(), "{}", bad);
+        }
+    }
+
+    fn verify(ciphertext: &[u8], manifest: &Manifest) -> Result<(), CryptoIoError> {
+        block_on(verify_manifest(ciphertext, manifest))
+    }
+
+    #[test]
+    fn finds_the_damaged_chunk() {
+        let (manifest, ciphertext) = encrypt(&sample(4500));
+        let mut damaged = ciphertext.clone();
+        damaged[2 * CHUNK_LEN as usize + 7] ^= 1;
+        assert!(matches!(
+            verify(&damaged, &manifest),
+            Err(CryptoIoError::ManifestMismatch { chunk: 2 })
+        ));
+        let last = manifest.chunks.len() as u64 - 1;
+        let extended = [&ciphertext[..], b"extra"].concat();
+        assert!(matches!(
+            verify(&extended, &manifest),
+            Err(CryptoIoError::ManifestMismatch { chunk }) if chunk == last
+        ));
+        // shorter than recorded, whether or not it ends on a chunk boundary
+        for &len in &[3 * CHUNK_LEN as usize, ciphertext.len() - 1] {
+            assert!(matches!(
+                verify(&ciphertext[..len], &manifest),
+                Err(CryptoIoError::Truncated)
+            ));
+        }
+    }
+}
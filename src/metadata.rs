@@ -0,0 +1,217 @@
+use std::collections::BTreeMap;
+use std::convert::TryInto;
+
+use crate::CryptoIoError;
+
+// Key/value pairs (a filename, content type, original length, ...) carried in the clear after
+// the stream header and bound into the AEAD tag or MAC, as written by
+// `EncryptWriter::with_metadata` and read back by `DecryptReader::with_metadata`.
+pub type Metadata = BTreeMap<String, Vec<u8>>;
+
+const LEN_BYTES: usize = 4;
+// so a corrupt length cannot make the reader buffer without bound
+const MAX_METADATA_LEN: usize = 1 << 20;
+
+// the metadata block: its length as a u32, then each entry as a u16 key length, the key, a u32
+// value length and the value, in key order
+pub(crate) fn encode(metadata: &Metadata) -> Result<Vec<u8>, CryptoIoError> {
+    let mut block = vec![0; LEN_BYTES];
+    for (key, value) in metadata {
+        if key.len() > u16::MAX as usize {
+            return Err(CryptoIoError::InvalidMetadata);
+        }
+        block.extend_from_slice(&(key.len() as u16).to_be_bytes());
+        block.extend_from_slice(key.as_bytes());
+        block.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        block.extend_from_slice(value);
+    }
+    let len = block.len() - LEN_BYTES;
+    if len > MAX_METADATA_LEN {
+        return Err(CryptoIoError::InvalidMetadata);
+    }
+    block[..LEN_BYTES].copy_from_slice(&(len as u32).to_be_bytes());
+    Ok(block)
+}
+
+// how much of the block `prefix` (its first bytes) needs before it is complete: just the length
+// until that has been read
+pub(crate) fn block_len(prefix: &[u8]) -> Result<usize, CryptoIoError> {
+    if prefix.len() < LEN_BYTES {
+        return Ok(LEN_BYTES);
+    }
+    let len = u32::from_be_bytes(prefix[..LEN_BYTES].try_into().unwrap()) as usize;
+    if len > MAX_METADATA_LEN {
+        return Err(CryptoIoError::InvalidMetadata);
+    }
+    Ok(LEN_BYTES + len)
+}
+
+pub(crate) fn decode(block: &[u8]) -> Result<Metadata, CryptoIoError> {
+    fn take<'a>(data: &mut &'a [u8], n: usize) -> Result<&'a [u8], CryptoIoError> {
+        if data.len() < n {
+            return Err(CryptoIoError::InvalidMetadata);
+        }
+        let (head, rest) = data.split_at(n);
+        *data = rest;
+        Ok(head)
+    }
+    let mut data = &block[LEN_BYTES..];
+    let mut metadata = Metadata::new();
+    while !data.is_empty() {
+        let key_len = u16::from_be_bytes(take(&mut data, 2)?.try_into().unwrap()) as usize;
+        let key = std::str::from_utf8(take(&mut data, key_len)?)
+            .map_err(|_| CryptoIoError::InvalidMetadata)?;
+        let value_len = u32::from_be_bytes(take(&mut data, 4)?.try_into().unwrap()) as usize;
+        let value = take(&mut data, value_len)?;
+        if metadata.insert(key.to_owned(), value.to_vec()).is_some() {
+            return Err(CryptoIoError::InvalidMetadata);
+        }
+    }
+    Ok(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Result as IoResult;
+
+    use openssl::symm::Cipher;
+
+    use super::{block_len, decode, encode, Metadata, MAX_METADATA_LEN};
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+    fn metadata() -> Metadata {
+        let mut metadata = Metadata::new();
+        metadata.insert("content-type".to_owned(), b"text/plain".to_vec());
+        metadata.insert("name".to_owned(), b"notes.txt".to_vec());
+        metadata.insert("empty".to_owned(), Vec::new());
+        metadata
+    }
+
+    #[test]
+    fn block_round_trip() {
+        let block = encode(&metadata()).unwrap();
+        assert_eq!(block_len(&block[..3]).unwrap(), 4);
+        assert_eq!(block_len(&block).unwrap(), block.len());
+        assert_eq!(decode(&block).unwrap(), metadata());
+        assert_eq!(encode(&Metadata::new()).unwrap(), [0; 4]);
+    }
+
+    #[test]
+    fn malformed_blocks() {
+        let block = encode(&metadata()).unwrap();
+        // a cut entry
+        assert!(decode(&block[..block.len() - 1]).is_err());
+        let mut bad_utf8 = block.clone();
+        bad_utf8[6] = 0xff;
+        assert!(decode(&bad_utf8).is_err());
+        // the same key twice
+        let mut one = Metadata::new();
+        one.insert("a".to_owned(), b"1".to_vec());
+        let entry = encode(&one).unwrap()[4..].to_vec();
+        let twice = [&[0, 0, 0, 2 * entry.len() as u8][..], &entry, &entry].concat();
+        assert!(matches!(
+            decode(&twice),
+            Err(CryptoIoError::InvalidMetadata)
+        ));
+        let too_long = ((MAX_METADATA_LEN + 1) as u32).to_be_bytes();
+        assert!(matches!(
+            block_len(&too_long),
+            Err(CryptoIoError::InvalidMetadata)
+        ));
+        let mut huge = Metadata::new();
+        huge.insert("a".to_owned(), vec![0; MAX_METADATA_LEN]);
+        assert!(encode(&huge).is_err());
+    }
+
+    fn encrypt(cipher: Cipher, hmac: bool, plaintext: &[u8]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::new(&mut out, cipher, &key, iv.as_deref()).unwrap();
+            if hmac {
+                writer = writer.with_hmac(b"mac key").unwrap();
+            }
+            let mut writer = writer.with_metadata(&metadata()).unwrap();
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn decrypt(
+        cipher: Cipher,
+        hmac: bool,
+        ciphertext: &[u8],
+    ) -> IoResult<(Option<Metadata>, Vec<u8>)> {
+        let (key, iv) = key_iv(cipher);
+        block_on(async {
+            let mut reader = DecryptReader::new(ciphertext, cipher, &key, iv.as_deref())?;
+            if hmac {
+                reader = reader.with_hmac(b"mac key")?;
+            }
+            let mut reader = reader.with_metadata();
+            let metadata = reader.read_metadata().await?.cloned();
+            let plaintext = read_to_end(&mut reader).await?;
+            assert_eq!(reader.metadata(), metadata.as_ref());
+            Ok((metadata, plaintext))
+        })
+    }
+
+    fn is_bad_tag(result: IoResult<(Option<Metadata>, Vec<u8>)>) -> bool {
+        match result {
+            Err(e) => matches!(
+                CryptoIoError::from_io(&e).map(|e| e.root()),
+                Some(CryptoIoError::BadTag)
+            ),
+            Ok(_) => false,
+        }
+    }
+
+    // AEAD tags and the HMAC both cover the block
+    const AUTHENTICATED: [(fn() -> Cipher, bool); 3] = [
+        (Cipher::aes_256_gcm, false),
+        (Cipher::chacha20_poly1305, false),
+        (Cipher::aes_256_cbc, true),
+    ];
+
+    #[test]
+    fn round_trip() {
+        let plaintext = sample(5000);
+        for &(cipher, hmac) in &AUTHENTICATED {
+            let ciphertext = encrypt(cipher(), hmac, &plaintext);
+            // in the clear, after the tag length byte of AEAD streams
+            let block = encode(&metadata()).unwrap();
+            let header = if hmac { 0 } else { 1 };
+            assert_eq!(&ciphertext[header..header + block.len()], &block[..]);
+            let (read, decrypted) = decrypt(cipher(), hmac, &ciphertext).unwrap();
+            assert_eq!(read, Some(metadata()));
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn tampered_metadata() {
+        for &(cipher, hmac) in &AUTHENTICATED {
+            let ciphertext = encrypt(cipher(), hmac, b"hello");
+            let header = if hmac { 0 } else { 1 };
+            let mut tampered = ciphertext.clone();
+            // the last byte of the "content-type" value
+            tampered[header + 4 + 2 + 12 + 4 + 9] ^= 1;
+            let result = decrypt(cipher(), hmac, &tampered);
+            assert!(is_bad_tag(result), "{:?}", cipher().nid());
+        }
+    }
+
+    #[test]
+    fn corrupt_metadata_length() {
+        let cipher = Cipher::aes_256_gcm();
+        let mut ciphertext = encrypt(cipher, false, b"hello");
+        ciphertext[1] = 0xff;
+        let err = decrypt(cipher, false, &ciphertext).unwrap_err();
+        assert!(matches!(
+            CryptoIoError::from_io(&err).map(|e| e.root()),
+            Some(CryptoIoError::InvalidMetadata)
+        ));
+    }
+}
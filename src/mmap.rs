@@ -0,0 +1,199 @@
+use std::fs::File;
+use std::io::Error as IoError;
+use std::sync::Arc;
+
+use memmap2::Mmap;
+use openssl::symm::{Cipher, Mode};
+use tokio::io::{AsyncWrite, AsyncWriteExt};
+use tokio::task::{spawn_blocking, JoinHandle};
+
+use crate::{check_nonce, CryptoIoError, OpensslBackend, SymmetricBackend};
+
+const SLICE_LEN: usize = 4 * 1024 * 1024;
+
+type Slice<B> = Result<(B, Vec<u8>), CryptoIoError>;
+
+fn process<B>(mut backend: B, map: Arc<Mmap>, start: usize) -> JoinHandle<Slice<B>>
+where
+    B: SymmetricBackend + Send + 'static,
+{
+    spawn_blocking(move || {
+        let mut out = vec![0; SLICE_LEN + backend.block_size()];
+        let len = if start < map.len() {
+            let end = (start + SLICE_LEN).min(map.len());
+            backend.update(&map[start..end], &mut out)?
+        } else {
+            backend.finalize(&mut out)?
+        };
+        out.truncate(len);
+        Ok((backend, out))
+    })
+}
+
+// Runs the whole of `file` through `backend` via a memory map and writes the output to `writer`,
+// returning the number of bytes written. The cipher work happens on blocking threads, one slice
+// at a time, overlapping with the write of the previous slice's output. The file must not be
+// modified while this runs.
+pub async fn process_mmap<B, W>(
+    file: &File,
+    mut writer: W,
+    backend: B,
+) -> Result<u64, CryptoIoError>
+where
+    B: SymmetricBackend + Send + 'static,
+    W: AsyncWrite + Unpin,
+{
+    let map = Arc::new(unsafe { Mmap::map(file)? });
+    let mut written = 0;
+    let mut start = 0;
+    let mut next = process(backend, map.clone(), start);
+    loop {
+        let (backend, out) = next.await.map_err(IoError::other)??;
+        let finished = start >= map.len();
+        start += SLICE_LEN;
+        let pending = if finished {
+            None
+        } else {
+            Some(process(backend, map.clone(), start))
+        };
+        writer.write_all(&out).await?;
+        written += out.len() as u64;
+        match pending {
+            Some(a) => next = a,
+            None => break,
+        }
+    }
+    writer.flush().await?;
+    Ok(written)
+}
+
+pub async fn encrypt_mmap<W>(
+    file: &File,
+    writer: W,
+    cipher: Cipher,
+    key: &[u8],
+    iv: Option<&[u8]>,
+) -> Result<u64, CryptoIoError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let backend = OpensslBackend::new(cipher, Mode::Encrypt, key, iv)?;
+    check_nonce(key, iv)?;
+    process_mmap(file, writer, backend).await
+}
+
+pub async fn decrypt_mmap<W>(
+    file: &File,
+    writer: W,
+    cipher: Cipher,
+    key: &[u8],
+    iv: Option<&[u8]>,
+) -> Result<u64, CryptoIoError>
+where
+    W: AsyncWrite + Unpin,
+{
+    let backend = OpensslBackend::new(cipher, Mode::Decrypt, key, iv)?;
+    process_mmap(file, writer, backend).await
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, File};
+    use std::path::PathBuf;
+    use std::process;
+
+    use openssl::symm::Cipher;
+
+    use super::{decrypt_mmap, encrypt_mmap, SLICE_LEN};
+    use crate::testing::{block_on, key_iv, read_to_end, sample};
+    use crate::{CryptoIoError, DecryptReader};
+
+    // removed again when dropped
+    struct TempFile(PathBuf);
+    impl TempFile {
+        fn new(name: &str, contents: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("{}-{}", name, process::id()));
+            fs::write(&path, contents).unwrap();
+            TempFile(path)
+        }
+
+        fn open(&self) -> File {
+            File::open(&self.0).unwrap()
+        }
+    }
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    fn runtime() -> tokio::runtime::Runtime {
+        tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .build()
+            .unwrap()
+    }
+
+    // the same stream as the adapters produce, over more than one slice
+    #[test]
+    fn round_trip() {
+        let cipher = Cipher::aes_256_cbc();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(SLICE_LEN + 100);
+        let file = TempFile::new("mmap-round-trip", &plaintext);
+        let mut ciphertext = Vec::new();
+        let mut rt = runtime();
+        let written = rt
+            .block_on(encrypt_mmap(
+                &file.open(),
+                &mut ciphertext,
+                cipher,
+                &key,
+                iv.as_deref(),
+            ))
+            .unwrap();
+        assert_eq!(written, ciphertext.len() as u64);
+        assert_eq!(ciphertext.len(), SLICE_LEN + 112);
+        let mut reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref()).unwrap();
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+
+        let file = TempFile::new("mmap-round-trip-ciphertext", &ciphertext);
+        let mut decrypted = Vec::new();
+        rt.block_on(decrypt_mmap(
+            &file.open(),
+            &mut decrypted,
+            cipher,
+            &key,
+            iv.as_deref(),
+        ))
+        .unwrap();
+        assert_eq!(decrypted, plaintext);
+    }
+
+    #[test]
+    fn bad_padding() {
+        let cipher = Cipher::aes_256_cbc();
+        let (key, iv) = key_iv(cipher);
+        let file = TempFile::new("mmap-bad-padding", &sample(100));
+        let mut rt = runtime();
+        let mut ciphertext = Vec::new();
+        rt.block_on(encrypt_mmap(
+            &file.open(),
+            &mut ciphertext,
+            cipher,
+            &key,
+            iv.as_deref(),
+        ))
+        .unwrap();
+        *ciphertext.last_mut().unwrap() ^= 1;
+        let file = TempFile::new("mmap-bad-padding-ciphertext", &ciphertext);
+        let res = rt.block_on(decrypt_mmap(
+            &file.open(),
+            Vec::new(),
+            cipher,
+            &key,
+            iv.as_deref(),
+        ));
+        assert!(matches!(res, Err(CryptoIoError::OpenSsl(_))));
+    }
+}
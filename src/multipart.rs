@@ -0,0 +1,197 @@
+use std::fmt;
+
+use openssl::symm::{Cipher, Mode};
+use zeroize::Zeroizing;
+
+use crate::cipher::tag_len_range;
+use crate::rng::fill_random;
+use crate::{
+    check_nonce, CryptoIoError, DecryptCore, EncryptCore, OpensslBackend, SymmetricBackend,
+};
+
+// Encrypts each part of a multipart/form-data upload on its own, as it streams, for use with
+// `multer` or axum's `Multipart`: feed every chunk from `Field::chunk` to the part's core. A
+// sealed part is a fresh random nonce, the ciphertext and the tag, with the part's name as
+// associated data, so a part cannot be passed off under another field's name.
+#[derive(Clone)]
+pub struct MultipartCipher {
+    cipher: Cipher,
+    key: Zeroizing<Vec<u8>>,
+}
+impl MultipartCipher {
+    pub fn new(cipher: Cipher, key: &[u8]) -> Result<Self, CryptoIoError> {
+        if tag_len_range(cipher).1 == 0 {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "multipart encryption needs an AEAD cipher",
+            )
+            .into());
+        }
+        if key.len() != cipher.key_len() {
+            return Err(CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_len(),
+                actual: key.len(),
+            });
+        }
+        Ok(MultipartCipher {
+            cipher,
+            key: Zeroizing::new(key.to_vec()),
+        })
+    }
+
+    fn nonce_len(&self) -> usize {
+        self.cipher.iv_len().unwrap_or(0)
+    }
+
+    // the bytes a sealed part adds to its contents
+    pub fn overhead(&self) -> usize {
+        self.nonce_len() + tag_len_range(self.cipher).1
+    }
+
+    // a core for the part named `name`, whose ciphertext starts with its nonce
+    pub fn encrypt_part(&self, name: &str) -> Result<EncryptCore, CryptoIoError> {
+        let mut nonce = vec![0; self.nonce_len()];
+        fill_random(&mut nonce)?;
+        let mut backend = OpensslBackend::new(self.cipher, Mode::Encrypt, &self.key, Some(&nonce))?;
+        check_nonce(&self.key, Some(&nonce))?;
+        backend.update_aad(name.as_bytes())?;
+        Ok(EncryptCore::with_backend(backend)
+            .without_header()
+            .with_prefix(&nonce))
+    }
+
+    pub fn decrypt_part(&self, name: &str) -> PartDecryptor {
+        PartDecryptor {
+            cipher: self.cipher,
+            key: self.key.clone(),
+            name: name.to_owned(),
+            nonce: Vec::new(),
+            core: None,
+        }
+    }
+}
+
+impl fmt::Debug for MultipartCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MultipartCipher")
+            .field("cipher", &crate::telemetry::cipher_name(self.cipher))
+            .finish_non_exhaustive()
+    }
+}
+
+// Decrypts one part sealed by `MultipartCipher::encrypt_part`, in whatever chunks it arrives;
+// the plaintext is only authentic once `finish` has succeeded.
+pub struct PartDecryptor {
+    cipher: Cipher,
+    key: Zeroizing<Vec<u8>>,
+    name: String,
+    // until the whole nonce has arrived and the core can be set up
+    nonce: Vec<u8>,
+    core: Option<DecryptCore>,
+}
+impl PartDecryptor {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn push_ciphertext(&mut self, mut data: &[u8]) -> Result<(), CryptoIoError> {
+        if self.core.is_none() {
+            let nonce_len = self.cipher.iv_len().unwrap_or(0);
+            let n = (nonce_len - self.nonce.len()).min(data.len());
+            self.nonce.extend_from_slice(&data[..n]);
+            data = &data[n..];
+            if self.nonce.len() < nonce_len {
+                return Ok(());
+            }
+            let mut backend =
+                OpensslBackend::new(self.cipher, Mode::Decrypt, &self.key, Some(&self.nonce))?;
+            backend.update_aad(self.name.as_bytes())?;
+            self.core = Some(DecryptCore::with_backend(backend).without_header());
+        }
+        self.core.as_mut().unwrap().push_ciphertext(data)
+    }
+
+    // called at the end of the part; fails with `BadTag` if it was altered, renamed or cut short
+    pub fn finish(&mut self) -> Result<(), CryptoIoError> {
+        match &mut self.core {
+            Some(core) => core.finish(),
+            None => Err(CryptoIoError::Truncated),
+        }
+    }
+
+    pub fn take_plaintext(&mut self) -> Vec<u8> {
+        match &mut self.core {
+            Some(core) => core.take_plaintext(),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Cipher;
+
+    use super::MultipartCipher;
+    use crate::testing::sample;
+    use crate::CryptoIoError;
+
+    fn seal(parts: &MultipartCipher, name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut core = parts.encrypt_part(name).unwrap();
+        for chunk in contents.chunks(777) {
+            core.push_plaintext(chunk).unwrap();
+        }
+        core.finish().unwrap();
+        core.take_ciphertext()
+    }
+
+    // in small chunks, so the nonce also arrives in pieces
+    fn open(parts: &MultipartCipher, name: &str, sealed: &[u8]) -> Result<Vec<u8>, CryptoIoError> {
+        let mut part = parts.decrypt_part(name);
+        assert_eq!(part.name(), name);
+        let mut out = Vec::new();
+        for chunk in sealed.chunks(5) {
+            part.push_ciphertext(chunk)?;
+            out.extend(part.take_plaintext());
+        }
+        part.finish()?;
+        out.extend(part.take_plaintext());
+        Ok(out)
+    }
+
+    #[test]
+    fn round_trip() {
+        let parts = MultipartCipher::new(Cipher::aes_256_gcm(), &[7; 32]).unwrap();
+        for len in [0, 1, 10_000] {
+            let contents = sample(len);
+            let sealed = seal(&parts, "file", &contents);
+            assert_eq!(sealed.len(), len + parts.overhead());
+            assert_eq!(open(&parts, "file", &sealed).unwrap(), contents);
+        }
+    }
+
+    #[test]
+    fn rejects_bad_parts() {
+        let parts = MultipartCipher::new(Cipher::chacha20_poly1305(), &[7; 32]).unwrap();
+        let sealed = seal(&parts, "avatar", &sample(1000));
+        assert!(matches!(
+            open(&parts, "resume", &sealed),
+            Err(CryptoIoError::BadTag)
+        ));
+        let mut tampered = sealed.clone();
+        tampered[500] ^= 1;
+        assert!(matches!(
+            open(&parts, "avatar", &tampered),
+            Err(CryptoIoError::BadTag)
+        ));
+        assert!(open(&parts, "avatar", &sealed[..sealed.len() - 1]).is_err());
+        assert!(matches!(
+            open(&parts, "avatar", &sealed[..5]),
+            Err(CryptoIoError::Truncated)
+        ));
+        assert!(MultipartCipher::new(Cipher::aes_256_ctr(), &[7; 32]).is_err());
+        assert!(matches!(
+            MultipartCipher::new(Cipher::aes_256_gcm(), &[7; 16]),
+            Err(CryptoIoError::InvalidKeyLen { .. })
+        ));
+    }
+}
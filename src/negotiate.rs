@@ -0,0 +1,318 @@
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use openssl::symm::Cipher;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::telemetry::cipher_name;
+use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+// Helpers for rolling out encrypted HTTP bodies gradually, negotiated through an
+// Accept-Encoding/Content-Encoding style header (such as a custom `X-Encryption`) that names
+// schemes by tokens the server chooses, e.g. `aes-256-gcm`. They take header values as `&str`
+// (`HeaderValue::to_str` in hyper and axum), so clients that send nothing keep getting plain
+// bodies.
+
+// the token for a body sent as is
+pub const IDENTITY: &str = "identity";
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Negotiated<'a> {
+    Identity,
+    // the offered token chosen, and its cipher
+    Encrypted(&'a str, Cipher),
+}
+impl<'a> Negotiated<'a> {
+    // the token to send back in the response header
+    pub fn token(&self) -> &'a str {
+        match self {
+            Negotiated::Identity => IDENTITY,
+            Negotiated::Encrypted(token, _) => token,
+        }
+    }
+
+    // wraps a response body writer to match
+    pub fn wrap_writer<W>(
+        &self,
+        writer: W,
+        key: &[u8],
+        iv: Option<&[u8]>,
+    ) -> Result<MaybeEncrypted<W>, CryptoIoError> {
+        Ok(match *self {
+            Negotiated::Identity => MaybeEncrypted::Plain(writer),
+            Negotiated::Encrypted(_, cipher) => {
+                MaybeEncrypted::Encrypted(Box::new(EncryptWriter::new(writer, cipher, key, iv)?))
+            }
+        })
+    }
+
+    // wraps a request body reader to match
+    pub fn wrap_reader<R>(
+        &self,
+        reader: R,
+        key: &[u8],
+        iv: Option<&[u8]>,
+    ) -> Result<MaybeDecrypted<R>, CryptoIoError> {
+        Ok(match *self {
+            Negotiated::Identity => MaybeDecrypted::Plain(reader),
+            Negotiated::Encrypted(_, cipher) => {
+                MaybeDecrypted::Decrypted(Box::new(DecryptReader::new(reader, cipher, key, iv)?))
+            }
+        })
+    }
+}
+
+impl fmt::Debug for Negotiated<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Negotiated::Identity => write!(f, "Identity"),
+            Negotiated::Encrypted(token, cipher) => f
+                .debug_tuple("Encrypted")
+                .field(token)
+                .field(&cipher_name(*cipher))
+                .finish(),
+        }
+    }
+}
+
+// the token and quality of each entry in a comma-separated header value such as
+// `aes-256-gcm;q=0.8, identity;q=0.1`; entries with an unreadable quality count as q=0
+fn preferences(header: &str) -> impl Iterator<Item = (&str, f32)> {
+    header
+        .split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(|item| {
+            let mut params = item.split(';').map(str::trim);
+            let token = params.next().unwrap_or("");
+            let q = params
+                .find_map(|p| p.strip_prefix("q=").or_else(|| p.strip_prefix("Q=")))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .filter(|q| (0.0..=1.0).contains(q))
+                .unwrap_or(0.0);
+            (token, q)
+        })
+}
+
+// Picks how to send a response from the preferences in `accept` (the request's header value, if
+// any) among the `offered` tokens, in the server's order of preference. A cipher the client
+// accepts wins over identity unless identity is listed with a higher quality, and `*` stands
+// for anything not listed. Returns None when the client has ruled out identity and accepts none
+// of the offered ciphers, which calls for a 406.
+pub fn negotiate<'a>(
+    accept: Option<&str>,
+    offered: &[(&'a str, Cipher)],
+) -> Option<Negotiated<'a>> {
+    let accept = match accept {
+        Some(a) => a,
+        None => return Some(Negotiated::Identity),
+    };
+    let quality = |token: &str| {
+        let mut wildcard = None;
+        for (t, q) in preferences(accept) {
+            if t.eq_ignore_ascii_case(token) {
+                return Some(q);
+            }
+            if t == "*" {
+                wildcard = Some(q);
+            }
+        }
+        wildcard
+    };
+    let mut best: Option<(Negotiated<'a>, f32)> = None;
+    for &(token, cipher) in offered {
+        if let Some(q) = quality(token).filter(|&q| q > 0.0) {
+            if best.map_or(true, |(_, b)| q > b) {
+                best = Some((Negotiated::Encrypted(token, cipher), q));
+            }
+        }
+    }
+    // identity is the last resort unless the client lists it, or refuses it outright
+    let identity = quality(IDENTITY);
+    match best {
+        Some((n, q)) if identity.map_or(true, |i| q >= i) => Some(n),
+        _ if identity != Some(0.0) => Some(Negotiated::Identity),
+        _ => None,
+    }
+}
+
+// Reads the scheme a request body was sent with from its header value, if any, failing with
+// `InvalidInput` for a token that is not offered (a 415).
+pub fn body_scheme<'a>(
+    header: Option<&str>,
+    offered: &[(&'a str, Cipher)],
+) -> Result<Negotiated<'a>, CryptoIoError> {
+    let token = match header.map(str::trim) {
+        None => return Ok(Negotiated::Identity),
+        Some(token) if token.eq_ignore_ascii_case(IDENTITY) => return Ok(Negotiated::Identity),
+        Some(token) => token,
+    };
+    match offered.iter().find(|(t, _)| t.eq_ignore_ascii_case(token)) {
+        Some(&(t, cipher)) => Ok(Negotiated::Encrypted(t, cipher)),
+        None => Err(IoError::new(
+            IoErrorKind::InvalidInput,
+            format!("unsupported body encryption {:?}", token),
+        )
+        .into()),
+    }
+}
+
+// a body writer that encrypts or not, as negotiated
+pub enum MaybeEncrypted<W> {
+    Plain(W),
+    // boxed, being much larger than most writers
+    Encrypted(Box<EncryptWriter<W>>),
+}
+
+impl<W> AsyncWrite for MaybeEncrypted<W>
+where
+    W: AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        unsafe {
+            match self.get_unchecked_mut() {
+                MaybeEncrypted::Plain(w) => Pin::new_unchecked(w).poll_write(cx, buf),
+                MaybeEncrypted::Encrypted(w) => Pin::new_unchecked(&mut **w).poll_write(cx, buf),
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe {
+            match self.get_unchecked_mut() {
+                MaybeEncrypted::Plain(w) => Pin::new_unchecked(w).poll_flush(cx),
+                MaybeEncrypted::Encrypted(w) => Pin::new_unchecked(&mut **w).poll_flush(cx),
+            }
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe {
+            match self.get_unchecked_mut() {
+                MaybeEncrypted::Plain(w) => Pin::new_unchecked(w).poll_shutdown(cx),
+                MaybeEncrypted::Encrypted(w) => Pin::new_unchecked(&mut **w).poll_shutdown(cx),
+            }
+        }
+    }
+}
+
+// a body reader that decrypts or not, as negotiated
+pub enum MaybeDecrypted<R> {
+    Plain(R),
+    Decrypted(Box<DecryptReader<R>>),
+}
+
+impl<R> AsyncRead for MaybeDecrypted<R>
+where
+    R: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        unsafe {
+            match self.get_unchecked_mut() {
+                MaybeDecrypted::Plain(r) => Pin::new_unchecked(r).poll_read(cx, buf),
+                MaybeDecrypted::Decrypted(r) => Pin::new_unchecked(&mut **r).poll_read(cx, buf),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Cipher;
+
+    use super::{body_scheme, negotiate, Negotiated};
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::CryptoIoError;
+
+    fn offered() -> [(&'static str, Cipher); 2] {
+        [
+            ("aes-256-gcm", Cipher::aes_256_gcm()),
+            ("chacha20-poly1305", Cipher::chacha20_poly1305()),
+        ]
+    }
+
+    fn token(accept: Option<&str>) -> Option<&'static str> {
+        negotiate(accept, &offered()).map(|n| n.token())
+    }
+
+    #[test]
+    fn picks_by_quality() {
+        assert_eq!(token(None), Some("identity"));
+        assert_eq!(token(Some("")), Some("identity"));
+        assert_eq!(token(Some("gzip")), Some("identity"));
+        assert_eq!(token(Some("chacha20-poly1305")), Some("chacha20-poly1305"));
+        assert_eq!(
+            token(Some("AES-256-GCM, chacha20-poly1305")),
+            Some("aes-256-gcm")
+        );
+        assert_eq!(
+            token(Some("aes-256-gcm;q=0.5, chacha20-poly1305;q=0.9")),
+            Some("chacha20-poly1305")
+        );
+        assert_eq!(token(Some("*")), Some("aes-256-gcm"));
+        assert_eq!(
+            token(Some("*;q=0.5, aes-256-gcm;q=0")),
+            Some("chacha20-poly1305")
+        );
+        // identity only wins when the client ranks it higher
+        assert_eq!(
+            token(Some("aes-256-gcm;q=0.5, identity;q=0.5")),
+            Some("aes-256-gcm")
+        );
+        assert_eq!(
+            token(Some("aes-256-gcm;q=0.5, identity;q=0.8")),
+            Some("identity")
+        );
+        // unreadable qualities count as zero
+        assert_eq!(token(Some("aes-256-gcm;q=high")), Some("identity"));
+        assert_eq!(token(Some("aes-256-gcm;q=2")), Some("identity"));
+        // nothing acceptable
+        assert_eq!(token(Some("gzip, identity;q=0")), None);
+        assert_eq!(token(Some("*;q=0")), None);
+    }
+
+    #[test]
+    fn body_schemes() {
+        let offered = offered();
+        assert_eq!(body_scheme(None, &offered).unwrap(), Negotiated::Identity);
+        assert_eq!(
+            body_scheme(Some(" Identity "), &offered).unwrap(),
+            Negotiated::Identity
+        );
+        assert_eq!(
+            body_scheme(Some("aes-256-gcm"), &offered).unwrap(),
+            Negotiated::Encrypted("aes-256-gcm", Cipher::aes_256_gcm())
+        );
+        assert!(matches!(
+            body_scheme(Some("rot13"), &offered),
+            Err(CryptoIoError::Io(e)) if e.kind() == std::io::ErrorKind::InvalidInput
+        ));
+    }
+
+    #[test]
+    fn wrapped_bodies_round_trip() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(10_000);
+        for scheme in [
+            Negotiated::Identity,
+            Negotiated::Encrypted("aes-256-gcm", cipher),
+        ] {
+            let mut body = Vec::new();
+            block_on(async {
+                let mut writer = scheme.wrap_writer(&mut body, &key, iv.as_deref()).unwrap();
+                write_all(&mut writer, &plaintext).await.unwrap();
+                shutdown(&mut writer).await.unwrap();
+            });
+            assert_eq!(body == plaintext, scheme == Negotiated::Identity);
+            let mut reader = scheme.wrap_reader(&body[..], &key, iv.as_deref()).unwrap();
+            assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+        }
+    }
+}
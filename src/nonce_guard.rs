@@ -0,0 +1,121 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::Mutex;
+
+use openssl::hash::{hash, MessageDigest};
+use openssl::rand::rand_bytes;
+use zeroize::Zeroize;
+
+use crate::CryptoIoError;
+
+struct Guard {
+    // keeps the fingerprints from being usable as key hashes outside this process
+    salt: [u8; 32],
+    capacity: usize,
+    seen: HashSet<Vec<u8>>,
+    // any repeat is an error rather than a use, so least recently used is oldest inserted
+    order: VecDeque<Vec<u8>>,
+}
+impl Guard {
+    fn new(capacity: usize) -> Result<Self, CryptoIoError> {
+        let mut salt = [0; 32];
+        rand_bytes(&mut salt)?;
+        Ok(Guard {
+            salt,
+            capacity,
+            seen: HashSet::new(),
+            order: VecDeque::new(),
+        })
+    }
+
+    fn check(&mut self, key: &[u8], iv: &[u8]) -> Result<(), CryptoIoError> {
+        let mut input = self.salt.to_vec();
+        input.extend_from_slice(&(key.len() as u64).to_be_bytes());
+        input.extend_from_slice(key);
+        input.extend_from_slice(iv);
+        let fingerprint = hash(MessageDigest::sha256(), &input);
+        input.zeroize();
+        let fingerprint = fingerprint?.to_vec();
+        if !self.seen.insert(fingerprint.clone()) {
+            event!(
+                tracing::Level::ERROR,
+                "IV reused for encryption under the same key"
+            );
+            return Err(CryptoIoError::NonceReuse);
+        }
+        self.order.push_back(fingerprint);
+        while self.order.len() > self.capacity {
+            if let Some(a) = self.order.pop_front() {
+                self.seen.remove(&a);
+            }
+        }
+        Ok(())
+    }
+}
+
+static GUARD: Mutex<Option<Guard>> = Mutex::new(None);
+
+// Starts remembering the (key, IV) pairs passed to the encrypting constructors, so that using
+// the same pair twice fails with `NonceReuse` instead of silently destroying the security of
+// GCM and the stream modes. Only salted fingerprints of the last `capacity` pairs are kept, so
+// reuse further apart than that goes unnoticed; this is a development and staging aid, not a
+// guarantee. Calling it again clears what has been remembered.
+pub fn enable_nonce_guard(capacity: usize) -> Result<(), CryptoIoError> {
+    let guard = Guard::new(capacity)?;
+    *GUARD.lock().unwrap_or_else(|e| e.into_inner()) = Some(guard);
+    Ok(())
+}
+
+pub fn disable_nonce_guard() {
+    *GUARD.lock().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+// Records `key` and `iv` as used for encryption, failing if the guard is enabled and has seen
+// them before. `EncryptWriter::new`, `EncryptCore::new` and `encrypt_mmap` call this; code that
+// keys its own backend for `with_backend` should call it too.
+pub fn check_nonce(key: &[u8], iv: Option<&[u8]>) -> Result<(), CryptoIoError> {
+    let iv = match iv {
+        Some(iv) => iv,
+        None => return Ok(()),
+    };
+    let mut guard = GUARD.lock().unwrap_or_else(|e| e.into_inner());
+    match &mut *guard {
+        Some(guard) => guard.check(key, iv),
+        None => Ok(()),
+    }
+}
+
+// exercised on a local guard: enabling the global one would fail the other tests, which reuse
+// their keys and IVs freely
+#[cfg(test)]
+mod tests {
+    use super::Guard;
+    use crate::CryptoIoError;
+
+    #[test]
+    fn rejects_reuse() {
+        let mut guard = Guard::new(100).unwrap();
+        guard.check(&[1; 32], &[2; 12]).unwrap();
+        guard.check(&[1; 32], &[3; 12]).unwrap();
+        guard.check(&[4; 32], &[2; 12]).unwrap();
+        assert!(matches!(
+            guard.check(&[1; 32], &[2; 12]),
+            Err(CryptoIoError::NonceReuse)
+        ));
+        // the key length is part of the fingerprint, so the split between key and IV matters
+        guard
+            .check(&[1; 31], &[1, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2, 2])
+            .unwrap();
+    }
+
+    #[test]
+    fn forgets_the_oldest() {
+        let mut guard = Guard::new(2).unwrap();
+        for iv in 0..3 {
+            guard.check(&[1; 32], &[iv; 12]).unwrap();
+        }
+        guard.check(&[1; 32], &[0; 12]).unwrap();
+        assert!(guard.check(&[1; 32], &[2; 12]).is_err());
+        // salted per guard, so a new one starts out knowing nothing
+        assert!(Guard::new(2).unwrap().check(&[1; 32], &[2; 12]).is_ok());
+    }
+}
@@ -0,0 +1,47 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+use keyring::Entry;
+use openssl::symm::Cipher;
+use zeroize::Zeroizing;
+
+use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+// Looks up the secret stored in the platform credential store (the macOS keychain, Windows
+// credential manager or Linux kernel keyring) under `service` and `name`, as raw bytes.
+pub fn keyring_key(service: &str, name: &str) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+    let secret = Entry::new(service, name)
+        .and_then(|entry| entry.get_secret())
+        .map_err(|e| {
+            let kind = match e {
+                keyring::Error::NoEntry => IoErrorKind::NotFound,
+                keyring::Error::NoStorageAccess(_) => IoErrorKind::PermissionDenied,
+                _ => IoErrorKind::Other,
+            };
+            IoError::new(kind, e)
+        })?;
+    Ok(Zeroizing::new(secret))
+}
+
+impl<W> EncryptWriter<W> {
+    pub fn from_keyring(
+        writer: W,
+        cipher: Cipher,
+        service: &str,
+        name: &str,
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError> {
+        Self::new(writer, cipher, &keyring_key(service, name)?, iv)
+    }
+}
+
+impl<R> DecryptReader<R> {
+    pub fn from_keyring(
+        reader: R,
+        cipher: Cipher,
+        service: &str,
+        name: &str,
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError> {
+        Self::new(reader, cipher, &keyring_key(service, name)?, iv)
+    }
+}
@@ -0,0 +1,399 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::CryptoIoError;
+
+// Pads plaintext before it reaches an `EncryptWriter`, so the ciphertext length gives away only
+// which size class the plaintext falls in, and strips it again after a `DecryptReader`. The
+// padding is a 0x80 byte followed by zeros (ISO/IEC 7816-4), so the reader needs no
+// configuration; it holds back a trailing 0x80 and any zeros after it until it sees more data or
+// the end of the stream.
+
+const MARKER: u8 = 0x80;
+const ZEROS: [u8; 256] = [0; 256];
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Padding {
+    // up to a multiple of this many bytes
+    Multiple(u64),
+    // up to the smallest of these sizes that fits, or a multiple of the largest beyond them
+    Buckets(Vec<u64>),
+    // Padmé (from "Reducing Metadata Leakage from Encrypted Files and Communication with PURBs"),
+    // which leaks O(log log n) bits of the length and adds at most about 12% overhead
+    Padme,
+}
+impl Padding {
+    // the length of a padded stream holding `len` bytes, including the marker byte
+    pub fn padded_len(&self, len: u64) -> u64 {
+        let len = len + 1;
+        match self {
+            Padding::Multiple(n) => round_up(len, *n),
+            Padding::Buckets(sizes) => match sizes.iter().copied().filter(|&b| b >= len).min() {
+                Some(a) => a,
+                None => round_up(len, sizes.iter().copied().max().unwrap_or(1)),
+            },
+            Padding::Padme => padme(len),
+        }
+    }
+}
+
+fn round_up(len: u64, multiple: u64) -> u64 {
+    len.div_ceil(multiple.max(1)) * multiple.max(1)
+}
+
+// rounds `len` up so that only its top floor(log2(floor(log2 len))) + 1 bits can be nonzero
+fn padme(len: u64) -> u64 {
+    if len < 2 {
+        return len;
+    }
+    let e = 63 - len.leading_zeros() as u64;
+    let s = 64 - e.leading_zeros() as u64;
+    let mask = (1 << (e - s)) - 1;
+    (len + mask) & !mask
+}
+
+pub struct PadWriter<W> {
+    writer: W,
+    padding: Padding,
+    len: u64,
+    // padding still to write, once shutdown has started
+    remaining: Option<u64>,
+}
+impl<W> PadWriter<W> {
+    pub fn new(writer: W, padding: Padding) -> Self {
+        PadWriter {
+            writer,
+            padding,
+            len: 0,
+            remaining: None,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+impl<W> AsyncWrite for PadWriter<W>
+where
+    W: AsyncWrite,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            if inner.remaining.is_some() {
+                return Poll::Ready(Err(CryptoIoError::Finalized.into()));
+            }
+            match Pin::new_unchecked(&mut inner.writer).poll_write(cx, buf) {
+                Poll::Ready(Ok(n)) => {
+                    inner.len += n as u64;
+                    Poll::Ready(Ok(n))
+                }
+                a => a,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe { Pin::new_unchecked(&mut self.get_unchecked_mut().writer).poll_flush(cx) }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            let total = inner.padding.padded_len(inner.len) - inner.len;
+            let remaining = inner.remaining.get_or_insert(total);
+            while *remaining > 0 {
+                let chunk = if *remaining == total {
+                    &[MARKER][..]
+                } else {
+                    &ZEROS[..(*remaining).min(ZEROS.len() as u64) as usize]
+                };
+                match Pin::new_unchecked(&mut inner.writer).poll_write(cx, chunk) {
+                    Poll::Ready(Ok(0)) => {
+                        return Poll::Ready(Err(IoError::new(
+                            IoErrorKind::WriteZero,
+                            "failed to write padding",
+                        )))
+                    }
+                    Poll::Ready(Ok(n)) => *remaining -= n as u64,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            Pin::new_unchecked(&mut inner.writer).poll_shutdown(cx)
+        }
+    }
+}
+
+// a trailing marker and the zeros after it, which are padding if the stream ends here
+#[derive(Clone, Copy)]
+struct Held {
+    marker: bool,
+    zeros: u64,
+}
+
+pub struct UnpadReader<R> {
+    reader: R,
+    input: Box<[u8]>,
+    pos: usize,
+    len: usize,
+    eof: bool,
+    held: Option<Held>,
+    // the held bytes turned out to be data and are being handed out
+    releasing: bool,
+}
+impl<R> UnpadReader<R> {
+    pub fn new(reader: R) -> Self {
+        UnpadReader {
+            reader,
+            input: vec![0; 16 * 1024].into_boxed_slice(),
+            pos: 0,
+            len: 0,
+            eof: false,
+            held: None,
+            releasing: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R> UnpadReader<R>
+where
+    R: AsyncRead,
+{
+    // hands out held bytes that were data after all
+    fn release(&mut self, buf: &mut [u8]) -> usize {
+        let held = self.held.as_mut().unwrap();
+        let n = if held.marker {
+            buf[0] = MARKER;
+            held.marker = false;
+            1
+        } else {
+            let n = held.zeros.min(buf.len() as u64) as usize;
+            buf[..n].iter_mut().for_each(|b| *b = 0);
+            held.zeros -= n as u64;
+            n
+        };
+        if !held.marker && held.zeros == 0 {
+            self.held = None;
+            self.releasing = false;
+        }
+        n
+    }
+
+    // self must be pinned
+    unsafe fn read_impl(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        loop {
+            if self.releasing {
+                return Poll::Ready(Ok(self.release(buf)));
+            }
+            let data = &self.input[self.pos..self.len];
+            if data.is_empty() {
+                if self.eof {
+                    if self.held.is_none() {
+                        return Poll::Ready(Err(IoError::new(
+                            IoErrorKind::InvalidData,
+                            "stream is not padded",
+                        )));
+                    }
+                    return Poll::Ready(Ok(0));
+                }
+                match Pin::new_unchecked(&mut self.reader).poll_read(cx, &mut self.input) {
+                    Poll::Ready(Ok(n)) => {
+                        self.pos = 0;
+                        self.len = n;
+                        self.eof = n == 0;
+                        continue;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            let last = data.iter().rposition(|&b| b != 0);
+            if let Some(held) = &mut self.held {
+                match last {
+                    None => {
+                        held.zeros += data.len() as u64;
+                        self.pos = self.len;
+                    }
+                    Some(_) => self.releasing = true,
+                }
+                continue;
+            }
+            // everything before a trailing marker is data, as is everything if there is none
+            let end = match last {
+                Some(i) if data[i] == MARKER => i,
+                _ => data.len(),
+            };
+            if end == 0 {
+                self.held = Some(Held {
+                    marker: true,
+                    zeros: data.len() as u64 - 1,
+                });
+                self.pos = self.len;
+                continue;
+            }
+            let n = end.min(buf.len());
+            buf[..n].copy_from_slice(&data[..n]);
+            self.pos += n;
+            return Poll::Ready(Ok(n));
+        }
+    }
+}
+
+impl<R> AsyncRead for UnpadReader<R>
+where
+    R: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        unsafe { self.get_unchecked_mut().read_impl(cx, buf) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Cipher;
+
+    use super::{PadWriter, Padding, UnpadReader, MARKER};
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+    #[test]
+    fn padded_lengths() {
+        let multiple = Padding::Multiple(16);
+        assert_eq!(multiple.padded_len(0), 16);
+        assert_eq!(multiple.padded_len(15), 16);
+        assert_eq!(multiple.padded_len(16), 32);
+        let buckets = Padding::Buckets(vec![1000, 100]);
+        assert_eq!(buckets.padded_len(50), 100);
+        assert_eq!(buckets.padded_len(99), 100);
+        assert_eq!(buckets.padded_len(100), 1000);
+        assert_eq!(buckets.padded_len(2500), 3000);
+        assert_eq!(Padding::Padme.padded_len(0), 1);
+        assert_eq!(Padding::Padme.padded_len(99), 104);
+        assert_eq!(Padding::Padme.padded_len(1000), 1024);
+        for len in (0..1_000_000).step_by(997) {
+            let padded = Padding::Padme.padded_len(len);
+            assert!(padded > len && padded as f64 <= (len + 1) as f64 * 1.12 + 1.0);
+        }
+    }
+
+    fn pad(padding: Padding, plaintext: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = PadWriter::new(&mut out, padding);
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    // plaintexts that end like padding does, including across the reader's 16 KiB reads
+    fn plaintexts() -> Vec<Vec<u8>> {
+        let mut res = vec![
+            Vec::new(),
+            vec![MARKER],
+            vec![0; 3],
+            vec![1, MARKER, 0, 0],
+            sample(100),
+        ];
+        for &len in &[16 * 1024 - 1, 16 * 1024, 16 * 1024 + 1] {
+            let mut data = sample(len - 2);
+            data.extend_from_slice(&[MARKER, 0]);
+            res.push(data);
+        }
+        res
+    }
+
+    #[test]
+    fn round_trip() {
+        for padding in &[
+            Padding::Multiple(1),
+            Padding::Multiple(4096),
+            Padding::Buckets(vec![10, 20_000]),
+            Padding::Padme,
+        ] {
+            for plaintext in plaintexts() {
+                let padded = pad(padding.clone(), &plaintext);
+                assert_eq!(
+                    padded.len() as u64,
+                    padding.padded_len(plaintext.len() as u64)
+                );
+                let unpadded = block_on(read_to_end(&mut UnpadReader::new(&padded[..])));
+                assert_eq!(unpadded.unwrap(), plaintext, "{:?}", padding);
+            }
+        }
+    }
+
+    #[test]
+    fn encrypted_round_trip() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        for plaintext in plaintexts() {
+            let mut out = Vec::new();
+            block_on(async {
+                let writer = EncryptWriter::new(&mut out, cipher, &key, iv.as_deref()).unwrap();
+                let mut writer = writer.padded(Padding::Multiple(1024));
+                write_all(&mut writer, &plaintext).await.unwrap();
+                shutdown(&mut writer).await.unwrap();
+            });
+            let padded_len = Padding::Multiple(1024).padded_len(plaintext.len() as u64);
+            assert_eq!(out.len() as u64, 1 + padded_len + 16);
+            let reader = DecryptReader::new(&out[..], cipher, &key, iv.as_deref()).unwrap();
+            let decrypted = block_on(read_to_end(&mut reader.unpadded())).unwrap();
+            assert_eq!(decrypted, plaintext);
+        }
+    }
+
+    #[test]
+    fn not_padded() {
+        for data in &[&b""[..], b"abc", b"abc\0\0"] {
+            let err = block_on(read_to_end(&mut UnpadReader::new(*data))).unwrap_err();
+            assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        }
+    }
+
+    #[test]
+    fn write_after_shutdown() {
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = PadWriter::new(&mut out, Padding::Padme);
+            shutdown(&mut writer).await.unwrap();
+            let err = write_all(&mut writer, b"late").await.unwrap_err();
+            assert!(matches!(
+                CryptoIoError::from_io(&err),
+                Some(CryptoIoError::Finalized)
+            ));
+        });
+    }
+}
@@ -0,0 +1,302 @@
+use std::future::{poll_fn, Future};
+use std::io::Result as IoResult;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::{Bytes, BytesMut};
+use openssl::sha::sha256;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::reencrypt::copy_zeroizing;
+use crate::{CryptoIoError, EncryptCore, EncryptWriter, SymmetricBackend};
+
+// An `AsyncWrite` sink that cuts what is written to it into parts of exactly `part_len` bytes,
+// except for a shorter last part, and hands each to `upload` along with its index (counting from
+// 0; S3 part numbers are the index plus one). Put it under an `EncryptWriter` to upload the
+// ciphertext as a multipart object. Only one part is buffered and in flight at a time: writes
+// wait for the previous upload to finish, and `shutdown` sends the last part (an empty one if
+// nothing was written, since a multipart upload needs at least one part) and waits for it.
+pub struct PartWriter<F, Fut> {
+    part_len: usize,
+    buf: BytesMut,
+    index: u64,
+    upload: F,
+    // boxed so the writer stays `Unpin` for async block futures
+    pending: Option<Pin<Box<Fut>>>,
+    finished: bool,
+}
+impl<F, Fut> PartWriter<F, Fut>
+where
+    F: FnMut(u64, Bytes) -> Fut,
+    Fut: Future<Output = IoResult<()>>,
+{
+    pub fn new(part_len: usize, upload: F) -> Self {
+        assert!(part_len > 0, "part length must be nonzero");
+        PartWriter {
+            part_len,
+            buf: BytesMut::with_capacity(part_len),
+            index: 0,
+            upload,
+            pending: None,
+            finished: false,
+        }
+    }
+
+    // the number of parts handed to `upload` so far
+    pub fn parts(&self) -> u64 {
+        self.index
+    }
+
+    fn start_part(&mut self) {
+        let part = self.buf.split().freeze();
+        self.pending = Some(Box::pin((self.upload)(self.index, part)));
+        self.index += 1;
+        if !self.finished {
+            self.buf.reserve(self.part_len);
+        }
+    }
+
+    fn poll_pending(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        if let Some(fut) = &mut self.pending {
+            let res = match fut.as_mut().poll(cx) {
+                Poll::Ready(res) => res,
+                Poll::Pending => return Poll::Pending,
+            };
+            self.pending = None;
+            if let Err(e) = res {
+                event!(tracing::Level::ERROR, error = %e, part = self.index - 1, "part upload failed");
+                return Poll::Ready(Err(e));
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<F, Fut> AsyncWrite for PartWriter<F, Fut>
+where
+    F: FnMut(u64, Bytes) -> Fut,
+    Fut: Future<Output = IoResult<()>>,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            match inner.poll_pending(cx) {
+                Poll::Ready(Ok(())) => (),
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+            if inner.finished {
+                return Poll::Ready(Err(CryptoIoError::Finalized.into()));
+            }
+            let n = buf.len().min(inner.part_len - inner.buf.len());
+            inner.buf.extend_from_slice(&buf[..n]);
+            if inner.buf.len() == inner.part_len {
+                inner.start_part();
+            }
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    // waits for the part in flight; a partial part is held until it fills or the stream ends
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe { self.get_unchecked_mut().poll_pending(cx) }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            match inner.poll_pending(cx) {
+                Poll::Ready(Ok(())) => (),
+                a => return a,
+            }
+            if !inner.finished {
+                inner.finished = true;
+                if !inner.buf.is_empty() || inner.index == 0 {
+                    inner.start_part();
+                }
+            }
+            inner.poll_pending(cx)
+        }
+    }
+}
+
+// The destination of a multipart upload, such as an S3 or GCS multipart upload already created
+// by the caller. Methods take `&self` so uploads can share a client; implementations can be
+// written with `async fn`.
+pub trait PartSink {
+    // `index` counts from 0; `sha256` is the digest of `part`, e.g. for S3's
+    // x-amz-checksum-sha256 so the store rejects a part corrupted in transit
+    fn upload_part(
+        &self,
+        index: u64,
+        part: Bytes,
+        sha256: [u8; 32],
+    ) -> impl Future<Output = IoResult<()>> + Send;
+
+    // called once every part has uploaded
+    fn complete(&self) -> impl Future<Output = IoResult<()>> + Send;
+
+    // called instead of `complete` after any error, to discard the uploaded parts
+    fn abort(&self) -> impl Future<Output = IoResult<()>> + Send;
+}
+
+// Encrypts `reader` with `core` straight into `sink` as parts of `part_len` bytes (the last may
+// be shorter), one part in flight at a time, then completes the upload. On any error the upload
+// is aborted and the original error returned. Returns the number of parts.
+pub async fn encrypt_to_parts<R, S, B>(
+    mut reader: R,
+    core: EncryptCore<B>,
+    sink: &S,
+    part_len: usize,
+) -> IoResult<u64>
+where
+    R: AsyncRead + Unpin,
+    S: PartSink,
+    B: SymmetricBackend + Unpin,
+{
+    let parts = PartWriter::new(part_len, |index, part: Bytes| {
+        let sha256 = sha256(&part);
+        sink.upload_part(index, part, sha256)
+    });
+    let mut writer = EncryptWriter::with_core(parts, core);
+    let res = async {
+        copy_zeroizing(&mut reader, &mut writer).await?;
+        poll_fn(|cx| Pin::new(&mut writer).poll_shutdown(cx)).await?;
+        sink.complete().await
+    }
+    .await;
+    match res {
+        Ok(()) => Ok(writer.writer.parts()),
+        Err(e) => {
+            event!(tracing::Level::ERROR, error = %e, "multipart upload failed, aborting");
+            if let Err(_e) = sink.abort().await {
+                event!(tracing::Level::ERROR, error = %_e, "failed to abort multipart upload");
+            }
+            Err(e)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+    use std::sync::Mutex;
+
+    use bytes::Bytes;
+    use openssl::sha::sha256;
+    use openssl::symm::Cipher;
+
+    use super::{encrypt_to_parts, PartSink, PartWriter};
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptCore};
+
+    fn cut(part_len: usize, data: &[u8]) -> Vec<(u64, Bytes)> {
+        let parts = RefCell::new(Vec::new());
+        block_on(async {
+            let mut writer = PartWriter::new(part_len, |index, part| {
+                parts.borrow_mut().push((index, part));
+                async { Ok(()) }
+            });
+            write_all(&mut writer, data).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+            assert_eq!(writer.parts(), parts.borrow().len() as u64);
+            let e = write_all(&mut writer, b"more").await.unwrap_err();
+            let root = CryptoIoError::from_io(&e).map(|e| e.root());
+            assert!(matches!(root, Some(CryptoIoError::Finalized)));
+        });
+        parts.into_inner()
+    }
+
+    #[test]
+    fn cuts_fixed_size_parts() {
+        let data = sample(2500);
+        let parts = cut(1000, &data);
+        let lens: Vec<_> = parts.iter().map(|(i, p)| (*i, p.len())).collect();
+        assert_eq!(lens, [(0, 1000), (1, 1000), (2, 500)]);
+        let joined: Vec<u8> = parts.iter().flat_map(|(_, p)| p.to_vec()).collect();
+        assert_eq!(joined, data);
+        // no empty part after a full one
+        assert_eq!(cut(1000, &data[..2000]).len(), 2);
+        // but one for an empty stream
+        let parts = cut(1000, &[]);
+        assert_eq!(parts.len(), 1);
+        assert!(parts[0].1.is_empty());
+    }
+
+    #[test]
+    fn upload_errors() {
+        let mut writer = PartWriter::new(10, |index, _| async move {
+            match index {
+                1 => Err(IoError::new(IoErrorKind::ConnectionReset, "upload failed")),
+                _ => Ok(()),
+            }
+        });
+        // the failure shows on the write after the part was started
+        let e = block_on(write_all(&mut writer, &sample(25))).unwrap_err();
+        assert_eq!(e.kind(), IoErrorKind::ConnectionReset);
+        assert_eq!(writer.parts(), 2);
+    }
+
+    // records what it is sent, failing the upload of part `fail_at`
+    #[derive(Default)]
+    struct Sink {
+        fail_at: Option<u64>,
+        parts: Mutex<Vec<Bytes>>,
+        completed: Mutex<bool>,
+        aborted: Mutex<bool>,
+    }
+    impl PartSink for Sink {
+        async fn upload_part(&self, index: u64, part: Bytes, digest: [u8; 32]) -> IoResult<()> {
+            assert_eq!(digest, sha256(&part));
+            if self.fail_at == Some(index) {
+                return Err(IoError::new(IoErrorKind::ConnectionReset, "upload failed"));
+            }
+            let mut parts = self.parts.lock().unwrap();
+            assert_eq!(index, parts.len() as u64);
+            parts.push(part);
+            Ok(())
+        }
+
+        async fn complete(&self) -> IoResult<()> {
+            *self.completed.lock().unwrap() = true;
+            Ok(())
+        }
+
+        async fn abort(&self) -> IoResult<()> {
+            *self.aborted.lock().unwrap() = true;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn encrypts_to_parts() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(10_000);
+        let sink = Sink::default();
+        let core = EncryptCore::new(cipher, &key, iv.as_deref()).unwrap();
+        let parts = block_on(encrypt_to_parts(&plaintext[..], core, &sink, 4096)).unwrap();
+        // a tag length byte, the ciphertext and the tag
+        assert_eq!(parts, 3);
+        assert!(*sink.completed.lock().unwrap() && !*sink.aborted.lock().unwrap());
+        let ciphertext = sink.parts.into_inner().unwrap().concat();
+        assert_eq!(ciphertext.len(), 1 + 10_000 + 16);
+        let mut reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref()).unwrap();
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn aborts_on_error() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let sink = Sink {
+            fail_at: Some(1),
+            ..Sink::default()
+        };
+        let core = EncryptCore::new(cipher, &key, iv.as_deref()).unwrap();
+        let e = block_on(encrypt_to_parts(&sample(10_000)[..], core, &sink, 4096)).unwrap_err();
+        assert_eq!(e.kind(), IoErrorKind::ConnectionReset);
+        assert!(!*sink.completed.lock().unwrap() && *sink.aborted.lock().unwrap());
+    }
+}
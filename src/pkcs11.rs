@@ -0,0 +1,164 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::sync::{Arc, Mutex};
+
+use cryptoki::context::{CInitializeArgs, Pkcs11};
+use cryptoki::error::{Error as Pkcs11Error, RvError};
+use cryptoki::mechanism::Mechanism;
+use cryptoki::object::{Attribute, AttributeType, KeyType, ObjectClass, ObjectHandle};
+use cryptoki::session::{Session, UserType};
+use cryptoki::types::AuthPin;
+use tokio::task::spawn_blocking;
+use zeroize::Zeroizing;
+
+use crate::{CryptoIoError, KeyId, KeyProvider};
+
+fn pkcs11_err(e: Pkcs11Error) -> CryptoIoError {
+    let kind = match e {
+        Pkcs11Error::Pkcs11(RvError::PinIncorrect, _)
+        | Pkcs11Error::Pkcs11(RvError::PinLocked, _)
+        | Pkcs11Error::Pkcs11(RvError::UserNotLoggedIn, _) => IoErrorKind::PermissionDenied,
+        Pkcs11Error::Pkcs11(RvError::WrappedKeyInvalid, _)
+        | Pkcs11Error::Pkcs11(RvError::WrappedKeyLenRange, _) => IoErrorKind::InvalidData,
+        _ => IoErrorKind::Other,
+    };
+    IoError::new(kind, e).into()
+}
+
+// Unwraps content keys inside a PKCS#11 token (an HSM or smartcard) with an AES key-encryption
+// key that never leaves it, for environments where that key must stay in certified hardware.
+// `generate_key` creates a content key and its wrapped form (AES key wrap with padding, RFC
+// 5649); the wrapped form is the `KeyId` that `key_for` later unwraps. Content keys only exist in
+// the token as session objects, destroyed as soon as their value has been read out. Token calls
+// block, so they run on tokio's blocking pool, one at a time.
+pub struct Pkcs11Provider {
+    session: Arc<Mutex<Session>>,
+    kek: ObjectHandle,
+}
+impl Pkcs11Provider {
+    // loads the PKCS#11 module at `module` (e.g. `/usr/lib/softhsm/libsofthsm2.so`), logs in to
+    // the token labelled `token_label` with the user PIN and looks up the AES key labelled
+    // `kek_label` on it
+    pub fn open(
+        module: &str,
+        token_label: &str,
+        pin: &str,
+        kek_label: &str,
+    ) -> Result<Self, CryptoIoError> {
+        let pkcs11 = Pkcs11::new(module).map_err(pkcs11_err)?;
+        match pkcs11.initialize(CInitializeArgs::OsThreads) {
+            Ok(()) | Err(Pkcs11Error::AlreadyInitialized) => (),
+            Err(e) => return Err(pkcs11_err(e)),
+        }
+        let mut slot = None;
+        for s in pkcs11.get_slots_with_token().map_err(pkcs11_err)? {
+            if pkcs11.get_token_info(s).map_err(pkcs11_err)?.label() == token_label {
+                slot = Some(s);
+                break;
+            }
+        }
+        let slot = slot.ok_or_else(|| {
+            IoError::new(
+                IoErrorKind::NotFound,
+                format!("no PKCS#11 token labelled {:?}", token_label),
+            )
+        })?;
+        let session = pkcs11.open_ro_session(slot).map_err(pkcs11_err)?;
+        session
+            .login(UserType::User, Some(&AuthPin::new(pin.to_owned())))
+            .map_err(pkcs11_err)?;
+        let kek = session
+            .find_objects(&[
+                Attribute::Class(ObjectClass::SECRET_KEY),
+                Attribute::KeyType(KeyType::AES),
+                Attribute::Label(kek_label.as_bytes().to_vec()),
+            ])
+            .map_err(pkcs11_err)?
+            .into_iter()
+            .next()
+            .ok_or_else(|| {
+                IoError::new(
+                    IoErrorKind::NotFound,
+                    format!("no AES key labelled {:?} on the token", kek_label),
+                )
+            })?;
+        Ok(Pkcs11Provider {
+            session: Arc::new(Mutex::new(session)),
+            kek,
+        })
+    }
+
+    async fn with_session<T, F>(&self, f: F) -> Result<T, CryptoIoError>
+    where
+        T: Send + 'static,
+        F: FnOnce(&Session, ObjectHandle) -> Result<T, Pkcs11Error> + Send + 'static,
+    {
+        let session = self.session.clone();
+        let kek = self.kek;
+        spawn_blocking(move || {
+            let session = session.lock().unwrap_or_else(|e| e.into_inner());
+            f(&session, kek).map_err(pkcs11_err)
+        })
+        .await
+        .map_err(IoError::other)?
+    }
+
+    // a fresh `key_len`-byte content key, generated in the token, and its wrapped form to store
+    // alongside the data
+    pub async fn generate_key(
+        &self,
+        key_len: usize,
+    ) -> Result<(Zeroizing<Vec<u8>>, KeyId), CryptoIoError> {
+        self.with_session(move |session, kek| {
+            let key = session.generate_key(
+                &Mechanism::AesKeyGen,
+                &[
+                    Attribute::Class(ObjectClass::SECRET_KEY),
+                    Attribute::KeyType(KeyType::AES),
+                    Attribute::ValueLen((key_len as u64).into()),
+                    Attribute::Token(false),
+                    Attribute::Sensitive(false),
+                    Attribute::Extractable(true),
+                ],
+            )?;
+            let res = session
+                .wrap_key(&Mechanism::AesKeyWrapPad, kek, key)
+                .and_then(|wrapped| Ok((read_value(session, key)?, KeyId::from(wrapped))));
+            session.destroy_object(key)?;
+            res
+        })
+        .await
+    }
+}
+
+fn read_value(session: &Session, key: ObjectHandle) -> Result<Zeroizing<Vec<u8>>, Pkcs11Error> {
+    for attribute in session.get_attributes(key, &[AttributeType::Value])? {
+        if let Attribute::Value(value) = attribute {
+            return Ok(Zeroizing::new(value));
+        }
+    }
+    Err(Pkcs11Error::InvalidValue)
+}
+
+impl KeyProvider for Pkcs11Provider {
+    async fn key_for(&self, key_id: &KeyId) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+        let wrapped = key_id.as_bytes().to_vec();
+        self.with_session(move |session, kek| {
+            let key = session.unwrap_key(
+                &Mechanism::AesKeyWrapPad,
+                kek,
+                &wrapped,
+                &[
+                    Attribute::Class(ObjectClass::SECRET_KEY),
+                    Attribute::KeyType(KeyType::AES),
+                    Attribute::Token(false),
+                    Attribute::Sensitive(false),
+                    Attribute::Extractable(true),
+                ],
+            )?;
+            let res = read_value(session, key);
+            session.destroy_object(key)?;
+            res
+        })
+        .await
+    }
+}
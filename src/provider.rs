@@ -0,0 +1,130 @@
+use openssl::cipher::Cipher;
+use openssl::cipher_ctx::CipherCtx;
+use openssl::lib_ctx::LibCtxRef;
+use openssl::symm::Mode;
+
+use crate::{CryptoIoError, SymmetricBackend};
+
+// A cipher fetched by name through the OpenSSL 3 provider API, e.g. `fetch(None, "AES-256-CBC",
+// Some("fips=yes"), ..)`, so the implementation comes from whichever provider (default, fips,
+// legacy) the library context and property query select. Load non-default providers with
+// `openssl::provider::Provider` beforehand.
+pub struct ProviderBackend {
+    name: &'static str,
+    ctx: CipherCtx,
+}
+impl ProviderBackend {
+    pub fn fetch(
+        lib_ctx: Option<&LibCtxRef>,
+        algorithm: &str,
+        properties: Option<&str>,
+        mode: Mode,
+        key: &[u8],
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError> {
+        let cipher = Cipher::fetch(lib_ctx, algorithm, properties)?;
+        if key.len() != cipher.key_length() {
+            return Err(CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_length(),
+                actual: key.len(),
+            });
+        }
+        let iv_len = iv.map_or(0, <[u8]>::len);
+        if iv_len != cipher.iv_length() {
+            return Err(CryptoIoError::InvalidIvLen {
+                expected: cipher.iv_length(),
+                actual: iv_len,
+            });
+        }
+        let iv = iv.filter(|_| iv_len > 0);
+        let mut ctx = CipherCtx::new()?;
+        match mode {
+            Mode::Encrypt => ctx.encrypt_init(Some(&cipher), Some(key), iv)?,
+            Mode::Decrypt => ctx.decrypt_init(Some(&cipher), Some(key), iv)?,
+        }
+        Ok(ProviderBackend {
+            name: cipher.nid().short_name().unwrap_or("unknown"),
+            ctx,
+        })
+    }
+}
+
+impl SymmetricBackend for ProviderBackend {
+    fn update(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        Ok(self.ctx.cipher_update(input, Some(output))?)
+    }
+
+    fn finalize(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        Ok(self.ctx.cipher_final(output)?)
+    }
+
+    fn block_size(&self) -> usize {
+        self.ctx.block_size()
+    }
+
+    fn name(&self) -> &'static str {
+        self.name
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::{Cipher, Mode};
+
+    use super::ProviderBackend;
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+    #[test]
+    fn matches_openssl() {
+        let cipher = Cipher::aes_256_cbc();
+        let (key, iv) = key_iv(cipher);
+        let plaintext = sample(2500);
+        let mut expected = Vec::new();
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer =
+                EncryptWriter::new(&mut expected, cipher, &key, iv.as_deref()).unwrap();
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+            let backend = ProviderBackend::fetch(
+                None,
+                "AES-256-CBC",
+                None,
+                Mode::Encrypt,
+                &key,
+                iv.as_deref(),
+            )
+            .unwrap();
+            let mut writer = EncryptWriter::with_backend(&mut out, backend);
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        assert_eq!(out, expected);
+        let backend = ProviderBackend::fetch(
+            None,
+            "AES-256-CBC",
+            None,
+            Mode::Decrypt,
+            &key,
+            iv.as_deref(),
+        )
+        .unwrap();
+        let mut reader = DecryptReader::with_backend(&out[..], backend);
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn fetch_errors() {
+        let res = ProviderBackend::fetch(None, "NOT-A-CIPHER", None, Mode::Encrypt, &[0; 32], None);
+        assert!(matches!(res, Err(CryptoIoError::OpenSsl(_))));
+        let res = ProviderBackend::fetch(None, "AES-256-CTR", None, Mode::Encrypt, &[0; 32], None);
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidIvLen {
+                expected: 16,
+                actual: 0
+            })
+        ));
+    }
+}
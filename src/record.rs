@@ -0,0 +1,678 @@
+use std::convert::TryInto;
+use std::fmt;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use openssl::symm::{encrypt_aead, Cipher, Crypter, Mode};
+use tokio::io::{AsyncRead, AsyncWrite};
+use zeroize::Zeroizing;
+
+use crate::cipher::tag_len_range;
+use crate::error::Poison;
+use crate::mac::hkdf_expand;
+use crate::telemetry::{cipher_name, record_tag_failure};
+use crate::CryptoIoError;
+
+// The sealed records each direction of an `EncryptedStream` (and of `tunnel`) is sent as. A
+// record is a 5-byte header (the flags, then the plaintext length as a u32), the ciphertext and
+// the AEAD tag. Record `n` of a direction is sealed under the nonce `prefix || n`, where the prefix
+// is the direction's IV less its last 8 bytes and `n` a big-endian u64, with `n` and the header as
+// associated data. The counter never wraps: a direction that reaches its last value fails with
+// `RecordLimit` instead. Each record is checked before any of its plaintext is released, and one
+// that has been altered, dropped, reordered or replayed fails its tag. The last record carries the
+// FINAL flag, so a direction that stops without one is `Truncated`, and a reader can tell a
+// clean end from a cut connection. A record with the REKEY flag carries no data: after it, the
+// direction goes on under a key derived from the last one, numbered from 0 again, so a long-lived
+// stream can move off a key (and forget it) before it reaches the record limit.
+
+const HEADER_LEN: usize = 5;
+const COUNTER_LEN: usize = 8;
+// plaintext bytes per record, at most
+const MAX_RECORD_LEN: usize = 16 * 1024;
+// set on the last record of a direction
+const FINAL: u8 = 1;
+// set on an empty record after which the direction moves to its next key
+const REKEY: u8 = 2;
+const REKEY_LABEL: &[u8] = b"tokio-openssl-symm record rekey";
+
+// a direction's key and nonce prefix, and the number of the next record
+struct RecordKeys {
+    cipher: Cipher,
+    key: Zeroizing<Vec<u8>>,
+    prefix: Vec<u8>,
+    counter: u64,
+}
+impl RecordKeys {
+    fn new(cipher: Cipher, key: &[u8], iv: Option<&[u8]>) -> Result<Self, CryptoIoError> {
+        let iv_len = cipher.iv_len().unwrap_or(0);
+        if tag_len_range(cipher).1 == 0 || iv_len < COUNTER_LEN {
+            return Err(IoError::new(
+                IoErrorKind::InvalidInput,
+                "sealed records need an AEAD cipher",
+            )
+            .into());
+        }
+        if key.len() != cipher.key_len() {
+            return Err(CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_len(),
+                actual: key.len(),
+            });
+        }
+        let iv = match iv {
+            Some(iv) if iv.len() == iv_len => iv,
+            iv => {
+                return Err(CryptoIoError::InvalidIvLen {
+                    expected: iv_len,
+                    actual: iv.map_or(0, <[u8]>::len),
+                })
+            }
+        };
+        Ok(RecordKeys {
+            cipher,
+            key: Zeroizing::new(key.to_vec()),
+            prefix: iv[..iv_len - COUNTER_LEN].to_vec(),
+            counter: 0,
+        })
+    }
+
+    fn tag_len(&self) -> usize {
+        tag_len_range(self.cipher).1
+    }
+
+    // the nonce of the next record, refused once the counter has nowhere left to go: the last
+    // value is never used, so the counter cannot wrap around to a nonce already sealed under
+    fn nonce(&self) -> Result<Vec<u8>, CryptoIoError> {
+        if self.counter == u64::MAX {
+            return Err(CryptoIoError::RecordLimit);
+        }
+        Ok([&self.prefix[..], &self.counter.to_be_bytes()].concat())
+    }
+
+    // the key that follows this one, from which this one cannot be recovered
+    fn next_key(&self) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+        Ok(hkdf_expand(&self.key, &[REKEY_LABEL], self.key.len())?)
+    }
+
+    fn rekey(&mut self, key: Zeroizing<Vec<u8>>) {
+        self.key = key;
+        self.counter = 0;
+    }
+
+    fn aad(&self, header: &[u8]) -> Vec<u8> {
+        [&self.counter.to_be_bytes()[..], header].concat()
+    }
+}
+
+// Encrypts what is written to it into sealed records, up to `MAX_RECORD_LEN` bytes each: a record
+// goes out once it is full or on flush, and `shutdown` sends the final one.
+pub(crate) struct RecordWriter<W> {
+    writer: W,
+    keys: RecordKeys,
+    // plaintext waiting to be sealed
+    pending: Zeroizing<Vec<u8>>,
+    // sealed records not yet written out
+    sealed: Vec<u8>,
+    plaintext_bytes: u64,
+    ciphertext_bytes: u64,
+    // the final record has been sealed
+    finished: bool,
+    poison: Poison,
+}
+impl<W> RecordWriter<W> {
+    pub(crate) fn new(
+        writer: W,
+        cipher: Cipher,
+        key: &[u8],
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError> {
+        Ok(RecordWriter {
+            writer,
+            keys: RecordKeys::new(cipher, key, iv)?,
+            pending: Zeroizing::new(Vec::new()),
+            sealed: Vec::new(),
+            plaintext_bytes: 0,
+            ciphertext_bytes: 0,
+            finished: false,
+            poison: Poison::default(),
+        })
+    }
+
+    // seals what is pending as the next record
+    fn seal(&mut self, flags: u8) -> Result<(), CryptoIoError> {
+        let mut header = [flags, 0, 0, 0, 0];
+        header[1..].copy_from_slice(&(self.pending.len() as u32).to_be_bytes());
+        let keys = &self.keys;
+        let mut tag = vec![0; keys.tag_len()];
+        let ciphertext = encrypt_aead(
+            keys.cipher,
+            &keys.key,
+            Some(&keys.nonce()?),
+            &keys.aad(&header),
+            &self.pending,
+            &mut tag,
+        )?;
+        self.sealed.extend_from_slice(&header);
+        self.sealed.extend_from_slice(&ciphertext);
+        self.sealed.extend_from_slice(&tag);
+        self.pending.clear();
+        self.keys.counter += 1;
+        Ok(())
+    }
+
+    // seals what is pending, then a rekey record, and moves on to the next key; the records go
+    // out with the next write, flush or shutdown
+    pub(crate) fn rekey(&mut self) -> Result<(), CryptoIoError> {
+        self.poison.check()?;
+        if self.finished {
+            return Err(CryptoIoError::Closed);
+        }
+        let key = self.keys.next_key()?;
+        if !self.pending.is_empty() {
+            self.seal(0)?;
+        }
+        self.seal(REKEY)?;
+        self.keys.rekey(key);
+        Ok(())
+    }
+}
+
+impl<W> RecordWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write_sealed(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        while !self.sealed.is_empty() {
+            match Pin::new(&mut self.writer).poll_write(cx, &self.sealed) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(IoErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => {
+                    self.sealed.drain(..n);
+                    self.ciphertext_bytes += n as u64;
+                }
+                Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    fn write_impl(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        if buf.is_empty() {
+            return Poll::Ready(Ok(0));
+        }
+        match self.poll_write_sealed(cx) {
+            Poll::Ready(Ok(())) => (),
+            res => return res.map_ok(|()| 0),
+        }
+        let n = buf.len().min(MAX_RECORD_LEN - self.pending.len());
+        self.pending.extend_from_slice(&buf[..n]);
+        self.plaintext_bytes += n as u64;
+        if self.pending.len() == MAX_RECORD_LEN {
+            self.seal(0)?;
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn flush_impl(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        if !self.pending.is_empty() && !self.finished {
+            self.seal(0)?;
+        }
+        match self.poll_write_sealed(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.writer).poll_flush(cx),
+            res => res,
+        }
+    }
+
+    fn shutdown_impl(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        if !self.finished {
+            self.seal(FINAL)?;
+            self.finished = true;
+        }
+        match self.poll_write_sealed(cx) {
+            Poll::Ready(Ok(())) => Pin::new(&mut self.writer).poll_shutdown(cx),
+            res => res,
+        }
+    }
+}
+
+impl<W> fmt::Debug for RecordWriter<W> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordWriter")
+            .field("cipher", &cipher_name(self.keys.cipher))
+            .field("records", &self.keys.counter)
+            .field("plaintext_bytes", &self.plaintext_bytes)
+            .field("ciphertext_bytes", &self.ciphertext_bytes)
+            .field("finished", &self.finished)
+            .field("poisoned", &self.poison.is_poisoned())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<W> AsyncWrite for RecordWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<IoResult<usize>> {
+        let inner = self.get_mut();
+        if let Err(e) = inner.poison.check() {
+            return Poll::Ready(Err(e));
+        }
+        if inner.finished {
+            return Poll::Ready(Err(CryptoIoError::Closed.into()));
+        }
+        let res = inner.write_impl(cx, buf);
+        inner
+            .poison
+            .track(res, inner.plaintext_bytes, inner.ciphertext_bytes)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let inner = self.get_mut();
+        if let Err(e) = inner.poison.check() {
+            return Poll::Ready(Err(e));
+        }
+        let res = inner.flush_impl(cx);
+        inner
+            .poison
+            .track(res, inner.plaintext_bytes, inner.ciphertext_bytes)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<IoResult<()>> {
+        let inner = self.get_mut();
+        if let Err(e) = inner.poison.check() {
+            return Poll::Ready(Err(e));
+        }
+        let res = inner.shutdown_impl(cx);
+        inner
+            .poison
+            .track(res, inner.plaintext_bytes, inner.ciphertext_bytes)
+    }
+}
+
+// Opens the records a `RecordWriter` sends, handing out each one's plaintext only once its tag
+// has been checked. Reads EOF after the final record; an error after that point, or a stream
+// that ends without one, poisons the reader.
+pub(crate) struct RecordReader<R> {
+    reader: R,
+    keys: RecordKeys,
+    // the record being read in, header first
+    record: Vec<u8>,
+    filled: usize,
+    // the plaintext of the last record opened, and how much of it has been read
+    plaintext: Zeroizing<Vec<u8>>,
+    pos: usize,
+    plaintext_bytes: u64,
+    ciphertext_bytes: u64,
+    // the final record has been opened
+    finished: bool,
+    poison: Poison,
+}
+impl<R> RecordReader<R> {
+    pub(crate) fn new(
+        reader: R,
+        cipher: Cipher,
+        key: &[u8],
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError> {
+        Ok(RecordReader {
+            reader,
+            keys: RecordKeys::new(cipher, key, iv)?,
+            record: Vec::new(),
+            filled: 0,
+            plaintext: Zeroizing::new(Vec::new()),
+            pos: 0,
+            plaintext_bytes: 0,
+            ciphertext_bytes: 0,
+            finished: false,
+            poison: Poison::default(),
+        })
+    }
+
+    // the length of the record being read in, once its header is
+    fn record_len(&self) -> Result<usize, CryptoIoError> {
+        if self.filled < HEADER_LEN {
+            return Ok(HEADER_LEN);
+        }
+        let len = u32::from_be_bytes(self.record[1..HEADER_LEN].try_into().unwrap()) as usize;
+        if len > MAX_RECORD_LEN {
+            return Err(IoError::new(IoErrorKind::InvalidData, "record too long").into());
+        }
+        Ok(HEADER_LEN + len + self.keys.tag_len())
+    }
+
+    // opens the record that has been read in
+    fn open(&mut self) -> Result<(), CryptoIoError> {
+        let keys = &self.keys;
+        let (header, rest) = self.record.split_at(HEADER_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - keys.tag_len());
+        let mut crypter =
+            Crypter::new(keys.cipher, Mode::Decrypt, &keys.key, Some(&keys.nonce()?))?;
+        crypter.aad_update(&keys.aad(header))?;
+        let mut plaintext = Zeroizing::new(vec![0; ciphertext.len() + keys.cipher.block_size()]);
+        let count = crypter.update(ciphertext, &mut plaintext)?;
+        crypter.set_tag(tag)?;
+        match crypter.finalize(&mut plaintext[count..]) {
+            Ok(n) => plaintext.truncate(count + n),
+            Err(_) => {
+                event!(
+                    tracing::Level::ERROR,
+                    record = keys.counter,
+                    "record failed to authenticate"
+                );
+                record_tag_failure(cipher_name(keys.cipher));
+                return Err(CryptoIoError::BadTag);
+            }
+        }
+        let flags = header[0];
+        if flags & !(FINAL | REKEY) != 0 {
+            return Err(IoError::new(IoErrorKind::InvalidData, "unknown record flags").into());
+        }
+        if flags & REKEY != 0 {
+            if !plaintext.is_empty() {
+                return Err(
+                    IoError::new(IoErrorKind::InvalidData, "rekey record with data").into(),
+                );
+            }
+            let key = self.keys.next_key()?;
+            self.keys.rekey(key);
+        } else {
+            self.keys.counter += 1;
+        }
+        self.finished = flags & FINAL != 0;
+        self.plaintext = plaintext;
+        self.pos = 0;
+        self.filled = 0;
+        Ok(())
+    }
+}
+
+impl<R> RecordReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn read_impl(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        loop {
+            if self.pos < self.plaintext.len() {
+                let n = buf.len().min(self.plaintext.len() - self.pos);
+                buf[..n].copy_from_slice(&self.plaintext[self.pos..self.pos + n]);
+                self.pos += n;
+                self.plaintext_bytes += n as u64;
+                return Poll::Ready(Ok(n));
+            }
+            if self.finished || buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            let len = self.record_len()?;
+            self.record.resize(len, 0);
+            while self.filled < len {
+                let dst = &mut self.record[self.filled..len];
+                match Pin::new(&mut self.reader).poll_read(cx, dst) {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(CryptoIoError::Truncated.into())),
+                    Poll::Ready(Ok(n)) => {
+                        self.filled += n;
+                        self.ciphertext_bytes += n as u64;
+                    }
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            // with just the header in, go round again for the rest of the record
+            if self.filled > HEADER_LEN {
+                self.open()?;
+            }
+        }
+    }
+}
+
+impl<R> fmt::Debug for RecordReader<R> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecordReader")
+            .field("cipher", &cipher_name(self.keys.cipher))
+            .field("records", &self.keys.counter)
+            .field("plaintext_bytes", &self.plaintext_bytes)
+            .field("ciphertext_bytes", &self.ciphertext_bytes)
+            .field("finished", &self.finished)
+            .field("poisoned", &self.poison.is_poisoned())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<R> AsyncRead for RecordReader<R>
+where
+    R: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        let inner = self.get_mut();
+        if let Err(e) = inner.poison.check() {
+            return Poll::Ready(Err(e));
+        }
+        let res = inner.read_impl(cx, buf);
+        inner
+            .poison
+            .track(res, inner.plaintext_bytes, inner.ciphertext_bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::future::poll_fn;
+    use std::io::Result as IoResult;
+    use std::pin::Pin;
+
+    use openssl::symm::Cipher;
+    use tokio::io::AsyncWrite;
+
+    use super::{RecordReader, RecordWriter, HEADER_LEN, MAX_RECORD_LEN};
+    use crate::testing::{block_on, key_iv, read_to_end, sample, shutdown, write_all};
+    use crate::CryptoIoError;
+
+    const TAG_LEN: usize = 16;
+
+    // writes each chunk as a record of its own, then the (empty) final one
+    fn seal(cipher: Cipher, chunks: &[&[u8]]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let mut writer = RecordWriter::new(Vec::new(), cipher, &key, iv.as_deref()).unwrap();
+        block_on(async {
+            for chunk in chunks {
+                write_all(&mut writer, chunk).await.unwrap();
+                poll_fn(|cx| Pin::new(&mut writer).poll_flush(cx))
+                    .await
+                    .unwrap();
+            }
+            shutdown(&mut writer).await.unwrap();
+        });
+        writer.writer
+    }
+
+    fn open(cipher: Cipher, sealed: &[u8]) -> IoResult<Vec<u8>> {
+        let (key, iv) = key_iv(cipher);
+        let mut reader = RecordReader::new(sealed, cipher, &key, iv.as_deref()).unwrap();
+        block_on(read_to_end(&mut reader))
+    }
+
+    fn fails_with(res: IoResult<Vec<u8>>, expected: fn(&CryptoIoError) -> bool) -> bool {
+        match res {
+            Err(e) => CryptoIoError::from_io(&e).is_some_and(|e| expected(e.root())),
+            Ok(_) => false,
+        }
+    }
+
+    fn is_bad_tag(e: &CryptoIoError) -> bool {
+        matches!(e, CryptoIoError::BadTag)
+    }
+
+    #[test]
+    fn round_trip() {
+        for cipher in [Cipher::aes_128_gcm(), Cipher::chacha20_poly1305()] {
+            for len in [0, 1, MAX_RECORD_LEN, MAX_RECORD_LEN + 1, 100_000] {
+                let plaintext = sample(len);
+                let sealed = seal(cipher, &[&plaintext]);
+                assert_eq!(open(cipher, &sealed).unwrap(), plaintext);
+            }
+        }
+    }
+
+    // full records go out as they fill, and a partial one on flush
+    #[test]
+    fn record_sizes() {
+        let cipher = Cipher::aes_256_gcm();
+        let sealed = seal(cipher, &[&sample(MAX_RECORD_LEN + 10), b"abc"]);
+        let overhead = HEADER_LEN + TAG_LEN;
+        let lens = [MAX_RECORD_LEN, 10, 3, 0];
+        assert_eq!(
+            sealed.len(),
+            lens.iter().map(|len| len + overhead).sum::<usize>()
+        );
+        // the last record is the final one, with nothing in it
+        let last = &sealed[sealed.len() - overhead..];
+        assert_eq!(last[..HEADER_LEN], [1, 0, 0, 0, 0]);
+    }
+
+    // records are each checked before their plaintext is read, and numbered, so none can be
+    // altered, dropped, reordered or replayed
+    #[test]
+    fn tampered_records() {
+        let cipher = Cipher::aes_256_gcm();
+        let record = |len: usize| HEADER_LEN + len + TAG_LEN;
+        let sealed = seal(cipher, &[b"first", b"second"]);
+        let (first, rest) = sealed.split_at(record(5));
+        let (second, last) = rest.split_at(record(6));
+
+        let mut flipped = sealed.clone();
+        flipped[first.len() + HEADER_LEN] ^= 1;
+        let (key, iv) = key_iv(cipher);
+        let mut reader = RecordReader::new(&flipped[..], cipher, &key, iv.as_deref()).unwrap();
+        let mut buf = [0; 100];
+        let n = block_on(poll_fn(|cx| {
+            tokio::io::AsyncRead::poll_read(Pin::new(&mut reader), cx, &mut buf)
+        }))
+        .unwrap();
+        // the first record is read, and the second fails before any of it is handed out
+        assert_eq!(&buf[..n], b"first");
+        assert!(fails_with(block_on(read_to_end(&mut reader)), is_bad_tag));
+
+        let dropped = [first, last].concat();
+        let reordered = [second, first, last].concat();
+        let replayed = [first, first, second, last].concat();
+        for stream in [dropped, reordered, replayed] {
+            assert!(fails_with(open(cipher, &stream), is_bad_tag));
+        }
+    }
+
+    // a stream that stops without its final record is cut off, even at a record boundary
+    #[test]
+    fn truncated() {
+        let cipher = Cipher::aes_256_gcm();
+        let sealed = seal(cipher, &[b"first"]);
+        let final_at = sealed.len() - HEADER_LEN - TAG_LEN;
+        for len in [0, 3, final_at, sealed.len() - 1] {
+            assert!(fails_with(open(cipher, &sealed[..len]), |e| {
+                matches!(e, CryptoIoError::Truncated)
+            }));
+        }
+    }
+
+    // the counter stops short of wrapping, on both ends
+    #[test]
+    fn record_limit() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let mut writer = RecordWriter::new(Vec::new(), cipher, &key, iv.as_deref()).unwrap();
+        writer.keys.counter = u64::MAX - 1;
+        block_on(async {
+            write_all(&mut writer, b"last").await.unwrap();
+            poll_fn(|cx| Pin::new(&mut writer).poll_flush(cx))
+                .await
+                .unwrap();
+            let e = shutdown(&mut writer).await.unwrap_err();
+            assert!(matches!(
+                CryptoIoError::from_io(&e).map(|e| e.root()),
+                Some(CryptoIoError::RecordLimit)
+            ));
+        });
+
+        let sealed = writer.writer;
+        let mut reader = RecordReader::new(&sealed[..], cipher, &key, iv.as_deref()).unwrap();
+        reader.keys.counter = u64::MAX;
+        assert!(fails_with(block_on(read_to_end(&mut reader)), |e| {
+            matches!(e, CryptoIoError::RecordLimit)
+        }));
+        let mut reader = RecordReader::new(&sealed[..], cipher, &key, iv.as_deref()).unwrap();
+        reader.keys.counter = u64::MAX - 1;
+        let mut buf = [0; 100];
+        let n = block_on(poll_fn(|cx| {
+            tokio::io::AsyncRead::poll_read(Pin::new(&mut reader), cx, &mut buf)
+        }))
+        .unwrap();
+        assert_eq!(&buf[..n], b"last");
+    }
+
+    // a rekey record moves both ends onto the next key, numbered from 0 again
+    #[test]
+    fn rekey() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let mut writer = RecordWriter::new(Vec::new(), cipher, &key, iv.as_deref()).unwrap();
+        writer.keys.counter = u64::MAX - 2;
+        block_on(async {
+            write_all(&mut writer, b"before").await.unwrap();
+            writer.rekey().unwrap();
+            assert_eq!(writer.keys.counter, 0);
+            assert_ne!(writer.keys.key[..], key[..]);
+            write_all(&mut writer, b"after").await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        let sealed = writer.writer;
+        let record = |len: usize| HEADER_LEN + len + TAG_LEN;
+        assert_eq!(sealed.len(), record(6) + record(0) + record(5));
+        let (first, rest) = sealed.split_at(record(6));
+        let (rekey, rest) = rest.split_at(record(0));
+        assert_eq!(rekey[..HEADER_LEN], [2, 0, 0, 0, 0]);
+
+        let mut reader = RecordReader::new(&sealed[..], cipher, &key, iv.as_deref()).unwrap();
+        reader.keys.counter = u64::MAX - 2;
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), b"beforeafter");
+        // without the rekey record, the rest is under a key the reader doesn't have yet
+        let dropped = [first, rest].concat();
+        let mut reader = RecordReader::new(&dropped[..], cipher, &key, iv.as_deref()).unwrap();
+        reader.keys.counter = u64::MAX - 2;
+        assert!(fails_with(block_on(read_to_end(&mut reader)), is_bad_tag));
+    }
+
+    #[test]
+    fn invalid_setup() {
+        let (key, iv) = key_iv(Cipher::aes_256_ctr());
+        assert!(matches!(
+            RecordWriter::new(Vec::<u8>::new(), Cipher::aes_256_ctr(), &key, iv.as_deref()),
+            Err(CryptoIoError::Io(_))
+        ));
+        assert!(matches!(
+            RecordReader::new(&b""[..], Cipher::aes_256_gcm(), &key, Some(&[0; 16])),
+            Err(CryptoIoError::InvalidIvLen {
+                expected: 12,
+                actual: 16
+            })
+        ));
+    }
+
+    #[test]
+    fn closed_after_shutdown() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = key_iv(cipher);
+        let mut writer = RecordWriter::new(Vec::new(), cipher, &key, iv.as_deref()).unwrap();
+        block_on(shutdown(&mut writer)).unwrap();
+        let e = block_on(write_all(&mut writer, b"late")).unwrap_err();
+        assert!(matches!(
+            CryptoIoError::from_io(&e),
+            Some(CryptoIoError::Closed)
+        ));
+        assert!(matches!(writer.rekey(), Err(CryptoIoError::Closed)));
+        // and a second shutdown doesn't send another final record
+        block_on(shutdown(&mut writer)).unwrap();
+        assert_eq!(writer.writer.len(), HEADER_LEN + TAG_LEN);
+    }
+}
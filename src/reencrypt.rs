@@ -0,0 +1,145 @@
+use std::future::poll_fn;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::pin::Pin;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use zeroize::Zeroizing;
+
+use crate::{DecryptCore, DecryptReader, EncryptCore, EncryptWriter, SymmetricBackend};
+
+const CHUNK_LEN: usize = 64 * 1024;
+
+// Decrypts `reader` with `old` and encrypts the plaintext straight into `writer` with `new`, for
+// key rotation over stored ciphertext. The cores carry the whole configuration (cipher, key, IV,
+// MAC, commitment, tag placement), and only one chunk of plaintext is held at a time, in a buffer
+// that is zeroed afterwards.
+//
+// AEAD plaintext is only verified at the end of the old stream, so on error `writer` has already
+// been sent re-encrypted data that is not authentic. It is then left unfinalized, and the output
+// must be discarded. Returns the number of plaintext bytes carried over.
+pub async fn reencrypt<R, W, D, E>(
+    reader: R,
+    old: DecryptCore<D>,
+    writer: W,
+    new: EncryptCore<E>,
+) -> IoResult<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    D: SymmetricBackend + Unpin,
+    E: SymmetricBackend + Unpin,
+{
+    let mut reader = DecryptReader::with_core(reader, old);
+    let mut writer = EncryptWriter::with_core(writer, new);
+    let total = copy_zeroizing(&mut reader, &mut writer).await?;
+    poll_fn(|cx| Pin::new(&mut writer).poll_shutdown(cx)).await?;
+    Ok(total)
+}
+
+// copies `reader` to `writer` through a buffer that is zeroed afterwards, for plaintext; returns
+// the number of bytes copied
+pub(crate) async fn copy_zeroizing<R, W>(reader: &mut R, writer: &mut W) -> IoResult<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = Zeroizing::new(vec![0; CHUNK_LEN]);
+    let mut total = 0;
+    loop {
+        let n = poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut buf)).await?;
+        if n == 0 {
+            return Ok(total);
+        }
+        let mut written = 0;
+        while written < n {
+            let chunk = &buf[written..n];
+            match poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, chunk)).await? {
+                0 => {
+                    return Err(IoError::new(
+                        IoErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ))
+                }
+                m => written += m,
+            }
+        }
+        total += n as u64;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Cipher;
+
+    use super::reencrypt;
+    use crate::testing::{block_on, sample};
+    use crate::{CryptoIoError, DecryptCore, EncryptCore};
+
+    fn seal(mut core: EncryptCore, plaintext: &[u8]) -> Vec<u8> {
+        core.push_plaintext(plaintext).unwrap();
+        core.finish().unwrap();
+        core.take_ciphertext()
+    }
+
+    fn open(mut core: DecryptCore, ciphertext: &[u8]) -> Result<Vec<u8>, CryptoIoError> {
+        core.push_ciphertext(ciphertext)?;
+        core.finish()?;
+        Ok(core.take_plaintext())
+    }
+
+    // from AES-CBC with an HMAC to ChaCha20-Poly1305 with a key commitment
+    #[test]
+    fn rotates_keys_and_ciphers() {
+        let (old_cipher, new_cipher) = (Cipher::aes_128_cbc(), Cipher::chacha20_poly1305());
+        let (old_key, new_key) = ([1; 16], [2; 32]);
+        let (old_iv, new_iv) = ([3; 16], [4; 12]);
+        let old = || {
+            EncryptCore::new(old_cipher, &old_key, Some(&old_iv))
+                .unwrap()
+                .with_hmac(b"mac key")
+                .unwrap()
+        };
+        let plaintext = sample(200_000);
+        let ciphertext = seal(old(), &plaintext);
+
+        let decryptor = DecryptCore::new(old_cipher, &old_key, Some(&old_iv))
+            .unwrap()
+            .with_hmac(b"mac key")
+            .unwrap();
+        let encryptor = EncryptCore::new(new_cipher, &new_key, Some(&new_iv))
+            .unwrap()
+            .with_key_commitment(&new_key)
+            .unwrap();
+        let mut rotated = Vec::new();
+        let n = block_on(reencrypt(
+            &ciphertext[..],
+            decryptor,
+            &mut rotated,
+            encryptor,
+        ))
+        .unwrap();
+        assert_eq!(n, plaintext.len() as u64);
+
+        let decryptor = DecryptCore::new(new_cipher, &new_key, Some(&new_iv))
+            .unwrap()
+            .with_key_commitment(&new_key)
+            .unwrap();
+        assert_eq!(open(decryptor, &rotated).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn fails_on_bad_input() {
+        let cipher = Cipher::aes_256_gcm();
+        let (key, iv) = ([1; 32], [3; 12]);
+        let mut ciphertext = seal(
+            EncryptCore::new(cipher, &key, Some(&iv)).unwrap(),
+            &sample(100),
+        );
+        ciphertext[50] ^= 1;
+        let decryptor = DecryptCore::new(cipher, &key, Some(&iv)).unwrap();
+        let encryptor = EncryptCore::new(cipher, &[2; 32], Some(&iv)).unwrap();
+        let e = block_on(reencrypt(&ciphertext[..], decryptor, Vec::new(), encryptor)).unwrap_err();
+        let root = CryptoIoError::from_io(&e).map(|e| e.root());
+        assert!(matches!(root, Some(CryptoIoError::BadTag)));
+    }
+}
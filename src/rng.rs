@@ -0,0 +1,84 @@
+use std::sync::{Arc, RwLock};
+
+use openssl::rand::rand_bytes;
+
+use crate::CryptoIoError;
+
+// Where the random IVs, nonces and salts this crate generates come from: `MessageCipher`,
+// `MultipartCipher`, `CipherFactory::writer`, the handshake, `encrypted_duplex` and
+// `EncryptedFile`. By default that is OpenSSL's RAND, which is what a hardware RNG or a certified
+// DRBG can replace in builds that require one, and a seeded generator in tests. Keys generated by
+// `LockedKey::generate` and the nonce guard's own salt always come from OpenSSL.
+pub trait RandomSource: Send + Sync {
+    fn fill(&self, buf: &mut [u8]) -> Result<(), CryptoIoError>;
+}
+
+static SOURCE: RwLock<Option<Arc<dyn RandomSource>>> = RwLock::new(None);
+
+// Routes all IV, nonce and salt generation through `source` from now on, in every thread. A
+// source that repeats itself repeats IVs, so anything but a test source must be a proper CSPRNG.
+pub fn set_random_source(source: impl RandomSource + 'static) {
+    *SOURCE.write().unwrap_or_else(|e| e.into_inner()) = Some(Arc::new(source));
+}
+
+// goes back to OpenSSL's RAND
+pub fn reset_random_source() {
+    *SOURCE.write().unwrap_or_else(|e| e.into_inner()) = None;
+}
+
+pub(crate) fn fill_random(buf: &mut [u8]) -> Result<(), CryptoIoError> {
+    // cloned out so a slow source does not hold the lock, or deadlock by setting another
+    let source = SOURCE.read().unwrap_or_else(|e| e.into_inner()).clone();
+    match source {
+        Some(source) => source.fill(buf),
+        None => Ok(rand_bytes(buf)?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::Cell;
+    use std::io::Error as IoError;
+
+    use openssl::rand::rand_bytes;
+    use openssl::symm::Cipher;
+
+    use super::{reset_random_source, set_random_source, RandomSource};
+    use crate::{CryptoIoError, MessageCipher};
+
+    thread_local! {
+        static FILLED: Cell<usize> = const { Cell::new(0) };
+        static FAIL: Cell<bool> = const { Cell::new(false) };
+    }
+
+    // Still OpenSSL's RAND, since tests on other threads need their IVs to differ; only what this
+    // test's thread asks for is counted or failed.
+    struct Watched;
+    impl RandomSource for Watched {
+        fn fill(&self, buf: &mut [u8]) -> Result<(), CryptoIoError> {
+            if FAIL.with(Cell::get) {
+                return Err(IoError::other("no entropy").into());
+            }
+            FILLED.with(|n| n.set(n.get() + buf.len()));
+            Ok(rand_bytes(buf)?)
+        }
+    }
+
+    #[test]
+    fn source_is_used_until_reset() {
+        let messages = MessageCipher::new(Cipher::aes_256_gcm(), &[7; 32]).unwrap();
+        set_random_source(Watched);
+        messages.encrypt_message(b"hello").unwrap();
+        assert_eq!(FILLED.with(Cell::get), 12);
+
+        FAIL.with(|f| f.set(true));
+        assert!(matches!(
+            messages.encrypt_message(b"hello"),
+            Err(CryptoIoError::Io(e)) if e.to_string() == "no entropy"
+        ));
+
+        reset_random_source();
+        messages.encrypt_message(b"hello").unwrap();
+        assert_eq!(FILLED.with(Cell::get), 12);
+    }
+}
@@ -0,0 +1,370 @@
+use aes::{Aes128, Aes192, Aes256};
+use chacha20::ChaCha20;
+use cipher::{
+    consts::U16, generic_array::GenericArray, BlockCipher, BlockDecrypt, BlockDecryptMut,
+    BlockEncrypt, BlockEncryptMut, BlockSizeUser, KeyInit, KeyIvInit, StreamCipher,
+    StreamCipherSeek,
+};
+use openssl::symm::Mode;
+use zeroize::Zeroize;
+
+use crate::{CryptoIoError, SymmetricBackend};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RustCryptoCipher {
+    Aes128Cbc,
+    Aes192Cbc,
+    Aes256Cbc,
+    Aes128Ctr,
+    Aes192Ctr,
+    Aes256Ctr,
+    ChaCha20,
+}
+impl RustCryptoCipher {
+    pub fn key_len(self) -> usize {
+        match self {
+            RustCryptoCipher::Aes128Cbc | RustCryptoCipher::Aes128Ctr => 16,
+            RustCryptoCipher::Aes192Cbc | RustCryptoCipher::Aes192Ctr => 24,
+            RustCryptoCipher::Aes256Cbc | RustCryptoCipher::Aes256Ctr => 32,
+            RustCryptoCipher::ChaCha20 => 32,
+        }
+    }
+
+    pub fn iv_len(self) -> usize {
+        16
+    }
+
+    pub fn block_size(self) -> usize {
+        match self {
+            RustCryptoCipher::Aes128Cbc
+            | RustCryptoCipher::Aes192Cbc
+            | RustCryptoCipher::Aes256Cbc => 16,
+            _ => 1,
+        }
+    }
+
+    // the name OpenSSL uses for the same cipher, which these are interoperable with
+    pub fn name(self) -> &'static str {
+        match self {
+            RustCryptoCipher::Aes128Cbc => "AES-128-CBC",
+            RustCryptoCipher::Aes192Cbc => "AES-192-CBC",
+            RustCryptoCipher::Aes256Cbc => "AES-256-CBC",
+            RustCryptoCipher::Aes128Ctr => "AES-128-CTR",
+            RustCryptoCipher::Aes192Ctr => "AES-192-CTR",
+            RustCryptoCipher::Aes256Ctr => "AES-256-CTR",
+            RustCryptoCipher::ChaCha20 => "ChaCha20",
+        }
+    }
+}
+
+trait Keystream: Send {
+    fn apply(&mut self, buf: &mut [u8]);
+}
+impl<T: StreamCipher + Send> Keystream for T {
+    fn apply(&mut self, buf: &mut [u8]) {
+        self.apply_keystream(buf)
+    }
+}
+
+trait BlockMode: Send {
+    fn process(&mut self, block: &mut [u8]);
+}
+struct Encrypt<T>(T);
+impl<T: BlockEncryptMut + Send> BlockMode for Encrypt<T> {
+    fn process(&mut self, block: &mut [u8]) {
+        self.0
+            .encrypt_block_mut(GenericArray::from_mut_slice(block))
+    }
+}
+struct Decrypt<T>(T);
+impl<T: BlockDecryptMut + Send> BlockMode for Decrypt<T> {
+    fn process(&mut self, block: &mut [u8]) {
+        self.0
+            .decrypt_block_mut(GenericArray::from_mut_slice(block))
+    }
+}
+
+enum Inner {
+    Stream(Box<dyn Keystream>),
+    Cbc {
+        mode: Mode,
+        blocks: Box<dyn BlockMode>,
+        // input not yet processed: a partial block, or when decrypting, the last full block
+        // (which carries the padding) until finalize
+        pending: Vec<u8>,
+    },
+}
+
+// A pure-Rust backend for the unauthenticated ciphers, producing the same ciphertext as OpenSSL
+// (PKCS#7 padding for CBC, a 128-bit big-endian counter for CTR, and a 16-byte ChaCha20 IV
+// holding the little-endian block counter followed by the nonce).
+pub struct RustCryptoBackend {
+    cipher: RustCryptoCipher,
+    inner: Inner,
+}
+impl RustCryptoBackend {
+    pub fn new(
+        cipher: RustCryptoCipher,
+        mode: Mode,
+        key: &[u8],
+        iv: &[u8],
+    ) -> Result<Self, CryptoIoError> {
+        if key.len() != cipher.key_len() {
+            return Err(CryptoIoError::InvalidKeyLen {
+                expected: cipher.key_len(),
+                actual: key.len(),
+            });
+        }
+        if iv.len() != cipher.iv_len() {
+            return Err(CryptoIoError::InvalidIvLen {
+                expected: cipher.iv_len(),
+                actual: iv.len(),
+            });
+        }
+        fn cbc<C>(mode: Mode, key: &[u8], iv: &[u8]) -> Inner
+        where
+            C: BlockCipher + BlockEncrypt + BlockDecrypt + KeyInit + Send + 'static,
+        {
+            // lengths were checked above
+            let blocks: Box<dyn BlockMode> = match mode {
+                Mode::Encrypt => Box::new(Encrypt(
+                    cbc::Encryptor::<C>::new_from_slices(key, iv).unwrap(),
+                )),
+                Mode::Decrypt => Box::new(Decrypt(
+                    cbc::Decryptor::<C>::new_from_slices(key, iv).unwrap(),
+                )),
+            };
+            Inner::Cbc {
+                mode,
+                blocks,
+                pending: Vec::new(),
+            }
+        }
+        fn ctr<C>(key: &[u8], iv: &[u8]) -> Inner
+        where
+            C: BlockCipher
+                + BlockEncrypt
+                + BlockSizeUser<BlockSize = U16>
+                + KeyInit
+                + Send
+                + 'static,
+        {
+            Inner::Stream(Box::new(
+                ctr::Ctr128BE::<C>::new_from_slices(key, iv).unwrap(),
+            ))
+        }
+        let inner = match cipher {
+            RustCryptoCipher::Aes128Cbc => cbc::<Aes128>(mode, key, iv),
+            RustCryptoCipher::Aes192Cbc => cbc::<Aes192>(mode, key, iv),
+            RustCryptoCipher::Aes256Cbc => cbc::<Aes256>(mode, key, iv),
+            RustCryptoCipher::Aes128Ctr => ctr::<Aes128>(key, iv),
+            RustCryptoCipher::Aes192Ctr => ctr::<Aes192>(key, iv),
+            RustCryptoCipher::Aes256Ctr => ctr::<Aes256>(key, iv),
+            RustCryptoCipher::ChaCha20 => {
+                let mut counter = [0; 4];
+                counter.copy_from_slice(&iv[..4]);
+                let mut c = ChaCha20::new_from_slices(key, &iv[4..]).unwrap();
+                c.seek(u32::from_le_bytes(counter) as u64 * 64);
+                Inner::Stream(Box::new(c))
+            }
+        };
+        Ok(RustCryptoBackend { cipher, inner })
+    }
+
+    pub fn cipher(&self) -> RustCryptoCipher {
+        self.cipher
+    }
+}
+
+impl SymmetricBackend for RustCryptoBackend {
+    fn update(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        match &mut self.inner {
+            Inner::Stream(keystream) => {
+                let output = &mut output[..input.len()];
+                output.copy_from_slice(input);
+                keystream.apply(output);
+                Ok(input.len())
+            }
+            Inner::Cbc {
+                mode,
+                blocks,
+                pending,
+            } => {
+                let block_size = self.cipher.block_size();
+                pending.extend_from_slice(input);
+                let ready = match mode {
+                    Mode::Encrypt => pending.len() / block_size * block_size,
+                    Mode::Decrypt => pending.len().saturating_sub(1) / block_size * block_size,
+                };
+                let output = &mut output[..ready];
+                output.copy_from_slice(&pending[..ready]);
+                output
+                    .chunks_mut(block_size)
+                    .for_each(|b| blocks.process(b));
+                pending.drain(..ready);
+                Ok(ready)
+            }
+        }
+    }
+
+    fn finalize(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        match &mut self.inner {
+            Inner::Stream(_) => Ok(0),
+            Inner::Cbc {
+                mode,
+                blocks,
+                pending,
+            } => {
+                let block_size = self.cipher.block_size();
+                let block = &mut output[..block_size];
+                match mode {
+                    Mode::Encrypt => {
+                        let pad = block_size - pending.len();
+                        block[..pending.len()].copy_from_slice(pending);
+                        block[pending.len()..]
+                            .iter_mut()
+                            .for_each(|b| *b = pad as u8);
+                        blocks.process(block);
+                        pending.clear();
+                        Ok(block_size)
+                    }
+                    Mode::Decrypt => {
+                        if pending.len() != block_size {
+                            return Err(CryptoIoError::Truncated);
+                        }
+                        block.copy_from_slice(pending);
+                        pending.clear();
+                        blocks.process(block);
+                        let pad = block[block_size - 1] as usize;
+                        if pad == 0
+                            || pad > block_size
+                            || block[block_size - pad..].iter().any(|b| *b as usize != pad)
+                        {
+                            return Err(CryptoIoError::BadPadding);
+                        }
+                        Ok(block_size - pad)
+                    }
+                }
+            }
+        }
+    }
+
+    fn block_size(&self) -> usize {
+        self.cipher.block_size()
+    }
+
+    fn name(&self) -> &'static str {
+        self.cipher.name()
+    }
+}
+
+impl Drop for RustCryptoBackend {
+    fn drop(&mut self) {
+        if let Inner::Cbc { pending, .. } = &mut self.inner {
+            pending.zeroize();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Result as IoResult;
+
+    use openssl::symm::{Cipher, Mode};
+
+    use super::{RustCryptoBackend, RustCryptoCipher};
+    use crate::testing::{block_on, read_to_end, sample, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter, OpensslBackend};
+
+    fn ciphers() -> Vec<(RustCryptoCipher, Cipher)> {
+        vec![
+            (RustCryptoCipher::Aes128Cbc, Cipher::aes_128_cbc()),
+            (RustCryptoCipher::Aes192Cbc, Cipher::aes_192_cbc()),
+            (RustCryptoCipher::Aes256Cbc, Cipher::aes_256_cbc()),
+            (RustCryptoCipher::Aes128Ctr, Cipher::aes_128_ctr()),
+            (RustCryptoCipher::Aes192Ctr, Cipher::aes_192_ctr()),
+            (RustCryptoCipher::Aes256Ctr, Cipher::aes_256_ctr()),
+            (RustCryptoCipher::ChaCha20, Cipher::chacha20()),
+        ]
+    }
+
+    fn key_iv(cipher: RustCryptoCipher) -> (Vec<u8>, Vec<u8>) {
+        (vec![0x42; cipher.key_len()], vec![0x24; cipher.iv_len()])
+    }
+
+    fn backend(cipher: RustCryptoCipher, mode: Mode) -> RustCryptoBackend {
+        let (key, iv) = key_iv(cipher);
+        RustCryptoBackend::new(cipher, mode, &key, &iv).unwrap()
+    }
+
+    fn seal_openssl(cipher: RustCryptoCipher, openssl: Cipher, plaintext: &[u8]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let backend = OpensslBackend::new(openssl, Mode::Encrypt, &key, Some(&iv)).unwrap();
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::with_backend(&mut out, backend);
+            write_all(&mut writer, plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn open(cipher: RustCryptoCipher, ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        let mut reader = DecryptReader::with_backend(ciphertext, backend(cipher, Mode::Decrypt));
+        block_on(read_to_end(&mut reader))
+    }
+
+    // the backends are interchangeable: each decrypts what the other encrypts
+    #[test]
+    fn matches_openssl() {
+        for (cipher, openssl) in ciphers() {
+            for &len in &[0, 1, 15, 16, 17, 2500] {
+                let plaintext = sample(len);
+                let mut out = Vec::new();
+                block_on(async {
+                    let backend = backend(cipher, Mode::Encrypt);
+                    let mut writer = EncryptWriter::with_backend(&mut out, backend);
+                    write_all(&mut writer, &plaintext).await.unwrap();
+                    shutdown(&mut writer).await.unwrap();
+                });
+                let expected = seal_openssl(cipher, openssl, &plaintext);
+                assert_eq!(out, expected, "{} at {} bytes", cipher.name(), len);
+                assert_eq!(open(cipher, &expected).unwrap(), plaintext);
+            }
+        }
+    }
+
+    #[test]
+    fn bad_padding_and_truncation() {
+        let cipher = RustCryptoCipher::Aes128Cbc;
+        let mut ciphertext = seal_openssl(cipher, Cipher::aes_128_cbc(), &sample(100));
+        *ciphertext.last_mut().unwrap() ^= 1;
+        let e = open(cipher, &ciphertext).unwrap_err();
+        let root = CryptoIoError::from_io(&e).map(|e| e.root());
+        assert!(matches!(root, Some(CryptoIoError::BadPadding)));
+        let e = open(cipher, &ciphertext[..100]).unwrap_err();
+        let root = CryptoIoError::from_io(&e).map(|e| e.root());
+        assert!(matches!(root, Some(CryptoIoError::Truncated)));
+    }
+
+    #[test]
+    fn invalid_lengths() {
+        let cipher = RustCryptoCipher::Aes256Ctr;
+        let res = RustCryptoBackend::new(cipher, Mode::Encrypt, &[0; 16], &[0; 16]);
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidKeyLen {
+                expected: 32,
+                actual: 16
+            })
+        ));
+        let res = RustCryptoBackend::new(cipher, Mode::Encrypt, &[0; 32], &[0; 12]);
+        assert!(matches!(
+            res,
+            Err(CryptoIoError::InvalidIvLen {
+                expected: 16,
+                actual: 12
+            })
+        ));
+    }
+}
@@ -0,0 +1,32 @@
+use openssl::symm::Cipher;
+use secrecy::ExposeSecret;
+
+use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+impl<W> EncryptWriter<W> {
+    pub fn new_secret<K>(
+        writer: W,
+        cipher: Cipher,
+        key: &K,
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError>
+    where
+        K: ExposeSecret<[u8]>,
+    {
+        Self::new(writer, cipher, key.expose_secret(), iv)
+    }
+}
+
+impl<R> DecryptReader<R> {
+    pub fn new_secret<K>(
+        reader: R,
+        cipher: Cipher,
+        key: &K,
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError>
+    where
+        K: ExposeSecret<[u8]>,
+    {
+        Self::new(reader, cipher, key.expose_secret(), iv)
+    }
+}
@@ -0,0 +1,87 @@
+use std::alloc::{self, Layout};
+use std::fmt;
+use std::io::{Error as IoError, Result as IoResult};
+use std::ops::Deref;
+use std::ptr::NonNull;
+use std::slice;
+
+use zeroize::Zeroize;
+
+// Key bytes held in their own page-aligned, mlocked allocation so they are never written to swap
+// (and, on linux, are excluded from core dumps). The pages are wiped before they are unlocked.
+pub struct LockedKey {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+unsafe impl Send for LockedKey {}
+unsafe impl Sync for LockedKey {}
+impl LockedKey {
+    pub fn new(key: &[u8]) -> IoResult<Self> {
+        let mut res = Self::alloc(key.len())?;
+        res.as_mut_slice().copy_from_slice(key);
+        Ok(res)
+    }
+
+    pub fn generate(len: usize) -> IoResult<Self> {
+        let mut res = Self::alloc(len)?;
+        openssl::rand::rand_bytes(res.as_mut_slice()).map_err(IoError::other)?;
+        Ok(res)
+    }
+
+    fn alloc(len: usize) -> IoResult<Self> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let size = len.max(1).div_ceil(page) * page;
+        let layout = Layout::from_size_align(size, page).map_err(IoError::other)?;
+        let ptr = match NonNull::new(unsafe { alloc::alloc_zeroed(layout) }) {
+            Some(a) => a,
+            None => alloc::handle_alloc_error(layout),
+        };
+        if unsafe { libc::mlock(ptr.as_ptr() as *const libc::c_void, size) } != 0 {
+            let err = IoError::last_os_error();
+            unsafe { alloc::dealloc(ptr.as_ptr(), layout) };
+            return Err(err);
+        }
+        #[cfg(target_os = "linux")]
+        unsafe {
+            libc::madvise(ptr.as_ptr() as *mut libc::c_void, size, libc::MADV_DONTDUMP);
+        }
+        Ok(LockedKey { ptr, len, layout })
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl Deref for LockedKey {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for LockedKey {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl fmt::Debug for LockedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LockedKey")
+            .field("len", &self.len)
+            .field("key", &"<redacted>")
+            .finish()
+    }
+}
+
+impl Drop for LockedKey {
+    fn drop(&mut self) {
+        unsafe {
+            slice::from_raw_parts_mut(self.ptr.as_ptr(), self.layout.size()).zeroize();
+            libc::munlock(self.ptr.as_ptr() as *const libc::c_void, self.layout.size());
+            alloc::dealloc(self.ptr.as_ptr(), self.layout);
+        }
+    }
+}
@@ -0,0 +1,245 @@
+use openssl::symm::{Cipher, Mode};
+
+use crate::telemetry::cipher_name;
+use crate::{CryptoIoError, OpensslBackend, SymmetricBackend};
+
+struct KnownAnswer {
+    name: &'static str,
+    cipher: fn() -> Cipher,
+    key: &'static str,
+    iv: &'static str,
+    plaintext: &'static str,
+    // without padding; padded ciphers must produce this followed by one block of padding
+    ciphertext: &'static str,
+}
+
+const SP800_38A_PLAINTEXT: &str =
+    "6bc1bee22e409f96e93d7e117393172aae2d8a571e03ac9c9eb76fac45af8e51\
+    30c81c46a35ce411e5fbc1191a0a52eff69f2445df4f9b17ad2b417be66c3710";
+
+const VECTORS: &[KnownAnswer] = &[
+    KnownAnswer {
+        name: "SP 800-38A F.2.1 CBC-AES128",
+        cipher: Cipher::aes_128_cbc,
+        key: "2b7e151628aed2a6abf7158809cf4f3c",
+        iv: "000102030405060708090a0b0c0d0e0f",
+        plaintext: SP800_38A_PLAINTEXT,
+        ciphertext: "7649abac8119b246cee98e9b12e9197d5086cb9b507219ee95db113a917678b2\
+            73bed6b8e3c1743b7116e69e222295163ff1caa1681fac09120eca307586e1a7",
+    },
+    KnownAnswer {
+        name: "SP 800-38A F.2.5 CBC-AES256",
+        cipher: Cipher::aes_256_cbc,
+        key: "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+        iv: "000102030405060708090a0b0c0d0e0f",
+        plaintext: SP800_38A_PLAINTEXT,
+        ciphertext: "f58c4c04d6e5f1ba779eabfb5f7bfbd69cfc4e967edb808d679f777bc6702c7d\
+            39f23369a9d9bacfa530e26304231461b2eb05e2c39be9fcda6c19078c6a9d1b",
+    },
+    KnownAnswer {
+        name: "SP 800-38A F.5.1 CTR-AES128",
+        cipher: Cipher::aes_128_ctr,
+        key: "2b7e151628aed2a6abf7158809cf4f3c",
+        iv: "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+        plaintext: SP800_38A_PLAINTEXT,
+        ciphertext: "874d6191b620e3261bef6864990db6ce9806f66b7970fdff8617187bb9fffdff\
+            5ae4df3edbd5d35e5b4f09020db03eab1e031dda2fbe03d1792170a0f3009cee",
+    },
+    KnownAnswer {
+        name: "SP 800-38A F.5.5 CTR-AES256",
+        cipher: Cipher::aes_256_ctr,
+        key: "603deb1015ca71be2b73aef0857d77811f352c073b6108d72d9810a30914dff4",
+        iv: "f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff",
+        plaintext: SP800_38A_PLAINTEXT,
+        ciphertext: "601ec313775789a5b7a7f504bbf3d228f443e3ca4d62b59aca84e990cacaf5c5\
+            2b0930daa23de94ce87017ba2d84988ddfc9c58db67aada613c2dd08457941a6",
+    },
+    // the 16-byte IV is the little-endian block counter (1) followed by the nonce
+    KnownAnswer {
+        name: "RFC 8439 2.4.2 ChaCha20",
+        cipher: Cipher::chacha20,
+        key: "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f",
+        iv: "01000000000000000000004a00000000",
+        plaintext: "4c616469657320616e642047656e746c656d656e206f662074686520636c6173\
+            73206f66202739393a204966204920636f756c64206f6666657220796f75206f\
+            6e6c79206f6e652074697020666f7220746865206675747572652c2073756e73\
+            637265656e20776f756c642062652069742e",
+        ciphertext: "6e2e359a2568f98041ba0728dd0d6981e97e7aec1d4360c20a27afccfd9fae0b\
+            f91b65c5524733ab8f593dabcd62b3571639d624e65152ab8f530c359f0861d8\
+            07ca0dbf500d6a6156a38e088a22b65e52bc514d16ccf806818ce91ab7793736\
+            5af90bbf74a35be6b40b8eedf2785e42874d",
+    },
+];
+
+fn unhex(s: &str) -> Vec<u8> {
+    let digits: Vec<u8> = s
+        .bytes()
+        .filter(|b| !b.is_ascii_whitespace())
+        .map(|b| (b as char).to_digit(16).expect("bad hex in test vector") as u8)
+        .collect();
+    digits.chunks(2).map(|d| d[0] << 4 | d[1]).collect()
+}
+
+#[derive(Debug)]
+pub enum SelfTestOutcome {
+    Passed,
+    // the backend ran, but produced the wrong output
+    Mismatch,
+    // the backend could not be created or failed while processing
+    Failed(CryptoIoError),
+}
+
+#[derive(Debug)]
+pub struct SelfTestResult {
+    pub name: &'static str,
+    pub cipher: &'static str,
+    pub outcome: SelfTestOutcome,
+}
+
+#[derive(Debug)]
+pub struct SelfTestReport {
+    pub results: Vec<SelfTestResult>,
+}
+impl SelfTestReport {
+    pub fn passed(&self) -> bool {
+        self.results
+            .iter()
+            .all(|r| matches!(r.outcome, SelfTestOutcome::Passed))
+    }
+}
+
+// runs `input` through `backend` in two uneven pieces, so the vectors also exercise buffering
+// across `update` calls
+fn run<B: SymmetricBackend>(mut backend: B, input: &[u8]) -> Result<Vec<u8>, CryptoIoError> {
+    let block_size = backend.block_size();
+    let mut out = vec![0; input.len() + 3 * block_size];
+    let (a, b) = input.split_at(input.len() / 3);
+    let mut len = backend.update(a, &mut out)?;
+    len += backend.update(b, &mut out[len..])?;
+    len += backend.finalize(&mut out[len..])?;
+    out.truncate(len);
+    Ok(out)
+}
+
+fn check<B, F>(vector: &KnownAnswer, new: &mut F) -> Result<bool, CryptoIoError>
+where
+    B: SymmetricBackend,
+    F: FnMut(Cipher, Mode, &[u8], Option<&[u8]>) -> Result<B, CryptoIoError>,
+{
+    let cipher = (vector.cipher)();
+    let (key, iv) = (unhex(vector.key), unhex(vector.iv));
+    let (plaintext, expected) = (unhex(vector.plaintext), unhex(vector.ciphertext));
+    let ciphertext = run(new(cipher, Mode::Encrypt, &key, Some(&iv))?, &plaintext)?;
+    let padding = if cipher.block_size() > 1 {
+        cipher.block_size()
+    } else {
+        0
+    };
+    if ciphertext.len() != expected.len() + padding || !ciphertext.starts_with(&expected) {
+        return Ok(false);
+    }
+    let decrypted = run(new(cipher, Mode::Decrypt, &key, Some(&iv))?, &ciphertext)?;
+    Ok(decrypted == plaintext)
+}
+
+// Runs the built-in known-answer vectors through backends created by `new` (called like
+// `OpensslBackend::new`, once per vector and direction) and reports the result of each. A
+// backend that does not support a vector's cipher should return an error, which is reported as
+// a failure of that vector.
+pub fn self_test_with<B, F>(mut new: F) -> SelfTestReport
+where
+    B: SymmetricBackend,
+    F: FnMut(Cipher, Mode, &[u8], Option<&[u8]>) -> Result<B, CryptoIoError>,
+{
+    let results = VECTORS
+        .iter()
+        .map(|vector| {
+            let outcome = match check(vector, &mut new) {
+                Ok(true) => SelfTestOutcome::Passed,
+                Ok(false) => SelfTestOutcome::Mismatch,
+                Err(e) => SelfTestOutcome::Failed(e),
+            };
+            if !matches!(outcome, SelfTestOutcome::Passed) {
+                event!(tracing::Level::ERROR, vector = vector.name, outcome = ?outcome, "self-test failed");
+            }
+            SelfTestResult {
+                name: vector.name,
+                cipher: cipher_name((vector.cipher)()),
+                outcome,
+            }
+        })
+        .collect();
+    SelfTestReport { results }
+}
+
+// `self_test_with` for the OpenSSL backend
+pub fn self_test() -> SelfTestReport {
+    self_test_with(OpensslBackend::new)
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::{Cipher, Mode};
+
+    use super::{self_test, self_test_with, SelfTestOutcome};
+    use crate::{CryptoIoError, OpensslBackend, SymmetricBackend};
+
+    #[test]
+    fn openssl_passes() {
+        let report = self_test();
+        assert_eq!(report.results.len(), 5);
+        assert!(report.passed(), "{:?}", report);
+    }
+
+    // a broken backend: flips the first bit of its output
+    struct Flipped(OpensslBackend, bool);
+    impl SymmetricBackend for Flipped {
+        fn update(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError> {
+            let len = self.0.update(input, output)?;
+            if len > 0 && !self.1 {
+                output[0] ^= 1;
+                self.1 = true;
+            }
+            Ok(len)
+        }
+
+        fn finalize(&mut self, output: &mut [u8]) -> Result<usize, CryptoIoError> {
+            self.0.finalize(output)
+        }
+
+        fn block_size(&self) -> usize {
+            self.0.block_size()
+        }
+    }
+
+    #[test]
+    fn reports_failures() {
+        let report = self_test_with(|cipher, mode, key, iv| {
+            Ok(Flipped(OpensslBackend::new(cipher, mode, key, iv)?, false))
+        });
+        assert!(!report.passed());
+        assert!(report
+            .results
+            .iter()
+            .all(|r| matches!(r.outcome, SelfTestOutcome::Mismatch)));
+
+        // a backend without CTR
+        let report = self_test_with(|cipher: Cipher, mode: Mode, key: &[u8], iv| {
+            if cipher.nid() == Cipher::aes_128_ctr().nid() {
+                return Err(CryptoIoError::UnknownAlgorithm(4));
+            }
+            OpensslBackend::new(cipher, mode, key, iv)
+        });
+        let failed: Vec<_> = report
+            .results
+            .iter()
+            .filter(|r| !matches!(r.outcome, SelfTestOutcome::Passed))
+            .collect();
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].name, "SP 800-38A F.5.1 CTR-AES128");
+        assert!(matches!(
+            failed[0].outcome,
+            SelfTestOutcome::Failed(CryptoIoError::UnknownAlgorithm(4))
+        ));
+    }
+}
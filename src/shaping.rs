@@ -0,0 +1,307 @@
+use std::future::poll_fn;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind, Result as IoResult};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use tokio::io::{AsyncRead, AsyncWrite};
+use zeroize::Zeroizing;
+
+use crate::CryptoIoError;
+
+// Frames carried inside the plaintext by `shape_traffic`: a kind byte, the payload length as a
+// u16 and the payload, zero-filled to `frame_len`, so every frame is the same size whether it
+// carries data or is a dummy sent to keep the rate up.
+const HEADER_LEN: usize = 3;
+const KIND_DUMMY: u8 = 0;
+const KIND_DATA: u8 = 1;
+const KIND_END: u8 = 2;
+
+async fn write_all<W>(writer: &mut W, mut data: &[u8]) -> IoResult<()>
+where
+    W: AsyncWrite + Unpin,
+{
+    while !data.is_empty() {
+        match poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, data)).await? {
+            0 => {
+                return Err(IoError::new(
+                    IoErrorKind::WriteZero,
+                    "failed to write whole frame",
+                ))
+            }
+            n => data = &data[n..],
+        }
+    }
+    Ok(())
+}
+
+// For traffic-analysis-sensitive links: sends one fixed-size frame to `writer` (normally an
+// `EncryptWriter`) every `interval`, carrying up to `frame_len` bytes of whatever `plain` has
+// ready, or nothing at all when it is idle, so an observer sees the same sizes and timing either
+// way. Block ciphers only release whole blocks on flush, so pick a `frame_len` that makes frames
+// a whole number of blocks (a multiple of the block size, less 3). At EOF on `plain` it sends an
+// end frame and shuts `writer` down; returns the number of bytes of `plain` carried. Read the
+// other end through `UnshapeReader`.
+pub async fn shape_traffic<R, W>(
+    mut plain: R,
+    mut writer: W,
+    frame_len: u16,
+    interval: Duration,
+) -> IoResult<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut frame = Zeroizing::new(vec![0; HEADER_LEN + frame_len as usize]);
+    let mut ticks = tokio::time::interval(interval);
+    let mut total = 0;
+    let mut eof = false;
+    while !eof {
+        ticks.tick().await;
+        // take what is ready without waiting for more
+        let ready = poll_fn(|cx| {
+            Poll::Ready(
+                match Pin::new(&mut plain).poll_read(cx, &mut frame[HEADER_LEN..]) {
+                    Poll::Ready(res) => res.map(Some),
+                    Poll::Pending => Ok(None),
+                },
+            )
+        })
+        .await?;
+        let (kind, len) = match ready {
+            Some(0) => {
+                eof = true;
+                (KIND_END, 0)
+            }
+            Some(n) => (KIND_DATA, n),
+            None => (KIND_DUMMY, 0),
+        };
+        frame[0] = kind;
+        frame[1..HEADER_LEN].copy_from_slice(&(len as u16).to_be_bytes());
+        for b in &mut frame[HEADER_LEN + len..] {
+            *b = 0;
+        }
+        write_all(&mut writer, &frame).await?;
+        poll_fn(|cx| Pin::new(&mut writer).poll_flush(cx)).await?;
+        total += len as u64;
+    }
+    poll_fn(|cx| Pin::new(&mut writer).poll_shutdown(cx)).await?;
+    Ok(total)
+}
+
+// Reads the data back out of frames written by `shape_traffic` with the same `frame_len`,
+// dropping the dummies. A stream that ends without an end frame fails with `Truncated`.
+pub struct UnshapeReader<R> {
+    reader: R,
+    frame: Zeroizing<Vec<u8>>,
+    // bytes of `frame` read from `reader`
+    filled: usize,
+    // the unread payload of a data frame
+    pos: usize,
+    end: usize,
+    done: bool,
+}
+impl<R> UnshapeReader<R> {
+    pub fn new(reader: R, frame_len: u16) -> Self {
+        UnshapeReader {
+            reader,
+            frame: Zeroizing::new(vec![0; HEADER_LEN + frame_len as usize]),
+            filled: 0,
+            pos: 0,
+            end: 0,
+            done: false,
+        }
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.reader
+    }
+
+    pub fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+impl<R> UnshapeReader<R>
+where
+    R: AsyncRead,
+{
+    // Reads `reader` to its end after the end frame, so a `DecryptReader` gets to check its tag
+    // or MAC before this reports EOF; until then, the frames read so far are unauthenticated.
+    // self must be pinned
+    unsafe fn poll_drain(&mut self, cx: &mut Context<'_>) -> Poll<IoResult<usize>> {
+        match Pin::new_unchecked(&mut self.reader).poll_read(cx, &mut self.frame) {
+            Poll::Ready(Ok(0)) => Poll::Ready(Ok(0)),
+            Poll::Ready(Ok(_)) => Poll::Ready(Err(IoError::new(
+                IoErrorKind::InvalidData,
+                "data after the traffic shaping end frame",
+            ))),
+            Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+            Poll::Pending => Poll::Pending,
+        }
+    }
+
+    // self must be pinned
+    unsafe fn read_impl(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<IoResult<usize>> {
+        loop {
+            if self.pos < self.end {
+                let n = (self.end - self.pos).min(buf.len());
+                buf[..n].copy_from_slice(&self.frame[self.pos..self.pos + n]);
+                self.pos += n;
+                return Poll::Ready(Ok(n));
+            }
+            if self.done {
+                return self.poll_drain(cx);
+            }
+            if buf.is_empty() {
+                return Poll::Ready(Ok(0));
+            }
+            while self.filled < self.frame.len() {
+                match Pin::new_unchecked(&mut self.reader)
+                    .poll_read(cx, &mut self.frame[self.filled..])
+                {
+                    Poll::Ready(Ok(0)) => return Poll::Ready(Err(CryptoIoError::Truncated.into())),
+                    Poll::Ready(Ok(n)) => self.filled += n,
+                    Poll::Ready(Err(e)) => return Poll::Ready(Err(e)),
+                    Poll::Pending => return Poll::Pending,
+                }
+            }
+            self.filled = 0;
+            let len = u16::from_be_bytes([self.frame[1], self.frame[2]]) as usize;
+            match self.frame[0] {
+                KIND_DATA if HEADER_LEN + len <= self.frame.len() => {
+                    self.pos = HEADER_LEN;
+                    self.end = HEADER_LEN + len;
+                }
+                KIND_DUMMY => (),
+                KIND_END => self.done = true,
+                _ => {
+                    return Poll::Ready(Err(IoError::new(
+                        IoErrorKind::InvalidData,
+                        "malformed traffic shaping frame",
+                    )))
+                }
+            }
+        }
+    }
+}
+
+impl<R> AsyncRead for UnshapeReader<R>
+where
+    R: AsyncRead,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<IoResult<usize>> {
+        unsafe { self.get_unchecked_mut().read_impl(cx, buf) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{ErrorKind as IoErrorKind, Result as IoResult};
+
+    use openssl::symm::Cipher;
+
+    use super::{UnshapeReader, HEADER_LEN, KIND_DATA, KIND_DUMMY, KIND_END};
+    use crate::testing::{block_on, key_iv, read_to_end, shutdown, write_all};
+    use crate::{CryptoIoError, DecryptReader, EncryptWriter};
+
+    const FRAME_LEN: u16 = 61;
+
+    fn frame(kind: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![kind];
+        frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+        frame.extend_from_slice(payload);
+        frame.resize(HEADER_LEN + FRAME_LEN as usize, 0);
+        frame
+    }
+
+    // what `shape_traffic` writes for "hello, " then an idle tick then "world", encrypted
+    fn shaped(cipher: Cipher, trailer: &[u8]) -> Vec<u8> {
+        let mut plaintext = frame(KIND_DATA, b"hello, ");
+        plaintext.extend(frame(KIND_DUMMY, b""));
+        plaintext.extend(frame(KIND_DATA, b"world"));
+        plaintext.extend(frame(KIND_END, b""));
+        plaintext.extend_from_slice(trailer);
+        let (key, iv) = key_iv(cipher);
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::new(&mut out, cipher, &key, iv.as_deref()).unwrap();
+            write_all(&mut writer, &plaintext).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    fn unshape(cipher: Cipher, ciphertext: &[u8]) -> IoResult<Vec<u8>> {
+        let (key, iv) = key_iv(cipher);
+        block_on(async {
+            let reader = DecryptReader::new(ciphertext, cipher, &key, iv.as_deref())?;
+            read_to_end(&mut UnshapeReader::new(reader, FRAME_LEN)).await
+        })
+    }
+
+    fn is_bad_tag(result: IoResult<Vec<u8>>) -> bool {
+        match result {
+            Err(e) => matches!(
+                CryptoIoError::from_io(&e).map(|e| e.root()),
+                Some(CryptoIoError::BadTag)
+            ),
+            Ok(_) => false,
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        for cipher in [Cipher::aes_256_gcm(), Cipher::aes_128_ctr()] {
+            assert_eq!(
+                unshape(cipher, &shaped(cipher, b"")).unwrap(),
+                b"hello, world"
+            );
+        }
+    }
+
+    #[test]
+    fn tampered_tag() {
+        let cipher = Cipher::aes_256_gcm();
+        let mut ciphertext = shaped(cipher, b"");
+        *ciphertext.last_mut().unwrap() ^= 1;
+        assert!(is_bad_tag(unshape(cipher, &ciphertext)));
+    }
+
+    // GCM is malleable, so a flipped payload bit decrypts to a flipped plaintext bit until the
+    // tag is checked
+    #[test]
+    fn tampered_payload() {
+        let cipher = Cipher::aes_256_gcm();
+        let mut ciphertext = shaped(cipher, b"");
+        ciphertext[1 + HEADER_LEN] ^= 1;
+        assert!(is_bad_tag(unshape(cipher, &ciphertext)));
+    }
+
+    #[test]
+    fn data_after_end_frame() {
+        let cipher = Cipher::aes_256_gcm();
+        let err = unshape(cipher, &shaped(cipher, b"extra")).unwrap_err();
+        assert_eq!(err.kind(), IoErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn missing_end_frame() {
+        let cipher = Cipher::aes_128_ctr();
+        let ciphertext = shaped(cipher, b"");
+        let frames = ciphertext.len() - (HEADER_LEN + FRAME_LEN as usize);
+        let err = unshape(cipher, &ciphertext[..frames]).unwrap_err();
+        assert!(matches!(
+            CryptoIoError::from_io(&err),
+            Some(CryptoIoError::Truncated)
+        ));
+    }
+}
@@ -0,0 +1,161 @@
+use std::io::{Error as IoError, ErrorKind};
+
+use openssl::cipher::Cipher;
+use openssl::cipher_ctx::CipherCtx;
+use zeroize::Zeroize;
+
+use crate::CryptoIoError;
+
+const TAG_LEN: usize = 16;
+
+// Deterministic AES-SIV (RFC 5297): there is no IV, so the same key, associated data and
+// plaintext always give the same ciphertext. That is what makes it usable for lookup keys and
+// deduplication, and also what it gives away, as equal messages are visible as such. It is
+// deliberately not a `SymmetricBackend`: SIV needs the whole message before it can write
+// anything, so it works on messages rather than streams, and keeping it out of the IV-taking
+// constructors means it can't be mistaken for one of the randomized modes.
+pub struct AesSiv {
+    name: &'static str,
+    cipher: Cipher,
+    key: Vec<u8>,
+}
+impl AesSiv {
+    // SIV keys are double length: 32 bytes for AES-128-SIV, 48 for AES-192 and 64 for AES-256
+    pub fn new(key: &[u8]) -> Result<Self, CryptoIoError> {
+        let name = match key.len() {
+            32 => "AES-128-SIV",
+            48 => "AES-192-SIV",
+            64 => "AES-256-SIV",
+            actual => {
+                return Err(CryptoIoError::InvalidKeyLen {
+                    expected: 64,
+                    actual,
+                })
+            }
+        };
+        Ok(AesSiv {
+            name,
+            cipher: Cipher::fetch(None, name, None)?,
+            key: key.to_vec(),
+        })
+    }
+
+    pub fn name(&self) -> &'static str {
+        self.name
+    }
+
+    // each element of `associated_data` is authenticated as a separate component, so `[a, b]`
+    // and `[a || b]` are not interchangeable. The output is the 16-byte synthetic IV followed by
+    // the ciphertext. OpenSSL can't encrypt an empty plaintext with SIV.
+    pub fn encrypt(
+        &self,
+        associated_data: &[&[u8]],
+        plaintext: &[u8],
+    ) -> Result<Vec<u8>, CryptoIoError> {
+        if plaintext.is_empty() {
+            return Err(IoError::new(ErrorKind::InvalidInput, "AES-SIV plaintext is empty").into());
+        }
+        let mut ctx = CipherCtx::new()?;
+        ctx.encrypt_init(Some(&self.cipher), Some(&self.key), None)?;
+        for ad in associated_data {
+            ctx.cipher_update(ad, None)?;
+        }
+        let mut out = vec![0; TAG_LEN + plaintext.len()];
+        let len = ctx.cipher_update(plaintext, Some(&mut out[TAG_LEN..]))?;
+        ctx.cipher_final(&mut out[TAG_LEN + len..])?;
+        ctx.tag(&mut out[..TAG_LEN])?;
+        Ok(out)
+    }
+
+    pub fn decrypt(
+        &self,
+        associated_data: &[&[u8]],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, CryptoIoError> {
+        if ciphertext.len() < TAG_LEN {
+            return Err(CryptoIoError::Truncated);
+        }
+        let (tag, ciphertext) = ciphertext.split_at(TAG_LEN);
+        let mut ctx = CipherCtx::new()?;
+        ctx.decrypt_init(Some(&self.cipher), Some(&self.key), None)?;
+        ctx.set_tag(tag)?;
+        for ad in associated_data {
+            ctx.cipher_update(ad, None)?;
+        }
+        let mut out = vec![0; ciphertext.len()];
+        // OpenSSL checks the synthetic IV as part of the update
+        let res = ctx
+            .cipher_update(ciphertext, Some(&mut out))
+            .and_then(|len| ctx.cipher_final(&mut out[len..]));
+        if res.is_err() {
+            out.zeroize();
+            return Err(CryptoIoError::BadTag);
+        }
+        Ok(out)
+    }
+}
+
+impl Drop for AesSiv {
+    fn drop(&mut self) {
+        self.key.zeroize();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AesSiv;
+    use crate::testing::hex;
+    use crate::CryptoIoError;
+
+    // RFC 5297 A.1
+    const KEY: &str = "fffefdfcfbfaf9f8f7f6f5f4f3f2f1f0f0f1f2f3f4f5f6f7f8f9fafbfcfdfeff";
+    const AD: &str = "101112131415161718191a1b1c1d1e1f2021222324252627";
+    const PLAINTEXT: &str = "112233445566778899aabbccddee";
+    const OUTPUT: &str = "85632d07c6e8f37f950acd320a2ecc9340c02b9690c4dc04daef7f6afe5c";
+
+    #[test]
+    fn rfc_5297_vector() {
+        let siv = AesSiv::new(&hex(KEY)).unwrap();
+        assert_eq!(siv.name(), "AES-128-SIV");
+        let ad = hex(AD);
+        let ciphertext = siv.encrypt(&[&ad], &hex(PLAINTEXT)).unwrap();
+        assert_eq!(ciphertext, hex(OUTPUT));
+        assert_eq!(siv.decrypt(&[&ad], &ciphertext).unwrap(), hex(PLAINTEXT));
+    }
+
+    #[test]
+    fn rejects_tampering() {
+        let siv = AesSiv::new(&hex(KEY)).unwrap();
+        let ad = hex(AD);
+        let ciphertext = hex(OUTPUT);
+        for i in [0, 16, ciphertext.len() - 1] {
+            let mut tampered = ciphertext.clone();
+            tampered[i] ^= 1;
+            let res = siv.decrypt(&[&ad], &tampered);
+            assert!(matches!(res, Err(CryptoIoError::BadTag)));
+        }
+        // the associated data is split into components, not concatenated
+        let (a, b) = ad.split_at(8);
+        assert!(matches!(
+            siv.decrypt(&[a, b], &ciphertext),
+            Err(CryptoIoError::BadTag)
+        ));
+        assert!(matches!(
+            siv.decrypt(&[&ad], &ciphertext[..15]),
+            Err(CryptoIoError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn invalid_input() {
+        assert!(matches!(
+            AesSiv::new(&[0; 16]),
+            Err(CryptoIoError::InvalidKeyLen {
+                expected: 64,
+                actual: 16
+            })
+        ));
+        assert_eq!(AesSiv::new(&[0; 64]).unwrap().name(), "AES-256-SIV");
+        assert!(AesSiv::new(&[0; 32]).unwrap().encrypt(&[], &[]).is_err());
+    }
+}
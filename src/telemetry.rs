@@ -0,0 +1,58 @@
+use std::time::Instant;
+
+use openssl::symm::Cipher;
+
+#[cfg(feature = "tracing")]
+macro_rules! event {
+    ($($arg:tt)*) => { tracing::event!($($arg)*) };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! event {
+    ($($arg:tt)*) => {};
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! enter_span {
+    ($span:expr) => {
+        let _enter = $span.clone().entered();
+    };
+}
+#[cfg(not(feature = "tracing"))]
+macro_rules! enter_span {
+    ($span:expr) => {};
+}
+
+pub(crate) fn cipher_name(cipher: Cipher) -> &'static str {
+    cipher.nid().short_name().unwrap_or("unknown")
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_encrypted(cipher: &'static str, len: usize) {
+    metrics::counter!("tokio_openssl_symm_bytes_encrypted", "cipher" => cipher)
+        .increment(len as u64);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_encrypted(_cipher: &'static str, _len: usize) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_decrypted(cipher: &'static str, len: usize) {
+    metrics::counter!("tokio_openssl_symm_bytes_decrypted", "cipher" => cipher)
+        .increment(len as u64);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_decrypted(_cipher: &'static str, _len: usize) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_finalize(cipher: &'static str, mode: &'static str, start: Instant) {
+    metrics::histogram!("tokio_openssl_symm_finalize_seconds", "cipher" => cipher, "mode" => mode)
+        .record(start.elapsed());
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_finalize(_cipher: &'static str, _mode: &'static str, _start: Instant) {}
+
+#[cfg(feature = "metrics")]
+pub(crate) fn record_tag_failure(cipher: &'static str) {
+    metrics::counter!("tokio_openssl_symm_tag_failures", "cipher" => cipher).increment(1);
+}
+#[cfg(not(feature = "metrics"))]
+pub(crate) fn record_tag_failure(_cipher: &'static str) {}
@@ -0,0 +1,143 @@
+use std::sync::Mutex;
+
+use openssl::symm::Cipher;
+
+use crate::{CryptoIoError, RandomSource, SymmetricBackend};
+
+// A reproducible stand-in for a real cipher in tests: the data is XORed with the key, repeated.
+// It offers no security whatsoever, but round-trips under the same key, garbles output under any
+// other, and needs no key management or IV.
+pub struct XorBackend {
+    key: Vec<u8>,
+    pos: usize,
+}
+impl XorBackend {
+    pub fn new(key: &[u8]) -> Self {
+        assert!(!key.is_empty(), "XorBackend key must not be empty");
+        XorBackend {
+            key: key.to_vec(),
+            pos: 0,
+        }
+    }
+}
+
+impl SymmetricBackend for XorBackend {
+    fn update(&mut self, input: &[u8], output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        for (o, i) in output.iter_mut().zip(input) {
+            *o = i ^ self.key[self.pos];
+            self.pos = (self.pos + 1) % self.key.len();
+        }
+        Ok(input.len())
+    }
+
+    fn finalize(&mut self, _output: &mut [u8]) -> Result<usize, CryptoIoError> {
+        Ok(0)
+    }
+
+    fn block_size(&self) -> usize {
+        1
+    }
+
+    fn name(&self) -> &'static str {
+        "XOR (test only)"
+    }
+}
+
+// Hands out IVs from a fixed seed, so tests that would otherwise use random IVs produce the same
+// ciphertext on every run. Never use this outside tests.
+#[derive(Clone, Debug)]
+pub struct SeededIvs {
+    state: u64,
+}
+impl SeededIvs {
+    pub fn new(seed: u64) -> Self {
+        SeededIvs { state: seed }
+    }
+
+    // splitmix64
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+
+    pub fn fill(&mut self, buf: &mut [u8]) {
+        for chunk in buf.chunks_mut(8) {
+            let bytes = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&bytes[..chunk.len()]);
+        }
+    }
+
+    // the next IV for `cipher`, or `None` if it takes none
+    pub fn next_iv(&mut self, cipher: Cipher) -> Option<Vec<u8>> {
+        let mut iv = vec![0; cipher.iv_len()?];
+        self.fill(&mut iv);
+        Some(iv)
+    }
+}
+
+// so tests can `set_random_source(Mutex::new(SeededIvs::new(seed)))`
+impl RandomSource for Mutex<SeededIvs> {
+    fn fill(&self, buf: &mut [u8]) -> Result<(), CryptoIoError> {
+        self.lock().unwrap_or_else(|e| e.into_inner()).fill(buf);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::symm::Cipher;
+
+    use super::{SeededIvs, XorBackend};
+    use crate::testing::{block_on, read_to_end, sample, shutdown, write_all};
+    use crate::{DecryptReader, EncryptWriter};
+
+    fn xor(key: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::new();
+        block_on(async {
+            let mut writer = EncryptWriter::with_backend(&mut out, XorBackend::new(key));
+            write_all(&mut writer, data).await.unwrap();
+            shutdown(&mut writer).await.unwrap();
+        });
+        out
+    }
+
+    #[test]
+    fn xor_round_trip() {
+        let plaintext = sample(1000);
+        let ciphertext = xor(b"key", &plaintext);
+        assert_eq!(ciphertext.len(), plaintext.len());
+        assert_eq!(
+            ciphertext[..3],
+            [
+                plaintext[0] ^ b'k',
+                plaintext[1] ^ b'e',
+                plaintext[2] ^ b'y'
+            ]
+        );
+        let mut reader = DecryptReader::with_backend(&ciphertext[..], XorBackend::new(b"key"));
+        assert_eq!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+        let mut reader = DecryptReader::with_backend(&ciphertext[..], XorBackend::new(b"other"));
+        assert_ne!(block_on(read_to_end(&mut reader)).unwrap(), plaintext);
+    }
+
+    #[test]
+    fn seeded_ivs() {
+        // the first splitmix64 output for seed 0
+        let mut buf = [0; 8];
+        SeededIvs::new(0).fill(&mut buf);
+        assert_eq!(u64::from_le_bytes(buf), 0xe220_a839_7b1d_cdaf);
+
+        let cipher = Cipher::aes_256_gcm();
+        let mut ivs = SeededIvs::new(42);
+        let (first, second) = (ivs.next_iv(cipher).unwrap(), ivs.next_iv(cipher).unwrap());
+        assert_eq!(first.len(), 12);
+        assert_ne!(first, second);
+        assert_eq!(SeededIvs::new(42).next_iv(cipher).unwrap(), first);
+        assert_ne!(SeededIvs::new(43).next_iv(cipher).unwrap(), first);
+        assert_eq!(ivs.next_iv(Cipher::chacha20()).unwrap().len(), 16);
+        assert!(ivs.next_iv(Cipher::aes_128_ecb()).is_none());
+    }
+}
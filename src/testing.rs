@@ -0,0 +1,99 @@
+use std::future::{poll_fn, Future};
+use std::io::Result as IoResult;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll, Wake, Waker};
+
+use openssl::symm::Cipher;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+struct NoopWake;
+impl Wake for NoopWake {
+    fn wake(self: Arc<Self>) {}
+}
+
+// Runs a future over in-memory IO to completion. Slices and `Vec`s are always ready, so a
+// future that returns Pending is waiting on something the test never provides.
+pub(crate) fn block_on<F: Future>(future: F) -> F::Output {
+    let waker = Waker::from(Arc::new(NoopWake));
+    let mut cx = Context::from_waker(&waker);
+    let mut future = Box::pin(future);
+    match future.as_mut().poll(&mut cx) {
+        Poll::Ready(a) => a,
+        Poll::Pending => panic!("test future is waiting on IO that never happens"),
+    }
+}
+
+// Runs two futures that talk to each other, e.g. the two ends of a handshake over an in-memory
+// pipe, polling each in turn until both finish. Neither making progress means each is waiting on
+// the other.
+pub(crate) fn join<A: Future, B: Future>(a: A, b: B) -> (A::Output, B::Output) {
+    let waker = Waker::from(Arc::new(NoopWake));
+    let mut cx = Context::from_waker(&waker);
+    let (mut a, mut b) = (Box::pin(a), Box::pin(b));
+    let (mut a_out, mut b_out) = (None, None);
+    for _ in 0..10_000 {
+        if a_out.is_none() {
+            if let Poll::Ready(out) = a.as_mut().poll(&mut cx) {
+                a_out = Some(out);
+            }
+        }
+        if b_out.is_none() {
+            if let Poll::Ready(out) = b.as_mut().poll(&mut cx) {
+                b_out = Some(out);
+            }
+        }
+        if let (Some(_), Some(_)) = (&a_out, &b_out) {
+            return (a_out.unwrap(), b_out.unwrap());
+        }
+    }
+    panic!("test futures are waiting on each other");
+}
+
+pub(crate) async fn write_all<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    mut data: &[u8],
+) -> IoResult<()> {
+    while !data.is_empty() {
+        let n = poll_fn(|cx| Pin::new(&mut *writer).poll_write(cx, data)).await?;
+        assert!(n > 0, "writer accepted nothing");
+        data = &data[n..];
+    }
+    Ok(())
+}
+
+pub(crate) async fn shutdown<W: AsyncWrite + Unpin>(writer: &mut W) -> IoResult<()> {
+    poll_fn(|cx| Pin::new(&mut *writer).poll_shutdown(cx)).await
+}
+
+// reads in uneven pieces, so adapters also see reads that split their frames and blocks
+pub(crate) async fn read_to_end<R: AsyncRead + Unpin>(reader: &mut R) -> IoResult<Vec<u8>> {
+    let mut out = Vec::new();
+    let mut buf = [0; 1000];
+    loop {
+        let n = poll_fn(|cx| Pin::new(&mut *reader).poll_read(cx, &mut buf)).await?;
+        if n == 0 {
+            return Ok(out);
+        }
+        out.extend_from_slice(&buf[..n]);
+    }
+}
+
+// plaintext that compresses, but not to nothing
+pub(crate) fn sample(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * i / 7 % 251) as u8).collect()
+}
+
+pub(crate) fn key_iv(cipher: Cipher) -> (Vec<u8>, Option<Vec<u8>>) {
+    (
+        vec![0x42; cipher.key_len()],
+        cipher.iv_len().map(|len| vec![0x24; len]),
+    )
+}
+
+pub(crate) fn hex(s: &str) -> Vec<u8> {
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+        .collect()
+}
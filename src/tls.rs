@@ -0,0 +1,161 @@
+use openssl::ssl::SslRef;
+use openssl::symm::Cipher;
+use zeroize::Zeroizing;
+
+use crate::{derive_stream_keys, CryptoIoError, Role, StreamKeys};
+
+const EXPORTER_LABEL: &str = "EXPORTER-tokio-openssl-symm";
+const EXPORTER_LEN: usize = 32;
+
+// Derives keys for an inner encryption layer from the keying material exporter (RFC 5705, RFC
+// 8446 section 7.5) of an established TLS session, e.g. `tokio_openssl::SslStream::ssl()`, so the
+// inner layer is bound to that session: both ends get the same keys only over the same TLS
+// connection. The role comes from which side of the handshake `ssl` was. `context` goes to the
+// exporter and must match on both ends. Exporter output obtained some other way can be passed to
+// `derive_stream_keys` directly.
+pub fn tls_stream_keys(
+    ssl: &SslRef,
+    cipher: Cipher,
+    context: &[u8],
+) -> Result<StreamKeys, CryptoIoError> {
+    let mut secret = Zeroizing::new(vec![0; EXPORTER_LEN]);
+    ssl.export_keying_material(&mut secret, EXPORTER_LABEL, Some(context))?;
+    let role = if ssl.is_server() {
+        Role::Server
+    } else {
+        Role::Client
+    };
+    derive_stream_keys(&secret, &[], cipher, role)
+}
+
+// Layers an `EncryptedStream` over an established `tokio_openssl::SslStream`, keyed from its
+// exporter with `tls_stream_keys`, for payload encryption that doesn't depend on the transport
+// TLS. The TLS stream needs an `Unpin` transport; pin one that isn't with `Box::pin` before the
+// TLS handshake.
+#[cfg(feature = "tls")]
+impl<S> crate::EncryptedStream<tokio_openssl::SslStream<S>>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    pub fn over_tls(
+        stream: tokio_openssl::SslStream<S>,
+        cipher: Cipher,
+        context: &[u8],
+    ) -> Result<Self, CryptoIoError> {
+        tls_stream_keys(stream.ssl(), cipher, context)?.encrypted_stream(stream)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::{TcpListener, TcpStream};
+    use std::thread;
+
+    use openssl::asn1::Asn1Time;
+    use openssl::ec::{EcGroup, EcKey};
+    use openssl::hash::MessageDigest;
+    use openssl::nid::Nid;
+    use openssl::pkey::PKey;
+    use openssl::ssl::{SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
+    use openssl::symm::Cipher;
+    use openssl::x509::{X509NameBuilder, X509};
+
+    use super::tls_stream_keys;
+    use crate::StreamKeys;
+
+    // a self-signed server and a client that doesn't check it
+    fn endpoints() -> (SslAcceptor, SslConnector) {
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let pkey = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", "localhost").unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&pkey).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        cert.sign(&pkey, MessageDigest::sha256()).unwrap();
+        let cert = cert.build();
+
+        let mut acceptor = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).unwrap();
+        acceptor.set_private_key(&pkey).unwrap();
+        acceptor.set_certificate(&cert).unwrap();
+        let mut connector = SslConnector::builder(SslMethod::tls()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        (acceptor.build(), connector.build())
+    }
+
+    // runs a handshake over loopback and returns (client, server) keys for `contexts`
+    fn handshake(contexts: (&'static [u8], &'static [u8])) -> (StreamKeys, StreamKeys) {
+        let cipher = Cipher::aes_256_gcm();
+        let (acceptor, connector) = endpoints();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = thread::spawn(move || {
+            let stream = acceptor.accept(listener.accept().unwrap().0).unwrap();
+            tls_stream_keys(stream.ssl(), cipher, contexts.1).unwrap()
+        });
+        let stream = connector
+            .connect("localhost", TcpStream::connect(addr).unwrap())
+            .unwrap();
+        let client = tls_stream_keys(stream.ssl(), cipher, contexts.0).unwrap();
+        (client, server.join().unwrap())
+    }
+
+    #[test]
+    fn both_ends_agree() {
+        let (client, server) = handshake((b"ctx", b"ctx"));
+        assert_eq!(client.send_key(), server.receive_key());
+        assert_eq!(client.send_iv(), server.receive_iv());
+        assert_eq!(client.receive_key(), server.send_key());
+        assert_ne!(client.send_key(), client.receive_key());
+
+        // another session gets other keys
+        let (other, _) = handshake((b"ctx", b"ctx"));
+        assert_ne!(other.send_key(), client.send_key());
+    }
+
+    #[test]
+    fn context_must_match() {
+        let (client, server) = handshake((b"ctx", b"other"));
+        assert_ne!(client.send_key(), server.receive_key());
+    }
+
+    #[cfg(feature = "tls")]
+    #[test]
+    fn over_tls() {
+        use crate::testing::{join, read_to_end, sample, shutdown, write_all};
+        use crate::EncryptedStream;
+
+        let cipher = Cipher::chacha20_poly1305();
+        let (acceptor, connector) = endpoints();
+        let config = connector.configure().unwrap();
+        let (a, b) = tokio::io::duplex(4096);
+        let (client, server) = join(
+            tokio_openssl::connect(config, "localhost", a),
+            tokio_openssl::accept(&acceptor, b),
+        );
+        let mut client = EncryptedStream::over_tls(client.unwrap(), cipher, b"ctx").unwrap();
+        let mut server = EncryptedStream::over_tls(server.unwrap(), cipher, b"ctx").unwrap();
+        let (ping, pong) = (sample(20_000), sample(10_000));
+        let (read_by_client, read_by_server) = join(
+            async {
+                write_all(&mut client, &ping).await?;
+                shutdown(&mut client).await?;
+                read_to_end(&mut client).await
+            },
+            async {
+                let read = read_to_end(&mut server).await?;
+                write_all(&mut server, &pong).await?;
+                shutdown(&mut server).await?;
+                Ok::<_, std::io::Error>(read)
+            },
+        );
+        assert_eq!(read_by_server.unwrap(), ping);
+        assert_eq!(read_by_client.unwrap(), pong);
+    }
+}
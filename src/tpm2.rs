@@ -0,0 +1,88 @@
+use std::ffi::OsStr;
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::process::Command;
+
+use tokio::task::spawn_blocking;
+use zeroize::Zeroizing;
+
+use crate::{CryptoIoError, KeyId, KeyProvider};
+
+// Unseals keys held in TPM 2.0 sealed objects whose policy binds them to PCR values, so a key
+// (e.g. for backups on an edge device) can only be recovered on that device, booted the expected
+// way. It runs `tpm2_unseal` from tpm2-tools rather than linking the TSS libraries. The `KeyId`
+// is what `tpm2_unseal -c` takes: the path of a loaded object's context file, or a persistent
+// handle such as `0x81010001`. Sealing is left to tpm2-tools (`tpm2_createpolicy --policy-pcr`,
+// then `tpm2_create -i key.bin -L policy.dat`), with the same PCR selection as here.
+#[derive(Clone, Debug)]
+pub struct Tpm2Unsealer {
+    tool: PathBuf,
+    pcrs: String,
+    tcti: Option<String>,
+}
+impl Tpm2Unsealer {
+    // `pcrs` is the PCR selection the key was sealed to, e.g. `sha256:0,2,4,7`
+    pub fn new(pcrs: &str) -> Self {
+        Tpm2Unsealer {
+            tool: PathBuf::from("tpm2_unseal"),
+            pcrs: pcrs.to_owned(),
+            tcti: None,
+        }
+    }
+
+    // runs `tool` instead of looking up `tpm2_unseal` in PATH
+    pub fn with_tool(mut self, tool: impl Into<PathBuf>) -> Self {
+        self.tool = tool.into();
+        self
+    }
+
+    // how to reach the TPM, e.g. `device:/dev/tpmrm0` or `swtpm:port=2321`, rather than the
+    // tools' default
+    pub fn with_tcti(mut self, tcti: &str) -> Self {
+        self.tcti = Some(tcti.to_owned());
+        self
+    }
+
+    fn unseal(&self, object: &[u8]) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+        let mut command = Command::new(&self.tool);
+        command
+            .arg("-c")
+            .arg(OsStr::from_bytes(object))
+            .arg("-p")
+            .arg(format!("pcr:{}", self.pcrs));
+        if let Some(tcti) = &self.tcti {
+            command.arg("-T").arg(tcti);
+        }
+        let output = command.output().map_err(|e| {
+            IoError::new(
+                e.kind(),
+                format!("failed to run {}: {}", self.tool.display(), e),
+            )
+        })?;
+        let key = Zeroizing::new(output.stdout);
+        if !output.status.success() {
+            // most often a policy failure: the PCRs no longer hold the values sealed to
+            return Err(IoError::new(
+                IoErrorKind::PermissionDenied,
+                format!(
+                    "tpm2_unseal failed ({}): {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                ),
+            )
+            .into());
+        }
+        Ok(key)
+    }
+}
+
+impl KeyProvider for Tpm2Unsealer {
+    async fn key_for(&self, key_id: &KeyId) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+        let unsealer = self.clone();
+        let object = key_id.as_bytes().to_vec();
+        spawn_blocking(move || unsealer.unseal(&object))
+            .await
+            .map_err(IoError::other)?
+    }
+}
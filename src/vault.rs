@@ -0,0 +1,115 @@
+use std::io::{Error as IoError, ErrorKind as IoErrorKind};
+
+use openssl::base64::decode_block;
+use reqwest::Client;
+use serde_json::{json, Value};
+use zeroize::{Zeroize, Zeroizing};
+
+use crate::{CryptoIoError, KeyId, KeyProvider};
+
+// Wraps and unwraps content keys with a named key in Vault's transit secrets engine, so only
+// wrapped keys are ever stored and Vault controls who may unwrap them. `generate_key` creates a
+// content key and its wrapped form; the wrapped form (`vault:v1:...`) is the `KeyId` that
+// `key_for` later unwraps.
+pub struct VaultTransit {
+    client: Client,
+    addr: String,
+    token: Zeroizing<String>,
+    key_name: String,
+}
+impl VaultTransit {
+    // `addr` is the Vault server's base URL, e.g. `https://vault.example.com:8200`
+    pub fn new(addr: &str, token: &str, key_name: &str) -> Self {
+        VaultTransit {
+            client: Client::new(),
+            addr: addr.trim_end_matches('/').to_owned(),
+            token: Zeroizing::new(token.to_owned()),
+            key_name: key_name.to_owned(),
+        }
+    }
+
+    async fn call(&self, endpoint: &str, body: Value) -> Result<Value, CryptoIoError> {
+        let url = format!("{}/v1/transit/{}/{}", self.addr, endpoint, self.key_name);
+        let res = self
+            .client
+            .post(&url)
+            .header("X-Vault-Token", self.token.as_str())
+            .json(&body)
+            .send()
+            .await
+            .map_err(IoError::other)?;
+        let status = res.status();
+        let body: Value = res.json().await.map_err(IoError::other)?;
+        if !status.is_success() {
+            let kind = match status.as_u16() {
+                403 => IoErrorKind::PermissionDenied,
+                404 => IoErrorKind::NotFound,
+                _ => IoErrorKind::Other,
+            };
+            let errors = body["errors"].as_array().map(|errors| {
+                let errors: Vec<_> = errors.iter().filter_map(Value::as_str).collect();
+                errors.join("; ")
+            });
+            return Err(IoError::new(
+                kind,
+                format!(
+                    "vault transit {} failed with {}: {}",
+                    endpoint,
+                    status,
+                    errors.as_deref().unwrap_or("no details")
+                ),
+            )
+            .into());
+        }
+        Ok(body)
+    }
+
+    // a fresh `bits`-bit content key, and its wrapped form to store alongside the data
+    pub async fn generate_key(
+        &self,
+        bits: u32,
+    ) -> Result<(Zeroizing<Vec<u8>>, KeyId), CryptoIoError> {
+        let mut res = self
+            .call("datakey/plaintext", json!({ "bits": bits }))
+            .await?;
+        let key = plaintext(&mut res)?;
+        let wrapped = res["data"]["ciphertext"]
+            .as_str()
+            .ok_or_else(|| malformed("ciphertext"))?;
+        Ok((key, KeyId::from(wrapped)))
+    }
+}
+
+fn malformed(field: &str) -> CryptoIoError {
+    IoError::new(
+        IoErrorKind::InvalidData,
+        format!("vault transit response has no {}", field),
+    )
+    .into()
+}
+
+// decodes `data.plaintext`, scrubbing the base64 copy from the response
+fn plaintext(res: &mut Value) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+    let mut encoded = match res["data"]["plaintext"].take() {
+        Value::String(a) => a,
+        _ => return Err(malformed("plaintext")),
+    };
+    let key = decode_block(&encoded).map(Zeroizing::new);
+    encoded.zeroize();
+    Ok(key?)
+}
+
+impl KeyProvider for VaultTransit {
+    async fn key_for(&self, key_id: &KeyId) -> Result<Zeroizing<Vec<u8>>, CryptoIoError> {
+        let wrapped = std::str::from_utf8(key_id.as_bytes()).map_err(|_| {
+            IoError::new(
+                IoErrorKind::InvalidInput,
+                "vault transit key IDs are wrapped keys (vault:v1:...)",
+            )
+        })?;
+        let mut res = self
+            .call("decrypt", json!({ "ciphertext": wrapped }))
+            .await?;
+        plaintext(&mut res)
+    }
+}
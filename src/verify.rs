@@ -0,0 +1,179 @@
+use std::future::poll_fn;
+use std::io::Result as IoResult;
+use std::task::Poll;
+
+use openssl::symm::Cipher;
+use tokio::io::AsyncRead;
+
+use crate::{
+    CryptoIoError, DecryptCore, DecryptReader, Footer, Metadata, OpensslBackend, SymmetricBackend,
+};
+
+const CHUNK_LEN: usize = 64 * 1024;
+
+// Reads a stream to the end to check its tag, MAC and footer (whichever the core expects)
+// without handing out any plaintext, for integrity audits of encrypted archives by operators who
+// should not see their contents. Plaintext is zeroed and dropped as soon as it is decrypted; only
+// its length, digest, footer and metadata can be asked for.
+pub struct VerifyReader<R, B = OpensslBackend> {
+    reader: DecryptReader<R, B>,
+}
+impl<R> VerifyReader<R> {
+    pub fn new(
+        reader: R,
+        cipher: Cipher,
+        key: &[u8],
+        iv: Option<&[u8]>,
+    ) -> Result<Self, CryptoIoError> {
+        Ok(VerifyReader {
+            reader: DecryptReader::new(reader, cipher, key, iv)?,
+        })
+    }
+}
+
+impl<R, B> VerifyReader<R, B>
+where
+    B: SymmetricBackend,
+{
+    // checks the ciphertext read from `reader` against everything `core` is set up to expect
+    pub fn with_core(reader: R, core: DecryptCore<B>) -> Self {
+        VerifyReader {
+            reader: DecryptReader::with_core(reader, core),
+        }
+    }
+}
+
+impl<R, B> VerifyReader<R, B> {
+    pub(crate) fn from_reader(reader: DecryptReader<R, B>) -> Self {
+        VerifyReader { reader }
+    }
+
+    pub fn digest(&self) -> Option<&[u8]> {
+        self.reader.digest()
+    }
+
+    pub fn trailer(&self) -> Option<&Footer> {
+        self.reader.trailer()
+    }
+
+    pub fn metadata(&self) -> Option<&Metadata> {
+        self.reader.metadata()
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader.reader
+    }
+}
+
+impl<R, B> VerifyReader<R, B>
+where
+    R: AsyncRead + Unpin,
+    B: SymmetricBackend + Unpin,
+{
+    // reads the rest of the stream, returning the length of the plaintext it holds once that has
+    // been verified; later calls return the same length without reading more
+    pub async fn verify(&mut self) -> IoResult<u64> {
+        let reader = &mut self.reader;
+        let mut chunk = vec![0; CHUNK_LEN];
+        poll_fn(|cx| unsafe {
+            enter_span!(reader.span);
+            if let Err(e) = reader.poison.check() {
+                return Poll::Ready(Err(e));
+            }
+            let res = loop {
+                let n = reader.core.plaintext().len();
+                reader.core.consume(n);
+                if reader.core.is_finalized() {
+                    break Poll::Ready(Ok(()));
+                }
+                match reader.poll_fill(cx, &mut chunk) {
+                    Poll::Ready(Ok(())) => (),
+                    res => break res,
+                }
+            };
+            reader.poison.track(
+                res,
+                reader.core.plaintext_bytes(),
+                reader.core.ciphertext_bytes(),
+            )
+        })
+        .await?;
+        Ok(self.reader.core.plaintext_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use openssl::hash::MessageDigest;
+    use openssl::sha::sha256;
+    use openssl::symm::Cipher;
+
+    use super::VerifyReader;
+    use crate::testing::{block_on, key_iv, sample};
+    use crate::{CryptoIoError, DecryptCore, DecryptReader, EncryptCore};
+
+    fn seal(cipher: Cipher, plaintext: &[u8]) -> Vec<u8> {
+        let (key, iv) = key_iv(cipher);
+        let mut core = EncryptCore::new(cipher, &key, iv.as_deref())
+            .unwrap()
+            .with_footer()
+            .unwrap();
+        core.push_plaintext(plaintext).unwrap();
+        core.finish().unwrap();
+        core.take_ciphertext()
+    }
+
+    fn checker(cipher: Cipher, ciphertext: &[u8]) -> VerifyReader<&[u8]> {
+        let (key, iv) = key_iv(cipher);
+        let core = DecryptCore::new(cipher, &key, iv.as_deref())
+            .unwrap()
+            .with_footer()
+            .unwrap()
+            .with_digest(MessageDigest::sha256())
+            .unwrap();
+        VerifyReader::with_core(ciphertext, core)
+    }
+
+    // longer than a chunk, so verification takes several reads
+    #[test]
+    fn verifies_without_plaintext() {
+        let plaintext = sample(200_000);
+        for cipher in [Cipher::aes_256_gcm(), Cipher::aes_128_cbc()] {
+            let ciphertext = seal(cipher, &plaintext);
+            let mut verifier = checker(cipher, &ciphertext);
+            assert_eq!(block_on(verifier.verify()).unwrap(), 200_000);
+            assert_eq!(block_on(verifier.verify()).unwrap(), 200_000);
+            assert_eq!(verifier.digest().unwrap(), sha256(&plaintext));
+            let footer = verifier.trailer().unwrap();
+            assert_eq!(footer.plaintext_len, 200_000);
+            assert_eq!(footer.sha256, sha256(&plaintext));
+            assert!(verifier.into_inner().is_empty());
+
+            let (key, iv) = key_iv(cipher);
+            let reader = DecryptReader::new(&ciphertext[..], cipher, &key, iv.as_deref())
+                .unwrap()
+                .with_footer()
+                .unwrap();
+            assert_eq!(block_on(reader.verify_only().verify()).unwrap(), 200_000);
+        }
+    }
+
+    #[test]
+    fn fails_on_tampering() {
+        let cipher = Cipher::aes_256_gcm();
+        let mut ciphertext = seal(cipher, &sample(100_000));
+        ciphertext[50_000] ^= 1;
+        let mut verifier = checker(cipher, &ciphertext);
+        let e = block_on(verifier.verify()).unwrap_err();
+        assert!(matches!(
+            CryptoIoError::from_io(&e).map(|e| e.root()),
+            Some(CryptoIoError::BadTag)
+        ));
+        // and it stays failed
+        assert!(block_on(verifier.verify()).is_err());
+
+        let truncated = seal(cipher, &sample(1000));
+        let mut truncated = checker(cipher, &truncated[..500]);
+        assert!(block_on(truncated.verify()).is_err());
+    }
+}
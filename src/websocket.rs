@@ -0,0 +1,209 @@
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::Bytes;
+use futures_core::Stream;
+use futures_sink::Sink;
+use tungstenite::{Error as WsError, Message};
+
+use crate::MessageCipher;
+
+// Wraps a WebSocket (such as a `tokio_tungstenite::WebSocketStream`, or either half of one) so
+// every Binary message is sealed with `cipher` on the way out and opened on the way in, each
+// under its own random nonce, for end-to-end encryption across proxies or load balancers that
+// terminate WSS. Text, ping, pong and close frames pass through as they are. A received Binary
+// message that fails to open is returned as an `Io` error carrying the `CryptoIoError`.
+pub struct EncryptedWebSocket<S> {
+    inner: S,
+    cipher: MessageCipher,
+}
+impl<S> EncryptedWebSocket<S> {
+    pub fn new(inner: S, cipher: MessageCipher) -> Self {
+        EncryptedWebSocket { inner, cipher }
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S> Stream for EncryptedWebSocket<S>
+where
+    S: Stream<Item = Result<Message, WsError>>,
+{
+    type Item = Result<Message, WsError>;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            match Pin::new_unchecked(&mut inner.inner).poll_next(cx) {
+                Poll::Ready(Some(Ok(Message::Binary(data)))) => {
+                    let res = match inner.cipher.decrypt_message(Bytes::from(data)) {
+                        Ok(plaintext) => Ok(Message::Binary(plaintext.to_vec())),
+                        Err(e) => {
+                            event!(tracing::Level::ERROR, error = %e, "websocket message failed to open");
+                            Err(WsError::Io(e.into()))
+                        }
+                    };
+                    Poll::Ready(Some(res))
+                }
+                a => a,
+            }
+        }
+    }
+}
+
+impl<S> Sink<Message> for EncryptedWebSocket<S>
+where
+    S: Sink<Message, Error = WsError>,
+{
+    type Error = WsError;
+
+    fn poll_ready(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        unsafe { Pin::new_unchecked(&mut self.get_unchecked_mut().inner).poll_ready(cx) }
+    }
+
+    fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), WsError> {
+        unsafe {
+            let inner = self.get_unchecked_mut();
+            let item = match item {
+                Message::Binary(data) => match inner.cipher.encrypt_message(&data) {
+                    Ok(sealed) => Message::Binary(sealed.to_vec()),
+                    Err(e) => return Err(WsError::Io(e.into())),
+                },
+                item => item,
+            };
+            Pin::new_unchecked(&mut inner.inner).start_send(item)
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        unsafe { Pin::new_unchecked(&mut self.get_unchecked_mut().inner).poll_flush(cx) }
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+        unsafe { Pin::new_unchecked(&mut self.get_unchecked_mut().inner).poll_close(cx) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::future::poll_fn;
+    use std::pin::Pin;
+    use std::task::{Context, Poll};
+
+    use futures_core::Stream;
+    use futures_sink::Sink;
+    use openssl::symm::Cipher;
+    use tungstenite::{Error as WsError, Message};
+
+    use super::EncryptedWebSocket;
+    use crate::testing::block_on;
+    use crate::{CryptoIoError, MessageCipher};
+
+    // a socket whose peer echoes everything sent
+    #[derive(Default)]
+    struct Echo(VecDeque<Message>);
+    impl Stream for Echo {
+        type Item = Result<Message, WsError>;
+
+        fn poll_next(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+            Poll::Ready(self.get_mut().0.pop_front().map(Ok))
+        }
+    }
+    impl Sink<Message> for Echo {
+        type Error = WsError;
+
+        fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn start_send(self: Pin<&mut Self>, item: Message) -> Result<(), WsError> {
+            self.get_mut().0.push_back(item);
+            Ok(())
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), WsError>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn socket() -> EncryptedWebSocket<Echo> {
+        let cipher = MessageCipher::new(Cipher::aes_256_gcm(), &[7; 32]).unwrap();
+        EncryptedWebSocket::new(Echo::default(), cipher)
+    }
+
+    fn send(socket: &mut EncryptedWebSocket<Echo>, message: Message) {
+        Pin::new(&mut *socket).start_send(message).unwrap();
+    }
+
+    fn next(socket: &mut EncryptedWebSocket<Echo>) -> Option<Result<Message, WsError>> {
+        block_on(poll_fn(|cx| Pin::new(&mut *socket).poll_next(cx)))
+    }
+
+    #[test]
+    fn seals_binary_messages() {
+        let mut socket = socket();
+        send(&mut socket, Message::Binary(b"secret".to_vec()));
+        send(&mut socket, Message::Text("hello".into()));
+        send(&mut socket, Message::Ping(vec![1]));
+        let wire: Vec<_> = socket.get_ref().0.iter().cloned().collect();
+        match &wire[0] {
+            Message::Binary(sealed) => {
+                assert_eq!(sealed.len(), 6 + 28);
+                assert!(!sealed.windows(6).any(|w| w == b"secret"));
+            }
+            m => panic!("sent {:?}", m),
+        }
+        assert_eq!(
+            wire[1..],
+            [Message::Text("hello".into()), Message::Ping(vec![1])]
+        );
+
+        assert_eq!(
+            next(&mut socket).unwrap().unwrap(),
+            Message::Binary(b"secret".to_vec())
+        );
+        assert_eq!(
+            next(&mut socket).unwrap().unwrap(),
+            Message::Text("hello".into())
+        );
+        assert_eq!(next(&mut socket).unwrap().unwrap(), Message::Ping(vec![1]));
+        assert!(next(&mut socket).is_none());
+    }
+
+    #[test]
+    fn tampered_messages_fail() {
+        let mut socket = socket();
+        send(&mut socket, Message::Binary(b"secret".to_vec()));
+        if let Some(Message::Binary(sealed)) = socket.get_mut().0.front_mut() {
+            sealed[20] ^= 1;
+        }
+        match next(&mut socket) {
+            Some(Err(WsError::Io(e))) => assert!(matches!(
+                CryptoIoError::from_io(&e),
+                Some(CryptoIoError::BadTag)
+            )),
+            m => panic!("received {:?}", m),
+        }
+        // unsealed binary messages are too short to open
+        socket
+            .get_mut()
+            .0
+            .push_back(Message::Binary(b"plain".to_vec()));
+        assert!(matches!(next(&mut socket), Some(Err(WsError::Io(_)))));
+    }
+}